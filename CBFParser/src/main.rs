@@ -106,7 +106,10 @@ fn decode_ecu(e: &ECU) {
                     common::schema::ServerType::UDS
                 } else {
                      common::schema::ServerType::KWP2000
-                }
+                },
+                p2_timeout: x.get_cp_by_name("CP_P2_TIMEOUT"),
+                p2_ext_timeout: x.get_cp_by_name("CP_P2_EXT_TIMEOUT_7F_78"),
+                s3_timer: x.get_cp_by_name("CP_S3_TP_PHYS_TIMER"),
             }
         } else {
             // Assume LIN
@@ -120,7 +123,10 @@ fn decode_ecu(e: &ECU) {
                     max_segment_size: x.get_cp_by_name("CP_SEGMENTSIZE").unwrap_or(254), // Default for ISO14230
                     wake_up_method: common::schema::LinWakeUpType::FiveBaudInit, // MB always uses this with KWP2000 LIN
                 },
-                server_type: common::schema::ServerType::KWP2000 // Always with LIN
+                server_type: common::schema::ServerType::KWP2000, // Always with LIN
+                p2_timeout: x.get_cp_by_name("CP_P2_TIMEOUT"),
+                p2_ext_timeout: x.get_cp_by_name("CP_P2_EXT_TIMEOUT_7F_78"),
+                s3_timer: x.get_cp_by_name("CP_S3_TP_PHYS_TIMER"),
             }
         };
         connections.push(connection);
@@ -139,6 +145,7 @@ fn decode_ecu(e: &ECU) {
             adjustments: Vec::new(),
             actuations: Vec::new(),
             functions: Vec::new(),
+            routines: Vec::new(),
             downloads: Vec::new(),
         };
         
@@ -249,14 +256,14 @@ fn decode_ecu(e: &ECU) {
                 match s.service_type {
                     ServiceType::Data | ServiceType::StoredData => ecu_variant.downloads.push(service),
                     ServiceType::DiagnosticFunction => ecu_variant.functions.push(service),
-                    ServiceType::Routine => ecu_variant.functions.push(service),
+                    ServiceType::Routine => ecu_variant.routines.push(service),
                     _ => {
                         
                     }
                 }
             }
         });
-        println!("Data: {}, Diag Func: {}, Routine: {}", ecu_variant.downloads.len(), ecu_variant.functions.len(), ecu_variant.functions.len());
+        println!("Data: {}, Diag Func: {}, Routine: {}", ecu_variant.downloads.len(), ecu_variant.functions.len(), ecu_variant.routines.len());
 
         // We need to cleanup the data functions. Seems MB has multiple functions that all use the same payload
         // Except output params differ
@@ -301,7 +308,7 @@ fn decode_ecu(e: &ECU) {
     }
     println!("SORTED");
     for v in &ecu.variants {
-        println!("Data: {}, Diag Func: {}, Routine: {}", v.downloads.len(), v.functions.len(), v.functions.len());
+        println!("Data: {}, Diag Func: {}, Routine: {}", v.downloads.len(), v.functions.len(), v.routines.len());
     }
     println!("Writing to file");
     let mut f = File::create(format!("{}.json", ecu.name)).expect("Cannot open output file");
@@ -4,11 +4,34 @@ use std::fmt::Debug;
 use std::time::Instant;
 use std::{fmt::Formatter, result::Result};
 
-#[derive(Debug, Copy, Clone, Default)]
+/// Valid CAN FD data lengths, indexed by their DLC
+const CAN_FD_LENGTHS: [usize; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 12, 16, 20, 24, 32, 48, 64];
+
+#[derive(Debug, Copy, Clone)]
 pub struct CanFrame {
     pub id: u32,
+    /// Number of data bytes in the frame (Not the DLC code for CAN FD frames)
     pub dlc: u8,
-    data: [u8; 8],
+    data: [u8; 64],
+    /// Frame is a CAN FD frame
+    pub fd: bool,
+    /// CAN FD bit rate switch. Data phase is sent at the higher bit rate
+    pub brs: bool,
+    /// CAN FD error state indicator. Set by a transmitter that is error passive
+    pub esi: bool,
+}
+
+impl Default for CanFrame {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            dlc: 0,
+            data: [0; 64],
+            fd: false,
+            brs: false,
+            esi: false,
+        }
+    }
 }
 
 impl CanFrame {
@@ -17,36 +40,74 @@ impl CanFrame {
     }
     pub fn new(id: u32, data: &[u8]) -> Self {
         let dlc = min(data.len(), 8) as usize;
-        let mut can_data: [u8; 8] = [0; 8];
-        can_data[0..dlc].copy_from_slice(&data[0..dlc]);
-        Self {
+        let mut res = Self {
             id,
             dlc: dlc as u8,
-            data: can_data,
+            ..Default::default()
+        };
+        res.data[0..dlc].copy_from_slice(&data[0..dlc]);
+        res
+    }
+
+    /// Creates a CAN FD frame. CAN FD frames can only be certain lengths, so if the
+    /// data does not fit one exactly, it is padded with 0x00 up to the next valid length
+    pub fn new_fd(id: u32, data: &[u8], brs: bool, esi: bool) -> Self {
+        let len = min(data.len(), 64);
+        let mut res = Self {
+            id,
+            dlc: Self::fd_padded_len(len) as u8,
+            fd: true,
+            brs,
+            esi,
+            ..Default::default()
+        };
+        res.data[0..len].copy_from_slice(&data[0..len]);
+        res
+    }
+
+    /// Returns the 4 bit DLC code that is sent on the bus for this frame
+    pub fn get_dlc_code(&self) -> u8 {
+        Self::len_to_dlc_code(self.dlc as usize)
+    }
+
+    /// Converts a data length to the smallest DLC code that can hold it
+    pub fn len_to_dlc_code(len: usize) -> u8 {
+        CAN_FD_LENGTHS
+            .iter()
+            .position(|l| *l >= len)
+            .unwrap_or(CAN_FD_LENGTHS.len() - 1) as u8
+    }
+
+    /// Converts a DLC code to the number of data bytes. On classic CAN, codes
+    /// above 8 still mean 8 bytes
+    pub fn dlc_code_to_len(dlc: u8, fd: bool) -> usize {
+        let len = CAN_FD_LENGTHS[(dlc & 0x0F) as usize];
+        if fd {
+            len
+        } else {
+            min(len, 8)
         }
     }
+
+    /// Returns the smallest valid CAN FD frame length that can hold `len` bytes
+    pub fn fd_padded_len(len: usize) -> usize {
+        CAN_FD_LENGTHS[Self::len_to_dlc_code(len) as usize]
+    }
 }
 
 #[cfg(target_os = "linux")]
 impl From<CanFrame> for socketcan::CANFrame {
     fn from(s: CanFrame) -> Self {
-        Self::new(s.id, s.get_data(), false, false).unwrap()
+        // socketcan::CANFrame can only hold classic CAN frames
+        let data = s.get_data();
+        Self::new(s.id, &data[0..min(data.len(), 8)], false, false).unwrap()
     }
 }
 
 #[cfg(target_os = "linux")]
 impl From<socketcan::CANFrame> for CanFrame {
     fn from(s: socketcan::CANFrame) -> Self {
-        let data = s.data();
-        let mut res = Self {
-            id: s.id(),
-            dlc: data.len() as u8,
-            data: [0, 0, 0, 0, 0, 0, 0, 0],
-        };
-        for x in 0..data.len() {
-            res.data[x] = data[x];
-        }
-        res
+        Self::new(s.id(), s.data())
     }
 }
 
@@ -57,8 +118,13 @@ impl std::fmt::Display for CanFrame {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "ID: 0x{:04X} Data: {:02X?}",
+            "ID: 0x{:04X}{} Data: {:02X?}",
             self.id,
+            match (self.fd, self.brs) {
+                (true, true) => " (FD, BRS)",
+                (true, false) => " (FD)",
+                _ => "",
+            },
             &self.data[0..self.dlc as usize]
         )
     }
@@ -85,6 +151,39 @@ impl std::fmt::Display for ISO15765Data {
 unsafe impl Send for ISO15765Data {}
 unsafe impl Sync for ISO15765Data {}
 
+/// Raw K-Line message (ISO9141 / ISO14230). The data contains the message header
+/// followed by the payload. The checksum is appended and verified by the adapter.
+#[derive(Clone, Debug)]
+pub struct KLineData {
+    pub(crate) data: Vec<u8>,
+}
+
+impl std::fmt::Display for KLineData {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "K-Line: {:02X?}", self.data)
+    }
+}
+
+unsafe impl Send for KLineData {}
+unsafe impl Sync for KLineData {}
+
+/// Raw SAE J1850 message (VPW or PWM). The data contains the 3 byte header
+/// (Priority/type, target address, source address) followed by the payload.
+/// The CRC is appended and verified by the adapter.
+#[derive(Clone, Debug)]
+pub struct J1850Data {
+    pub(crate) data: Vec<u8>,
+}
+
+impl std::fmt::Display for J1850Data {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "J1850: {:02X?}", self.data)
+    }
+}
+
+unsafe impl Send for J1850Data {}
+unsafe impl Sync for J1850Data {}
+
 #[derive(Clone, Copy, Debug)]
 pub struct ISO15765Config {
     pub baud: u32,
@@ -151,6 +250,8 @@ pub struct DeviceCapabilities {
     pub(crate) j1850pwm: Capability,
     /// Supports regular CAN
     pub(crate) can: Capability,
+    /// Supports CAN FD
+    pub(crate) can_fd: Capability,
     /// Supports ISO15765 (ISO-TP)
     pub(crate) iso15765: Capability,
     /// Supports K-Line OBD ISO9141
@@ -174,9 +275,12 @@ impl DeviceCapabilities {
         self.library_path.clone()
     }
 
-    pub fn support_can_fd(&self) -> Capability {
+    pub fn supports_can(&self) -> Capability {
         self.can
     }
+    pub fn support_can_fd(&self) -> Capability {
+        self.can_fd
+    }
     pub fn supports_iso15765(&self) -> Capability {
         self.iso15765
     }
@@ -360,6 +464,213 @@ pub trait ComServer: Send + Sync + Debug {
         block_size: u32,
     ) -> Result<(), ComServerError>;
 
+    /// Tells the adapter to use CAN FD frames (ISO15765-2:2016) on the open ISO15765 channel.
+    /// This allows for single frames of up to 62 bytes, and first frames with the
+    /// escape sequence for payloads over 4095 bytes
+    ///
+    /// ## Params
+    /// * tx_dl - Maximum frame length to send (8, 12, 16, 20, 24, 32, 48 or 64)
+    /// * brs - Use bit rate switching for the data phase of frames
+    fn set_iso15765_fd_params(&mut self, tx_dl: u32, brs: bool) -> Result<(), ComServerError>;
+
+    /// Attempts to open a K-Line ISO14230 (KWP2000) interface with the adapter to the vehicles OBD-II port
+    ///
+    /// ## Params
+    /// * `baud` - Speed of the K-Line in bps, typically 10400
+    fn open_iso14230_interface(&mut self, baud: u32) -> Result<(), ComServerError>;
+
+    /// Attempts to destroy the ISO14230 Interface on the adapter
+    fn close_iso14230_interface(&mut self) -> Result<(), ComServerError>;
+
+    /// Wakes up the K-Line using the fast init pattern (25ms low, 25ms high), followed
+    /// by sending the StartCommunication request to the ECU.
+    ///
+    /// ## Params
+    /// * `init_msg` - The StartCommunication request, including its header. The checksum is added by the adapter
+    ///
+    /// ## Returns
+    /// The ECU's StartCommunication response, including its header
+    fn iso14230_fast_init(&mut self, init_msg: &[u8]) -> Result<Vec<u8>, ComServerError>;
+
+    /// Wakes up the K-Line by sending the ECU's address at 5 baud.
+    ///
+    /// ## Params
+    /// * `address` - Address of the ECU to wake up
+    ///
+    /// ## Returns
+    /// The 2 key bytes sent by the ECU
+    fn iso14230_five_baud_init(&mut self, address: u8) -> Result<Vec<u8>, ComServerError>;
+
+    /// Sends a list of raw K-Line messages over an open ISO14230 channel
+    ///
+    /// ## Params
+    /// * `data` - List of K-Line messages to send. Each message must already contain its header
+    /// * `timeout_ms` - Timeout for waiting for conformation from the adapter. A value of 0
+    ///                will tell the adapter to queue to messages and return instantly, meaning
+    ///                no conformation is provided
+    ///
+    /// ## Returns
+    /// The number of messages successfully written to the vehicle
+    fn send_iso14230_data(
+        &self,
+        data: &[KLineData],
+        timeout_ms: u32,
+    ) -> Result<usize, ComServerError>;
+
+    /// Attempts to read a list of [K-Line messages](KLineData) from an open ISO14230 channel.
+    ///
+    /// ## Params
+    /// * timeout_ms - Timeout for waiting for data from the vehicle. A value of 0 tells the adapter
+    /// to return whatever data it has in its Rx queue, and don't wait for any more
+    ///
+    /// * max_msgs - The maximum number of messages to read from the adapter.
+    fn read_iso14230_packets(
+        &self,
+        timeout_ms: u32,
+        max_msgs: usize,
+    ) -> Result<Vec<KLineData>, ComServerError>;
+
+    /// Tells the adapter the K-Line timing parameters to use on an active ISO14230 channel.
+    /// All values are in milliseconds
+    ///
+    /// # Params
+    /// * p1_max - Maximum inter-byte time of messages sent by the ECU
+    /// * p3_min - Minimum time between the end of an ECU response and the next request
+    /// * p4_min - Minimum inter-byte time of messages sent by the tester
+    fn set_iso14230_params(
+        &mut self,
+        p1_max: u32,
+        p3_min: u32,
+        p4_min: u32,
+    ) -> Result<(), ComServerError>;
+
+    /// Attempts to open a K-Line ISO9141 interface with the adapter to the vehicles OBD-II port
+    ///
+    /// ## Params
+    /// * `baud` - Speed of the K-Line in bps, typically 10400
+    fn open_iso9141_interface(&mut self, baud: u32) -> Result<(), ComServerError>;
+
+    /// Attempts to destroy the ISO9141 Interface on the adapter
+    fn close_iso9141_interface(&mut self) -> Result<(), ComServerError>;
+
+    /// Wakes up the K-Line by sending the ECU's address at 5 baud.
+    ///
+    /// ## Params
+    /// * `address` - Address to wake up. 0x33 for OBD-II
+    ///
+    /// ## Returns
+    /// The 2 key bytes sent by the ECU
+    fn iso9141_five_baud_init(&mut self, address: u8) -> Result<Vec<u8>, ComServerError>;
+
+    /// Sends a list of raw K-Line messages over an open ISO9141 channel
+    ///
+    /// ## Params
+    /// * `data` - List of K-Line messages to send. Each message must already contain its header
+    /// * `timeout_ms` - Timeout for waiting for conformation from the adapter. A value of 0
+    ///                will tell the adapter to queue to messages and return instantly, meaning
+    ///                no conformation is provided
+    ///
+    /// ## Returns
+    /// The number of messages successfully written to the vehicle
+    fn send_iso9141_data(
+        &self,
+        data: &[KLineData],
+        timeout_ms: u32,
+    ) -> Result<usize, ComServerError>;
+
+    /// Attempts to read a list of [K-Line messages](KLineData) from an open ISO9141 channel.
+    ///
+    /// ## Params
+    /// * timeout_ms - Timeout for waiting for data from the vehicle. A value of 0 tells the adapter
+    /// to return whatever data it has in its Rx queue, and don't wait for any more
+    ///
+    /// * max_msgs - The maximum number of messages to read from the adapter.
+    fn read_iso9141_packets(
+        &self,
+        timeout_ms: u32,
+        max_msgs: usize,
+    ) -> Result<Vec<KLineData>, ComServerError>;
+
+    /// Tells the adapter the K-Line timing parameters to use on an active ISO9141 channel.
+    /// All values are in milliseconds. See [set_iso14230_params](fn@set_iso14230_params)
+    fn set_iso9141_params(
+        &mut self,
+        p1_max: u32,
+        p3_min: u32,
+        p4_min: u32,
+    ) -> Result<(), ComServerError>;
+
+    /// Attempts to open a SAE J1850 VPW interface with the adapter
+    ///
+    /// ## Params
+    /// * `baud` - Speed of the bus in bps, 10400 (Or 41600 in 4x mode)
+    fn open_j1850vpw_interface(&mut self, baud: u32) -> Result<(), ComServerError>;
+
+    /// Attempts to destroy the J1850 VPW Interface on the adapter
+    fn close_j1850vpw_interface(&mut self) -> Result<(), ComServerError>;
+
+    /// Sends a list of raw J1850 messages over an open J1850 VPW channel
+    ///
+    /// ## Params
+    /// * `data` - List of J1850 messages to send. Each message must already contain its header
+    /// * `timeout_ms` - Timeout for waiting for conformation from the adapter. A value of 0
+    ///                will tell the adapter to queue to messages and return instantly, meaning
+    ///                no conformation is provided
+    ///
+    /// ## Returns
+    /// The number of messages successfully written to the vehicle
+    fn send_j1850vpw_data(
+        &self,
+        data: &[J1850Data],
+        timeout_ms: u32,
+    ) -> Result<usize, ComServerError>;
+
+    /// Attempts to read a list of [J1850 messages](J1850Data) from an open J1850 VPW channel.
+    ///
+    /// ## Params
+    /// * timeout_ms - Timeout for waiting for data from the vehicle. A value of 0 tells the adapter
+    /// to return whatever data it has in its Rx queue, and don't wait for any more
+    ///
+    /// * max_msgs - The maximum number of messages to read from the adapter.
+    fn read_j1850vpw_packets(
+        &self,
+        timeout_ms: u32,
+        max_msgs: usize,
+    ) -> Result<Vec<J1850Data>, ComServerError>;
+
+    /// Attempts to open a SAE J1850 PWM interface with the adapter
+    ///
+    /// ## Params
+    /// * `baud` - Speed of the bus in bps, 41600 (Or 83300 in 2x mode)
+    /// * `node_addr` - Physical address of the adapter on the bus. 0xF1 for a scan tool
+    /// * `func_addrs` - Functional addresses the adapter should acknowledge and receive.
+    ///                  J1850 PWM only receives functional messages which are in this list
+    fn open_j1850pwm_interface(
+        &mut self,
+        baud: u32,
+        node_addr: u8,
+        func_addrs: &[u8],
+    ) -> Result<(), ComServerError>;
+
+    /// Attempts to destroy the J1850 PWM Interface on the adapter
+    fn close_j1850pwm_interface(&mut self) -> Result<(), ComServerError>;
+
+    /// Sends a list of raw J1850 messages over an open J1850 PWM channel.
+    /// See [send_j1850vpw_data](fn@send_j1850vpw_data)
+    fn send_j1850pwm_data(
+        &self,
+        data: &[J1850Data],
+        timeout_ms: u32,
+    ) -> Result<usize, ComServerError>;
+
+    /// Attempts to read a list of [J1850 messages](J1850Data) from an open J1850 PWM channel.
+    /// See [read_j1850vpw_packets](fn@read_j1850vpw_packets)
+    fn read_j1850pwm_packets(
+        &self,
+        timeout_ms: u32,
+        max_msgs: usize,
+    ) -> Result<Vec<J1850Data>, ComServerError>;
+
     /// Tells the adapter to clear any data in its Rx buffer
     /// that is from CAN protocol
     fn clear_can_rx_buffer(&self) -> Result<(), ComServerError>;
@@ -376,6 +687,38 @@ pub trait ComServer: Send + Sync + Debug {
     /// that is from the ISO15765 protocol
     fn clear_iso15765_tx_buffer(&self) -> Result<(), ComServerError>;
 
+    /// Tells the adapter to clear any data in its Rx buffer
+    /// that is from the ISO14230 protocol
+    fn clear_iso14230_rx_buffer(&self) -> Result<(), ComServerError>;
+
+    /// Tells the adapter to clear any data in its Tx buffer
+    /// that is from the ISO14230 protocol
+    fn clear_iso14230_tx_buffer(&self) -> Result<(), ComServerError>;
+
+    /// Tells the adapter to clear any data in its Rx buffer
+    /// that is from the ISO9141 protocol
+    fn clear_iso9141_rx_buffer(&self) -> Result<(), ComServerError>;
+
+    /// Tells the adapter to clear any data in its Tx buffer
+    /// that is from the ISO9141 protocol
+    fn clear_iso9141_tx_buffer(&self) -> Result<(), ComServerError>;
+
+    /// Tells the adapter to clear any data in its Rx buffer
+    /// that is from the J1850 VPW protocol
+    fn clear_j1850vpw_rx_buffer(&self) -> Result<(), ComServerError>;
+
+    /// Tells the adapter to clear any data in its Tx buffer
+    /// that is from the J1850 VPW protocol
+    fn clear_j1850vpw_tx_buffer(&self) -> Result<(), ComServerError>;
+
+    /// Tells the adapter to clear any data in its Rx buffer
+    /// that is from the J1850 PWM protocol
+    fn clear_j1850pwm_rx_buffer(&self) -> Result<(), ComServerError>;
+
+    /// Tells the adapter to clear any data in its Tx buffer
+    /// that is from the J1850 PWM protocol
+    fn clear_j1850pwm_tx_buffer(&self) -> Result<(), ComServerError>;
+
     /// Returns the voltage read by the adapter on the +12V line of the OBD-II
     /// adapter, which is normally connected to the car battery
     ///
@@ -398,3 +741,87 @@ impl Clone for Box<dyn ComServer> {
         self.clone_box()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_dlc_codes() {
+        // Classic CAN lengths map directly to their DLC
+        for len in 0..=8 {
+            assert_eq!(CanFrame::len_to_dlc_code(len), len as u8);
+            assert_eq!(CanFrame::dlc_code_to_len(len as u8, false), len);
+            assert_eq!(CanFrame::dlc_code_to_len(len as u8, true), len);
+        }
+        let fd = [
+            (9, 12),
+            (10, 16),
+            (11, 20),
+            (12, 24),
+            (13, 32),
+            (14, 48),
+            (15, 64),
+        ];
+        for &(dlc, len) in &fd {
+            assert_eq!(CanFrame::len_to_dlc_code(len), dlc);
+            assert_eq!(CanFrame::dlc_code_to_len(dlc, true), len);
+            // Codes above 8 are still 8 bytes on classic CAN
+            assert_eq!(CanFrame::dlc_code_to_len(dlc, false), 8);
+        }
+        // Lengths between valid FD lengths round up
+        assert_eq!(CanFrame::len_to_dlc_code(9), 9);
+        assert_eq!(CanFrame::len_to_dlc_code(13), 10);
+        assert_eq!(CanFrame::len_to_dlc_code(33), 14);
+        assert_eq!(CanFrame::len_to_dlc_code(49), 15);
+        // Too long for any frame
+        assert_eq!(CanFrame::len_to_dlc_code(65), 15);
+        // Only the lower 4 bits are the DLC
+        assert_eq!(CanFrame::dlc_code_to_len(0x1F, true), 64);
+    }
+
+    #[test]
+    fn can_fd_padding() {
+        for len in 0..=64 {
+            let padded = CanFrame::fd_padded_len(len);
+            assert!(padded >= len);
+            assert!(CAN_FD_LENGTHS.contains(&padded));
+            // The next smallest valid length must not fit
+            let dlc = CanFrame::len_to_dlc_code(len) as usize;
+            assert!(dlc == 0 || CAN_FD_LENGTHS[dlc - 1] < len);
+        }
+        assert_eq!(CanFrame::fd_padded_len(8), 8);
+        assert_eq!(CanFrame::fd_padded_len(9), 12);
+        assert_eq!(CanFrame::fd_padded_len(17), 20);
+        assert_eq!(CanFrame::fd_padded_len(63), 64);
+    }
+
+    #[test]
+    fn can_fd_frames() {
+        let data: Vec<u8> = (1..=70).collect();
+        let frame = CanFrame::new_fd(0x7E0, &data[0..10], true, false);
+        assert!(frame.fd && frame.brs && !frame.esi);
+        assert_eq!(frame.dlc, 12);
+        assert_eq!(frame.get_dlc_code(), 9);
+        // Padded with 0x00
+        assert_eq!(frame.get_data()[0..10], data[0..10]);
+        assert_eq!(frame.get_data()[10..], [0x00, 0x00]);
+
+        let frame = CanFrame::new_fd(0x7E0, &data[0..48], false, true);
+        assert!(frame.fd && !frame.brs && frame.esi);
+        assert_eq!(frame.get_data(), &data[0..48]);
+        assert_eq!(frame.get_dlc_code(), 14);
+
+        // Data longer than 64 bytes is truncated
+        let frame = CanFrame::new_fd(0x7E0, &data, false, false);
+        assert_eq!(frame.get_data(), &data[0..64]);
+        assert_eq!(frame.get_dlc_code(), 15);
+
+        // Classic frames are not padded, and are at most 8 bytes
+        let frame = CanFrame::new(0x7E0, &data[0..10]);
+        assert!(!frame.fd);
+        assert_eq!(frame.get_data(), &data[0..8]);
+        let frame = CanFrame::new(0x7E0, &data[0..3]);
+        assert_eq!(frame.get_data(), &data[0..3]);
+    }
+}
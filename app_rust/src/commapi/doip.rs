@@ -0,0 +1,631 @@
+use std::{
+    collections::VecDeque,
+    io::{ErrorKind, Read, Write},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use super::comm_api::ComServerError;
+
+/// UDP and TCP port used by DoIP entities
+pub const DOIP_PORT: u16 = 13400;
+
+/// ISO13400-2:2012
+const PROTOCOL_VERSION: u8 = 0x02;
+
+/// Version byte used by testers for vehicle identification requests,
+/// which DoIP entities of any version must answer
+const PROTOCOL_VERSION_ANY: u8 = 0xFF;
+
+const HEADER_SIZE: usize = 8;
+
+/// Largest message accepted from an entity, anything bigger is a corrupt header
+const MAX_PAYLOAD_SIZE: usize = 0x10000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PayloadType {
+    GenericNack,
+    VehicleIdRequest,
+    VehicleIdRequestEid,
+    VehicleIdRequestVin,
+    VehicleAnnouncement,
+    RoutingActivationRequest,
+    RoutingActivationResponse,
+    AliveCheckRequest,
+    AliveCheckResponse,
+    DiagMessage,
+    DiagMessageAck,
+    DiagMessageNack,
+}
+
+impl PayloadType {
+    fn from_u16(x: u16) -> Option<Self> {
+        match x {
+            0x0000 => Some(Self::GenericNack),
+            0x0001 => Some(Self::VehicleIdRequest),
+            0x0002 => Some(Self::VehicleIdRequestEid),
+            0x0003 => Some(Self::VehicleIdRequestVin),
+            0x0004 => Some(Self::VehicleAnnouncement),
+            0x0005 => Some(Self::RoutingActivationRequest),
+            0x0006 => Some(Self::RoutingActivationResponse),
+            0x0007 => Some(Self::AliveCheckRequest),
+            0x0008 => Some(Self::AliveCheckResponse),
+            0x8001 => Some(Self::DiagMessage),
+            0x8002 => Some(Self::DiagMessageAck),
+            0x8003 => Some(Self::DiagMessageNack),
+            _ => None,
+        }
+    }
+}
+
+impl Into<u16> for PayloadType {
+    fn into(self) -> u16 {
+        match self {
+            PayloadType::GenericNack => 0x0000,
+            PayloadType::VehicleIdRequest => 0x0001,
+            PayloadType::VehicleIdRequestEid => 0x0002,
+            PayloadType::VehicleIdRequestVin => 0x0003,
+            PayloadType::VehicleAnnouncement => 0x0004,
+            PayloadType::RoutingActivationRequest => 0x0005,
+            PayloadType::RoutingActivationResponse => 0x0006,
+            PayloadType::AliveCheckRequest => 0x0007,
+            PayloadType::AliveCheckResponse => 0x0008,
+            PayloadType::DiagMessage => 0x8001,
+            PayloadType::DiagMessageAck => 0x8002,
+            PayloadType::DiagMessageNack => 0x8003,
+        }
+    }
+}
+
+/// Builds a complete DoIP message (Generic header + payload)
+pub(crate) fn encode_message(version: u8, ptype: PayloadType, payload: &[u8]) -> Vec<u8> {
+    let ptype: u16 = ptype.into();
+    let mut res = Vec::with_capacity(HEADER_SIZE + payload.len());
+    res.push(version);
+    res.push(!version);
+    res.extend_from_slice(&ptype.to_be_bytes());
+    res.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    res.extend_from_slice(payload);
+    res
+}
+
+/// Attempts to decode a single DoIP message from the start of `buf`.
+///
+/// ## Returns
+/// * Ok(None) - Not enough data in the buffer yet
+/// * Ok(Some((payload type, payload, total message size)))
+pub(crate) fn decode_message(buf: &[u8]) -> Result<Option<(u16, Vec<u8>, usize)>, ComServerError> {
+    if buf.len() < HEADER_SIZE {
+        return Ok(None);
+    }
+    if buf[0] != !buf[1] {
+        return Err(doip_error(
+            5,
+            "Invalid DoIP protocol version in header".into(),
+        ));
+    }
+    let ptype = u16::from_be_bytes([buf[2], buf[3]]);
+    let len = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]) as usize;
+    if len > MAX_PAYLOAD_SIZE {
+        return Err(doip_error(
+            5,
+            format!("DoIP payload of {} bytes is too large", len),
+        ));
+    }
+    if buf.len() < HEADER_SIZE + len {
+        return Ok(None);
+    }
+    Ok(Some((
+        ptype,
+        Vec::from(&buf[HEADER_SIZE..HEADER_SIZE + len]),
+        HEADER_SIZE + len,
+    )))
+}
+
+fn doip_error(code: u32, desc: String) -> ComServerError {
+    ComServerError {
+        err_code: code,
+        err_desc: desc,
+    }
+}
+
+fn io_error(e: std::io::Error) -> ComServerError {
+    doip_error(
+        e.raw_os_error().unwrap_or(1) as u32,
+        format!("DoIP socket error: {}", e),
+    )
+}
+
+/// A DoIP entity (Gateway or ECU) found on the network
+#[derive(Debug, Clone)]
+pub struct DoIpEntity {
+    pub ip: Ipv4Addr,
+    pub vin: String,
+    pub logical_addr: u16,
+    pub eid: [u8; 6],
+    pub gid: [u8; 6],
+}
+
+impl DoIpEntity {
+    fn from_announcement(ip: Ipv4Addr, payload: &[u8]) -> Option<Self> {
+        // VIN (17) + logical address (2) + EID (6) + GID (6) + further action (1)
+        if payload.len() < 32 {
+            return None;
+        }
+        let mut eid = [0u8; 6];
+        let mut gid = [0u8; 6];
+        eid.copy_from_slice(&payload[19..25]);
+        gid.copy_from_slice(&payload[25..31]);
+        Some(Self {
+            ip,
+            vin: String::from_utf8_lossy(&payload[0..17]).to_string(),
+            logical_addr: u16::from_be_bytes([payload[17], payload[18]]),
+            eid,
+            gid,
+        })
+    }
+}
+
+/// Sends a vehicle identification request and collects every vehicle announcement
+/// received before the timeout.
+///
+/// This is only part of the library for now. The GUI does not discover entities itself,
+/// and connects to the address given by [IFACE_CFG::DOIP_IP_ADDR](super::iface::IFACE_CFG::DOIP_IP_ADDR)
+///
+/// ## Params
+/// * `dest` - Address to send the request to. [Ipv4Addr::BROADCAST] to find all entities on the network
+/// * `port` - UDP port of the entities, normally [DOIP_PORT]
+/// * `timeout_ms` - How long to wait for entities to respond
+pub fn discover_entities(
+    dest: Ipv4Addr,
+    port: u16,
+    timeout_ms: u32,
+) -> Result<Vec<DoIpEntity>, ComServerError> {
+    let socket = UdpSocket::bind("0.0.0.0:0").map_err(io_error)?;
+    socket.set_broadcast(true).map_err(io_error)?;
+    let req = encode_message(PROTOCOL_VERSION_ANY, PayloadType::VehicleIdRequest, &[]);
+    socket
+        .send_to(&req, SocketAddrV4::new(dest, port))
+        .map_err(io_error)?;
+
+    let mut res: Vec<DoIpEntity> = Vec::new();
+    let mut buf = [0u8; 512];
+    let start = Instant::now();
+    while let Some(remaining) =
+        Duration::from_millis(timeout_ms as u64).checked_sub(start.elapsed())
+    {
+        if remaining.as_millis() == 0 {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining)).map_err(io_error)?;
+        let (size, addr) = match socket.recv_from(&mut buf) {
+            Ok(x) => x,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => break,
+            Err(e) => return Err(io_error(e)),
+        };
+        let ip = match addr {
+            SocketAddr::V4(a) => *a.ip(),
+            SocketAddr::V6(_) => continue,
+        };
+        if let Ok(Some((ptype, payload, _))) = decode_message(&buf[..size]) {
+            if PayloadType::from_u16(ptype) == Some(PayloadType::VehicleAnnouncement) {
+                if let Some(entity) = DoIpEntity::from_announcement(ip, &payload) {
+                    // Entities may announce themselves more than once
+                    if !res
+                        .iter()
+                        .any(|e| e.ip == entity.ip && e.logical_addr == entity.logical_addr)
+                    {
+                        res.push(entity);
+                    }
+                }
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// An active TCP connection to a DoIP entity, with routing activated
+#[derive(Debug)]
+pub struct DoIpConnection {
+    stream: TcpStream,
+    source_addr: u16,
+    entity_addr: u16,
+    rx_buf: Vec<u8>,
+    /// Diagnostic messages (Source address, data) received whilst waiting for something else
+    rx_queue: VecDeque<(u16, Vec<u8>)>,
+}
+
+impl DoIpConnection {
+    /// Connects to a DoIP entity and activates routing for the tester
+    ///
+    /// ## Params
+    /// * `addr` - IP address and TCP port of the entity
+    /// * `source_addr` - Logical address of the tester (0x0E00 - 0x0FFF)
+    /// * `activation_type` - Routing activation type. 0x00 for default, 0x01 for WWH-OBD
+    /// * `timeout_ms` - Timeout for connecting and for the routing activation response
+    pub fn connect(
+        addr: SocketAddrV4,
+        source_addr: u16,
+        activation_type: u8,
+        timeout_ms: u32,
+    ) -> Result<Self, ComServerError> {
+        let stream = TcpStream::connect_timeout(
+            &SocketAddr::V4(addr),
+            Duration::from_millis(timeout_ms as u64),
+        )
+        .map_err(io_error)?;
+        stream.set_nodelay(true).map_err(io_error)?;
+        let mut conn = Self {
+            stream,
+            source_addr,
+            entity_addr: 0,
+            rx_buf: Vec::new(),
+            rx_queue: VecDeque::new(),
+        };
+        conn.activate_routing(activation_type, timeout_ms)?;
+        Ok(conn)
+    }
+
+    /// Logical address of the entity which accepted the routing activation
+    pub fn get_entity_addr(&self) -> u16 {
+        self.entity_addr
+    }
+
+    fn activate_routing(
+        &mut self,
+        activation_type: u8,
+        timeout_ms: u32,
+    ) -> Result<(), ComServerError> {
+        let mut req = Vec::from(self.source_addr.to_be_bytes());
+        req.push(activation_type);
+        req.extend_from_slice(&[0x00; 4]); // Reserved
+        self.write(PayloadType::RoutingActivationRequest, &req)?;
+
+        let resp = self.wait_for(PayloadType::RoutingActivationResponse, timeout_ms)?;
+        if resp.len() < 5 {
+            return Err(doip_error(
+                6,
+                "Routing activation response is too short".into(),
+            ));
+        }
+        self.entity_addr = u16::from_be_bytes([resp[2], resp[3]]);
+        match resp[4] {
+            0x10 => Ok(()),
+            0x00 => Err(doip_error(
+                7,
+                "Routing activation denied - Unknown source address".into(),
+            )),
+            0x01 => Err(doip_error(
+                7,
+                "Routing activation denied - All sockets are in use".into(),
+            )),
+            0x02 => Err(doip_error(
+                7,
+                "Routing activation denied - Source address already in use".into(),
+            )),
+            0x03 => Err(doip_error(
+                7,
+                "Routing activation denied - Source address already registered".into(),
+            )),
+            0x04 => Err(doip_error(
+                7,
+                "Routing activation denied - Missing authentication".into(),
+            )),
+            0x05 => Err(doip_error(
+                7,
+                "Routing activation denied - Rejected confirmation".into(),
+            )),
+            0x06 => Err(doip_error(
+                7,
+                "Routing activation denied - Unsupported activation type".into(),
+            )),
+            0x11 => Err(doip_error(
+                7,
+                "Routing activation requires confirmation".into(),
+            )),
+            x => Err(doip_error(
+                7,
+                format!("Routing activation denied - Code 0x{:02X}", x),
+            )),
+        }
+    }
+
+    /// Sends a diagnostic message to an ECU behind the entity, and waits for the entity to
+    /// acknowledge it
+    ///
+    /// ## Params
+    /// * `target_addr` - Logical address of the ECU
+    /// * `data` - UDS payload
+    /// * `timeout_ms` - Timeout for the acknowledgement. A value of 0 does not wait for it
+    pub fn send_diag_message(
+        &mut self,
+        target_addr: u16,
+        data: &[u8],
+        timeout_ms: u32,
+    ) -> Result<(), ComServerError> {
+        let mut msg = Vec::from(self.source_addr.to_be_bytes());
+        msg.extend_from_slice(&target_addr.to_be_bytes());
+        msg.extend_from_slice(data);
+        self.write(PayloadType::DiagMessage, &msg)?;
+        if timeout_ms == 0 {
+            return Ok(());
+        }
+
+        let start = Instant::now();
+        while start.elapsed().as_millis() <= timeout_ms as u128 {
+            let remaining = timeout_ms.saturating_sub(start.elapsed().as_millis() as u32);
+            match self.poll(remaining)? {
+                Some((ptype, payload)) => match PayloadType::from_u16(ptype) {
+                    Some(PayloadType::DiagMessageAck) => return Ok(()),
+                    Some(PayloadType::DiagMessageNack) => {
+                        return Err(doip_error(
+                            8,
+                            format!(
+                                "Diagnostic message rejected by entity (Code 0x{:02X})",
+                                payload.get(4).copied().unwrap_or(0xFF)
+                            ),
+                        ))
+                    }
+                    _ => self.handle_unsolicited(ptype, payload)?,
+                },
+                None => break,
+            }
+        }
+        Err(doip_error(
+            2,
+            "Timeout waiting for diagnostic message acknowledgement".into(),
+        ))
+    }
+
+    /// Attempts to read a diagnostic message sent to the tester
+    ///
+    /// ## Returns
+    /// The source address of the ECU and its UDS payload, or None if nothing was received
+    /// before the timeout
+    pub fn read_diag_message(
+        &mut self,
+        timeout_ms: u32,
+    ) -> Result<Option<(u16, Vec<u8>)>, ComServerError> {
+        if let Some(msg) = self.rx_queue.pop_front() {
+            return Ok(Some(msg));
+        }
+        let start = Instant::now();
+        loop {
+            let remaining = timeout_ms.saturating_sub(start.elapsed().as_millis() as u32);
+            match self.poll(remaining)? {
+                Some((ptype, payload)) => self.handle_unsolicited(ptype, payload)?,
+                None => return Ok(None),
+            }
+            if let Some(msg) = self.rx_queue.pop_front() {
+                return Ok(Some(msg));
+            }
+            if remaining == 0 {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Discards any received diagnostic messages
+    pub fn clear_rx(&mut self) -> Result<(), ComServerError> {
+        self.rx_queue.clear();
+        while let Some((ptype, payload)) = self.poll(0)? {
+            self.handle_unsolicited(ptype, payload)?;
+        }
+        self.rx_queue.clear();
+        Ok(())
+    }
+
+    /// Handles a message which was not being waited for. Diagnostic messages for the tester are
+    /// queued, and alive checks are answered
+    fn handle_unsolicited(&mut self, ptype: u16, payload: Vec<u8>) -> Result<(), ComServerError> {
+        match PayloadType::from_u16(ptype) {
+            Some(PayloadType::DiagMessage) if payload.len() > 4 => {
+                let source = u16::from_be_bytes([payload[0], payload[1]]);
+                let target = u16::from_be_bytes([payload[2], payload[3]]);
+                if target == self.source_addr {
+                    self.rx_queue.push_back((source, Vec::from(&payload[4..])));
+                }
+                Ok(())
+            }
+            Some(PayloadType::AliveCheckRequest) => {
+                let addr = self.source_addr.to_be_bytes();
+                self.write(PayloadType::AliveCheckResponse, &addr)
+            }
+            Some(PayloadType::GenericNack) => Err(doip_error(
+                9,
+                format!(
+                    "Entity rejected message header (Code 0x{:02X})",
+                    payload.first().copied().unwrap_or(0xFF)
+                ),
+            )),
+            _ => Ok(()), // Late acknowledgements, or things we don't care about
+        }
+    }
+
+    fn wait_for(
+        &mut self,
+        expected: PayloadType,
+        timeout_ms: u32,
+    ) -> Result<Vec<u8>, ComServerError> {
+        let start = Instant::now();
+        while start.elapsed().as_millis() <= timeout_ms as u128 {
+            let remaining = timeout_ms.saturating_sub(start.elapsed().as_millis() as u32);
+            match self.poll(remaining)? {
+                Some((ptype, payload)) if PayloadType::from_u16(ptype) == Some(expected) => {
+                    return Ok(payload)
+                }
+                Some((ptype, payload)) => self.handle_unsolicited(ptype, payload)?,
+                None => break,
+            }
+        }
+        Err(doip_error(2, format!("Timeout waiting for {:?}", expected)))
+    }
+
+    fn write(&mut self, ptype: PayloadType, payload: &[u8]) -> Result<(), ComServerError> {
+        let msg = encode_message(PROTOCOL_VERSION, ptype, payload);
+        self.stream.write_all(&msg).map_err(io_error)
+    }
+
+    /// Reads the next complete message from the entity, waiting at most `timeout_ms` for it.
+    /// Partially received messages are kept until the rest of them arrives
+    fn poll(&mut self, timeout_ms: u32) -> Result<Option<(u16, Vec<u8>)>, ComServerError> {
+        let start = Instant::now();
+        let mut buf = [0u8; 4096];
+        loop {
+            if let Some((ptype, payload, size)) = decode_message(&self.rx_buf)? {
+                self.rx_buf.drain(0..size);
+                return Ok(Some((ptype, payload)));
+            }
+            let remaining = timeout_ms.saturating_sub(start.elapsed().as_millis() as u32);
+            // A read timeout of 0 is not allowed, so wait at least 1ms. Non blocking mode is
+            // not used, as it would also apply to writes
+            self.stream
+                .set_read_timeout(Some(Duration::from_millis(remaining.max(1) as u64)))
+                .map_err(io_error)?;
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err(doip_error(10, "DoIP entity closed the connection".into())),
+                Ok(size) => self.rx_buf.extend_from_slice(&buf[..size]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    return Ok(None)
+                }
+                Err(e) => return Err(io_error(e)),
+            }
+        }
+    }
+}
+
+impl Drop for DoIpConnection {
+    fn drop(&mut self) {
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    const VIN: &[u8; 17] = b"WDD2050001A123456";
+    const TESTER_ADDR: u16 = 0x0E00;
+    const GATEWAY_ADDR: u16 = 0x1010;
+    const ECU_ADDR: u16 = 0x1001;
+
+    /// Minimal DoIP entity on loopback. Announces itself, activates routing for any
+    /// tester and answers UDS TesterPresent for a single ECU
+    fn spawn_test_entity() -> (u16, u16) {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let udp_port = udp.local_addr().unwrap().port();
+        let tcp_port = tcp.local_addr().unwrap().port();
+
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            while let Ok((size, addr)) = udp.recv_from(&mut buf) {
+                if let Ok(Some((0x0001, _, _))) = decode_message(&buf[..size]) {
+                    let mut payload = VIN.to_vec();
+                    payload.extend_from_slice(&GATEWAY_ADDR.to_be_bytes());
+                    payload.extend_from_slice(&[0x00, 0x11, 0x22, 0x33, 0x44, 0x55]); // EID
+                    payload.extend_from_slice(&[0x00; 6]); // GID
+                    payload.push(0x00); // No further action
+                    let msg = encode_message(
+                        PROTOCOL_VERSION,
+                        PayloadType::VehicleAnnouncement,
+                        &payload,
+                    );
+                    udp.send_to(&msg, addr).unwrap();
+                }
+            }
+        });
+
+        std::thread::spawn(move || {
+            let (mut stream, _) = tcp.accept().unwrap();
+            let mut rx_buf = Vec::new();
+            let mut buf = [0u8; 512];
+            loop {
+                let size = match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => return,
+                    Ok(size) => size,
+                };
+                rx_buf.extend_from_slice(&buf[..size]);
+                while let Ok(Some((ptype, payload, size))) = decode_message(&rx_buf) {
+                    rx_buf.drain(0..size);
+                    let tester = [payload[0], payload[1]];
+                    match ptype {
+                        0x0005 => {
+                            let mut resp = tester.to_vec();
+                            resp.extend_from_slice(&GATEWAY_ADDR.to_be_bytes());
+                            resp.push(0x10); // Routing activated
+                            resp.extend_from_slice(&[0x00; 4]);
+                            let msg = encode_message(
+                                PROTOCOL_VERSION,
+                                PayloadType::RoutingActivationResponse,
+                                &resp,
+                            );
+                            stream.write_all(&msg).unwrap();
+                        }
+                        0x8001 => {
+                            let target = u16::from_be_bytes([payload[2], payload[3]]);
+                            let mut ack = payload[2..4].to_vec();
+                            ack.extend_from_slice(&tester);
+                            ack.push(if target == ECU_ADDR { 0x00 } else { 0x03 });
+                            let ack_type = if target == ECU_ADDR {
+                                PayloadType::DiagMessageAck
+                            } else {
+                                PayloadType::DiagMessageNack
+                            };
+                            stream
+                                .write_all(&encode_message(PROTOCOL_VERSION, ack_type, &ack))
+                                .unwrap();
+                            if target == ECU_ADDR && payload[4] == 0x3E {
+                                let mut resp = ECU_ADDR.to_be_bytes().to_vec();
+                                resp.extend_from_slice(&tester);
+                                resp.extend_from_slice(&[0x7E, 0x00]);
+                                // Split the response to check partial messages are handled
+                                let msg = encode_message(
+                                    PROTOCOL_VERSION,
+                                    PayloadType::DiagMessage,
+                                    &resp,
+                                );
+                                stream.write_all(&msg[..5]).unwrap();
+                                std::thread::sleep(Duration::from_millis(20));
+                                stream.write_all(&msg[5..]).unwrap();
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        });
+        (udp_port, tcp_port)
+    }
+
+    #[test]
+    fn test_doip_loopback() {
+        let (udp_port, tcp_port) = spawn_test_entity();
+
+        let entities = discover_entities(Ipv4Addr::LOCALHOST, udp_port, 500).unwrap();
+        assert_eq!(entities.len(), 1);
+        assert_eq!(entities[0].vin.as_bytes(), VIN);
+        assert_eq!(entities[0].logical_addr, GATEWAY_ADDR);
+        assert_eq!(entities[0].ip, Ipv4Addr::LOCALHOST);
+
+        let mut conn = DoIpConnection::connect(
+            SocketAddrV4::new(entities[0].ip, tcp_port),
+            TESTER_ADDR,
+            0x00,
+            1000,
+        )
+        .unwrap();
+        assert_eq!(conn.get_entity_addr(), GATEWAY_ADDR);
+
+        conn.send_diag_message(ECU_ADDR, &[0x3E, 0x00], 1000)
+            .unwrap();
+        let (source, data) = conn.read_diag_message(1000).unwrap().unwrap();
+        assert_eq!(source, ECU_ADDR);
+        assert_eq!(data, vec![0x7E, 0x00]);
+        assert!(conn.read_diag_message(50).unwrap().is_none());
+
+        // Unknown target address is rejected by the entity
+        assert!(conn.send_diag_message(0x1234, &[0x3E, 0x00], 1000).is_err());
+    }
+}
@@ -2,10 +2,16 @@ use std::fmt::Debug;
 use std::{
     borrow::BorrowMut,
     collections::HashMap,
+    net::{Ipv4Addr, SocketAddrV4},
     sync::{Arc, Mutex},
+    time::Instant,
 };
 
-use super::comm_api::{CanFrame, Capability, ComServer, ComServerError, FilterType, ISO15765Data};
+use super::comm_api::{
+    CanFrame, Capability, ComServer, ComServerError, FilterType, ISO15765Data, J1850Data, KLineData,
+};
+use super::doip::{DoIpConnection, DOIP_PORT};
+use super::isotp::{IsoTpParams, IsoTpStack};
 
 pub type InterfaceResult<T> = std::result::Result<T, ComServerError>;
 
@@ -20,6 +26,37 @@ pub enum IFACE_CFG {
     PAD_FLOW_CONTROL,
     ISOTP_BS,
     ISOTP_ST_MIN,
+    /// Max CAN FD frame length to send ISO-TP data with. 0 (Default) uses classic CAN
+    ISOTP_FD_TX_DL,
+    /// Use bit rate switching for ISO-TP CAN FD frames
+    ISOTP_FD_BRS,
+    /// Use the built in ISO-TP stack on top of raw CAN, rather than the adapter's own
+    ISOTP_SOFTWARE,
+    /// N_As timeout (ms) for the built in ISO-TP stack
+    ISOTP_N_AS,
+    /// N_Bs timeout (ms) for the built in ISO-TP stack
+    ISOTP_N_BS,
+    /// N_Cr timeout (ms) for the built in ISO-TP stack
+    ISOTP_N_CR,
+    // K-Line
+    KLINE_INIT_MODE,
+    KLINE_SOURCE_ADDR,
+    KLINE_TARGET_ADDR,
+    KLINE_FUNC_ADDR,
+    KLINE_MAX_SEGMENT_SIZE,
+    KLINE_P1_MAX,
+    KLINE_P3_MIN,
+    KLINE_P3_MAX,
+    KLINE_P4_MIN,
+    // J1850
+    J1850_PRIORITY,
+    J1850_SOURCE_ADDR,
+    J1850_RESPONSE_ADDR,
+    // DoIP
+    DOIP_IP_ADDR,
+    DOIP_PORT,
+    DOIP_SOURCE_ADDR,
+    DOIP_ACTIVATION_TYPE,
 }
 
 impl ToString for IFACE_CFG {
@@ -28,11 +65,36 @@ impl ToString for IFACE_CFG {
     }
 }
 
+/// How the K-Line is woken up before talking to the ECU.
+/// Set with [IFACE_CFG::KLINE_INIT_MODE]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KLineInitMode {
+    /// No wake up, the ECU is already awake
+    None = 0,
+    /// Send the target address at 5 baud, ECU replies with its key bytes
+    FiveBaud = 1,
+    /// 25ms wake up pattern, followed by a StartCommunication request
+    FastInit = 2,
+}
+
+impl From<u32> for KLineInitMode {
+    fn from(x: u32) -> Self {
+        match x {
+            1 => Self::FiveBaud,
+            2 => Self::FastInit,
+            _ => Self::None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq)]
 #[allow(non_camel_case_types)]
 pub enum PayloadFlag {
     ISOTP_PAD_FRAME,
     ISOTP_EXT_ADDR,
+    CAN_FD,
+    CAN_FD_BRS,
+    CAN_FD_ESI,
 }
 
 #[derive(Debug, Clone)]
@@ -135,7 +197,7 @@ pub struct CanbusInterface {
 
 impl CanbusInterface {
     pub fn new(dev: Box<dyn ComServer>) -> InterfaceResult<Box<dyn Interface>> {
-        if dev.get_capabilities().support_can_fd() != Capability::Yes {
+        if dev.get_capabilities().supports_can() != Capability::Yes {
             Err(ComServerError {
                 err_code: 1,
                 err_desc: "Device does not support CAN".into(),
@@ -174,18 +236,43 @@ impl Interface for CanbusInterface {
     }
 
     fn send_data(&mut self, data: &[InterfacePayload], timeout: u32) -> InterfaceResult<usize> {
-        let can_packets: Vec<CanFrame> =
-            data.iter().map(|f| CanFrame::new(f.id, &f.data)).collect();
+        let can_packets: Vec<CanFrame> = data
+            .iter()
+            .map(|f| {
+                if f.is_flag_set(PayloadFlag::CAN_FD) {
+                    CanFrame::new_fd(
+                        f.id,
+                        &f.data,
+                        f.is_flag_set(PayloadFlag::CAN_FD_BRS),
+                        f.is_flag_set(PayloadFlag::CAN_FD_ESI),
+                    )
+                } else {
+                    CanFrame::new(f.id, &f.data)
+                }
+            })
+            .collect();
         self.dev.send_can_packets(&can_packets, timeout)
     }
 
     fn recv_data(&mut self, max: usize, timeout: u32) -> InterfaceResult<Vec<InterfacePayload>> {
         self.dev.read_can_packets(timeout, max).map(|v| {
             v.iter()
-                .map(|f| InterfacePayload {
-                    id: f.id,
-                    data: Vec::from(f.get_data()),
-                    flags: vec![],
+                .map(|f| {
+                    let mut flags = Vec::new();
+                    if f.fd {
+                        flags.push(PayloadFlag::CAN_FD);
+                        if f.brs {
+                            flags.push(PayloadFlag::CAN_FD_BRS);
+                        }
+                        if f.esi {
+                            flags.push(PayloadFlag::CAN_FD_ESI);
+                        }
+                    }
+                    InterfacePayload {
+                        id: f.id,
+                        data: Vec::from(f.get_data()),
+                        flags,
+                    }
                 })
                 .collect()
         })
@@ -220,8 +307,13 @@ pub struct IsoTPInterface {
 }
 
 impl IsoTPInterface {
+    /// Creates an ISO-TP interface. If the adapter cannot do ISO-TP itself, but
+    /// can send raw CAN frames, the built in ISO-TP stack is used instead
     pub fn new(dev: Box<dyn ComServer>) -> InterfaceResult<Box<dyn Interface>> {
-        if dev.get_capabilities().supports_iso15765() != Capability::Yes {
+        let caps = dev.get_capabilities();
+        if caps.supports_iso15765() != Capability::Yes && caps.supports_can() == Capability::Yes {
+            SoftwareIsoTpInterface::new(dev)
+        } else if caps.supports_iso15765() != Capability::Yes {
             Err(ComServerError {
                 err_code: 1,
                 err_desc: "Device does not support IsoTP".into(),
@@ -256,7 +348,21 @@ impl Interface for IsoTPInterface {
         self.dev.set_iso15765_params(
             cfg.get_param_or_default(IFACE_CFG::ISOTP_ST_MIN, 20),
             cfg.get_param_or_default(IFACE_CFG::ISOTP_BS, 8),
-        )
+        )?;
+        let tx_dl = cfg.get_param_or_default(IFACE_CFG::ISOTP_FD_TX_DL, 0);
+        if tx_dl > 0 {
+            if self.dev.get_capabilities().support_can_fd() != Capability::Yes {
+                return Err(ComServerError {
+                    err_code: 1,
+                    err_desc: "Device does not support CAN FD".into(),
+                });
+            }
+            self.dev.set_iso15765_fd_params(
+                tx_dl,
+                cfg.get_param_or_default(IFACE_CFG::ISOTP_FD_BRS, 0) > 0,
+            )?;
+        }
+        Ok(())
     }
 
     fn send_data(&mut self, data: &[InterfacePayload], timeout: u32) -> InterfaceResult<usize> {
@@ -306,9 +412,362 @@ impl Interface for IsoTPInterface {
         })
     }
 }
+
+#[derive(Debug)]
+struct SoftwareIsoTpState {
+    stack: IsoTpStack<Box<dyn ComServer>>,
+    /// ISO-TP filter ID -> CAN filter ID on the adapter
+    can_filters: HashMap<u32, u32>,
+}
+
+/// ISO-TP done by OpenVehicleDiag rather than the adapter, using raw CAN frames.
+/// Works with any adapter that supports CAN
+#[derive(Debug, Clone)]
+pub struct SoftwareIsoTpInterface {
+    dev: Box<dyn ComServer>,
+    state: Option<Arc<Mutex<SoftwareIsoTpState>>>,
+}
+
+impl SoftwareIsoTpInterface {
+    pub fn new(dev: Box<dyn ComServer>) -> InterfaceResult<Box<dyn Interface>> {
+        if dev.get_capabilities().supports_can() != Capability::Yes {
+            Err(ComServerError {
+                err_code: 1,
+                err_desc: "Device does not support CAN".into(),
+            })
+        } else {
+            Ok(Box::new(SoftwareIsoTpInterface {
+                dev: dev.clone_box(),
+                state: None,
+            }))
+        }
+    }
+
+    fn exec<R, F: FnOnce(&mut SoftwareIsoTpState) -> InterfaceResult<R>>(
+        &self,
+        func: F,
+    ) -> InterfaceResult<R> {
+        match &self.state {
+            Some(s) => func(&mut s.lock().unwrap()),
+            None => Err(ComServerError {
+                err_code: 98,
+                err_desc: "ISO-TP interface not open".into(),
+            }),
+        }
+    }
+}
+
+impl Interface for SoftwareIsoTpInterface {
+    fn clear_buffer(&mut self, buffer_type: BufferType) -> InterfaceResult<()> {
+        match buffer_type {
+            BufferType::TX => self.dev.clear_can_tx_buffer(),
+            BufferType::RX => {
+                self.dev.clear_can_rx_buffer()?;
+                self.exec(|s| {
+                    s.stack.clear_rx();
+                    Ok(())
+                })
+            }
+            BufferType::BOTH => {
+                self.dev.clear_can_tx_buffer()?;
+                self.clear_buffer(BufferType::RX)
+            }
+        }
+    }
+
+    fn setup(&mut self, cfg: &InterfaceConfig) -> InterfaceResult<()> {
+        let defaults = IsoTpParams::default();
+        let tx_dl = cfg.get_param_or_default(IFACE_CFG::ISOTP_FD_TX_DL, 8) as usize;
+        if tx_dl > 8 && self.dev.get_capabilities().support_can_fd() != Capability::Yes {
+            return Err(ComServerError {
+                err_code: 1,
+                err_desc: "Device does not support CAN FD".into(),
+            });
+        }
+        if CanFrame::fd_padded_len(tx_dl) != tx_dl || tx_dl < 8 {
+            return Err(ComServerError {
+                err_code: 1,
+                err_desc: format!("{} is not a valid CAN FD frame length", tx_dl),
+            });
+        }
+        let params = IsoTpParams {
+            block_size: cfg.get_param_or_default(IFACE_CFG::ISOTP_BS, 8) as u8,
+            st_min: cfg.get_param_or_default(IFACE_CFG::ISOTP_ST_MIN, 20) as u8,
+            pad_flow_control: cfg.get_param_or_default(IFACE_CFG::PAD_FLOW_CONTROL, 0) > 0,
+            ext_addressing: cfg.get_param_or_default(IFACE_CFG::EXT_ISOTP_ADDR, 0) > 0,
+            tx_dl,
+            brs: cfg.get_param_or_default(IFACE_CFG::ISOTP_FD_BRS, 0) > 0,
+            n_as: cfg.get_param_or_default(IFACE_CFG::ISOTP_N_AS, defaults.n_as),
+            n_bs: cfg.get_param_or_default(IFACE_CFG::ISOTP_N_BS, defaults.n_bs),
+            n_cr: cfg.get_param_or_default(IFACE_CFG::ISOTP_N_CR, defaults.n_cr),
+        };
+        self.dev.open_can_interface(
+            cfg.get_param(IFACE_CFG::BAUDRATE)?,
+            cfg.get_param_or_default(IFACE_CFG::EXT_CAN_ADDR, 0) > 0,
+        )?;
+        self.state = Some(Arc::new(Mutex::new(SoftwareIsoTpState {
+            stack: IsoTpStack::new(self.dev.clone_box(), params),
+            can_filters: HashMap::new(),
+        })));
+        Ok(())
+    }
+
+    fn send_data(&mut self, data: &[InterfacePayload], _timeout: u32) -> InterfaceResult<usize> {
+        // Timeouts are handled by N_As and N_Bs
+        self.exec(|s| {
+            for p in data {
+                s.stack
+                    .send_message(p.id, &p.data, p.is_flag_set(PayloadFlag::ISOTP_PAD_FRAME))?;
+            }
+            Ok(data.len())
+        })
+    }
+
+    fn recv_data(&mut self, max: usize, timeout: u32) -> InterfaceResult<Vec<InterfacePayload>> {
+        self.exec(|s| {
+            let mut res = Vec::new();
+            let start = Instant::now();
+            while res.len() < max {
+                let remaining = timeout.saturating_sub(start.elapsed().as_millis() as u32);
+                match s.stack.read_message(remaining)? {
+                    Some((id, data)) => res.push(InterfacePayload::new(id, &data)),
+                    None => break,
+                }
+            }
+            Ok(res)
+        })
+    }
+
+    fn close(&mut self) -> InterfaceResult<()> {
+        self.state.take();
+        self.dev.close_can_interface()
+    }
+
+    fn add_filter(&mut self, f: FilterType) -> InterfaceResult<u32> {
+        match f {
+            FilterType::IsoTP { id, mask, fc } => {
+                let mut dev = self.dev.clone_box();
+                self.exec(|s| {
+                    let can_filter = dev.add_can_filter(FilterType::Pass { id, mask })?;
+                    let f_id = s.stack.add_channel(id, mask, fc);
+                    s.can_filters.insert(f_id, can_filter);
+                    Ok(f_id)
+                })
+            }
+            _ => Err(ComServerError {
+                err_code: 99,
+                err_desc: "ISO-TP only supports flow control filters".into(),
+            }),
+        }
+    }
+
+    fn rem_filter(&mut self, f_id: u32) -> InterfaceResult<()> {
+        let mut dev = self.dev.clone_box();
+        self.exec(|s| {
+            s.stack.remove_channel(f_id);
+            match s.can_filters.remove(&f_id) {
+                Some(can_filter) => dev.rem_can_filter(can_filter),
+                None => Ok(()),
+            }
+        })
+    }
+
+    fn get_server(&self) -> Box<dyn ComServer> {
+        self.dev.clone_box()
+    }
+
+    fn clone_box(&self) -> Box<dyn Interface> {
+        Box::new(self.clone())
+    }
+}
+
+/// Filters applied by the interface itself, used by K-Line, J1850 and DoIP where everything
+/// on the bus is received. If no filters are set, everything is accepted
+#[derive(Debug, Clone, Default)]
+struct SoftwareFilters {
+    filters: [Option<FilterType>; 10],
+}
+
+impl SoftwareFilters {
+    fn add(&mut self, f: FilterType) -> InterfaceResult<u32> {
+        if let FilterType::IsoTP { .. } = f {
+            return Err(ComServerError {
+                err_code: 99,
+                err_desc: "Flow control filters are only supported by ISO-TP".into(),
+            });
+        }
+        match self.filters.iter().position(|x| x.is_none()) {
+            Some(pos) => {
+                self.filters[pos] = Some(f);
+                Ok(pos as u32)
+            }
+            None => Err(ComServerError {
+                err_code: 98,
+                err_desc: "No free filters were found".into(),
+            }),
+        }
+    }
+
+    fn remove(&mut self, f_id: u32) {
+        if let Some(f) = self.filters.get_mut(f_id as usize) {
+            *f = None
+        }
+    }
+
+    fn clear(&mut self) {
+        self.filters = [None; 10];
+    }
+
+    fn matches(&self, id: u32) -> bool {
+        if self.filters.iter().all(|f| f.is_none()) {
+            return true; // No filters, accept everything
+        }
+        let mut pass = false;
+        for f in self.filters.iter().flatten() {
+            match f {
+                FilterType::Pass { id: f_id, mask } | FilterType::IsoTP { id: f_id, mask, .. } => {
+                    if id & mask == f_id & mask {
+                        pass = true
+                    }
+                }
+                FilterType::Block { id: f_id, mask } => {
+                    if id & mask == f_id & mask {
+                        return false;
+                    }
+                }
+            }
+        }
+        pass
+    }
+}
+
+/// Enforces K-Line P3 timing before a request is sent, by waiting for P3 min to pass
+/// since the last message on the line.
+///
+/// Returns true if P3 max has already passed, meaning the ECU has gone back to sleep
+/// and has to be woken up again
+fn kline_p3_wait(cfg: &InterfaceConfig, last_activity: Instant) -> bool {
+    let elapsed = last_activity.elapsed().as_millis();
+    if elapsed > cfg.get_param_or_default(IFACE_CFG::KLINE_P3_MAX, 5000) as u128
+        && cfg.get_param_or_default(IFACE_CFG::KLINE_INIT_MODE, 0) != KLineInitMode::None as u32
+    {
+        return true;
+    }
+    let p3_min = cfg.get_param_or_default(IFACE_CFG::KLINE_P3_MIN, 55) as u128;
+    if elapsed < p3_min {
+        std::thread::sleep(std::time::Duration::from_millis((p3_min - elapsed) as u64));
+    }
+    false
+}
+
+/// ISO14230-2 header layout, negotiated with the ECU's key bytes during init
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Iso14230Header {
+    /// Header contains target and source address bytes
+    pub(crate) addresses: bool,
+    /// Length can be encoded in the format byte (Payloads up to 63 bytes)
+    pub(crate) len_in_fmt: bool,
+    /// Length can be sent as an additional byte (Payloads up to 255 bytes)
+    pub(crate) len_byte: bool,
+}
+
+impl Default for Iso14230Header {
+    fn default() -> Self {
+        Self {
+            addresses: true,
+            len_in_fmt: true,
+            len_byte: true,
+        }
+    }
+}
+
+impl Iso14230Header {
+    /// Decodes the header formats the ECU supports from key byte 1
+    pub(crate) fn from_key_byte(kb1: u8) -> Self {
+        let mut res = Self {
+            addresses: kb1 & 0x08 != 0,  // HB1
+            len_in_fmt: kb1 & 0x01 != 0, // AL0
+            len_byte: kb1 & 0x02 != 0,   // AL1
+        };
+        if kb1 & 0x04 == 0 {
+            res.addresses = true; // HB0 not set, header without addresses is not supported
+        }
+        if !res.len_in_fmt && !res.len_byte {
+            res.len_in_fmt = true;
+        }
+        res
+    }
+
+    /// Builds a complete K-Line message (Without checksum)
+    ///
+    /// ## Params
+    /// * functional - Use functional rather than physical addressing
+    pub(crate) fn build(
+        &self,
+        target: u8,
+        source: u8,
+        functional: bool,
+        payload: &[u8],
+    ) -> InterfaceResult<Vec<u8>> {
+        let mut fmt: u8 = match (self.addresses, functional) {
+            (false, _) => 0x00,
+            (true, false) => 0x80,
+            (true, true) => 0xC0,
+        };
+        let len = payload.len();
+        let mut len_byte = None;
+        if len == 0 || len > 0xFF || (len > 0x3F && !self.len_byte) {
+            return Err(ComServerError {
+                err_code: 3,
+                err_desc: format!("Cannot send a {} byte payload over ISO14230", len),
+            });
+        } else if len <= 0x3F && self.len_in_fmt {
+            fmt |= len as u8;
+        } else {
+            len_byte = Some(len as u8)
+        }
+        let mut res = vec![fmt];
+        if self.addresses {
+            res.push(target);
+            res.push(source);
+        }
+        if let Some(l) = len_byte {
+            res.push(l);
+        }
+        res.extend_from_slice(payload);
+        Ok(res)
+    }
+
+    /// Splits a received K-Line message into its target and source addresses (If present)
+    /// and its payload. Returns None if the message is malformed or has no payload
+    pub(crate) fn parse(msg: &[u8]) -> Option<(Option<(u8, u8)>, Vec<u8>)> {
+        let fmt = *msg.get(0)?;
+        let mut idx = 1;
+        let addresses = if fmt & 0xC0 != 0 {
+            idx += 2;
+            Some((*msg.get(1)?, *msg.get(2)?))
+        } else {
+            None
+        };
+        let mut len = (fmt & 0x3F) as usize;
+        if len == 0 {
+            len = *msg.get(idx)? as usize;
+            idx += 1;
+        }
+        if len == 0 {
+            return None; // Every message has at least a service ID
+        }
+        msg.get(idx..idx + len).map(|p| (addresses, Vec::from(p)))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Iso14230Interface {
     dev: Box<dyn ComServer>,
+    cfg: InterfaceConfig,
+    header: Iso14230Header,
+    filters: SoftwareFilters,
+    last_activity: Instant,
 }
 
 impl Iso14230Interface {
@@ -321,39 +780,166 @@ impl Iso14230Interface {
         } else {
             Ok(Box::new(Iso14230Interface {
                 dev: dev.clone_box(),
+                cfg: InterfaceConfig::new(),
+                header: Iso14230Header::default(),
+                filters: SoftwareFilters::default(),
+                last_activity: Instant::now(),
             }))
         }
     }
+
+    fn source_addr(&self) -> u8 {
+        self.cfg
+            .get_param_or_default(IFACE_CFG::KLINE_SOURCE_ADDR, 0xF1) as u8
+    }
+
+    /// Wakes up the ECU and works out which header format to use from its key bytes
+    fn wake_up(&mut self) -> InterfaceResult<()> {
+        let target = self.cfg.get_param(IFACE_CFG::KLINE_TARGET_ADDR)? as u8;
+        let key_bytes =
+            match KLineInitMode::from(self.cfg.get_param_or_default(IFACE_CFG::KLINE_INIT_MODE, 0))
+            {
+                KLineInitMode::None => None,
+                KLineInitMode::FiveBaud => Some(self.dev.iso14230_five_baud_init(target)?),
+                KLineInitMode::FastInit => {
+                    // StartCommunication request. ISO14230-4 (OBD) sends this functionally
+                    let functional =
+                        self.cfg.get_param(IFACE_CFG::KLINE_FUNC_ADDR).ok() == Some(target as u32);
+                    let req = Iso14230Header::default().build(
+                        target,
+                        self.source_addr(),
+                        functional,
+                        &[0x81],
+                    )?;
+                    let resp = self.dev.iso14230_fast_init(&req)?;
+                    match Iso14230Header::parse(&resp) {
+                        Some((_, p)) if p.len() >= 3 && p[0] == 0xC1 => Some(vec![p[1], p[2]]),
+                        _ => {
+                            return Err(ComServerError {
+                                err_code: 4,
+                                err_desc: format!(
+                                    "ECU rejected StartCommunication request. Response: {:02X?}",
+                                    resp
+                                ),
+                            })
+                        }
+                    }
+                }
+            };
+        if let Some(kb) = key_bytes {
+            // KB2 is always 0x8F for KWP2000
+            if kb.len() != 2 || kb[1] != 0x8F {
+                return Err(ComServerError {
+                    err_code: 4,
+                    err_desc: format!("ECU did not reply with KWP2000 key bytes ({:02X?})", kb),
+                });
+            }
+            self.header = Iso14230Header::from_key_byte(kb[0]);
+        }
+        self.last_activity = Instant::now();
+        Ok(())
+    }
 }
 
-#[allow(unused_variables)]
 impl Interface for Iso14230Interface {
     fn clear_buffer(&mut self, buffer_type: BufferType) -> InterfaceResult<()> {
-        todo!()
+        match buffer_type {
+            BufferType::TX => self.dev.clear_iso14230_tx_buffer(),
+            BufferType::RX => self.dev.clear_iso14230_rx_buffer(),
+            BufferType::BOTH => {
+                self.dev.clear_iso14230_tx_buffer()?;
+                self.dev.clear_iso14230_rx_buffer()
+            }
+        }
     }
 
     fn setup(&mut self, cfg: &InterfaceConfig) -> InterfaceResult<()> {
-        todo!()
+        self.cfg = cfg.clone();
+        self.dev
+            .open_iso14230_interface(cfg.get_param_or_default(IFACE_CFG::BAUDRATE, 10400))?;
+        // Use ISO14230-2 defaults if not specified
+        self.dev.set_iso14230_params(
+            cfg.get_param_or_default(IFACE_CFG::KLINE_P1_MAX, 20),
+            cfg.get_param_or_default(IFACE_CFG::KLINE_P3_MIN, 55),
+            cfg.get_param_or_default(IFACE_CFG::KLINE_P4_MIN, 5),
+        )?;
+        if let Err(e) = self.wake_up() {
+            let _ = self.dev.close_iso14230_interface();
+            return Err(e);
+        }
+        Ok(())
     }
 
     fn send_data(&mut self, data: &[InterfacePayload], timeout: u32) -> InterfaceResult<usize> {
-        todo!()
+        let max_size =
+            self.cfg
+                .get_param_or_default(IFACE_CFG::KLINE_MAX_SEGMENT_SIZE, 254) as usize;
+        let func_addr = self.cfg.get_param(IFACE_CFG::KLINE_FUNC_ADDR).ok();
+        let mut msgs = Vec::new();
+        for p in data {
+            if p.data.len() > max_size {
+                return Err(ComServerError {
+                    err_code: 3,
+                    err_desc: format!(
+                        "Payload of {} bytes exceeds the maximum segment size of {} bytes",
+                        p.data.len(),
+                        max_size
+                    ),
+                });
+            }
+            msgs.push(KLineData {
+                data: self.header.build(
+                    p.id as u8,
+                    self.source_addr(),
+                    func_addr == Some(p.id),
+                    &p.data,
+                )?,
+            });
+        }
+
+        if kline_p3_wait(&self.cfg, self.last_activity) {
+            self.wake_up()?;
+        }
+        let res = self.dev.send_iso14230_data(&msgs, timeout);
+        self.last_activity = Instant::now();
+        res
     }
 
     fn recv_data(&mut self, max: usize, timeout: u32) -> InterfaceResult<Vec<InterfacePayload>> {
-        todo!()
+        let source = self.source_addr();
+        let target = self
+            .cfg
+            .get_param_or_default(IFACE_CFG::KLINE_TARGET_ADDR, 0);
+        let msgs = self.dev.read_iso14230_packets(timeout, max)?;
+        if !msgs.is_empty() {
+            self.last_activity = Instant::now();
+        }
+        Ok(msgs
+            .iter()
+            .filter_map(|m| Iso14230Header::parse(&m.data))
+            .filter_map(|(addrs, data)| match addrs {
+                // Only accept messages for the tester
+                Some((tgt, src)) if tgt == source => Some(InterfacePayload::new(src as u32, &data)),
+                Some(_) => None,
+                // No address in header, must be from the ECU we woke up
+                None => Some(InterfacePayload::new(target, &data)),
+            })
+            .filter(|p| self.filters.matches(p.id))
+            .collect())
     }
 
     fn add_filter(&mut self, f: FilterType) -> InterfaceResult<u32> {
-        todo!()
+        self.filters.add(f)
     }
 
     fn rem_filter(&mut self, f_id: u32) -> InterfaceResult<()> {
-        todo!()
+        self.filters.remove(f_id);
+        Ok(())
     }
 
     fn close(&mut self) -> InterfaceResult<()> {
-        todo!()
+        self.filters.clear();
+        self.dev.close_iso14230_interface()
     }
 
     fn get_server(&self) -> Box<dyn ComServer> {
@@ -361,14 +947,21 @@ impl Interface for Iso14230Interface {
     }
 
     fn clone_box(&self) -> Box<dyn Interface> {
-        Box::new(Self {
-            dev: self.dev.clone(),
-        })
+        Box::new(self.clone())
     }
 }
+
+/// ISO9141-2 (OBD-II over K-Line) interface.
+///
+/// Messages always use the 3 byte OBD-II header. Requests are sent to the functional
+/// OBD-II address, and the interface payload ID of responses is the address of
+/// the ECU which sent them
 #[derive(Debug, Clone)]
 pub struct Iso9141Interface {
     dev: Box<dyn ComServer>,
+    cfg: InterfaceConfig,
+    filters: SoftwareFilters,
+    last_activity: Instant,
 }
 
 impl Iso9141Interface {
@@ -381,39 +974,124 @@ impl Iso9141Interface {
         } else {
             Ok(Box::new(Iso9141Interface {
                 dev: dev.clone_box(),
+                cfg: InterfaceConfig::new(),
+                filters: SoftwareFilters::default(),
+                last_activity: Instant::now(),
             }))
         }
     }
+
+    /// Wakes up the ECU with a 5 baud init, and checks its key bytes
+    fn wake_up(&mut self) -> InterfaceResult<()> {
+        if KLineInitMode::from(self.cfg.get_param_or_default(IFACE_CFG::KLINE_INIT_MODE, 0))
+            != KLineInitMode::None
+        {
+            let target = self
+                .cfg
+                .get_param_or_default(IFACE_CFG::KLINE_TARGET_ADDR, 0x33);
+            let kb = self.dev.iso9141_five_baud_init(target as u8)?;
+            // ISO9141-2 only allows 08 08 or 94 94 as key bytes
+            match kb.as_slice() {
+                [0x08, 0x08] | [0x94, 0x94] => {}
+                _ => {
+                    return Err(ComServerError {
+                        err_code: 4,
+                        err_desc: format!(
+                            "ECU did not reply with ISO9141-2 key bytes ({:02X?})",
+                            kb
+                        ),
+                    })
+                }
+            }
+        }
+        self.last_activity = Instant::now();
+        Ok(())
+    }
 }
 
-#[allow(unused_variables)]
 impl Interface for Iso9141Interface {
     fn clear_buffer(&mut self, buffer_type: BufferType) -> InterfaceResult<()> {
-        todo!()
+        match buffer_type {
+            BufferType::TX => self.dev.clear_iso9141_tx_buffer(),
+            BufferType::RX => self.dev.clear_iso9141_rx_buffer(),
+            BufferType::BOTH => {
+                self.dev.clear_iso9141_tx_buffer()?;
+                self.dev.clear_iso9141_rx_buffer()
+            }
+        }
     }
 
     fn setup(&mut self, cfg: &InterfaceConfig) -> InterfaceResult<()> {
-        todo!()
+        self.cfg = cfg.clone();
+        self.dev
+            .open_iso9141_interface(cfg.get_param_or_default(IFACE_CFG::BAUDRATE, 10400))?;
+        // Use ISO9141-2 defaults if not specified
+        self.dev.set_iso9141_params(
+            cfg.get_param_or_default(IFACE_CFG::KLINE_P1_MAX, 20),
+            cfg.get_param_or_default(IFACE_CFG::KLINE_P3_MIN, 55),
+            cfg.get_param_or_default(IFACE_CFG::KLINE_P4_MIN, 5),
+        )?;
+        if let Err(e) = self.wake_up() {
+            let _ = self.dev.close_iso9141_interface();
+            return Err(e);
+        }
+        Ok(())
     }
 
     fn send_data(&mut self, data: &[InterfacePayload], timeout: u32) -> InterfaceResult<usize> {
-        todo!()
+        // ISO9141-2 messages are at most 11 bytes, including header and checksum
+        let max_size =
+            self.cfg
+                .get_param_or_default(IFACE_CFG::KLINE_MAX_SEGMENT_SIZE, 7) as usize;
+        let source = self
+            .cfg
+            .get_param_or_default(IFACE_CFG::KLINE_SOURCE_ADDR, 0xF1) as u8;
+        let mut msgs = Vec::new();
+        for p in data {
+            if p.data.is_empty() || p.data.len() > max_size {
+                return Err(ComServerError {
+                    err_code: 3,
+                    err_desc: format!("Cannot send a {} byte payload over ISO9141", p.data.len()),
+                });
+            }
+            let mut msg = vec![0x68, 0x6A, source];
+            msg.extend_from_slice(&p.data);
+            msgs.push(KLineData { data: msg });
+        }
+        if kline_p3_wait(&self.cfg, self.last_activity) {
+            self.wake_up()?;
+        }
+        let res = self.dev.send_iso9141_data(&msgs, timeout);
+        self.last_activity = Instant::now();
+        res
     }
 
     fn recv_data(&mut self, max: usize, timeout: u32) -> InterfaceResult<Vec<InterfacePayload>> {
-        todo!()
+        let msgs = self.dev.read_iso9141_packets(timeout, max)?;
+        if !msgs.is_empty() {
+            self.last_activity = Instant::now();
+        }
+        // Response header is 0x48 0x6B <ECU address>
+        Ok(msgs
+            .iter()
+            .filter(|m| m.data.len() > 3 && m.data[1] == 0x6B)
+            .map(|m| InterfacePayload::new(m.data[2] as u32, &m.data[3..]))
+            .filter(|p| self.filters.matches(p.id))
+            .collect())
     }
 
     fn close(&mut self) -> InterfaceResult<()> {
-        todo!()
+        self.filters.clear();
+        self.dev.close_iso9141_interface()
     }
 
     fn add_filter(&mut self, f: FilterType) -> InterfaceResult<u32> {
-        todo!()
+        self.filters.add(f)
     }
 
     fn rem_filter(&mut self, f_id: u32) -> InterfaceResult<()> {
-        todo!()
+        self.filters.remove(f_id);
+        Ok(())
     }
 
     fn get_server(&self) -> Box<dyn ComServer> {
@@ -421,10 +1099,284 @@ impl Interface for Iso9141Interface {
     }
 
     fn clone_box(&self) -> Box<dyn Interface> {
-        Box::new(Self {
-            dev: self.dev.clone(),
+        Box::new(self.clone())
+    }
+}
+
+/// SAE J1850 (VPW or PWM) interface.
+///
+/// Messages use the 3 byte J1850 header (Priority/type, target address, source address).
+/// The target address of a message is the ID of the interface payload, and the interface
+/// payload ID of received messages is the address of the node which sent them
+#[derive(Debug, Clone)]
+pub struct J1850Interface {
+    dev: Box<dyn ComServer>,
+    cfg: InterfaceConfig,
+    pwm: bool,
+    filters: SoftwareFilters,
+}
+
+impl J1850Interface {
+    pub fn new(dev: Box<dyn ComServer>, pwm: bool) -> InterfaceResult<Box<dyn Interface>> {
+        let caps = dev.get_capabilities();
+        let supported = if pwm {
+            caps.supports_j1850pwm()
+        } else {
+            caps.supports_j1850vpw()
+        };
+        if supported != Capability::Yes {
+            Err(ComServerError {
+                err_code: 1,
+                err_desc: format!(
+                    "Device does not support J1850{}",
+                    if pwm { "PWM" } else { "VPW" }
+                ),
+            })
+        } else {
+            Ok(Box::new(J1850Interface {
+                dev: dev.clone_box(),
+                cfg: InterfaceConfig::new(),
+                pwm,
+                filters: SoftwareFilters::default(),
+            }))
+        }
+    }
+
+    /// Adds the J1850 header to a payload. The SAE J1979 header is used if
+    /// the priority byte is not specified
+    fn build_msg(&self, p: &InterfacePayload) -> InterfaceResult<J1850Data> {
+        let priority = self.cfg.get_param_or_default(
+            IFACE_CFG::J1850_PRIORITY,
+            if self.pwm { 0x61 } else { 0x68 },
+        ) as u8;
+        let source = self
+            .cfg
+            .get_param_or_default(IFACE_CFG::J1850_SOURCE_ADDR, 0xF1) as u8;
+        // J1850 frames are at most 12 bytes, including header and CRC
+        if p.data.is_empty() || p.data.len() > 8 {
+            return Err(ComServerError {
+                err_code: 3,
+                err_desc: format!("Cannot send a {} byte payload over J1850", p.data.len()),
+            });
+        }
+        let mut msg = vec![priority, p.id as u8, source];
+        msg.extend_from_slice(&p.data);
+        Ok(J1850Data { data: msg })
+    }
+
+    /// Strips the J1850 header from a received message. Returns None if the message
+    /// has no payload or was not sent to the tester, either physically or functionally
+    fn parse_msg(&self, m: &J1850Data) -> Option<InterfacePayload> {
+        let source = self
+            .cfg
+            .get_param_or_default(IFACE_CFG::J1850_SOURCE_ADDR, 0xF1) as u8;
+        let resp = self
+            .cfg
+            .get_param_or_default(IFACE_CFG::J1850_RESPONSE_ADDR, 0x6B) as u8;
+        if m.data.len() > 3 && (m.data[1] == resp || m.data[1] == source) {
+            Some(InterfacePayload::new(m.data[2] as u32, &m.data[3..]))
+        } else {
+            None
+        }
+    }
+}
+
+impl Interface for J1850Interface {
+    fn clear_buffer(&mut self, buffer_type: BufferType) -> InterfaceResult<()> {
+        match (buffer_type, self.pwm) {
+            (BufferType::TX, false) => self.dev.clear_j1850vpw_tx_buffer(),
+            (BufferType::RX, false) => self.dev.clear_j1850vpw_rx_buffer(),
+            (BufferType::BOTH, false) => {
+                self.dev.clear_j1850vpw_tx_buffer()?;
+                self.dev.clear_j1850vpw_rx_buffer()
+            }
+            (BufferType::TX, true) => self.dev.clear_j1850pwm_tx_buffer(),
+            (BufferType::RX, true) => self.dev.clear_j1850pwm_rx_buffer(),
+            (BufferType::BOTH, true) => {
+                self.dev.clear_j1850pwm_tx_buffer()?;
+                self.dev.clear_j1850pwm_rx_buffer()
+            }
+        }
+    }
+
+    fn setup(&mut self, cfg: &InterfaceConfig) -> InterfaceResult<()> {
+        self.cfg = cfg.clone();
+        if self.pwm {
+            let source = cfg.get_param_or_default(IFACE_CFG::J1850_SOURCE_ADDR, 0xF1);
+            let resp = cfg.get_param_or_default(IFACE_CFG::J1850_RESPONSE_ADDR, 0x6B);
+            self.dev.open_j1850pwm_interface(
+                cfg.get_param_or_default(IFACE_CFG::BAUDRATE, 41600),
+                source as u8,
+                &[resp as u8],
+            )
+        } else {
+            self.dev
+                .open_j1850vpw_interface(cfg.get_param_or_default(IFACE_CFG::BAUDRATE, 10400))
+        }
+    }
+
+    fn send_data(&mut self, data: &[InterfacePayload], timeout: u32) -> InterfaceResult<usize> {
+        let msgs = data
+            .iter()
+            .map(|p| self.build_msg(p))
+            .collect::<InterfaceResult<Vec<J1850Data>>>()?;
+        if self.pwm {
+            self.dev.send_j1850pwm_data(&msgs, timeout)
+        } else {
+            self.dev.send_j1850vpw_data(&msgs, timeout)
+        }
+    }
+
+    fn recv_data(&mut self, max: usize, timeout: u32) -> InterfaceResult<Vec<InterfacePayload>> {
+        let msgs = if self.pwm {
+            self.dev.read_j1850pwm_packets(timeout, max)?
+        } else {
+            self.dev.read_j1850vpw_packets(timeout, max)?
+        };
+        Ok(msgs
+            .iter()
+            .filter_map(|m| self.parse_msg(m))
+            .filter(|p| self.filters.matches(p.id))
+            .collect())
+    }
+
+    fn close(&mut self) -> InterfaceResult<()> {
+        self.filters.clear();
+        if self.pwm {
+            self.dev.close_j1850pwm_interface()
+        } else {
+            self.dev.close_j1850vpw_interface()
+        }
+    }
+
+    fn add_filter(&mut self, f: FilterType) -> InterfaceResult<u32> {
+        self.filters.add(f)
+    }
+
+    fn rem_filter(&mut self, f_id: u32) -> InterfaceResult<()> {
+        self.filters.remove(f_id);
+        Ok(())
+    }
+
+    fn get_server(&self) -> Box<dyn ComServer> {
+        self.dev.clone_box()
+    }
+
+    fn clone_box(&self) -> Box<dyn Interface> {
+        Box::new(self.clone())
+    }
+}
+
+/// DoIP (ISO13400) interface, for talking UDS to ECUs over Ethernet.
+///
+/// DoIP runs over the host's own network connection rather than the adapter, so
+/// the adapter is only kept in order to satisfy [Interface::get_server].
+/// The interface payload ID is the logical address of the target ECU, and the
+/// interface payload ID of received messages is the logical address of the ECU which sent them
+#[derive(Debug, Clone)]
+pub struct DoIpInterface {
+    dev: Box<dyn ComServer>,
+    conn: Option<Arc<Mutex<DoIpConnection>>>,
+    filters: SoftwareFilters,
+}
+
+impl DoIpInterface {
+    pub fn new(dev: Box<dyn ComServer>) -> InterfaceResult<Box<dyn Interface>> {
+        Ok(Box::new(DoIpInterface {
+            dev: dev.clone_box(),
+            conn: None,
+            filters: SoftwareFilters::default(),
+        }))
+    }
+
+    fn exec<R, F: FnOnce(&mut DoIpConnection) -> InterfaceResult<R>>(
+        &self,
+        func: F,
+    ) -> InterfaceResult<R> {
+        match &self.conn {
+            Some(c) => func(&mut c.lock().unwrap()),
+            None => Err(ComServerError {
+                err_code: 98,
+                err_desc: "DoIP connection not open".into(),
+            }),
+        }
+    }
+}
+
+impl Interface for DoIpInterface {
+    fn clear_buffer(&mut self, buffer_type: BufferType) -> InterfaceResult<()> {
+        match buffer_type {
+            BufferType::TX => Ok(()), // Sent instantly over TCP
+            BufferType::RX | BufferType::BOTH => self.exec(|c| c.clear_rx()),
+        }
+    }
+
+    fn setup(&mut self, cfg: &InterfaceConfig) -> InterfaceResult<()> {
+        let ip = Ipv4Addr::from(cfg.get_param(IFACE_CFG::DOIP_IP_ADDR)?);
+        let port = cfg.get_param_or_default(IFACE_CFG::DOIP_PORT, DOIP_PORT as u32) as u16;
+        let conn = DoIpConnection::connect(
+            SocketAddrV4::new(ip, port),
+            cfg.get_param_or_default(IFACE_CFG::DOIP_SOURCE_ADDR, 0x0E00) as u16,
+            cfg.get_param_or_default(IFACE_CFG::DOIP_ACTIVATION_TYPE, 0x00) as u8,
+            2000,
+        )?;
+        self.conn = Some(Arc::new(Mutex::new(conn)));
+        Ok(())
+    }
+
+    fn send_data(&mut self, data: &[InterfacePayload], timeout: u32) -> InterfaceResult<usize> {
+        // Always wait for the entity to acknowledge the message, so routing errors
+        // are reported rather than looking like the ECU did not respond
+        let timeout = if timeout == 0 { 1000 } else { timeout };
+        self.exec(|c| {
+            for p in data {
+                c.send_diag_message(p.id as u16, &p.data, timeout)?;
+            }
+            Ok(data.len())
+        })
+    }
+
+    fn recv_data(&mut self, max: usize, timeout: u32) -> InterfaceResult<Vec<InterfacePayload>> {
+        let filters = self.filters.clone();
+        self.exec(|c| {
+            let mut res = Vec::new();
+            let start = Instant::now();
+            while res.len() < max {
+                let remaining = timeout.saturating_sub(start.elapsed().as_millis() as u32);
+                match c.read_diag_message(remaining)? {
+                    Some((source, data)) if filters.matches(source as u32) => {
+                        res.push(InterfacePayload::new(source as u32, &data))
+                    }
+                    Some(_) => {}
+                    None => break,
+                }
+            }
+            Ok(res)
         })
     }
+
+    fn close(&mut self) -> InterfaceResult<()> {
+        self.filters.clear();
+        self.conn.take(); // Connection is closed on drop
+        Ok(())
+    }
+
+    fn add_filter(&mut self, f: FilterType) -> InterfaceResult<u32> {
+        self.filters.add(f)
+    }
+
+    fn rem_filter(&mut self, f_id: u32) -> InterfaceResult<()> {
+        self.filters.remove(f_id);
+        Ok(())
+    }
+
+    fn get_server(&self) -> Box<dyn ComServer> {
+        self.dev.clone_box()
+    }
+
+    fn clone_box(&self) -> Box<dyn Interface> {
+        Box::new(self.clone())
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -433,6 +1385,9 @@ pub enum InterfaceType {
     IsoTp,
     Iso14230,
     Iso9141,
+    J1850Vpw,
+    J1850Pwm,
+    DoIp,
 }
 
 #[derive(Debug, Clone)]
@@ -449,9 +1404,15 @@ impl DynamicInterface {
     ) -> InterfaceResult<Self> {
         let mut iface = match iface_type {
             InterfaceType::Can => CanbusInterface::new(server.clone_box())?,
+            InterfaceType::IsoTp if cfg.get_param_or_default(IFACE_CFG::ISOTP_SOFTWARE, 0) > 0 => {
+                SoftwareIsoTpInterface::new(server.clone_box())?
+            }
             InterfaceType::IsoTp => IsoTPInterface::new(server.clone_box())?,
             InterfaceType::Iso14230 => Iso14230Interface::new(server.clone_box())?,
             InterfaceType::Iso9141 => Iso9141Interface::new(server.clone_box())?,
+            InterfaceType::J1850Vpw => J1850Interface::new(server.clone_box(), false)?,
+            InterfaceType::J1850Pwm => J1850Interface::new(server.clone_box(), true)?,
+            InterfaceType::DoIp => DoIpInterface::new(server.clone_box())?,
         };
         iface.setup(cfg)?;
         Ok(Self {
@@ -475,6 +1436,9 @@ impl DynamicInterface {
                 InterfaceType::IsoTp => "ISO15765 (ISO-TP)",
                 InterfaceType::Iso14230 => "ISO14230 (KWP2000 over LIN)",
                 InterfaceType::Iso9141 => "ISO9141 (OBD-II)",
+                InterfaceType::J1850Vpw => "SAE J1850 VPW",
+                InterfaceType::J1850Pwm => "SAE J1850 PWM",
+                InterfaceType::DoIp => "ISO13400 (DoIP)",
             }
         } else {
             "Not configured"
@@ -547,3 +1511,185 @@ impl Interface for DynamicInterface {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commapi::simulator_api::SimulatorAPI;
+
+    #[test]
+    fn iso14230_key_bytes() {
+        let all = Iso14230Header::from_key_byte(0x8F);
+        assert!(all.addresses && all.len_in_fmt && all.len_byte);
+        // HB0 only, so the header has no addresses
+        let no_addr = Iso14230Header::from_key_byte(0x85);
+        assert!(!no_addr.addresses && no_addr.len_in_fmt && !no_addr.len_byte);
+        // Neither header type set, so addresses are used. Length byte only
+        let len_byte = Iso14230Header::from_key_byte(0x82);
+        assert!(len_byte.addresses && !len_byte.len_in_fmt && len_byte.len_byte);
+        // No length type set, so the length goes in the format byte
+        let fmt_len = Iso14230Header::from_key_byte(0x88);
+        assert!(fmt_len.addresses && fmt_len.len_in_fmt && !fmt_len.len_byte);
+    }
+
+    #[test]
+    fn iso14230_round_trip() {
+        let payload: Vec<u8> = (0..=0xFF).map(|x| x as u8).collect();
+        // Every header the ECU can ask for with its key byte
+        for kb1 in 0..=0x0F {
+            let header = Iso14230Header::from_key_byte(0x80 | kb1);
+            for &functional in &[false, true] {
+                for &len in &[1, 2, 0x3F, 0x40, 0xFF, 0x100] {
+                    let res = header.build(0x33, 0xF1, functional, &payload[0..len]);
+                    if len > 0xFF || (len > 0x3F && !header.len_byte) {
+                        assert!(res.is_err(), "KB1 {:02X}, {} bytes", kb1, len);
+                        continue;
+                    }
+                    let msg = res.unwrap();
+                    let fmt = msg[0];
+                    let addr_mode = match (header.addresses, functional) {
+                        (false, _) => 0x00,
+                        (true, false) => 0x80,
+                        (true, true) => 0xC0,
+                    };
+                    assert_eq!(fmt & 0xC0, addr_mode);
+                    if len <= 0x3F && header.len_in_fmt {
+                        assert_eq!((fmt & 0x3F) as usize, len);
+                    } else {
+                        assert_eq!(fmt & 0x3F, 0x00);
+                    }
+
+                    let (addrs, data) = Iso14230Header::parse(&msg).unwrap();
+                    let expected_addrs = if header.addresses {
+                        Some((0x33, 0xF1))
+                    } else {
+                        None
+                    };
+                    assert_eq!(addrs, expected_addrs, "KB1 {:02X}, {} bytes", kb1, len);
+                    assert_eq!(data, &payload[0..len]);
+                }
+            }
+        }
+        assert!(Iso14230Header::default()
+            .build(0x33, 0xF1, false, &[])
+            .is_err());
+    }
+
+    #[test]
+    fn iso14230_parse_every_format_byte() {
+        for fmt in 0..=0xFF {
+            let mut msg = vec![fmt as u8, 0x10, 0xF1, 0x05];
+            msg.extend((0..0x40).map(|x| x as u8));
+            let (addrs, data) = Iso14230Header::parse(&msg).unwrap();
+            let has_addr = fmt & 0xC0 != 0;
+            assert_eq!(addrs.is_some(), has_addr);
+            let expected_len = match fmt & 0x3F {
+                0 if has_addr => 0x05,
+                0 => 0x10, // Length byte follows the format byte
+                l => l,
+            };
+            assert_eq!(data.len(), expected_len, "Format byte {:02X}", fmt);
+        }
+    }
+
+    #[test]
+    fn iso14230_parse_malformed() {
+        assert!(Iso14230Header::parse(&[]).is_none());
+        // No payload
+        assert!(Iso14230Header::parse(&[0x80, 0x10, 0xF1, 0x00]).is_none());
+        assert!(Iso14230Header::parse(&[0x00, 0x00]).is_none());
+        assert!(Iso14230Header::parse(&[0x80, 0x10, 0xF1]).is_none());
+        // Truncated
+        assert!(Iso14230Header::parse(&[0x81, 0x10]).is_none());
+        assert!(Iso14230Header::parse(&[0x83, 0x10, 0xF1, 0x50, 0x81]).is_none());
+        assert!(Iso14230Header::parse(&[0x00, 0x03, 0x50]).is_none());
+        assert_eq!(
+            Iso14230Header::parse(&[0x02, 0x50, 0x81]),
+            Some((None, vec![0x50, 0x81]))
+        );
+    }
+
+    fn j1850(pwm: bool, cfg: InterfaceConfig) -> J1850Interface {
+        J1850Interface {
+            dev: Box::new(SimulatorAPI::new(Vec::new())),
+            cfg,
+            pwm,
+            filters: SoftwareFilters::default(),
+        }
+    }
+
+    #[test]
+    fn j1850_build_msg() {
+        // SAE J1979 headers by default
+        let vpw = j1850(false, InterfaceConfig::new());
+        let msg = vpw
+            .build_msg(&InterfacePayload::new(0x6A, &[0x01, 0x00]))
+            .unwrap();
+        assert_eq!(msg.data, vec![0x68, 0x6A, 0xF1, 0x01, 0x00]);
+        let pwm = j1850(true, InterfaceConfig::new());
+        let msg = pwm
+            .build_msg(&InterfacePayload::new(0x6A, &[0x09, 0x02]))
+            .unwrap();
+        assert_eq!(msg.data, vec![0x61, 0x6A, 0xF1, 0x09, 0x02]);
+
+        let mut cfg = InterfaceConfig::new();
+        cfg.add_param(IFACE_CFG::J1850_PRIORITY, 0x6C);
+        cfg.add_param(IFACE_CFG::J1850_SOURCE_ADDR, 0xF0);
+        let custom = j1850(false, cfg);
+        let msg = custom
+            .build_msg(&InterfacePayload::new(0x10, &[0x3E]))
+            .unwrap();
+        assert_eq!(msg.data, vec![0x6C, 0x10, 0xF0, 0x3E]);
+
+        // At most 8 bytes of payload fit in a frame
+        assert!(vpw
+            .build_msg(&InterfacePayload::new(0x6A, &[0x00; 8]))
+            .is_ok());
+        assert!(vpw
+            .build_msg(&InterfacePayload::new(0x6A, &[0x00; 9]))
+            .is_err());
+        assert!(vpw.build_msg(&InterfacePayload::new(0x6A, &[])).is_err());
+    }
+
+    #[test]
+    fn j1850_parse_msg() {
+        let iface = j1850(false, InterfaceConfig::new());
+        let parse = |data: &[u8]| {
+            iface
+                .parse_msg(&J1850Data {
+                    data: data.to_vec(),
+                })
+                .map(|p| (p.id, p.data))
+        };
+        // Functional response (0x6B) and physical response to the tester (0xF1)
+        assert_eq!(
+            parse(&[0x48, 0x6B, 0x10, 0x41, 0x00, 0xBE]),
+            Some((0x10, vec![0x41, 0x00, 0xBE]))
+        );
+        assert_eq!(
+            parse(&[0x48, 0xF1, 0x18, 0x7F, 0x01, 0x11]),
+            Some((0x18, vec![0x7F, 0x01, 0x11]))
+        );
+        // Our own request echoed back, another tester's traffic and messages with no payload
+        assert_eq!(parse(&[0x68, 0x6A, 0xF1, 0x01, 0x00]), None);
+        assert_eq!(parse(&[0x48, 0xF0, 0x10, 0x41, 0x00]), None);
+        assert_eq!(parse(&[0x48, 0x6B, 0x10]), None);
+        assert_eq!(parse(&[]), None);
+
+        let mut cfg = InterfaceConfig::new();
+        cfg.add_param(IFACE_CFG::J1850_SOURCE_ADDR, 0xF0);
+        cfg.add_param(IFACE_CFG::J1850_RESPONSE_ADDR, 0x6D);
+        let custom = j1850(true, cfg);
+        let parse = |data: &[u8]| {
+            custom
+                .parse_msg(&J1850Data {
+                    data: data.to_vec(),
+                })
+                .map(|p| p.id)
+        };
+        assert_eq!(parse(&[0x48, 0x6D, 0x10, 0x41]), Some(0x10));
+        assert_eq!(parse(&[0x48, 0xF0, 0x10, 0x41]), Some(0x10));
+        assert_eq!(parse(&[0x48, 0x6B, 0x10, 0x41]), None);
+        assert_eq!(parse(&[0x48, 0xF1, 0x10, 0x41]), None);
+    }
+}
@@ -0,0 +1,606 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use super::comm_api::{CanFrame, ComServer, ComServerError};
+
+/// Byte used to fill unused bytes of classic CAN frames when padding is requested
+const PAD_BYTE: u8 = 0x00;
+
+/// Max number of consecutive wait flow control frames accepted before giving up (N_WFTmax)
+const MAX_WAIT_FRAMES: u32 = 10;
+
+/// Largest payload a first frame without the escape sequence can describe
+const MAX_FF_DL_12BIT: usize = 0xFFF;
+
+/// Largest message we are willing to receive
+const MAX_RX_DL: usize = 0x1000000;
+
+const PCI_SF: u8 = 0x00;
+const PCI_FF: u8 = 0x10;
+const PCI_CF: u8 = 0x20;
+const PCI_FC: u8 = 0x30;
+
+const FC_CTS: u8 = 0x00;
+const FC_WAIT: u8 = 0x01;
+const FC_OVERFLOW: u8 = 0x02;
+
+/// Access to a raw CAN bus for [IsoTpStack]
+pub(crate) trait CanChannel: Send {
+    /// Sends a single frame, waiting up to `timeout_ms` for it to be transmitted
+    fn send_frame(&mut self, cf: CanFrame, timeout_ms: u32) -> Result<(), ComServerError>;
+    /// Returns any frames received since the last call, without blocking
+    fn read_frames(&mut self) -> Result<Vec<CanFrame>, ComServerError>;
+}
+
+impl CanChannel for Box<dyn ComServer> {
+    fn send_frame(&mut self, cf: CanFrame, timeout_ms: u32) -> Result<(), ComServerError> {
+        self.send_can_packets(&[cf], timeout_ms).map(|_| ())
+    }
+
+    fn read_frames(&mut self) -> Result<Vec<CanFrame>, ComServerError> {
+        self.read_can_packets(0, 32)
+    }
+}
+
+/// Settings for the software ISO15765-2 stack
+#[derive(Debug, Copy, Clone)]
+pub struct IsoTpParams {
+    /// Block size sent in our flow control frames. 0 = No limit
+    pub block_size: u8,
+    /// Separation time sent in our flow control frames (Raw STmin byte)
+    pub st_min: u8,
+    /// Pad flow control frames to 8 bytes
+    pub pad_flow_control: bool,
+    /// First byte of every frame is an address byte (ISO15765-2 extended addressing)
+    pub ext_addressing: bool,
+    /// Max frame length to send. 8 for classic CAN, 12-64 for CAN FD
+    pub tx_dl: usize,
+    /// Use bit rate switching when sending CAN FD frames
+    pub brs: bool,
+    /// Timeout for a frame to be transmitted by the adapter (ms)
+    pub n_as: u32,
+    /// Timeout waiting for a flow control frame from the ECU (ms)
+    pub n_bs: u32,
+    /// Timeout waiting for the next consecutive frame from the ECU (ms)
+    pub n_cr: u32,
+}
+
+impl Default for IsoTpParams {
+    fn default() -> Self {
+        Self {
+            block_size: 8,
+            st_min: 20,
+            pad_flow_control: false,
+            ext_addressing: false,
+            tx_dl: 8,
+            brs: false,
+            n_as: 1000,
+            n_bs: 1000,
+            n_cr: 1000,
+        }
+    }
+}
+
+/// A pair of CAN IDs that ISO-TP messages are exchanged on
+#[derive(Debug, Copy, Clone)]
+struct IsoTpChannel {
+    /// ID (and mask) the ECU sends on
+    rx_id: u32,
+    mask: u32,
+    /// ID we send on, and send flow control frames with
+    tx_id: u32,
+}
+
+impl IsoTpChannel {
+    fn matches(&self, id: u32) -> bool {
+        id & self.mask == self.rx_id & self.mask
+    }
+}
+
+/// Multi-frame message currently being received
+#[derive(Debug, Clone)]
+struct RxState {
+    data: Vec<u8>,
+    expected_len: usize,
+    next_sn: u8,
+    frames_until_fc: u8,
+    last_frame: Instant,
+}
+
+fn isotp_error(code: u32, desc: String) -> ComServerError {
+    ComServerError {
+        err_code: code,
+        err_desc: desc,
+    }
+}
+
+/// Converts the raw STmin byte of a flow control frame to a duration
+fn st_min_to_duration(st_min: u8) -> Duration {
+    match st_min {
+        0x00..=0x7F => Duration::from_millis(st_min as u64),
+        0xF1..=0xF9 => Duration::from_micros((st_min - 0xF0) as u64 * 100),
+        _ => Duration::from_millis(0x7F), // Reserved, use the longest time
+    }
+}
+
+/// Software implementation of ISO15765-2 (ISO-TP), running on top of a raw CAN channel.
+///
+/// Everything is done on the calling thread, so flow control frames are only sent
+/// whilst [IsoTpStack::read_message] or [IsoTpStack::send_message] are running
+#[derive(Debug)]
+pub(crate) struct IsoTpStack<C: CanChannel> {
+    io: C,
+    params: IsoTpParams,
+    channels: HashMap<u32, IsoTpChannel>,
+    next_channel_id: u32,
+    rx_states: HashMap<u32, RxState>,
+    rx_complete: VecDeque<(u32, Vec<u8>)>,
+    /// Last address byte sent to each ID, used in our flow control frames
+    /// when extended addressing is used
+    tx_addresses: HashMap<u32, u8>,
+}
+
+impl<C: CanChannel> IsoTpStack<C> {
+    pub fn new(io: C, params: IsoTpParams) -> Self {
+        Self {
+            io,
+            params,
+            channels: HashMap::new(),
+            next_channel_id: 0,
+            rx_states: HashMap::new(),
+            rx_complete: VecDeque::new(),
+            tx_addresses: HashMap::new(),
+        }
+    }
+
+    /// Starts listening for messages from `rx_id` (Matched with `mask`). Flow control
+    /// frames are sent back on `tx_id`
+    pub fn add_channel(&mut self, rx_id: u32, mask: u32, tx_id: u32) -> u32 {
+        let id = self.next_channel_id;
+        self.next_channel_id += 1;
+        self.channels
+            .insert(id, IsoTpChannel { rx_id, mask, tx_id });
+        id
+    }
+
+    pub fn remove_channel(&mut self, channel_id: u32) {
+        if let Some(c) = self.channels.remove(&channel_id) {
+            self.rx_states.retain(|id, _| !c.matches(*id));
+        }
+    }
+
+    /// Drops all received messages, including any partially received ones
+    pub fn clear_rx(&mut self) {
+        self.rx_states.clear();
+        self.rx_complete.clear();
+    }
+
+    fn addr_len(&self) -> usize {
+        if self.params.ext_addressing {
+            1
+        } else {
+            0
+        }
+    }
+
+    fn build_frame(&self, id: u32, addr: Option<u8>, body: &[u8], pad: bool) -> CanFrame {
+        let mut data = Vec::with_capacity(self.params.tx_dl);
+        if let Some(a) = addr {
+            data.push(a);
+        }
+        data.extend_from_slice(body);
+        if self.params.tx_dl > 8 {
+            // CAN FD frames are always padded to a valid length
+            CanFrame::new_fd(id, &data, self.params.brs, false)
+        } else {
+            if pad {
+                data.resize(8, PAD_BYTE);
+            }
+            CanFrame::new(id, &data)
+        }
+    }
+
+    fn send_flow_control(
+        &mut self,
+        tx_id: u32,
+        addr: Option<u8>,
+        status: u8,
+    ) -> Result<(), ComServerError> {
+        let body = [PCI_FC | status, self.params.block_size, self.params.st_min];
+        let cf = self.build_frame(tx_id, addr, &body, self.params.pad_flow_control);
+        self.io.send_frame(cf, self.params.n_as)
+    }
+
+    /// Sends a complete message to `tx_id`, segmenting it if required.
+    /// If extended addressing is used, the first byte of `data` is the target address
+    pub fn send_message(
+        &mut self,
+        tx_id: u32,
+        data: &[u8],
+        pad: bool,
+    ) -> Result<(), ComServerError> {
+        let addr_len = self.addr_len();
+        if data.len() <= addr_len {
+            return Err(isotp_error(1, "No data to send".into()));
+        }
+        let addr = if addr_len == 1 {
+            self.tx_addresses.insert(tx_id, data[0]);
+            Some(data[0])
+        } else {
+            None
+        };
+        let payload = &data[addr_len..];
+        let frame_len = self.params.tx_dl - addr_len;
+
+        // Single frame
+        if payload.len() <= 7 - addr_len {
+            let mut body = vec![PCI_SF | payload.len() as u8];
+            body.extend_from_slice(payload);
+            let cf = self.build_frame(tx_id, addr, &body, pad);
+            return self.io.send_frame(cf, self.params.n_as);
+        } else if payload.len() <= frame_len - 2 {
+            // CAN FD single frame, length is stored after the escape sequence
+            let mut body = vec![PCI_SF, payload.len() as u8];
+            body.extend_from_slice(payload);
+            let cf = self.build_frame(tx_id, addr, &body, pad);
+            return self.io.send_frame(cf, self.params.n_as);
+        }
+
+        // Multi frame, flow control is received on the channel that sends its flow control on tx_id
+        let rx_channel = match self.channels.values().find(|c| c.tx_id == tx_id) {
+            Some(c) => *c,
+            None => {
+                return Err(isotp_error(
+                    1,
+                    format!("No ISO-TP filter with flow control ID 0x{:04X}", tx_id),
+                ))
+            }
+        };
+        let mut body = if payload.len() <= MAX_FF_DL_12BIT {
+            vec![PCI_FF | (payload.len() >> 8) as u8, payload.len() as u8]
+        } else {
+            // First frame escape sequence, 32 bit length
+            let mut b = vec![PCI_FF, 0x00];
+            b.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            b
+        };
+        let mut pos = frame_len - body.len();
+        body.extend_from_slice(&payload[0..pos]);
+        let cf = self.build_frame(tx_id, addr, &body, pad);
+        self.io.send_frame(cf, self.params.n_as)?;
+
+        let mut sn: u8 = 1;
+        while pos < payload.len() {
+            let (block_size, st_min) = self.wait_flow_control(&rx_channel)?;
+            let mut sent_in_block = 0;
+            while pos < payload.len() && (block_size == 0 || sent_in_block < block_size) {
+                if sent_in_block > 0 {
+                    std::thread::sleep(st_min);
+                }
+                let end = std::cmp::min(pos + frame_len - 1, payload.len());
+                let mut body = vec![PCI_CF | sn];
+                body.extend_from_slice(&payload[pos..end]);
+                let cf = self.build_frame(tx_id, addr, &body, pad);
+                self.io.send_frame(cf, self.params.n_as)?;
+                pos = end;
+                sn = (sn + 1) & 0x0F;
+                sent_in_block += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for a flow control frame from the ECU. Any other frames received
+    /// whilst waiting are processed as normal
+    fn wait_flow_control(
+        &mut self,
+        channel: &IsoTpChannel,
+    ) -> Result<(u8, Duration), ComServerError> {
+        let addr_len = self.addr_len();
+        let mut wait_frames = 0;
+        let mut start = Instant::now();
+        while start.elapsed().as_millis() <= self.params.n_bs as u128 {
+            let frames = self.io.read_frames()?;
+            if frames.is_empty() {
+                std::thread::sleep(Duration::from_millis(1));
+                continue;
+            }
+            for cf in frames {
+                let data = cf.get_data();
+                if !channel.matches(cf.id)
+                    || data.len() < addr_len + 3
+                    || data[addr_len] & 0xF0 != PCI_FC
+                {
+                    self.process_frame(cf)?;
+                    continue;
+                }
+                match data[addr_len] & 0x0F {
+                    FC_CTS => {
+                        return Ok((data[addr_len + 1], st_min_to_duration(data[addr_len + 2])))
+                    }
+                    FC_WAIT => {
+                        wait_frames += 1;
+                        if wait_frames > MAX_WAIT_FRAMES {
+                            return Err(isotp_error(
+                                3,
+                                "ECU sent too many wait flow control frames".into(),
+                            ));
+                        }
+                        start = Instant::now(); // N_Bs starts again
+                    }
+                    FC_OVERFLOW => {
+                        return Err(isotp_error(
+                            3,
+                            "ECU reported buffer overflow in flow control frame".into(),
+                        ))
+                    }
+                    x => {
+                        return Err(isotp_error(
+                            3,
+                            format!("Invalid flow status 0x{:02X} in flow control frame", x),
+                        ))
+                    }
+                }
+            }
+        }
+        Err(isotp_error(
+            2,
+            "Timeout waiting for flow control frame (N_Bs)".into(),
+        ))
+    }
+
+    /// Handles an incoming frame, sending flow control frames if required
+    fn process_frame(&mut self, cf: CanFrame) -> Result<(), ComServerError> {
+        let channel = match self.channels.values().find(|c| c.matches(cf.id)) {
+            Some(c) => *c,
+            None => return Ok(()), // Not for us
+        };
+        let addr_len = self.addr_len();
+        let data = cf.get_data();
+        if data.len() < addr_len + 1 {
+            return Ok(());
+        }
+        let addr = if addr_len == 1 { Some(data[0]) } else { None };
+        let fc_addr = self.tx_addresses.get(&channel.tx_id).copied().or(addr);
+        let pci = data[addr_len];
+        match pci & 0xF0 {
+            PCI_SF => {
+                // Escape sequence is only valid in CAN FD frames
+                let (len, start) = if pci & 0x0F == 0 && data.len() > 8 {
+                    (data[addr_len + 1] as usize, addr_len + 2)
+                } else {
+                    ((pci & 0x0F) as usize, addr_len + 1)
+                };
+                if len == 0 || start + len > data.len() {
+                    return Ok(()); // Invalid single frame, ignore it
+                }
+                let mut msg = Vec::with_capacity(addr_len + len);
+                msg.extend_from_slice(&data[0..addr_len]);
+                msg.extend_from_slice(&data[start..start + len]);
+                self.rx_states.remove(&cf.id); // Single frame aborts any ongoing reception
+                self.rx_complete.push_back((cf.id, msg));
+            }
+            PCI_FF => {
+                if data.len() < addr_len + 2 {
+                    return Ok(());
+                }
+                let mut len = (((pci & 0x0F) as usize) << 8) | data[addr_len + 1] as usize;
+                let mut start = addr_len + 2;
+                if len == 0 && data.len() >= addr_len + 6 {
+                    let mut b = [0u8; 4];
+                    b.copy_from_slice(&data[addr_len + 2..addr_len + 6]);
+                    len = u32::from_be_bytes(b) as usize;
+                    start = addr_len + 6;
+                }
+                if len == 0 {
+                    return Ok(());
+                }
+                if len > MAX_RX_DL {
+                    return self.send_flow_control(channel.tx_id, fc_addr, FC_OVERFLOW);
+                }
+                let mut msg = Vec::with_capacity(addr_len + len);
+                msg.extend_from_slice(&data[0..addr_len]);
+                msg.extend_from_slice(&data[start..std::cmp::min(data.len(), start + len)]);
+                self.rx_states.insert(
+                    cf.id,
+                    RxState {
+                        data: msg,
+                        expected_len: addr_len + len,
+                        next_sn: 1,
+                        frames_until_fc: self.params.block_size,
+                        last_frame: Instant::now(),
+                    },
+                );
+                self.send_flow_control(channel.tx_id, fc_addr, FC_CTS)?;
+            }
+            PCI_CF => {
+                let block_size = self.params.block_size;
+                let mut send_fc = false;
+                let mut done = false;
+                if let Some(state) = self.rx_states.get_mut(&cf.id) {
+                    if pci & 0x0F != state.next_sn {
+                        // Wrong sequence number, reception is aborted
+                        self.rx_states.remove(&cf.id);
+                        return Ok(());
+                    }
+                    let remaining = state.expected_len - state.data.len();
+                    let end = std::cmp::min(data.len(), addr_len + 1 + remaining);
+                    state.data.extend_from_slice(&data[addr_len + 1..end]);
+                    state.next_sn = (state.next_sn + 1) & 0x0F;
+                    state.last_frame = Instant::now();
+                    if state.data.len() >= state.expected_len {
+                        done = true;
+                    } else if block_size != 0 {
+                        state.frames_until_fc -= 1;
+                        if state.frames_until_fc == 0 {
+                            state.frames_until_fc = block_size;
+                            send_fc = true;
+                        }
+                    }
+                }
+                if done {
+                    if let Some(state) = self.rx_states.remove(&cf.id) {
+                        self.rx_complete.push_back((cf.id, state.data));
+                    }
+                } else if send_fc {
+                    self.send_flow_control(channel.tx_id, fc_addr, FC_CTS)?;
+                }
+            }
+            _ => {} // Flow control frame that nothing is waiting for
+        }
+        Ok(())
+    }
+
+    /// Reads the next complete message, waiting up to `timeout_ms` for one.
+    ///
+    /// ## Returns
+    /// * Ok(Some((CAN ID, data))) - If extended addressing is used, the first byte of data is the address byte
+    /// * Ok(None) - No message was received before the timeout
+    pub fn read_message(
+        &mut self,
+        timeout_ms: u32,
+    ) -> Result<Option<(u32, Vec<u8>)>, ComServerError> {
+        let start = Instant::now();
+        loop {
+            if let Some(msg) = self.rx_complete.pop_front() {
+                return Ok(Some(msg));
+            }
+            let frames = self.io.read_frames()?;
+            let got_frames = !frames.is_empty();
+            for cf in frames {
+                self.process_frame(cf)?;
+            }
+            // Drop any receptions where the ECU stopped sending (N_Cr)
+            let n_cr = self.params.n_cr as u128;
+            self.rx_states
+                .retain(|_, s| s.last_frame.elapsed().as_millis() <= n_cr);
+            if !self.rx_complete.is_empty() {
+                continue;
+            }
+            if start.elapsed().as_millis() >= timeout_ms as u128 {
+                return Ok(None);
+            }
+            if !got_frames {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+
+    const TESTER_ID: u32 = 0x07E0;
+    const ECU_ID: u32 = 0x07E8;
+
+    /// One end of an in memory CAN bus
+    struct BusEnd {
+        tx: Sender<CanFrame>,
+        rx: Receiver<CanFrame>,
+    }
+
+    impl CanChannel for BusEnd {
+        fn send_frame(&mut self, cf: CanFrame, _timeout_ms: u32) -> Result<(), ComServerError> {
+            self.tx
+                .send(cf)
+                .map_err(|_| isotp_error(1, "Bus closed".into()))
+        }
+
+        fn read_frames(&mut self) -> Result<Vec<CanFrame>, ComServerError> {
+            Ok(self.rx.try_iter().collect())
+        }
+    }
+
+    fn bus() -> (BusEnd, BusEnd) {
+        let (tx_a, rx_b) = channel();
+        let (tx_b, rx_a) = channel();
+        (BusEnd { tx: tx_a, rx: rx_a }, BusEnd { tx: tx_b, rx: rx_b })
+    }
+
+    /// Spawns an ECU that echoes back the first message it receives
+    fn spawn_echo_ecu(io: BusEnd, params: IsoTpParams) -> std::thread::JoinHandle<()> {
+        std::thread::spawn(move || {
+            let mut ecu = IsoTpStack::new(io, params);
+            ecu.add_channel(TESTER_ID, 0xFFFF, ECU_ID);
+            if let Some((_, data)) = ecu.read_message(2000).unwrap() {
+                ecu.send_message(ECU_ID, &data, true).unwrap();
+            }
+        })
+    }
+
+    fn echo(params: IsoTpParams, msg: &[u8]) {
+        let (tester_io, ecu_io) = bus();
+        let ecu = spawn_echo_ecu(ecu_io, params);
+        let mut tester = IsoTpStack::new(tester_io, params);
+        tester.add_channel(ECU_ID, 0xFFFF, TESTER_ID);
+        tester.send_message(TESTER_ID, msg, true).unwrap();
+        let (id, data) = tester.read_message(2000).unwrap().unwrap();
+        ecu.join().unwrap();
+        assert_eq!(id, ECU_ID);
+        assert_eq!(data, msg);
+    }
+
+    #[test]
+    fn single_and_multi_frame() {
+        let params = IsoTpParams {
+            block_size: 2,
+            st_min: 0xF1,
+            ..Default::default()
+        };
+        echo(params, &[0x3E, 0x00]);
+        let msg: Vec<u8> = (0..300).map(|x| x as u8).collect();
+        echo(params, &msg);
+    }
+
+    #[test]
+    fn extended_addressing() {
+        let params = IsoTpParams {
+            ext_addressing: true,
+            st_min: 0,
+            ..Default::default()
+        };
+        let mut msg = vec![0x40];
+        msg.extend((0..50).map(|x| x as u8));
+        echo(params, &msg);
+    }
+
+    #[test]
+    fn can_fd_escape_sequences() {
+        let params = IsoTpParams {
+            tx_dl: 64,
+            st_min: 0,
+            block_size: 0,
+            ..Default::default()
+        };
+        // Single frame with the length after the escape sequence
+        let msg: Vec<u8> = (0..40).map(|x| x as u8).collect();
+        echo(params, &msg);
+        // First frame with a 32 bit length
+        let msg: Vec<u8> = (0..5000).map(|x| x as u8).collect();
+        echo(params, &msg);
+    }
+
+    #[test]
+    fn flow_control_timeout() {
+        let (tester_io, ecu_io) = bus();
+        let params = IsoTpParams {
+            n_bs: 50,
+            ..Default::default()
+        };
+        let mut tester = IsoTpStack::new(tester_io, params);
+        tester.add_channel(ECU_ID, 0xFFFF, TESTER_ID);
+        // ECU sends a wait frame, then goes quiet
+        let mut ecu_io = ecu_io;
+        ecu_io
+            .send_frame(CanFrame::new(ECU_ID, &[PCI_FC | FC_WAIT, 0, 0]), 0)
+            .unwrap();
+        let err = tester
+            .send_message(TESTER_ID, &[0x00; 20], false)
+            .unwrap_err();
+        assert_eq!(err.err_code, 2);
+    }
+}
@@ -1,9 +1,12 @@
 #[allow(dead_code)]
 pub mod comm_api;
+pub mod doip;
 pub mod iface;
+pub mod isotp;
 pub mod passthru_api;
 pub mod pdu_api;
 pub mod protocols;
+pub mod simulator_api;
 
 #[cfg(target_os = "linux")]
 pub mod socket_can_api;
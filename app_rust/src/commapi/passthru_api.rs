@@ -1,13 +1,14 @@
 use crate::commapi::comm_api::{
     CanFrame, Capability, ComServer, ComServerError, DeviceCapabilities, FilterType, ISO15765Data,
+    J1850Data, KLineData,
 };
 use crate::passthru::{self, DrvVersion, PassthruDevice, PassthruDrv};
 use j2534_rust::FilterType::{BLOCK_FILTER, FLOW_CONTROL_FILTER, PASS_FILTER};
 use j2534_rust::IoctlID::READ_VBATT;
-use j2534_rust::PassthruError::{ERR_FAILED, ERR_INVALID_CHANNEL_ID};
+use j2534_rust::PassthruError::{ERR_FAILED, ERR_INVALID_CHANNEL_ID, ERR_NOT_SUPPORTED};
 use j2534_rust::{
-    ConnectFlags, IoctlID, IoctlParam, Loggable, PassthruError, Protocol, SConfig, SConfigList,
-    TxFlag, PASSTHRU_MSG,
+    ConnectFlags, IoctlID, IoctlParam, Loggable, PassthruError, Protocol, SByteArray, SConfig,
+    SConfigList, TxFlag, PASSTHRU_MSG,
 };
 use std::sync::{Arc, Mutex, RwLock};
 use std::{os::raw::c_void, time::Instant};
@@ -21,6 +22,9 @@ pub struct PassthruApi {
     can_channel_idx: Arc<RwLock<Option<u32>>>,
     iso15765_channel_idx: Arc<RwLock<Option<u32>>>,
     iso9141_channel_idx: Arc<RwLock<Option<u32>>>,
+    iso14230_channel_idx: Arc<RwLock<Option<u32>>>,
+    j1850vpw_channel_idx: Arc<RwLock<Option<u32>>>,
+    j1850pwm_channel_idx: Arc<RwLock<Option<u32>>>,
 }
 
 impl ComServer for PassthruApi {
@@ -52,6 +56,9 @@ impl ComServer for PassthruApi {
             Some(id) => id,
             None => return Err(self.convert_error(ERR_INVALID_CHANNEL_ID)),
         };
+        if data.iter().any(|cf| cf.fd) {
+            return Err(Self::can_fd_unsupported());
+        }
         let mut msgs: Vec<PASSTHRU_MSG> = data
             .iter()
             .map(|cf| PassthruApi::can_frame_to_pt_msg(cf))
@@ -395,6 +402,240 @@ impl ComServer for PassthruApi {
             .map_err(|e| self.convert_error(e))
     }
 
+    fn set_iso15765_fd_params(&mut self, _tx_dl: u32, _brs: bool) -> Result<(), ComServerError> {
+        Err(Self::can_fd_unsupported())
+    }
+
+    fn open_iso14230_interface(&mut self, baud: u32) -> Result<(), ComServerError> {
+        if self.iso14230_channel_idx.read().unwrap().is_some() {
+            // Already open, close first
+            self.close_iso14230_interface()?;
+        }
+        let channel_id = self.open_raw_channel(Protocol::ISO14230, baud)?;
+        *self.iso14230_channel_idx.write().unwrap() = Some(channel_id);
+        Ok(())
+    }
+
+    fn close_iso14230_interface(&mut self) -> Result<(), ComServerError> {
+        self.close_raw_channel(&self.iso14230_channel_idx)
+    }
+
+    fn iso14230_fast_init(&mut self, init_msg: &[u8]) -> Result<Vec<u8>, ComServerError> {
+        let channel_id = match *self.iso14230_channel_idx.read().unwrap() {
+            Some(idx) => idx,
+            None => return Err(self.convert_error(ERR_INVALID_CHANNEL_ID)),
+        };
+        let mut input = PASSTHRU_MSG {
+            protocol_id: Protocol::ISO14230 as u32,
+            data_size: init_msg.len() as u32,
+            ..Default::default()
+        };
+        input.data[0..init_msg.len()].copy_from_slice(init_msg);
+        let mut output = PASSTHRU_MSG::default();
+        self.driver
+            .lock()
+            .unwrap()
+            .ioctl(
+                channel_id,
+                IoctlID::FAST_INIT,
+                (&mut input) as *mut _ as *mut c_void,
+                (&mut output) as *mut _ as *mut c_void,
+            )
+            .map(|_| Vec::from(&output.data[0..output.data_size as usize]))
+            .map_err(|e| self.convert_error(e))
+    }
+
+    fn iso14230_five_baud_init(&mut self, address: u8) -> Result<Vec<u8>, ComServerError> {
+        self.kline_five_baud_init(&self.iso14230_channel_idx, address)
+    }
+
+    fn send_iso14230_data(
+        &self,
+        data: &[KLineData],
+        timeout_ms: u32,
+    ) -> Result<usize, ComServerError> {
+        let raw: Vec<&[u8]> = data.iter().map(|d| d.data.as_slice()).collect();
+        self.send_raw_data(
+            &self.iso14230_channel_idx,
+            Protocol::ISO14230,
+            &raw,
+            timeout_ms,
+        )
+    }
+
+    fn read_iso14230_packets(
+        &self,
+        timeout_ms: u32,
+        max_msgs: usize,
+    ) -> Result<Vec<KLineData>, ComServerError> {
+        self.read_raw_packets(
+            &self.iso14230_channel_idx,
+            Protocol::ISO14230,
+            timeout_ms,
+            max_msgs,
+        )
+        .map(|msgs| msgs.into_iter().map(|data| KLineData { data }).collect())
+    }
+
+    fn set_iso14230_params(
+        &mut self,
+        p1_max: u32,
+        p3_min: u32,
+        p4_min: u32,
+    ) -> Result<(), ComServerError> {
+        self.set_kline_params(&self.iso14230_channel_idx, p1_max, p3_min, p4_min)
+    }
+
+    fn open_iso9141_interface(&mut self, baud: u32) -> Result<(), ComServerError> {
+        if self.iso9141_channel_idx.read().unwrap().is_some() {
+            // Already open, close first
+            self.close_iso9141_interface()?;
+        }
+        let channel_id = self.open_raw_channel(Protocol::ISO9141, baud)?;
+        *self.iso9141_channel_idx.write().unwrap() = Some(channel_id);
+        Ok(())
+    }
+
+    fn close_iso9141_interface(&mut self) -> Result<(), ComServerError> {
+        self.close_raw_channel(&self.iso9141_channel_idx)
+    }
+
+    fn iso9141_five_baud_init(&mut self, address: u8) -> Result<Vec<u8>, ComServerError> {
+        self.kline_five_baud_init(&self.iso9141_channel_idx, address)
+    }
+
+    fn send_iso9141_data(
+        &self,
+        data: &[KLineData],
+        timeout_ms: u32,
+    ) -> Result<usize, ComServerError> {
+        let raw: Vec<&[u8]> = data.iter().map(|d| d.data.as_slice()).collect();
+        self.send_raw_data(
+            &self.iso9141_channel_idx,
+            Protocol::ISO9141,
+            &raw,
+            timeout_ms,
+        )
+    }
+
+    fn read_iso9141_packets(
+        &self,
+        timeout_ms: u32,
+        max_msgs: usize,
+    ) -> Result<Vec<KLineData>, ComServerError> {
+        self.read_raw_packets(
+            &self.iso9141_channel_idx,
+            Protocol::ISO9141,
+            timeout_ms,
+            max_msgs,
+        )
+        .map(|msgs| msgs.into_iter().map(|data| KLineData { data }).collect())
+    }
+
+    fn set_iso9141_params(
+        &mut self,
+        p1_max: u32,
+        p3_min: u32,
+        p4_min: u32,
+    ) -> Result<(), ComServerError> {
+        self.set_kline_params(&self.iso9141_channel_idx, p1_max, p3_min, p4_min)
+    }
+
+    fn open_j1850vpw_interface(&mut self, baud: u32) -> Result<(), ComServerError> {
+        if self.j1850vpw_channel_idx.read().unwrap().is_some() {
+            // Already open, close first
+            self.close_j1850vpw_interface()?;
+        }
+        let channel_id = self.open_raw_channel(Protocol::J1850VPW, baud)?;
+        *self.j1850vpw_channel_idx.write().unwrap() = Some(channel_id);
+        Ok(())
+    }
+
+    fn close_j1850vpw_interface(&mut self) -> Result<(), ComServerError> {
+        self.close_raw_channel(&self.j1850vpw_channel_idx)
+    }
+
+    fn send_j1850vpw_data(
+        &self,
+        data: &[J1850Data],
+        timeout_ms: u32,
+    ) -> Result<usize, ComServerError> {
+        let raw: Vec<&[u8]> = data.iter().map(|d| d.data.as_slice()).collect();
+        self.send_raw_data(
+            &self.j1850vpw_channel_idx,
+            Protocol::J1850VPW,
+            &raw,
+            timeout_ms,
+        )
+    }
+
+    fn read_j1850vpw_packets(
+        &self,
+        timeout_ms: u32,
+        max_msgs: usize,
+    ) -> Result<Vec<J1850Data>, ComServerError> {
+        self.read_raw_packets(
+            &self.j1850vpw_channel_idx,
+            Protocol::J1850VPW,
+            timeout_ms,
+            max_msgs,
+        )
+        .map(|msgs| msgs.into_iter().map(|data| J1850Data { data }).collect())
+    }
+
+    fn open_j1850pwm_interface(
+        &mut self,
+        baud: u32,
+        node_addr: u8,
+        func_addrs: &[u8],
+    ) -> Result<(), ComServerError> {
+        if self.j1850pwm_channel_idx.read().unwrap().is_some() {
+            // Already open, close first
+            self.close_j1850pwm_interface()?;
+        }
+        let channel_id = self.open_raw_channel(Protocol::J1850PWM, baud)?;
+        *self.j1850pwm_channel_idx.write().unwrap() = Some(channel_id);
+        // PWM acknowledges messages in hardware, so the adapter has to know which
+        // physical and functional addresses belong to it
+        if let Err(e) = self.setup_j1850pwm_addresses(channel_id, node_addr, func_addrs) {
+            let _ = self.close_j1850pwm_interface();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn close_j1850pwm_interface(&mut self) -> Result<(), ComServerError> {
+        self.close_raw_channel(&self.j1850pwm_channel_idx)
+    }
+
+    fn send_j1850pwm_data(
+        &self,
+        data: &[J1850Data],
+        timeout_ms: u32,
+    ) -> Result<usize, ComServerError> {
+        let raw: Vec<&[u8]> = data.iter().map(|d| d.data.as_slice()).collect();
+        self.send_raw_data(
+            &self.j1850pwm_channel_idx,
+            Protocol::J1850PWM,
+            &raw,
+            timeout_ms,
+        )
+    }
+
+    fn read_j1850pwm_packets(
+        &self,
+        timeout_ms: u32,
+        max_msgs: usize,
+    ) -> Result<Vec<J1850Data>, ComServerError> {
+        self.read_raw_packets(
+            &self.j1850pwm_channel_idx,
+            Protocol::J1850PWM,
+            timeout_ms,
+            max_msgs,
+        )
+        .map(|msgs| msgs.into_iter().map(|data| J1850Data { data }).collect())
+    }
+
     fn clear_can_rx_buffer(&self) -> Result<(), ComServerError> {
         match *self.can_channel_idx.read().unwrap() {
             Some(idx) => self.driver.lock().unwrap().ioctl(
@@ -447,6 +688,110 @@ impl ComServer for PassthruApi {
         .map_err(|e| self.convert_error(e))
     }
 
+    fn clear_iso14230_rx_buffer(&self) -> Result<(), ComServerError> {
+        match *self.iso14230_channel_idx.read().unwrap() {
+            Some(idx) => self.driver.lock().unwrap().ioctl(
+                idx,
+                IoctlID::CLEAR_RX_BUFFER,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            ),
+            None => Ok(()),
+        }
+        .map_err(|e| self.convert_error(e))
+    }
+
+    fn clear_iso14230_tx_buffer(&self) -> Result<(), ComServerError> {
+        match *self.iso14230_channel_idx.read().unwrap() {
+            Some(idx) => self.driver.lock().unwrap().ioctl(
+                idx,
+                IoctlID::CLEAR_TX_BUFFER,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            ),
+            None => Ok(()),
+        }
+        .map_err(|e| self.convert_error(e))
+    }
+
+    fn clear_iso9141_rx_buffer(&self) -> Result<(), ComServerError> {
+        match *self.iso9141_channel_idx.read().unwrap() {
+            Some(idx) => self.driver.lock().unwrap().ioctl(
+                idx,
+                IoctlID::CLEAR_RX_BUFFER,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            ),
+            None => Ok(()),
+        }
+        .map_err(|e| self.convert_error(e))
+    }
+
+    fn clear_iso9141_tx_buffer(&self) -> Result<(), ComServerError> {
+        match *self.iso9141_channel_idx.read().unwrap() {
+            Some(idx) => self.driver.lock().unwrap().ioctl(
+                idx,
+                IoctlID::CLEAR_TX_BUFFER,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            ),
+            None => Ok(()),
+        }
+        .map_err(|e| self.convert_error(e))
+    }
+
+    fn clear_j1850vpw_rx_buffer(&self) -> Result<(), ComServerError> {
+        match *self.j1850vpw_channel_idx.read().unwrap() {
+            Some(idx) => self.driver.lock().unwrap().ioctl(
+                idx,
+                IoctlID::CLEAR_RX_BUFFER,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            ),
+            None => Ok(()),
+        }
+        .map_err(|e| self.convert_error(e))
+    }
+
+    fn clear_j1850vpw_tx_buffer(&self) -> Result<(), ComServerError> {
+        match *self.j1850vpw_channel_idx.read().unwrap() {
+            Some(idx) => self.driver.lock().unwrap().ioctl(
+                idx,
+                IoctlID::CLEAR_TX_BUFFER,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            ),
+            None => Ok(()),
+        }
+        .map_err(|e| self.convert_error(e))
+    }
+
+    fn clear_j1850pwm_rx_buffer(&self) -> Result<(), ComServerError> {
+        match *self.j1850pwm_channel_idx.read().unwrap() {
+            Some(idx) => self.driver.lock().unwrap().ioctl(
+                idx,
+                IoctlID::CLEAR_RX_BUFFER,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            ),
+            None => Ok(()),
+        }
+        .map_err(|e| self.convert_error(e))
+    }
+
+    fn clear_j1850pwm_tx_buffer(&self) -> Result<(), ComServerError> {
+        match *self.j1850pwm_channel_idx.read().unwrap() {
+            Some(idx) => self.driver.lock().unwrap().ioctl(
+                idx,
+                IoctlID::CLEAR_TX_BUFFER,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            ),
+            None => Ok(()),
+        }
+        .map_err(|e| self.convert_error(e))
+    }
+
     fn read_battery_voltage(&self) -> Result<f32, ComServerError> {
         let mut output = 0;
         self.driver
@@ -471,6 +816,9 @@ impl ComServer for PassthruApi {
             can_channel_idx: self.can_channel_idx.clone(),
             iso15765_channel_idx: self.iso15765_channel_idx.clone(),
             iso9141_channel_idx: self.iso9141_channel_idx.clone(),
+            iso14230_channel_idx: self.iso14230_channel_idx.clone(),
+            j1850vpw_channel_idx: self.j1850vpw_channel_idx.clone(),
+            j1850pwm_channel_idx: self.j1850pwm_channel_idx.clone(),
         })
     }
 
@@ -497,6 +845,7 @@ impl ComServer for PassthruApi {
             j1850vpw: Capability::from_bool(self.device.j1850vpw),
             j1850pwm: Capability::from_bool(self.device.j1850pwm),
             can: Capability::from_bool(self.device.can),
+            can_fd: Capability::No, // Not part of J2534-1 v04.04
             iso15765: Capability::from_bool(self.device.iso15765),
             iso9141: Capability::from_bool(self.device.iso9141),
             iso14230: Capability::from_bool(self.device.iso14230),
@@ -514,7 +863,10 @@ impl ComServer for PassthruApi {
     fn is_connected(&self) -> bool {
         return self.iso15765_channel_idx.read().unwrap().is_some()
             || self.can_channel_idx.read().unwrap().is_some()
-            || self.iso9141_channel_idx.read().unwrap().is_some();
+            || self.iso9141_channel_idx.read().unwrap().is_some()
+            || self.iso14230_channel_idx.read().unwrap().is_some()
+            || self.j1850vpw_channel_idx.read().unwrap().is_some()
+            || self.j1850pwm_channel_idx.read().unwrap().is_some();
     }
 }
 
@@ -528,6 +880,9 @@ impl PassthruApi {
             can_channel_idx: Arc::from(RwLock::new(None)),
             iso15765_channel_idx: Arc::from(RwLock::new(None)),
             iso9141_channel_idx: Arc::from(RwLock::new(None)),
+            iso14230_channel_idx: Arc::from(RwLock::new(None)),
+            j1850vpw_channel_idx: Arc::from(RwLock::new(None)),
+            j1850pwm_channel_idx: Arc::from(RwLock::new(None)),
         }
     }
 
@@ -581,6 +936,243 @@ impl PassthruApi {
         msg
     }
 
+    /// Opens a channel where the message headers are built by the application (K-Line or J1850),
+    /// and applies a pass-all filter to it
+    fn open_raw_channel(&self, protocol: Protocol, baud: u32) -> Result<u32, ComServerError> {
+        let channel_id = self
+            .driver
+            .lock()
+            .unwrap()
+            .connect(*self.device_idx.read().unwrap(), protocol, 0, baud)
+            .map_err(|e| self.convert_error(e))?;
+
+        // A filter is required in order to receive anything. Let everything
+        // through, the interface filters by address itself
+        let mask_msg = PASSTHRU_MSG {
+            protocol_id: protocol as u32,
+            data_size: 1,
+            ..Default::default()
+        };
+        let ptn_msg = PASSTHRU_MSG {
+            protocol_id: protocol as u32,
+            data_size: 1,
+            ..Default::default()
+        };
+        let res = self.driver.lock().unwrap().start_msg_filter(
+            channel_id,
+            PASS_FILTER,
+            &mask_msg,
+            &ptn_msg,
+            None,
+        );
+        if let Err(e) = res {
+            let err = self.convert_error(e);
+            let _ = self.driver.lock().unwrap().disconnect(channel_id);
+            return Err(err);
+        }
+        Ok(channel_id)
+    }
+
+    fn close_raw_channel(&self, channel: &RwLock<Option<u32>>) -> Result<(), ComServerError> {
+        if let Ok(mut lock) = channel.write() {
+            if lock.is_none() {
+                return Ok(());
+            }
+            self.driver
+                .lock()
+                .unwrap()
+                .disconnect(lock.unwrap())
+                .map_err(|e| self.convert_error(e))?;
+            *lock = None;
+        }
+        Ok(())
+    }
+
+    fn kline_five_baud_init(
+        &self,
+        channel: &RwLock<Option<u32>>,
+        address: u8,
+    ) -> Result<Vec<u8>, ComServerError> {
+        let channel_id = match *channel.read().unwrap() {
+            Some(idx) => idx,
+            None => return Err(self.convert_error(ERR_INVALID_CHANNEL_ID)),
+        };
+        let mut addr = [address];
+        let mut key_bytes = [0u8; 2];
+        let mut input = SByteArray {
+            num_of_bytes: 1,
+            byte_ptr: addr.as_mut_ptr(),
+        };
+        let mut output = SByteArray {
+            num_of_bytes: 2,
+            byte_ptr: key_bytes.as_mut_ptr(),
+        };
+        self.driver
+            .lock()
+            .unwrap()
+            .ioctl(
+                channel_id,
+                IoctlID::FIVE_BAUD_INIT,
+                (&mut input) as *mut _ as *mut c_void,
+                (&mut output) as *mut _ as *mut c_void,
+            )
+            .map(|_| Vec::from(&key_bytes[0..output.num_of_bytes.min(2) as usize]))
+            .map_err(|e| self.convert_error(e))
+    }
+
+    fn send_raw_data(
+        &self,
+        channel: &RwLock<Option<u32>>,
+        protocol: Protocol,
+        data: &[&[u8]],
+        timeout_ms: u32,
+    ) -> Result<usize, ComServerError> {
+        let channel_id = match *channel.read().unwrap() {
+            Some(id) => id,
+            None => return Err(self.convert_error(ERR_INVALID_CHANNEL_ID)),
+        };
+        let mut msgs: Vec<PASSTHRU_MSG> = data
+            .iter()
+            .map(|d| PassthruApi::raw_to_pt_msg(protocol, d))
+            .collect();
+        self.driver
+            .lock()
+            .unwrap()
+            .write_messages(channel_id, &mut msgs, timeout_ms)
+            .map_err(|e| self.convert_error(e))
+    }
+
+    fn read_raw_packets(
+        &self,
+        channel: &RwLock<Option<u32>>,
+        protocol: Protocol,
+        timeout_ms: u32,
+        max_msgs: usize,
+    ) -> Result<Vec<Vec<u8>>, ComServerError> {
+        let channel_id = match *channel.read().unwrap() {
+            Some(id) => id,
+            None => return Err(self.convert_error(ERR_INVALID_CHANNEL_ID)),
+        };
+        match self
+            .driver
+            .lock()
+            .unwrap()
+            .read_messages(channel_id, max_msgs as u32, timeout_ms)
+        {
+            Ok(read) => Ok(read
+                .iter()
+                .filter_map(|msg| PassthruApi::pt_msg_to_raw(protocol, msg))
+                .collect()),
+            Err(PassthruError::ERR_BUFFER_EMPTY) => Ok(Vec::new()),
+            Err(e) => Err(self.convert_error(e)),
+        }
+    }
+
+    fn set_kline_params(
+        &self,
+        channel: &RwLock<Option<u32>>,
+        p1_max: u32,
+        p3_min: u32,
+        p4_min: u32,
+    ) -> Result<(), ComServerError> {
+        let channel_id = match *channel.read().unwrap() {
+            Some(idx) => idx,
+            None => return Err(self.convert_error(ERR_INVALID_CHANNEL_ID)),
+        };
+        // J2534 K-Line timings are in 0.5ms increments
+        let mut params = [
+            SConfig {
+                parameter: IoctlParam::P1_MAX as u32,
+                value: p1_max * 2,
+            },
+            SConfig {
+                parameter: IoctlParam::P3_MIN as u32,
+                value: p3_min * 2,
+            },
+            SConfig {
+                parameter: IoctlParam::P4_MIN as u32,
+                value: p4_min * 2,
+            },
+        ];
+
+        let mut sconfig_list = SConfigList {
+            num_of_params: 3,
+            config_ptr: params.as_mut_ptr(),
+        };
+        self.driver
+            .lock()
+            .unwrap()
+            .ioctl(
+                channel_id,
+                IoctlID::SET_CONFIG,
+                (&mut sconfig_list) as *mut _ as *mut c_void,
+                std::ptr::null_mut(),
+            )
+            .map_err(|e| self.convert_error(e))
+    }
+
+    fn setup_j1850pwm_addresses(
+        &self,
+        channel_id: u32,
+        node_addr: u8,
+        func_addrs: &[u8],
+    ) -> Result<(), ComServerError> {
+        let mut params = [SConfig {
+            parameter: IoctlParam::NODE_ADDRESS as u32,
+            value: node_addr as u32,
+        }];
+        let mut sconfig_list = SConfigList {
+            num_of_params: 1,
+            config_ptr: params.as_mut_ptr(),
+        };
+        self.driver
+            .lock()
+            .unwrap()
+            .ioctl(
+                channel_id,
+                IoctlID::SET_CONFIG,
+                (&mut sconfig_list) as *mut _ as *mut c_void,
+                std::ptr::null_mut(),
+            )
+            .map_err(|e| self.convert_error(e))?;
+
+        if func_addrs.is_empty() {
+            return Ok(());
+        }
+        let mut addrs = Vec::from(func_addrs);
+        let mut input = SByteArray {
+            num_of_bytes: addrs.len() as u32,
+            byte_ptr: addrs.as_mut_ptr(),
+        };
+        self.driver
+            .lock()
+            .unwrap()
+            .ioctl(
+                channel_id,
+                IoctlID::ADD_TO_FUNCT_MSG_LOOKUP_TABLE,
+                (&mut input) as *mut _ as *mut c_void,
+                std::ptr::null_mut(),
+            )
+            .map_err(|e| self.convert_error(e))
+    }
+
+    fn raw_to_pt_msg(protocol: Protocol, data: &[u8]) -> PASSTHRU_MSG {
+        let mut msg = PASSTHRU_MSG {
+            protocol_id: protocol as u32,
+            data_size: data.len() as u32,
+            ..Default::default()
+        };
+        msg.data[0..data.len()].copy_from_slice(data);
+        msg
+    }
+
+    fn pt_msg_to_raw(protocol: Protocol, msg: &PASSTHRU_MSG) -> Option<Vec<u8>> {
+        if msg.protocol_id != protocol as u32 || msg.data_size == 0 {
+            return None; // Ignore indications with no data
+        }
+        Some(Vec::from(&msg.data[0..msg.data_size as usize]))
+    }
+
     #[inline(always)]
     fn msg_id_to_u32(m: &PASSTHRU_MSG) -> u32 {
         (m.data[0] as u32) << 24
@@ -597,6 +1189,13 @@ impl PassthruApi {
         msg.data[3] = i as u8;
     }
 
+    fn can_fd_unsupported() -> ComServerError {
+        ComServerError {
+            err_code: ERR_NOT_SUPPORTED as u32,
+            err_desc: "CAN FD is not supported by SAE J2534-1 v04.04".into(),
+        }
+    }
+
     fn convert_error(&self, e: PassthruError) -> ComServerError {
         let code = e as u32;
         let desc = if e == ERR_FAILED {
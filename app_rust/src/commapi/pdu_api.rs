@@ -1,22 +1,128 @@
 use crate::commapi;
 use crate::commapi::comm_api::{
-    CanFrame, ComServerError, DeviceCapabilities, FilterType, ISO15765Data,
+    CanFrame, Capability, ComServerError, DeviceCapabilities, FilterType, ISO15765Data, J1850Data,
+    KLineData,
+};
+use crate::dpdu::{
+    PduDevice, PduDrv, PduError, PduEvent, PduVersion, PDU_COPST_CANCELLED, PDU_COPST_FINISHED,
+    PDU_COPT_SENDRECV, PDU_COPT_UPDATEPARAM, PDU_COP_CTRL_DATA, PDU_EXP_RESP_DATA, PDU_FLAG_DATA,
+    PDU_HANDLE_UNDEF, PDU_IS_INFINITE, PDU_MODST_AVAIL, PDU_MODST_READY, PDU_OBJT_BUSTYPE,
+    PDU_OBJT_COMPARAM, PDU_OBJT_PINTYPE, PDU_OBJT_PROTOCOL, PDU_PC_BUSTYPE, PDU_PC_COM,
+    PDU_PC_TIMING, PDU_PC_UNIQUE_ID, PDU_PIN_DATA, PDU_RSC_DATA,
 };
 use commapi::comm_api::ComServer;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+/// Protocol short name for raw CAN frames
+const PROTOCOL_RAW_CAN: &str = "ISO_11898_RAW";
+/// Protocol short name for ISO-TP. UDS is used as the application layer since the
+/// D-PDU API does not interpret the payload of the messages we send
+const PROTOCOL_ISOTP: &str = "ISO_15765_3_on_ISO_15765_2";
+/// Bus type short name for high speed CAN
+const BUS_TYPE_CAN: &str = "ISO_11898_2_DWCAN";
+
+/// Bit in the CAN ID of a raw CAN PDU that marks the ID as 29bit
+const RAW_CAN_EXT_ID: u32 = 0x80000000;
+
+/// Tells the D-PDU API which CAN ID format to use for ISO-TP (CP_CanPhysReqFormat / CP_CanRespUSDTFormat).
+/// 0x05 is normal addressing with 11bit IDs
+fn isotp_format(ext_can: bool) -> u32 {
+    if ext_can {
+        0x09
+    } else {
+        0x05
+    }
+}
+
+/// A ComLogicalLink that is connected to the vehicle
+#[derive(Debug, Clone)]
+struct PduChannel {
+    /// ComLogicalLink handle
+    h_cll: u32,
+    /// Receive-only ComPrimitive which collects data that passes the filters
+    rx_cop: Option<u32>,
+    filters: [Option<FilterType>; 10],
+    /// Received (Unique response ID, PDU) pairs which have not been read yet
+    rx_queue: VecDeque<(u32, Vec<u8>)>,
+    /// Send ComPrimitives that have finished executing
+    done_cops: Vec<u32>,
+    ext_can: bool,
+}
+
+impl PduChannel {
+    fn new(h_cll: u32, ext_can: bool) -> Self {
+        Self {
+            h_cll,
+            rx_cop: None,
+            filters: [None; 10],
+            rx_queue: VecDeque::new(),
+            done_cops: Vec::new(),
+            ext_can,
+        }
+    }
 
-#[derive(Debug, Copy, Clone)]
+    /// Returns true if a raw CAN PDU is not blocked by a block filter. Block filters
+    /// cannot be expressed as a ComPrimitive's expected response, so they are applied here
+    fn pdu_allowed(&self, pdu: &[u8]) -> bool {
+        if pdu.len() < 4 {
+            return true;
+        }
+        let id = u32::from_be_bytes([pdu[0], pdu[1], pdu[2], pdu[3]]) & !RAW_CAN_EXT_ID;
+        !self.filters.iter().flatten().any(|f| match f {
+            FilterType::Block { id: f_id, mask } => id & mask == f_id & mask,
+            _ => false,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct DpduAPI {
-    // TODO DPDU
+    device: Arc<PduDevice>,
+    driver: Arc<Mutex<PduDrv>>,
+    caps: Arc<RwLock<Option<DeviceCapabilities>>>,
+    module_handle: Arc<RwLock<Option<u32>>>,
+    can_channel: Arc<Mutex<Option<PduChannel>>>,
+    iso15765_channel: Arc<Mutex<Option<PduChannel>>>,
 }
 
-#[allow(unused_variables)]
 impl ComServer for DpduAPI {
     fn open_device(&mut self) -> Result<(), ComServerError> {
-        unimplemented!()
+        let mut drv = self.driver.lock().unwrap();
+        drv.construct().map_err(|e| self.convert_error(e))?;
+        let modules = drv.get_module_ids().map_err(|e| self.convert_error(e))?;
+        let h_mod = match modules
+            .iter()
+            .filter(|(_, _, status)| *status == PDU_MODST_AVAIL || *status == PDU_MODST_READY)
+            .find(|(type_id, _, _)| {
+                self.device.module_type_id.is_none() || self.device.module_type_id == Some(*type_id)
+            }) {
+            Some((_, h_mod, _)) => *h_mod,
+            None => {
+                let _ = drv.destruct();
+                return Err(ComServerError {
+                    err_code: 99,
+                    err_desc: format!("{} is not connected", self.device.name),
+                });
+            }
+        };
+        drv.module_connect(h_mod)
+            .map_err(|e| self.convert_error(e))?;
+        *self.module_handle.write().unwrap() = Some(h_mod);
+        Ok(())
     }
 
     fn close_device(&mut self) -> Result<(), ComServerError> {
-        unimplemented!()
+        self.close_can_interface()?;
+        self.close_iso15765_interface()?;
+        if let Some(h_mod) = self.module_handle.write().unwrap().take() {
+            let mut drv = self.driver.lock().unwrap();
+            drv.module_disconnect(h_mod)
+                .map_err(|e| self.convert_error(e))?;
+            drv.destruct().map_err(|e| self.convert_error(e))?;
+        }
+        Ok(())
     }
 
     fn send_can_packets(
@@ -24,7 +130,25 @@ impl ComServer for DpduAPI {
         data: &[CanFrame],
         timeout_ms: u32,
     ) -> Result<usize, ComServerError> {
-        unimplemented!()
+        if data.iter().any(|cf| cf.fd) {
+            return Err(Self::can_fd_unsupported());
+        }
+        let payloads: Vec<Vec<u8>> = {
+            let lock = self.can_channel.lock().unwrap();
+            let channel = lock.as_ref().ok_or_else(|| self.no_channel_error())?;
+            data.iter()
+                .map(|cf| {
+                    let mut id = cf.id;
+                    if channel.ext_can {
+                        id |= RAW_CAN_EXT_ID
+                    }
+                    let mut pdu = id.to_be_bytes().to_vec();
+                    pdu.extend_from_slice(cf.get_data());
+                    pdu
+                })
+                .collect()
+        };
+        self.send_pdus(&self.can_channel, payloads, None, timeout_ms)
     }
 
     fn read_can_packets(
@@ -32,7 +156,15 @@ impl ComServer for DpduAPI {
         timeout_ms: u32,
         max_msgs: usize,
     ) -> Result<Vec<CanFrame>, ComServerError> {
-        unimplemented!()
+        Ok(self
+            .read_pdus(&self.can_channel, timeout_ms, max_msgs)?
+            .into_iter()
+            .filter(|(_, pdu)| pdu.len() >= 4)
+            .map(|(_, pdu)| {
+                let id = u32::from_be_bytes([pdu[0], pdu[1], pdu[2], pdu[3]]) & !RAW_CAN_EXT_ID;
+                CanFrame::new(id, &pdu[4..])
+            })
+            .collect())
     }
 
     fn send_iso15765_data(
@@ -40,7 +172,18 @@ impl ComServer for DpduAPI {
         data: &[ISO15765Data],
         timeout_ms: u32,
     ) -> Result<usize, ComServerError> {
-        unimplemented!()
+        let mut sent = 0;
+        // Each payload can go to a different ECU, so the request ID is set as a
+        // temporary ComParam for each ComPrimitive
+        for d in data {
+            sent += self.send_pdus(
+                &self.iso15765_channel,
+                vec![d.data.clone()],
+                Some(d.id),
+                timeout_ms,
+            )?;
+        }
+        Ok(sent)
     }
 
     fn read_iso15765_packets(
@@ -48,7 +191,25 @@ impl ComServer for DpduAPI {
         timeout_ms: u32,
         max_msgs: usize,
     ) -> Result<Vec<ISO15765Data>, ComServerError> {
-        unimplemented!()
+        let read = self.read_pdus(&self.iso15765_channel, timeout_ms, max_msgs)?;
+        let lock = self.iso15765_channel.lock().unwrap();
+        let channel = match lock.as_ref() {
+            Some(c) => c,
+            None => return Ok(Vec::new()),
+        };
+        // The unique response ID of each result is the index of the filter it matched
+        Ok(read
+            .into_iter()
+            .filter_map(|(urid, pdu)| match channel.filters.get(urid as usize) {
+                Some(Some(FilterType::IsoTP { id, .. })) => Some(ISO15765Data {
+                    id: *id,
+                    data: pdu,
+                    pad_frame: false,
+                    ext_addressing: false,
+                }),
+                _ => None,
+            })
+            .collect())
     }
 
     fn open_can_interface(
@@ -56,11 +217,17 @@ impl ComServer for DpduAPI {
         bus_speed: u32,
         is_ext_can: bool,
     ) -> Result<(), ComServerError> {
-        unimplemented!()
+        // Already open, close first, maybe going from non ext to ext can
+        self.close_can_interface()?;
+        // Both ComLogicalLinks would use the same pins on the VCI
+        self.close_iso15765_interface()?;
+        let h_cll = self.create_channel(PROTOCOL_RAW_CAN, bus_speed, &[])?;
+        *self.can_channel.lock().unwrap() = Some(PduChannel::new(h_cll, is_ext_can));
+        Ok(())
     }
 
     fn close_can_interface(&mut self) -> Result<(), ComServerError> {
-        unimplemented!()
+        self.destroy_channel(&self.can_channel)
     }
 
     fn open_iso15765_interface(
@@ -69,27 +236,70 @@ impl ComServer for DpduAPI {
         is_ext_can: bool,
         ext_addressing: bool,
     ) -> Result<(), ComServerError> {
-        unimplemented!()
+        if ext_addressing {
+            return Err(ComServerError {
+                err_code: 99,
+                err_desc: "Extended ISO-TP addressing is not supported by D-PDU devices".into(),
+            });
+        }
+        // Already open, close first, maybe we are changing modes?
+        self.close_iso15765_interface()?;
+        self.close_can_interface()?;
+        let format = isotp_format(is_ext_can);
+        let h_cll = self.create_channel(
+            PROTOCOL_ISOTP,
+            bus_speed,
+            &[
+                ("CP_CanPhysReqFormat", PDU_PC_COM, format),
+                ("CP_CanRespUSDTFormat", PDU_PC_COM, format),
+            ],
+        )?;
+        *self.iso15765_channel.lock().unwrap() = Some(PduChannel::new(h_cll, is_ext_can));
+        Ok(())
     }
 
     fn close_iso15765_interface(&mut self) -> Result<(), ComServerError> {
-        unimplemented!()
+        self.destroy_channel(&self.iso15765_channel)
     }
 
     fn add_can_filter(&mut self, f: FilterType) -> Result<u32, ComServerError> {
-        unimplemented!()
+        if let FilterType::IsoTP { .. } = f {
+            return Err(ComServerError {
+                err_code: 99,
+                err_desc: "Cannot apply a flow control filter to CAN".into(),
+            });
+        }
+        let idx = self.insert_filter(&self.can_channel, f)?;
+        self.restart_rx_cop(&self.can_channel)?;
+        Ok(idx)
     }
 
     fn rem_can_filter(&mut self, filter_idx: u32) -> Result<(), ComServerError> {
-        unimplemented!()
+        if self.remove_filter(&self.can_channel, filter_idx) {
+            self.restart_rx_cop(&self.can_channel)?;
+        }
+        Ok(())
     }
 
     fn add_iso15765_filter(&mut self, f: FilterType) -> Result<u32, ComServerError> {
-        unimplemented!()
+        if let FilterType::IsoTP { .. } = f {
+            let idx = self.insert_filter(&self.iso15765_channel, f)?;
+            self.update_urid_table()?;
+            Ok(idx)
+        } else {
+            // Error out
+            Err(ComServerError {
+                err_code: 99,
+                err_desc: "Cannot apply a pass/block filter to ISOTP".into(),
+            })
+        }
     }
 
     fn rem_iso15765_filter(&mut self, filter_idx: u32) -> Result<(), ComServerError> {
-        unimplemented!()
+        if self.remove_filter(&self.iso15765_channel, filter_idx) {
+            self.update_urid_table()?;
+        }
+        Ok(())
     }
 
     fn set_iso15765_params(
@@ -97,42 +307,727 @@ impl ComServer for DpduAPI {
         separation_time_min: u32,
         block_size: u32,
     ) -> Result<(), ComServerError> {
-        unimplemented!()
+        let h_cll = match self.iso15765_channel.lock().unwrap().as_ref() {
+            Some(c) => c.h_cll,
+            None => return Err(self.no_channel_error()),
+        };
+        let h_mod = self.get_module_handle()?;
+        let drv = self.driver.lock().unwrap();
+        // D-PDU timing ComParams are in microseconds
+        drv.set_com_param(
+            h_mod,
+            h_cll,
+            drv.get_object_id(PDU_OBJT_COMPARAM, "CP_STmin")
+                .map_err(|e| self.convert_error(e))?,
+            PDU_PC_TIMING,
+            separation_time_min * 1000,
+        )
+        .and_then(|_| {
+            drv.set_com_param(
+                h_mod,
+                h_cll,
+                drv.get_object_id(PDU_OBJT_COMPARAM, "CP_BlockSize")?,
+                PDU_PC_COM,
+                block_size,
+            )
+        })
+        .and_then(|_| drv.start_com_primitive(h_mod, h_cll, PDU_COPT_UPDATEPARAM, &mut [], None))
+        .map(|_| ())
+        .map_err(|e| self.convert_error(e))
+    }
+
+    fn set_iso15765_fd_params(&mut self, _tx_dl: u32, _brs: bool) -> Result<(), ComServerError> {
+        Err(Self::can_fd_unsupported())
+    }
+
+    fn open_iso14230_interface(&mut self, _baud: u32) -> Result<(), ComServerError> {
+        Err(Self::kline_unsupported())
+    }
+
+    fn close_iso14230_interface(&mut self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn iso14230_fast_init(&mut self, _init_msg: &[u8]) -> Result<Vec<u8>, ComServerError> {
+        Err(Self::kline_unsupported())
+    }
+
+    fn iso14230_five_baud_init(&mut self, _address: u8) -> Result<Vec<u8>, ComServerError> {
+        Err(Self::kline_unsupported())
+    }
+
+    fn send_iso14230_data(
+        &self,
+        _data: &[KLineData],
+        _timeout_ms: u32,
+    ) -> Result<usize, ComServerError> {
+        Err(Self::kline_unsupported())
+    }
+
+    fn read_iso14230_packets(
+        &self,
+        _timeout_ms: u32,
+        _max_msgs: usize,
+    ) -> Result<Vec<KLineData>, ComServerError> {
+        Err(Self::kline_unsupported())
+    }
+
+    fn set_iso14230_params(
+        &mut self,
+        _p1_max: u32,
+        _p3_min: u32,
+        _p4_min: u32,
+    ) -> Result<(), ComServerError> {
+        Err(Self::kline_unsupported())
+    }
+
+    fn open_iso9141_interface(&mut self, _baud: u32) -> Result<(), ComServerError> {
+        Err(Self::kline_unsupported())
+    }
+
+    fn close_iso9141_interface(&mut self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn iso9141_five_baud_init(&mut self, _address: u8) -> Result<Vec<u8>, ComServerError> {
+        Err(Self::kline_unsupported())
+    }
+
+    fn send_iso9141_data(
+        &self,
+        _data: &[KLineData],
+        _timeout_ms: u32,
+    ) -> Result<usize, ComServerError> {
+        Err(Self::kline_unsupported())
+    }
+
+    fn read_iso9141_packets(
+        &self,
+        _timeout_ms: u32,
+        _max_msgs: usize,
+    ) -> Result<Vec<KLineData>, ComServerError> {
+        Err(Self::kline_unsupported())
+    }
+
+    fn set_iso9141_params(
+        &mut self,
+        _p1_max: u32,
+        _p3_min: u32,
+        _p4_min: u32,
+    ) -> Result<(), ComServerError> {
+        Err(Self::kline_unsupported())
+    }
+
+    fn open_j1850vpw_interface(&mut self, _baud: u32) -> Result<(), ComServerError> {
+        Err(Self::j1850_unsupported())
+    }
+
+    fn close_j1850vpw_interface(&mut self) -> Result<(), ComServerError> {
+        Ok(()) // Never opened
+    }
+
+    fn send_j1850vpw_data(
+        &self,
+        _data: &[J1850Data],
+        _timeout_ms: u32,
+    ) -> Result<usize, ComServerError> {
+        Err(Self::j1850_unsupported())
+    }
+
+    fn read_j1850vpw_packets(
+        &self,
+        _timeout_ms: u32,
+        _max_msgs: usize,
+    ) -> Result<Vec<J1850Data>, ComServerError> {
+        Err(Self::j1850_unsupported())
+    }
+
+    fn open_j1850pwm_interface(
+        &mut self,
+        _baud: u32,
+        _node_addr: u8,
+        _func_addrs: &[u8],
+    ) -> Result<(), ComServerError> {
+        Err(Self::j1850_unsupported())
+    }
+
+    fn close_j1850pwm_interface(&mut self) -> Result<(), ComServerError> {
+        Ok(()) // Never opened
+    }
+
+    fn send_j1850pwm_data(
+        &self,
+        _data: &[J1850Data],
+        _timeout_ms: u32,
+    ) -> Result<usize, ComServerError> {
+        Err(Self::j1850_unsupported())
+    }
+
+    fn read_j1850pwm_packets(
+        &self,
+        _timeout_ms: u32,
+        _max_msgs: usize,
+    ) -> Result<Vec<J1850Data>, ComServerError> {
+        Err(Self::j1850_unsupported())
     }
 
     fn clear_can_rx_buffer(&self) -> Result<(), ComServerError> {
-        unimplemented!()
+        self.clear_rx_queue(&self.can_channel)
     }
 
     fn clear_can_tx_buffer(&self) -> Result<(), ComServerError> {
-        unimplemented!()
+        self.clear_tx_queue(&self.can_channel)
     }
 
     fn clear_iso15765_rx_buffer(&self) -> Result<(), ComServerError> {
-        unimplemented!()
+        self.clear_rx_queue(&self.iso15765_channel)
     }
 
     fn clear_iso15765_tx_buffer(&self) -> Result<(), ComServerError> {
-        unimplemented!()
+        self.clear_tx_queue(&self.iso15765_channel)
+    }
+
+    fn clear_iso14230_rx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn clear_iso14230_tx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn clear_iso9141_rx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn clear_iso9141_tx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn clear_j1850vpw_rx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn clear_j1850vpw_tx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn clear_j1850pwm_rx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn clear_j1850pwm_tx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(())
     }
 
     fn read_battery_voltage(&self) -> Result<f32, ComServerError> {
-        unimplemented!()
+        let h_mod = self.get_module_handle()?;
+        match self
+            .driver
+            .lock()
+            .unwrap()
+            .ioctl(h_mod, PDU_HANDLE_UNDEF, "PDU_IOCTL_READ_VBATT")
+            .map_err(|e| self.convert_error(e))?
+        {
+            Some(mv) => Ok(mv as f32 / 1000.0),
+            None => Err(ComServerError {
+                err_code: 99,
+                err_desc: "VCI did not report the battery voltage".into(),
+            }),
+        }
     }
 
     fn clone_box(&self) -> Box<dyn ComServer> {
-        unimplemented!()
+        Box::new(self.clone())
     }
 
     fn get_capabilities(&self) -> DeviceCapabilities {
-        unimplemented!()
+        if let Some(caps) = self.caps.read().unwrap().as_ref() {
+            return caps.clone();
+        }
+        let version = self
+            .module_handle
+            .read()
+            .unwrap()
+            .and_then(|h_mod| self.driver.lock().unwrap().get_version(h_mod).ok())
+            .unwrap_or(PduVersion {
+                api_version: "Unknown".into(),
+                fw_version: "Unknown".into(),
+            });
+        let dev = &self.device;
+        let caps = DeviceCapabilities {
+            name: dev.name.clone(),
+            vendor: dev.vendor.clone(),
+            library_path: dev.drv_path.clone(),
+            device_fw_version: version.fw_version,
+            library_version: version.api_version,
+            // D-PDU only exposes K-Line and J1850 through its own protocol stacks, which
+            // build the message headers themselves, so raw access to them is not possible
+            j1850vpw: Capability::NA,
+            j1850pwm: Capability::NA,
+            can: Capability::from_bool(dev.supports_protocol(PROTOCOL_RAW_CAN)),
+            // Raw CAN channels are opened with the classic CAN bus type
+            can_fd: Capability::No,
+            iso15765: Capability::from_bool(dev.supports_protocol("ISO_15765_2")),
+            iso9141: Capability::NA,
+            iso14230: Capability::NA,
+            ip: Capability::from_bool(dev.supports_protocol("ISO_13400_2")),
+            battery_voltage: Capability::Yes,
+        };
+        *self.caps.write().unwrap() = Some(caps.clone());
+        caps
     }
 
     fn get_api(&self) -> &str {
-        unimplemented!()
+        "D-PDU"
     }
 
     fn is_connected(&self) -> bool {
-        false
+        self.can_channel.lock().unwrap().is_some()
+            || self.iso15765_channel.lock().unwrap().is_some()
+    }
+}
+
+impl DpduAPI {
+    pub fn new(desc: PduDevice, driver: PduDrv) -> Self {
+        Self {
+            device: Arc::new(desc),
+            driver: Arc::new(Mutex::new(driver)),
+            caps: Arc::new(Default::default()),
+            module_handle: Arc::new(RwLock::new(None)),
+            can_channel: Arc::new(Mutex::new(None)),
+            iso15765_channel: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn get_module_handle(&self) -> Result<u32, ComServerError> {
+        self.module_handle
+            .read()
+            .unwrap()
+            .ok_or_else(|| self.convert_error(PduError::PDU_ERR_MODULE_NOT_CONNECTED))
+    }
+
+    /// Creates and connects a ComLogicalLink on the OBD-II CAN pins (6 and 14)
+    ///
+    /// ## Params
+    /// * protocol - Protocol short name
+    /// * bus_speed - Speed of the vehicle Canbus in bps
+    /// * params - Additional (ComParam short name, class, value) to set before connecting
+    fn create_channel(
+        &self,
+        protocol: &str,
+        bus_speed: u32,
+        params: &[(&str, u32, u32)],
+    ) -> Result<u32, ComServerError> {
+        let h_mod = self.get_module_handle()?;
+        let drv = self.driver.lock().unwrap();
+        let lookup = |t: u32, name: &str| {
+            drv.get_object_id(t, name)
+                .map_err(|e| self.convert_error(e))
+        };
+        let mut pins = [
+            PDU_PIN_DATA {
+                dlc_pin_number: 6,
+                dlc_pin_type_id: lookup(PDU_OBJT_PINTYPE, "HI")?,
+            },
+            PDU_PIN_DATA {
+                dlc_pin_number: 14,
+                dlc_pin_type_id: lookup(PDU_OBJT_PINTYPE, "LOW")?,
+            },
+        ];
+        let mut rsc = PDU_RSC_DATA {
+            bus_type_id: lookup(PDU_OBJT_BUSTYPE, BUS_TYPE_CAN)?,
+            protocol_id: lookup(PDU_OBJT_PROTOCOL, protocol)?,
+            num_pin_data: pins.len() as u32,
+            p_dlc_pin_data: pins.as_mut_ptr(),
+        };
+        let h_cll = drv
+            .create_cll(h_mod, &mut rsc)
+            .map_err(|e| self.convert_error(e))?;
+
+        let setup = || -> Result<(), ComServerError> {
+            drv.set_com_param(
+                h_mod,
+                h_cll,
+                lookup(PDU_OBJT_COMPARAM, "CP_Baudrate")?,
+                PDU_PC_BUSTYPE,
+                bus_speed,
+            )
+            .map_err(|e| self.convert_error(e))?;
+            for (name, class, value) in params {
+                drv.set_com_param(
+                    h_mod,
+                    h_cll,
+                    lookup(PDU_OBJT_COMPARAM, name)?,
+                    *class,
+                    *value,
+                )
+                .map_err(|e| self.convert_error(e))?;
+            }
+            drv.connect(h_mod, h_cll)
+                .and_then(|_| {
+                    drv.start_com_primitive(h_mod, h_cll, PDU_COPT_UPDATEPARAM, &mut [], None)
+                })
+                .map(|_| ())
+                .map_err(|e| self.convert_error(e))
+        };
+        if let Err(e) = setup() {
+            // Don't leave a half configured ComLogicalLink behind
+            let _ = drv.destroy_cll(h_mod, h_cll);
+            return Err(e);
+        }
+        Ok(h_cll)
+    }
+
+    fn destroy_channel(&self, channel: &Mutex<Option<PduChannel>>) -> Result<(), ComServerError> {
+        let c = match channel.lock().unwrap().take() {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        let h_mod = self.get_module_handle()?;
+        let drv = self.driver.lock().unwrap();
+        if let Some(h_cop) = c.rx_cop {
+            let _ = drv.cancel_com_primitive(h_mod, c.h_cll, h_cop);
+        }
+        drv.disconnect(h_mod, c.h_cll)
+            .and_then(|_| drv.destroy_cll(h_mod, c.h_cll))
+            .map_err(|e| self.convert_error(e))
+    }
+
+    fn insert_filter(
+        &self,
+        channel: &Mutex<Option<PduChannel>>,
+        f: FilterType,
+    ) -> Result<u32, ComServerError> {
+        let mut lock = channel.lock().unwrap();
+        let c = lock.as_mut().ok_or_else(|| self.no_channel_error())?;
+        match c.filters.iter().position(|x| x.is_none()) {
+            Some(idx) => {
+                c.filters[idx] = Some(f);
+                Ok(idx as u32)
+            }
+            None => Err(ComServerError {
+                err_code: 99,
+                err_desc: "No free filters left on the channel".into(),
+            }),
+        }
+    }
+
+    /// Removes a filter, returning true if the filter existed
+    fn remove_filter(&self, channel: &Mutex<Option<PduChannel>>, filter_idx: u32) -> bool {
+        // OK if the channel no longer exists, as the filter was removed when the channel was destroyed
+        match channel.lock().unwrap().as_mut() {
+            Some(c) => c
+                .filters
+                .get_mut(filter_idx as usize)
+                .and_then(|f| f.take())
+                .is_some(),
+            None => false,
+        }
+    }
+
+    /// Each ISO-TP filter becomes an entry in the ComLogicalLink's unique response ID table,
+    /// with the filter's index as its unique response ID
+    fn update_urid_table(&self) -> Result<(), ComServerError> {
+        let (h_cll, ext_can, filters) = match self.iso15765_channel.lock().unwrap().as_ref() {
+            Some(c) => (c.h_cll, c.ext_can, c.filters),
+            None => return Err(self.no_channel_error()),
+        };
+        let h_mod = self.get_module_handle()?;
+        {
+            let drv = self.driver.lock().unwrap();
+            let lookup = |name: &str| {
+                drv.get_object_id(PDU_OBJT_COMPARAM, name)
+                    .map_err(|e| self.convert_error(e))
+            };
+            let req_id = lookup("CP_CanPhysReqId")?;
+            let req_fmt = lookup("CP_CanPhysReqFormat")?;
+            let resp_id = lookup("CP_CanRespUSDTId")?;
+            let resp_fmt = lookup("CP_CanRespUSDTFormat")?;
+            let format = isotp_format(ext_can);
+            let entries: Vec<(u32, Vec<(u32, u32)>)> = filters
+                .iter()
+                .enumerate()
+                .filter_map(|(idx, f)| match f {
+                    Some(FilterType::IsoTP { id, fc, .. }) => Some((
+                        idx as u32,
+                        vec![
+                            (req_id, *fc),
+                            (req_fmt, format),
+                            (resp_id, *id),
+                            (resp_fmt, format),
+                        ],
+                    )),
+                    _ => None,
+                })
+                .collect();
+            drv.set_urid_table(h_mod, h_cll, &entries)
+                .map_err(|e| self.convert_error(e))?;
+        }
+        self.restart_rx_cop(&self.iso15765_channel)
+    }
+
+    /// Cancels the receive-only ComPrimitive of a channel, and starts a new one
+    /// which matches the channel's current filters. A receive-only ComPrimitive runs in
+    /// parallel to any send ComPrimitives on the ComLogicalLink.
+    fn restart_rx_cop(&self, channel: &Mutex<Option<PduChannel>>) -> Result<(), ComServerError> {
+        let h_mod = self.get_module_handle()?;
+        let mut lock = channel.lock().unwrap();
+        let c = lock.as_mut().ok_or_else(|| self.no_channel_error())?;
+        let drv = self.driver.lock().unwrap();
+        if let Some(h_cop) = c.rx_cop.take() {
+            drv.cancel_com_primitive(h_mod, c.h_cll, h_cop)
+                .map_err(|e| self.convert_error(e))?;
+        }
+
+        // Raw CAN pass filters are matched by the VCI against the 4 ID bytes of each PDU.
+        // ISO-TP responses are matched by unique response ID, so they accept any payload
+        let mut patterns: Vec<([u8; 4], [u8; 4], u32)> = Vec::new();
+        let mut urids: Vec<u32> = Vec::new();
+        for (idx, f) in c.filters.iter().enumerate() {
+            match f {
+                Some(FilterType::Pass { id, mask }) => {
+                    patterns.push((mask.to_be_bytes(), id.to_be_bytes(), 4))
+                }
+                Some(FilterType::IsoTP { .. }) => urids.push(idx as u32),
+                _ => {}
+            }
+        }
+        if !urids.is_empty() {
+            patterns.push(([0x00; 4], [0x00; 4], 1))
+        }
+        if patterns.is_empty() {
+            // Nothing can pass, so no need to receive anything
+            return Ok(());
+        }
+
+        let mut expected: Vec<PDU_EXP_RESP_DATA> = patterns
+            .iter_mut()
+            .enumerate()
+            .map(|(idx, (mask, pattern, len))| PDU_EXP_RESP_DATA {
+                response_type: 0,
+                acceptance_id: idx as u32 + 1,
+                num_mask_pattern_bytes: *len,
+                p_mask_data: mask.as_mut_ptr(),
+                p_pattern_data: pattern.as_mut_ptr(),
+                num_unique_resp_ids: urids.len() as u32,
+                p_unique_resp_ids: if urids.is_empty() {
+                    std::ptr::null_mut()
+                } else {
+                    urids.as_mut_ptr()
+                },
+            })
+            .collect();
+        let mut ctrl = PDU_COP_CTRL_DATA {
+            time: 0,
+            num_send_cycles: 0,
+            num_receive_cycles: PDU_IS_INFINITE,
+            temp_param_update: 0,
+            tx_flag: PDU_FLAG_DATA::default(),
+            num_possible_expected_responses: expected.len() as u32,
+            p_expected_response_array: expected.as_mut_ptr(),
+        };
+        c.rx_cop = Some(
+            drv.start_com_primitive(h_mod, c.h_cll, PDU_COPT_SENDRECV, &mut [], Some(&mut ctrl))
+                .map_err(|e| self.convert_error(e))?,
+        );
+        Ok(())
+    }
+
+    /// Reads every pending event on a channel, queuing any received data.
+    ///
+    /// An error event is returned straight away. Any events after it are left
+    /// with the driver, to be read by the next call
+    fn pump_events(
+        &self,
+        drv: &PduDrv,
+        h_mod: u32,
+        c: &mut PduChannel,
+    ) -> Result<(), ComServerError> {
+        while let Some(event) = drv
+            .get_event_item(h_mod, c.h_cll)
+            .map_err(|e| self.convert_error(e))?
+        {
+            match event {
+                PduEvent::Result { urid, data, .. } => c.rx_queue.push_back((urid, data)),
+                PduEvent::Status { h_cop, status } => {
+                    if status == PDU_COPST_FINISHED || status == PDU_COPST_CANCELLED {
+                        c.done_cops.push(h_cop)
+                    }
+                }
+                PduEvent::Error { h_cop, code, extra } => {
+                    return Err(ComServerError {
+                        err_code: code,
+                        err_desc: format!(
+                            "D-PDU error on ComPrimitive {}: 0x{:04X} (0x{:04X})",
+                            h_cop, code, extra
+                        ),
+                    })
+                }
+                PduEvent::Other { .. } => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn read_pdus(
+        &self,
+        channel: &Mutex<Option<PduChannel>>,
+        timeout_ms: u32,
+        max_msgs: usize,
+    ) -> Result<Vec<(u32, Vec<u8>)>, ComServerError> {
+        let h_mod = self.get_module_handle()?;
+        let start = Instant::now();
+        let mut res = Vec::new();
+        loop {
+            {
+                let mut lock = channel.lock().unwrap();
+                let c = lock.as_mut().ok_or_else(|| self.no_channel_error())?;
+                self.pump_events(&self.driver.lock().unwrap(), h_mod, c)?;
+                while res.len() < max_msgs {
+                    match c.rx_queue.pop_front() {
+                        Some((urid, pdu)) => {
+                            if c.pdu_allowed(&pdu) {
+                                res.push((urid, pdu))
+                            }
+                        }
+                        None => break,
+                    }
+                }
+            }
+            if res.len() >= max_msgs || start.elapsed().as_millis() >= timeout_ms as u128 {
+                return Ok(res);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
+
+    /// Sends a list of PDUs, each in its own ComPrimitive. If a timeout is specified, this waits
+    /// for the ComPrimitives to finish
+    ///
+    /// ## Params
+    /// * req_id - If set, the CAN ID to send the PDUs to (ISO-TP only)
+    fn send_pdus(
+        &self,
+        channel: &Mutex<Option<PduChannel>>,
+        pdus: Vec<Vec<u8>>,
+        req_id: Option<u32>,
+        timeout_ms: u32,
+    ) -> Result<usize, ComServerError> {
+        let h_mod = self.get_module_handle()?;
+        let mut cops = Vec::new();
+        {
+            let lock = channel.lock().unwrap();
+            let c = lock.as_ref().ok_or_else(|| self.no_channel_error())?;
+            let drv = self.driver.lock().unwrap();
+            if let Some(id) = req_id {
+                drv.get_object_id(PDU_OBJT_COMPARAM, "CP_CanPhysReqId")
+                    .and_then(|param| {
+                        drv.set_com_param(h_mod, c.h_cll, param, PDU_PC_UNIQUE_ID, id)
+                    })
+                    .map_err(|e| self.convert_error(e))?;
+            }
+            for mut pdu in pdus {
+                let mut ctrl = PDU_COP_CTRL_DATA {
+                    time: 0,
+                    num_send_cycles: 1,
+                    num_receive_cycles: 0,
+                    temp_param_update: req_id.is_some() as u32,
+                    tx_flag: PDU_FLAG_DATA::default(),
+                    num_possible_expected_responses: 0,
+                    p_expected_response_array: std::ptr::null_mut(),
+                };
+                cops.push(
+                    drv.start_com_primitive(
+                        h_mod,
+                        c.h_cll,
+                        PDU_COPT_SENDRECV,
+                        &mut pdu,
+                        Some(&mut ctrl),
+                    )
+                    .map_err(|e| self.convert_error(e))?,
+                );
+            }
+        }
+        if timeout_ms == 0 {
+            return Ok(cops.len());
+        }
+
+        // Wait for conformation that the ComPrimitives have finished
+        let start = Instant::now();
+        loop {
+            let done = {
+                let mut lock = channel.lock().unwrap();
+                let c = lock.as_mut().ok_or_else(|| self.no_channel_error())?;
+                self.pump_events(&self.driver.lock().unwrap(), h_mod, c)?;
+                let done = cops.iter().filter(|x| c.done_cops.contains(x)).count();
+                if done == cops.len() || start.elapsed().as_millis() >= timeout_ms as u128 {
+                    c.done_cops.retain(|x| !cops.contains(x));
+                    Some(done)
+                } else {
+                    None
+                }
+            };
+            if let Some(done) = done {
+                return Ok(done);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
+
+    fn clear_rx_queue(&self, channel: &Mutex<Option<PduChannel>>) -> Result<(), ComServerError> {
+        let h_mod = self.get_module_handle()?;
+        let mut lock = channel.lock().unwrap();
+        if let Some(c) = lock.as_mut() {
+            let drv = self.driver.lock().unwrap();
+            self.pump_events(&drv, h_mod, c)?;
+            c.rx_queue.clear();
+            drv.ioctl(h_mod, c.h_cll, "PDU_IOCTL_CLEAR_RX_QUEUE")
+                .map_err(|e| self.convert_error(e))?;
+        }
+        Ok(())
+    }
+
+    fn clear_tx_queue(&self, channel: &Mutex<Option<PduChannel>>) -> Result<(), ComServerError> {
+        let h_mod = self.get_module_handle()?;
+        if let Some(c) = channel.lock().unwrap().as_ref() {
+            self.driver
+                .lock()
+                .unwrap()
+                .ioctl(h_mod, c.h_cll, "PDU_IOCTL_CLEAR_TX_QUEUE")
+                .map_err(|e| self.convert_error(e))?;
+        }
+        Ok(())
+    }
+
+    fn kline_unsupported() -> ComServerError {
+        ComServerError {
+            err_code: PduError::PDU_ERR_ID_NOT_SUPPORTED as u32,
+            err_desc: "Raw K-Line access is not supported over D-PDU".into(),
+        }
+    }
+
+    fn j1850_unsupported() -> ComServerError {
+        ComServerError {
+            err_code: PduError::PDU_ERR_ID_NOT_SUPPORTED as u32,
+            err_desc: "Raw J1850 access is not supported over D-PDU".into(),
+        }
+    }
+
+    fn can_fd_unsupported() -> ComServerError {
+        ComServerError {
+            err_code: PduError::PDU_ERR_ID_NOT_SUPPORTED as u32,
+            err_desc: "CAN FD is not supported over D-PDU".into(),
+        }
+    }
+
+    fn no_channel_error(&self) -> ComServerError {
+        self.convert_error(PduError::PDU_ERR_INVALID_HANDLE)
+    }
+
+    fn convert_error(&self, e: PduError) -> ComServerError {
+        ComServerError {
+            err_code: e as u32,
+            err_desc: e.to_string(),
+        }
     }
 }
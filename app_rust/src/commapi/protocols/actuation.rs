@@ -0,0 +1,243 @@
+use common::schema::diag::service::Service;
+
+use super::{
+    adjustment, kwp2000,
+    uds::{self, UDSCommand},
+    DiagServer, ProtocolError, ProtocolResult,
+};
+
+// Actuations are services from the ECU definition that take control of a component of the ECU,
+// with InputOutputControlByIdentifier ($2F) on UDS, or InputOutputControlByLocalIdentifier ($30)
+// on KWP2000.
+//
+// The payload of the service holds the identifier of the component, optionally followed by
+// the control parameter and the state to set the component to. If there is no control parameter,
+// a short term adjustment is sent. The ECU tracks which components are being controlled, and
+// releases them when the diagnostic session ends.
+
+/// An IO control request, parsed out of an actuation service
+enum IOControlRequest {
+    Uds {
+        did: u16,
+        param: uds::io_control::IOControlParameter,
+        state: Vec<u8>,
+    },
+    Kwp {
+        local_id: u8,
+        param: kwp2000::io_control::IOControlParameter,
+        state: Vec<u8>,
+    },
+}
+
+fn parse_request(server: &DiagServer, payload: &[u8]) -> ProtocolResult<IOControlRequest> {
+    let uds_sid: u8 = UDSCommand::IOCTLById.into();
+    let kwp_sid: u8 = kwp2000::Service::IOCTLByLocalID.into();
+    let invalid_param =
+        |p: u8| ProtocolError::CustomError(format!("Invalid IO control parameter 0x{:02X}", p));
+    match server {
+        DiagServer::UDS(_) if payload.len() >= 3 && payload[0] == uds_sid => {
+            let param = match payload.get(3) {
+                Some(p) => uds::io_control::IOControlParameter::from_byte(*p)
+                    .ok_or_else(|| invalid_param(*p))?,
+                None => uds::io_control::IOControlParameter::ShortTermAdjustment,
+            };
+            Ok(IOControlRequest::Uds {
+                did: (payload[1] as u16) << 8 | payload[2] as u16,
+                param,
+                state: payload.get(4..).map(Vec::from).unwrap_or_default(),
+            })
+        }
+        DiagServer::KWP2000(_) if payload.len() >= 2 && payload[0] == kwp_sid => {
+            let param = match payload.get(2) {
+                Some(p) => kwp2000::io_control::IOControlParameter::from_byte(*p)
+                    .ok_or_else(|| invalid_param(*p))?,
+                None => kwp2000::io_control::IOControlParameter::ShortTermAdjustment,
+            };
+            Ok(IOControlRequest::Kwp {
+                local_id: payload[1],
+                param,
+                state: payload.get(3..).map(Vec::from).unwrap_or_default(),
+            })
+        }
+        _ => Err(ProtocolError::CustomError(format!(
+            "{:02X?} is not an IO control request for {}",
+            payload,
+            server.get_name()
+        ))),
+    }
+}
+
+/// Runs an actuation service.
+///
+/// ## Params
+/// * `service` - Actuation service from the ECU definition
+/// * `inputs` - A value for each input param of `service`. Empty inputs are left as they
+///   are in the service's payload
+///
+/// ## Returns
+/// The control status the ECU responded with
+pub fn actuate(
+    server: &mut DiagServer,
+    service: &Service,
+    inputs: &[String],
+) -> ProtocolResult<Vec<u8>> {
+    let payload = adjustment::encode_values(service, &service.payload, inputs)?;
+    match parse_request(server, &payload)? {
+        IOControlRequest::Uds { did, param, state } => {
+            let ecu = server.into_uds().unwrap();
+            uds::io_control::io_control(ecu, did, param, &state)
+        }
+        IOControlRequest::Kwp {
+            local_id,
+            param,
+            state,
+        } => {
+            let ecu = server.into_kwp().unwrap();
+            kwp2000::io_control::io_control(ecu, local_id, param, &state)
+        }
+    }
+}
+
+/// Gives control of the component an actuation service controls back to the ECU
+pub fn release(server: &mut DiagServer, service: &Service) -> ProtocolResult<()> {
+    match parse_request(server, &service.payload)? {
+        IOControlRequest::Uds { did, .. } => {
+            uds::io_control::return_control_to_ecu(server.into_uds().unwrap(), did)
+        }
+        IOControlRequest::Kwp { local_id, .. } => {
+            kwp2000::io_control::return_control_to_ecu(server.into_kwp().unwrap(), local_id)
+        }
+    }
+}
+
+/// Returns true if the component an actuation service controls is under the tester's control
+pub fn is_active(server: &DiagServer, service: &Service) -> bool {
+    match (server, parse_request(server, &service.payload)) {
+        (DiagServer::UDS(ecu), Ok(IOControlRequest::Uds { did, .. })) => {
+            ecu.get_active_io_controls().contains(&did)
+        }
+        (DiagServer::KWP2000(ecu), Ok(IOControlRequest::Kwp { local_id, .. })) => {
+            ecu.get_active_io_controls().contains(&local_id)
+        }
+        _ => false,
+    }
+}
+
+/// Returns the number of components under the tester's control
+pub fn get_active_count(server: &DiagServer) -> usize {
+    match server {
+        DiagServer::UDS(ecu) => ecu.get_active_io_controls().len(),
+        DiagServer::KWP2000(ecu) => ecu.get_active_io_controls().len(),
+    }
+}
+
+/// Gives control of every component back to the ECU
+pub fn release_all(server: &DiagServer) -> ProtocolResult<()> {
+    match server {
+        DiagServer::UDS(ecu) => uds::io_control::return_all_control_to_ecu(ecu),
+        DiagServer::KWP2000(ecu) => kwp2000::io_control::return_all_control_to_ecu(ecu),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commapi::{
+        protocols::DiagProtocol,
+        simulator_api::{SimulatorAPI, VirtualECU},
+    };
+    use common::schema::diag::{
+        service::{ParamByteOrder, Parameter},
+        DataFormat,
+    };
+
+    fn fan_service(payload: &[u8]) -> Service {
+        Service {
+            name: "ACT_FAN".into(),
+            description: "Cooling fan".into(),
+            payload: payload.to_vec(),
+            input_params: vec![Parameter {
+                name: "Fan speed".into(),
+                unit: "%".into(),
+                start_bit: (payload.len() - 1) * 8,
+                length_bits: 8,
+                byte_order: ParamByteOrder::BigEndian,
+                data_format: DataFormat::Identical,
+                valid_bounds: None,
+            }],
+            output_params: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn released_on_drop() {
+        for (protocol, payload) in &[
+            (DiagProtocol::UDS, vec![0x2F, 0x01, 0x20, 0x03, 0x00]),
+            (DiagProtocol::KWP2000, vec![0x30, 0x20, 0x07, 0x00]),
+        ] {
+            let sim = SimulatorAPI::new(vec![VirtualECU::new("ECU", *protocol, 0x7E0, 0x7E8)]);
+            let mut server = sim.start_test_server(Default::default());
+            let service = fan_service(payload);
+
+            let res = actuate(&mut server, &service, &["50".into()]).unwrap();
+            assert_eq!(res, vec![50]);
+            assert!(is_active(&server, &service));
+            assert_eq!(sim.get_ecus()[0].io_controls.len(), 1);
+
+            release(&mut server, &service).unwrap();
+            assert_eq!(get_active_count(&server), 0);
+            assert!(sim.get_ecus()[0].io_controls.is_empty());
+
+            // The copy of the server a background task uses must not end the session
+            let service_t = service.clone();
+            server
+                .spawn_task(move |s| actuate(s, &service_t, &["100".into()]))
+                .wait()
+                .unwrap();
+            assert!(is_active(&server, &service));
+
+            // Leaving the session must release anything still being actuated
+            assert_eq!(sim.get_ecus()[0].io_controls.len(), 1);
+            drop(server);
+            assert!(sim.get_ecus()[0].io_controls.is_empty());
+        }
+    }
+
+    #[test]
+    fn tracked_until_confirmed() {
+        for (protocol, payload, malformed, release_req, nrc) in &[
+            (
+                DiagProtocol::UDS,
+                vec![0x2F, 0x01, 0x20, 0x03, 0x00],
+                vec![0x6F, 0x01, 0x20],
+                vec![0x2F, 0x01, 0x20, 0x00],
+                vec![0x7F, 0x2F, 0x22],
+            ),
+            (
+                DiagProtocol::KWP2000,
+                vec![0x30, 0x20, 0x07, 0x00],
+                vec![0x70, 0x20],
+                vec![0x30, 0x20, 0x00],
+                vec![0x7F, 0x30, 0x22],
+            ),
+        ] {
+            let mut ecu = VirtualECU::new("ECU", *protocol, 0x7E0, 0x7E8);
+            let mut take_req = payload.clone();
+            *take_req.last_mut().unwrap() = 50;
+            ecu.custom_responses.push((take_req, malformed.clone()));
+            ecu.custom_responses
+                .push((release_req.clone(), nrc.clone()));
+            let sim = SimulatorAPI::new(vec![ecu]);
+            let mut server = sim.start_test_server(Default::default());
+            let service = fan_service(payload);
+
+            // The ECU may have taken control, even though its response is unusable
+            assert!(actuate(&mut server, &service, &["50".into()]).is_err());
+            assert!(is_active(&server, &service));
+
+            // Control is not confirmed to be back with the ECU
+            assert!(release(&mut server, &service).is_err());
+            assert!(is_active(&server, &service));
+        }
+    }
+}
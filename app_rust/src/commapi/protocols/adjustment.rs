@@ -0,0 +1,236 @@
+use common::schema::diag::service::Service;
+
+use super::{kwp2000, uds::UDSCommand, DiagServer, ProtocolError, ProtocolResult};
+
+// Adjustments are services from the ECU definition that write a value to the ECU, with
+// WriteDataByIdentifier ($2E) on UDS, or WriteDataByLocalIdentifier ($3B) on KWP2000.
+//
+// The payload of a write request has the same layout as the positive response to reading the
+// same identifier, only with a different SID. So the current value is read first and used as the
+// payload, meaning any bytes the user does not edit are written back unchanged. The input params
+// of the service mark which bits of the payload the user can edit.
+
+/// An adjustment that was written to the ECU
+#[derive(Debug, Clone)]
+pub struct AdjustmentLogEntry {
+    /// Service that was executed
+    pub service: Service,
+    /// Write request (Including SID) that restores the value from before the adjustment
+    pub original: Vec<u8>,
+    /// Write request (Including SID) that was sent to the ECU
+    pub written: Vec<u8>,
+}
+
+/// Runs adjustments on an ECU, keeping an undo log of all the values that were changed
+#[derive(Debug, Clone, Default)]
+pub struct AdjustmentLog {
+    entries: Vec<AdjustmentLogEntry>,
+}
+
+/// Returns the SID and identifier used to read back the value written by `service`
+fn get_read_request(service: &Service) -> ProtocolResult<(u8, Vec<u8>)> {
+    let uds_write: u8 = UDSCommand::WriteDataByID.into();
+    let kwp_write: u8 = kwp2000::Service::WriteDataByLocalID.into();
+    let (read_sid, id_len) = match service.payload.first() {
+        Some(sid) if *sid == uds_write => (UDSCommand::ReadDataByID.into(), 2),
+        Some(sid) if *sid == kwp_write => (kwp2000::Service::ReadDataByLocalID.into(), 1),
+        _ => {
+            return Err(ProtocolError::CustomError(format!(
+                "{} is not a WriteDataByIdentifier or WriteDataByLocalIdentifier service",
+                service.name
+            )))
+        }
+    };
+    match service.payload.get(1..1 + id_len) {
+        Some(id) => Ok((read_sid, Vec::from(id))),
+        None => Err(ProtocolError::InvalidResponseSize {
+            expect: 1 + id_len,
+            actual: service.payload.len(),
+        }),
+    }
+}
+
+/// Reads the current value of an adjustment, returning the payload that would write the
+/// same value back to the ECU
+pub fn read_current_value(server: &mut DiagServer, service: &Service) -> ProtocolResult<Vec<u8>> {
+    let (read_sid, id) = get_read_request(service)?;
+    let res = server.run_cmd(read_sid, &id)?;
+    if res.get(1..1 + id.len()) != Some(id.as_slice()) {
+        return Err(ProtocolError::CustomError(
+            "ECU responded with a different identifier".into(),
+        ));
+    }
+    let mut payload = res;
+    payload[0] = service.payload[0];
+    Ok(payload)
+}
+
+/// Decodes the value of each input param of `service` out of `payload`
+pub fn decode_values(service: &Service, payload: &[u8]) -> Vec<(String, String)> {
+    service
+        .input_params
+        .iter()
+        .map(|p| {
+            let value = match p.decode_value_to_string(payload) {
+                Ok(v) => match p.get_unit() {
+                    // Strip the unit, so the value can be edited and encoded again
+                    Some(unit) => v.trim_end_matches(&unit).trim_end().to_string(),
+                    None => v,
+                },
+                Err(e) => format!("{:?}", e),
+            };
+            (p.name.clone(), value)
+        })
+        .collect()
+}
+
+/// Encodes the user's input into a payload read by [read_current_value].
+/// `inputs` holds a value for each input param of `service`, in order.
+/// Empty inputs leave the current value unchanged
+pub fn encode_values(
+    service: &Service,
+    current: &[u8],
+    inputs: &[String],
+) -> ProtocolResult<Vec<u8>> {
+    let mut payload = Vec::from(current);
+    for (param, input) in service.input_params.iter().zip(inputs) {
+        if input.trim().is_empty() {
+            continue;
+        }
+        let min_len = (param.start_bit + param.length_bits).div_ceil(8);
+        if payload.len() < min_len {
+            payload.resize(min_len, 0x00);
+        }
+        param
+            .encode_value_from_string(input, &mut payload)
+            .map_err(|e| ProtocolError::CustomError(format!("{}: {:?}", param.name, e)))?;
+    }
+    Ok(payload)
+}
+
+/// Reads the value back from the ECU, and checks it matches what was written
+fn verify(server: &mut DiagServer, service: &Service, written: &[u8]) -> ProtocolResult<()> {
+    let read_back = read_current_value(server, service)?;
+    if read_back != written {
+        return Err(ProtocolError::CustomError(format!(
+            "Verification failed. Wrote {:02X?}, ECU reports {:02X?}",
+            written, read_back
+        )));
+    }
+    Ok(())
+}
+
+impl AdjustmentLog {
+    /// Writes a new value to the ECU, and verifies it.
+    ///
+    /// ## Params
+    /// * `service` - Adjustment service from the ECU definition
+    /// * `inputs` - A value for each input param of `service`, formatted the same way they
+    ///   are decoded. Empty inputs are left unchanged
+    ///
+    /// ## Returns
+    /// The payload that was written
+    pub fn adjust(
+        &mut self,
+        server: &mut DiagServer,
+        service: &Service,
+        inputs: &[String],
+    ) -> ProtocolResult<Vec<u8>> {
+        let original = read_current_value(server, service)?;
+        let payload = encode_values(service, &original, inputs)?;
+        server.run_cmd(payload[0], &payload[1..])?;
+        // Log before verifying, as the ECU may have only taken part of the value
+        self.entries.push(AdjustmentLogEntry {
+            service: service.clone(),
+            original,
+            written: payload.clone(),
+        });
+        verify(server, service, &payload)?;
+        Ok(payload)
+    }
+
+    /// Restores the value from before the last adjustment, removing it from the log
+    pub fn undo_last(&mut self, server: &mut DiagServer) -> ProtocolResult<AdjustmentLogEntry> {
+        let entry = match self.entries.last() {
+            Some(e) => e.clone(),
+            None => return Err(ProtocolError::CustomError("Nothing to undo".into())),
+        };
+        server.run_cmd(entry.original[0], &entry.original[1..])?;
+        verify(server, &entry.service, &entry.original)?;
+        self.entries.pop();
+        Ok(entry)
+    }
+
+    /// Returns all adjustments that can be undone, oldest first
+    pub fn get_entries(&self) -> &[AdjustmentLogEntry] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commapi::{
+        protocols::DiagProtocol,
+        simulator_api::{SimulatorAPI, VirtualECU},
+    };
+    use common::schema::diag::{
+        service::{Limit, ParamByteOrder, Parameter},
+        DataFormat,
+    };
+
+    #[test]
+    fn adjust_and_undo() {
+        let mut sim_ecu = VirtualECU::new("UDS", DiagProtocol::UDS, 0x7E0, 0x7E8);
+        sim_ecu.data_identifiers.insert(0x0101, vec![0x10, 0x20]);
+        let sim = SimulatorAPI::new(vec![sim_ecu]);
+        let mut server = sim.start_test_server(Default::default());
+
+        let service = Service {
+            name: "ADJ_IDLE_RPM".into(),
+            description: "Idle RPM".into(),
+            payload: vec![0x2E, 0x01, 0x01],
+            input_params: vec![Parameter {
+                name: "Idle RPM".into(),
+                unit: "rpm".into(),
+                start_bit: 24,
+                length_bits: 8,
+                byte_order: ParamByteOrder::BigEndian,
+                data_format: DataFormat::Linear {
+                    multiplier: 10.0,
+                    offset: 0.0,
+                },
+                valid_bounds: Some(Limit::new(500.0, 1200.0)),
+            }],
+            output_params: Vec::new(),
+        };
+
+        let current = read_current_value(&mut server, &service).unwrap();
+        assert_eq!(current, vec![0x2E, 0x01, 0x01, 0x10, 0x20]);
+        assert_eq!(
+            decode_values(&service, &current),
+            vec![("Idle RPM".to_string(), "160".to_string())]
+        );
+
+        let mut log = AdjustmentLog::default();
+        assert!(log.adjust(&mut server, &service, &["2000".into()]).is_err());
+        assert!(log.is_empty());
+        let written = log.adjust(&mut server, &service, &["800".into()]).unwrap();
+        assert_eq!(written, vec![0x2E, 0x01, 0x01, 0x50, 0x20]);
+        assert_eq!(
+            sim.get_ecus()[0].data_identifiers[&0x0101],
+            vec![0x50, 0x20]
+        );
+
+        log.undo_last(&mut server).unwrap();
+        assert!(log.is_empty());
+        assert_eq!(
+            sim.get_ecus()[0].data_identifiers[&0x0101],
+            vec![0x10, 0x20]
+        );
+    }
+}
@@ -0,0 +1,276 @@
+use common::schema::diag::service::Parameter;
+
+use super::{uds::read_dtc_info::DTCStatus, DTCState, DTC};
+
+/// Formats a 2 byte DTC as an SAE J2012 code (EG: 0x2001 -> P2001)
+pub fn j2012_code(code: u16) -> String {
+    let system = match code >> 14 {
+        0 => 'P', // Powertrain
+        1 => 'C', // Chassis
+        2 => 'B', // Body
+        _ => 'U', // Network
+    };
+    format!("{}{:1X}{:03X}", system, (code >> 12) & 0x03, code & 0x0FFF)
+}
+
+/// KWP2000 DTC status byte (ISO14230-3)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct KwpDTCStatus {
+    /// Bits 0-3 - Fault symptom. What each symptom is, is up to the ECU manufacturer
+    pub fault_symptom: u8,
+    /// Bit 4 - The test for this DTC has not completed
+    pub test_not_complete: bool,
+    /// Bits 5-6 - 0 = No DTC, 1 = Stored, not present, 2 = Present, not yet stored, 3 = Present and stored
+    pub storage_state: u8,
+    /// Bit 7 - Warning lamp is on
+    pub warning_lamp: bool,
+}
+
+impl KwpDTCStatus {
+    pub fn from_byte(b: u8) -> Self {
+        Self {
+            fault_symptom: b & 0x0F,
+            test_not_complete: b & 0x10 != 0,
+            storage_state: (b >> 5) & 0x03,
+            warning_lamp: b & 0x80 != 0,
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        (self.fault_symptom & 0x0F)
+            | (self.test_not_complete as u8) << 4
+            | (self.storage_state & 0x03) << 5
+            | (self.warning_lamp as u8) << 7
+    }
+}
+
+/// Status of a DTC, as reported by the ECU
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DTCStatusMask {
+    Uds(DTCStatus),
+    Kwp(KwpDTCStatus),
+    /// OBD-II has no status byte, only which service reported the DTC
+    Obd,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DTCRecordType {
+    /// Conditions at the time the DTC was stored. UDS snapshot records,
+    /// or the environment data of KWP2000 ReadDTCStatus
+    FreezeFrame,
+    /// UDS extended data records, such as counters
+    ExtendedData,
+}
+
+/// A record of data stored with a DTC
+#[derive(Debug, Clone)]
+pub struct DTCRecord {
+    pub(crate) record_type: DTCRecordType,
+    /// Record number, if the protocol numbers records
+    pub(crate) number: Option<u8>,
+    /// ECU response the record was read from
+    pub(crate) raw: Vec<u8>,
+    /// Name and value of each parameter decoded from [raw](DTCRecord::raw)
+    pub(crate) values: Vec<(String, String)>,
+}
+
+impl DTCRecord {
+    /// Creates a record, decoding `envs` from the ECU's definition out of the response
+    pub fn new(
+        record_type: DTCRecordType,
+        number: Option<u8>,
+        raw: Vec<u8>,
+        envs: &[Parameter],
+    ) -> Self {
+        let mut values = Vec::new();
+        for e in envs {
+            match e.decode_value_to_string(&raw) {
+                Ok(s) => values.push((e.name.clone(), s)),
+                Err(err) => eprintln!("Warning could not decode param: {:?}", err),
+            }
+        }
+        Self {
+            record_type,
+            number,
+            raw,
+            values,
+        }
+    }
+}
+
+impl DTC {
+    fn new(error: String, id: u32, state: DTCState, mil: bool, status: DTCStatusMask) -> Self {
+        Self {
+            error,
+            state,
+            check_engine_on: mil,
+            id,
+            status,
+            severity: None,
+            functional_unit: None,
+            occurrence_count: None,
+            records: Vec::new(),
+        }
+    }
+
+    /// Creates a DTC from a 3 byte UDS DTC and its status byte
+    pub fn from_uds(id: u32, status: DTCStatus) -> Self {
+        let state = if status.pending_dtc {
+            DTCState::Pending
+        } else if status.confirmed_dtc {
+            DTCState::Permanent
+        } else if status.test_failed {
+            DTCState::Stored
+        } else {
+            DTCState::None
+        };
+        // Upper 2 bytes are the J2012 code, lower byte is the failure type
+        let error = format!("{}-{:02X}", j2012_code((id >> 8) as u16), id as u8);
+        Self::new(
+            error,
+            id & 0xFFFFFF,
+            state,
+            status.warning_indicator_requested,
+            DTCStatusMask::Uds(status),
+        )
+    }
+
+    /// Creates a DTC from a 2 byte KWP2000 DTC and its status byte
+    pub fn from_kwp(id: u16, status: KwpDTCStatus) -> Self {
+        let state = match status.storage_state {
+            1 => DTCState::Stored,
+            2 => DTCState::Pending,
+            3 => DTCState::Permanent,
+            _ => DTCState::None,
+        };
+        Self::new(
+            j2012_code(id),
+            id as u32,
+            state,
+            status.warning_lamp,
+            DTCStatusMask::Kwp(status),
+        )
+    }
+
+    /// Creates a DTC from a 2 byte OBD-II DTC, and the state of the service that reported it
+    pub fn from_obd(id: u16, state: DTCState) -> Self {
+        Self::new(
+            j2012_code(id),
+            id as u32,
+            state,
+            state == DTCState::Stored || state == DTCState::Permanent,
+            DTCStatusMask::Obd,
+        )
+    }
+
+    /// Returns the raw DTC number as hex, as some ECU definitions name DTCs this way
+    pub fn get_raw_code(&self) -> String {
+        match self.status {
+            DTCStatusMask::Uds(_) => format!("{:06X}", self.id),
+            _ => format!("{:04X}", self.id),
+        }
+    }
+
+    /// Adds a record to the DTC. If the record contains an occurrence or frequency
+    /// counter, [occurrence_count](DTC::occurrence_count) is set from it
+    pub fn add_record(&mut self, record: DTCRecord) {
+        if self.occurrence_count.is_none() {
+            self.occurrence_count = record
+                .values
+                .iter()
+                .filter(|(name, _)| {
+                    let name = name.to_lowercase();
+                    name.contains("occurrence") || name.contains("frequency")
+                })
+                .find_map(|(_, v)| v.split_whitespace().next()?.parse::<u32>().ok());
+        }
+        self.records.push(record)
+    }
+
+    /// Returns the name and value of every status bit, severity and counter of the DTC
+    pub fn get_status_values(&self) -> Vec<(String, String)> {
+        let yes_no = |b: bool| if b { "YES" } else { "NO" }.to_string();
+        let mut res = Vec::new();
+        match &self.status {
+            DTCStatusMask::Uds(s) => {
+                res.push(("Test failed".into(), yes_no(s.test_failed)));
+                res.push((
+                    "Test failed this operation cycle".into(),
+                    yes_no(s.test_failed_this_operation_cycle),
+                ));
+                res.push(("Pending".into(), yes_no(s.pending_dtc)));
+                res.push(("Confirmed".into(), yes_no(s.confirmed_dtc)));
+                res.push((
+                    "Test not completed since last clear".into(),
+                    yes_no(s.test_not_completed_since_last_clear),
+                ));
+                res.push((
+                    "Test failed since last clear".into(),
+                    yes_no(s.test_failed_since_last_clear),
+                ));
+                res.push((
+                    "Test not completed this operation cycle".into(),
+                    yes_no(s.test_not_completed_this_operation_cycle),
+                ));
+                res.push((
+                    "Warning indicator requested".into(),
+                    yes_no(s.warning_indicator_requested),
+                ));
+            }
+            DTCStatusMask::Kwp(s) => {
+                res.push(("Warning lamp".into(), yes_no(s.warning_lamp)));
+                res.push(("Storage state".into(), format!("{:?}", self.state)));
+                res.push(("Test not complete".into(), yes_no(s.test_not_complete)));
+                res.push(("Fault symptom".into(), format!("0x{:X}", s.fault_symptom)));
+            }
+            DTCStatusMask::Obd => {}
+        }
+        if let Some(s) = &self.severity {
+            let severity = if s.check_immediately {
+                "Check immediately"
+            } else if s.check_at_next_halt {
+                "Check at next halt"
+            } else if s.maintenance_only {
+                "Maintenance only"
+            } else {
+                "None"
+            };
+            res.push(("Severity".into(), severity.into()));
+        }
+        if let Some(fu) = self.functional_unit {
+            res.push(("Functional unit".into(), format!("0x{:02X}", fu)));
+        }
+        if let Some(count) = self.occurrence_count {
+            res.push(("Occurrence count".into(), count.to_string()));
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn j2012() {
+        assert_eq!(j2012_code(0x0123), "P0123");
+        assert_eq!(j2012_code(0x2001), "P2001");
+        assert_eq!(j2012_code(0x9001), "B1001");
+        assert_eq!(j2012_code(0xC155), "U0155");
+        assert_eq!(j2012_code(0x5ABC), "C1ABC");
+        let dtc = DTC::from_uds(0xC15587, DTCStatus::from_byte(0x09));
+        assert_eq!(dtc.error, "U0155-87");
+        assert_eq!(dtc.get_raw_code(), "C15587");
+        assert_eq!(dtc.state, DTCState::Permanent);
+    }
+
+    #[test]
+    fn kwp_status() {
+        for b in 0..=0xFF {
+            assert_eq!(KwpDTCStatus::from_byte(b).to_byte(), b);
+        }
+        let dtc = DTC::from_kwp(0x2001, KwpDTCStatus::from_byte(0xE4));
+        assert_eq!(dtc.state, DTCState::Permanent);
+        assert!(dtc.check_engine_on);
+    }
+}
@@ -0,0 +1,570 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Condvar, Mutex,
+    },
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use crate::commapi::iface::Interface;
+
+use super::{ProtocolError, ProtocolResult};
+
+// Every diagnostic server owns one request executor. The executor owns the server's interface
+// and runs requests on it one at a time, in the order they were submitted, on its own thread.
+//
+// The thread sleeps until a request is submitted, so it uses no CPU whilst idle. If the
+// server needs to send something periodically, such as tester present, that is run by the
+// same thread once the interface has been idle for the periodic interval. This means the
+// periodic task can never interleave with a request.
+//
+// Submitting a request returns a [RequestHandle] straight away. The handle can be waited on,
+// polled as a future, given a callback, or cancelled. Cancelling a request that has not been
+// sent yet stops it from being sent. Requests that are already being sent cannot be
+// interrupted, but their response is discarded.
+//
+// A request with a timeout is not sent if it is still queued once the timeout has passed.
+// Once it is sent, its timeout starts again, so time spent queued behind a slow request
+// does not count against it. The timeout should be at least as long as the request can
+// take, otherwise the handle gives up whilst the executor is still busy with it.
+
+/// Error returned for requests that were cancelled before they were sent
+const ERR_CANCELLED: &str = "Request was cancelled";
+/// Error returned if the result was already taken through another handle
+const ERR_TAKEN: &str = "Result was already taken";
+/// Error returned for requests submitted after the executor stopped
+const ERR_STOPPED: &str = "Diagnostic server has stopped";
+
+type Job = Box<dyn FnOnce(&mut Box<dyn Interface>) + Send>;
+type Callback<T> = Box<dyn FnOnce(ProtocolResult<T>) + Send>;
+
+/// Task run whenever the interface has been idle for the periodic interval.
+/// Returning false stops the executor
+pub type PeriodicTask = Box<dyn FnMut(&mut Box<dyn Interface>) -> bool + Send>;
+
+enum ExecutorMsg {
+    Run(Job),
+    Stop,
+}
+
+struct HandleState<T> {
+    done: bool,
+    result: Option<ProtocolResult<T>>,
+    waker: Option<Waker>,
+    callback: Option<Callback<T>>,
+    /// Request is not sent if it has not started by this time, and is given up on
+    /// if it has not completed by this time once it has started
+    deadline: Option<Instant>,
+}
+
+struct Shared<T> {
+    state: Mutex<HandleState<T>>,
+    cond: Condvar,
+    cancelled: AtomicBool,
+    /// Time the request has to complete in
+    timeout: Option<Duration>,
+}
+
+impl<T> Shared<T> {
+    fn complete(&self, res: ProtocolResult<T>) {
+        let mut state = self.state.lock().unwrap();
+        if state.done {
+            return; // Cancelled or timed out, the response is not wanted
+        }
+        state.done = true;
+        let waker = state.waker.take();
+        match state.callback.take() {
+            Some(cb) => {
+                drop(state);
+                cb(res)
+            }
+            None => {
+                state.result = Some(res);
+                drop(state);
+            }
+        }
+        self.cond.notify_all();
+        if let Some(w) = waker {
+            w.wake()
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.deadline.map(|d| Instant::now() >= d).unwrap_or(false)
+    }
+
+    /// Called once the request is sent, restarting its timeout
+    fn start(&self) {
+        if let Some(t) = self.timeout {
+            self.state.lock().unwrap().deadline = Some(Instant::now() + t);
+        }
+    }
+
+    /// Gives up on the request, unless it completed in the meantime
+    fn expire(&self) -> ProtocolResult<T> {
+        self.cancelled.store(true, Ordering::Relaxed);
+        self.complete(Err(ProtocolError::Timeout));
+        self.state
+            .lock()
+            .unwrap()
+            .result
+            .take()
+            .unwrap_or(Err(ProtocolError::CustomError(ERR_TAKEN.into())))
+    }
+}
+
+/// Completes a request's handle if its job is dropped without running, which happens
+/// when the executor stops with requests still queued
+struct Completer<T>(Arc<Shared<T>>);
+
+impl<T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        self.0
+            .complete(Err(ProtocolError::CustomError(ERR_STOPPED.into())))
+    }
+}
+
+/// Handle to a request submitted to a [RequestExecutor]
+pub struct RequestHandle<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Clone for RequestHandle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for RequestHandle<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RequestHandle")
+            .field("done", &self.is_done())
+            .field("cancelled", &self.shared.cancelled.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+impl<T> RequestHandle<T> {
+    fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(HandleState {
+                    done: false,
+                    result: None,
+                    waker: None,
+                    callback: None,
+                    deadline: timeout.map(|t| Instant::now() + t),
+                }),
+                cond: Condvar::new(),
+                cancelled: AtomicBool::new(false),
+                timeout,
+            }),
+        }
+    }
+
+    /// Blocks until the request completes, or its timeout passes
+    pub fn wait(self) -> ProtocolResult<T> {
+        let mut state = self.shared.state.lock().unwrap();
+        while !state.done {
+            state = match state.deadline {
+                // The deadline moves back when the request starts, so check it again on waking
+                Some(d) => match d.checked_duration_since(Instant::now()) {
+                    Some(left) if !left.is_zero() => {
+                        self.shared.cond.wait_timeout(state, left).unwrap().0
+                    }
+                    _ => {
+                        drop(state);
+                        return self.shared.expire();
+                    }
+                },
+                None => self.shared.cond.wait(state).unwrap(),
+            };
+        }
+        state
+            .result
+            .take()
+            .unwrap_or(Err(ProtocolError::CustomError(ERR_TAKEN.into())))
+    }
+
+    /// Blocks until the request completes. If it does not complete within `timeout`,
+    /// it is cancelled and [ProtocolError::Timeout] is returned
+    pub fn wait_timeout(self, timeout: Duration) -> ProtocolResult<T> {
+        let state = self.shared.state.lock().unwrap();
+        let (mut state, _) = self
+            .shared
+            .cond
+            .wait_timeout_while(state, timeout, |s| !s.done)
+            .unwrap();
+        if state.done {
+            return state
+                .result
+                .take()
+                .unwrap_or(Err(ProtocolError::CustomError(ERR_TAKEN.into())));
+        }
+        drop(state);
+        self.shared.expire()
+    }
+
+    /// Returns the result of the request if it has completed, without blocking.
+    /// The result can only be taken once
+    pub fn try_take(&self) -> Option<ProtocolResult<T>> {
+        if !self.is_done() && self.shared.is_expired() {
+            self.shared.cancelled.store(true, Ordering::Relaxed);
+            self.shared.complete(Err(ProtocolError::Timeout));
+        }
+        self.shared.state.lock().unwrap().result.take()
+    }
+
+    /// Returns true once the request has completed, been cancelled or timed out
+    pub fn is_done(&self) -> bool {
+        self.shared.state.lock().unwrap().done
+    }
+
+    /// Cancels the request. If it has not been sent yet, it never will be
+    pub fn cancel(&self) {
+        self.shared.cancelled.store(true, Ordering::Relaxed);
+        self.shared
+            .complete(Err(ProtocolError::CustomError(ERR_CANCELLED.into())));
+    }
+
+    /// Calls `f` with the result of the request once it completes, on the executor's thread.
+    /// If the request has already completed, `f` is called straight away
+    pub fn on_complete<F: FnOnce(ProtocolResult<T>) + Send + 'static>(self, f: F) {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.done {
+            if let Some(res) = state.result.take() {
+                drop(state);
+                f(res)
+            }
+        } else {
+            state.callback = Some(Box::new(f));
+        }
+    }
+}
+
+impl<T: Send + 'static> RequestHandle<T> {
+    /// Runs `f` on its own thread, returning a handle to its result straight away.
+    ///
+    /// This is for tasks that send several requests in turn, such as adjustments. These cannot
+    /// be submitted to a [RequestExecutor] as one request, as each request they send would be
+    /// queued behind the task itself. Every request they send has its own deadline, so the
+    /// task always completes
+    pub fn spawn<F>(f: F) -> Self
+    where
+        F: FnOnce() -> ProtocolResult<T> + Send + 'static,
+    {
+        let handle = Self::new(None);
+        // If `f` panics, the completer fails the handle instead
+        let completer = Completer(handle.shared.clone());
+        std::thread::spawn(move || {
+            let res = f();
+            completer.0.complete(res)
+        });
+        handle
+    }
+}
+
+impl<T> Future for RequestHandle<T> {
+    type Output = ProtocolResult<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(res) = self.try_take() {
+            return Poll::Ready(res);
+        }
+        let mut state = self.shared.state.lock().unwrap();
+        match state.result.take() {
+            Some(res) => Poll::Ready(res), // Completed whilst checking
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Runs the requests of a diagnostic server on its interface
+#[derive(Clone)]
+pub struct RequestExecutor {
+    tx: Arc<Mutex<Sender<ExecutorMsg>>>,
+    running: Arc<AtomicBool>,
+}
+
+impl std::fmt::Debug for RequestExecutor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RequestExecutor")
+            .field("running", &self.is_running())
+            .finish()
+    }
+}
+
+impl RequestExecutor {
+    /// Starts an executor that only runs submitted requests
+    pub fn new(name: &'static str, interface: Box<dyn Interface>) -> Self {
+        Self::start(name, interface, None)
+    }
+
+    /// Starts an executor that also runs `task` every time the interface has been idle
+    /// for `interval`
+    pub fn with_periodic(
+        name: &'static str,
+        interface: Box<dyn Interface>,
+        interval: Duration,
+        task: PeriodicTask,
+    ) -> Self {
+        Self::start(name, interface, Some((interval, task)))
+    }
+
+    fn start(
+        name: &'static str,
+        mut interface: Box<dyn Interface>,
+        mut periodic: Option<(Duration, PeriodicTask)>,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel::<ExecutorMsg>();
+        let running = Arc::new(AtomicBool::new(true));
+        let running_t = running.clone();
+        std::thread::spawn(move || {
+            println!("{} Diag server start!", name);
+            let mut next_periodic = periodic.as_ref().map(|(i, _)| Instant::now() + *i);
+            loop {
+                // Sleep until there is a request, or the periodic task is due
+                let msg = match next_periodic {
+                    Some(at) => match rx.recv_timeout(at.saturating_duration_since(Instant::now()))
+                    {
+                        Ok(m) => Some(m),
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    },
+                    None => match rx.recv() {
+                        Ok(m) => Some(m),
+                        Err(_) => break,
+                    },
+                };
+                match msg {
+                    Some(ExecutorMsg::Run(job)) => {
+                        job(&mut interface);
+                        // The request counts as activity, so push the periodic task back
+                        next_periodic = periodic.as_ref().map(|(i, _)| Instant::now() + *i);
+                    }
+                    Some(ExecutorMsg::Stop) => break,
+                    None => {
+                        if let Some((interval, task)) = periodic.as_mut() {
+                            if !task(&mut interface) {
+                                break;
+                            }
+                            next_periodic = Some(Instant::now() + *interval);
+                        }
+                    }
+                }
+            }
+            running_t.store(false, Ordering::Relaxed);
+            drop(rx); // Fails any request still queued
+            println!("{} Diag server stop!", name);
+            let _res = interface.close();
+        });
+        Self {
+            tx: Arc::new(Mutex::new(tx)),
+            running,
+        }
+    }
+
+    /// Queues a request. It is run once every request submitted before it has completed
+    pub fn submit<T, F>(&self, f: F) -> RequestHandle<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Box<dyn Interface>) -> ProtocolResult<T> + Send + 'static,
+    {
+        self.queue(RequestHandle::new(None), f)
+    }
+
+    /// Queues a request that is abandoned with [ProtocolError::Timeout] if it is not sent
+    /// within `timeout`, or has not completed within `timeout` of being sent
+    pub fn submit_timeout<T, F>(&self, timeout: Duration, f: F) -> RequestHandle<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Box<dyn Interface>) -> ProtocolResult<T> + Send + 'static,
+    {
+        self.queue(RequestHandle::new(Some(timeout)), f)
+    }
+
+    /// Queues a request, and blocks until it has completed
+    pub fn run<T, F>(&self, f: F) -> ProtocolResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Box<dyn Interface>) -> ProtocolResult<T> + Send + 'static,
+    {
+        self.submit(f).wait()
+    }
+
+    fn queue<T, F>(&self, handle: RequestHandle<T>, f: F) -> RequestHandle<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Box<dyn Interface>) -> ProtocolResult<T> + Send + 'static,
+    {
+        let completer = Completer(handle.shared.clone());
+        let job: Job = Box::new(move |iface| {
+            let shared = &completer.0;
+            if shared.cancelled.load(Ordering::Relaxed) {
+                return; // Handle was already completed when it was cancelled
+            }
+            let res = if shared.is_expired() {
+                Err(ProtocolError::Timeout)
+            } else {
+                shared.start();
+                f(iface)
+            };
+            shared.complete(res)
+        });
+        // If the executor has stopped, the job is dropped and its handle is failed
+        let _ = self.tx.lock().unwrap().send(ExecutorMsg::Run(job));
+        handle
+    }
+
+    /// Stops the executor once every request already submitted has completed
+    pub fn stop(&self) {
+        let _ = self.tx.lock().unwrap().send(ExecutorMsg::Stop);
+    }
+
+    /// Returns false once the executor has stopped
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if there are other copies of this executor, such as the copy
+    /// of a server given to a task from [RequestHandle::spawn]
+    pub fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.tx) > 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commapi::{
+        iface::InterfacePayload,
+        protocols::DiagProtocol,
+        simulator_api::{SimulatorAPI, VirtualECU},
+    };
+    use std::sync::mpsc::channel;
+
+    fn start_executor(periodic: Option<(Duration, PeriodicTask)>) -> RequestExecutor {
+        let iface = SimulatorAPI::new(vec![VirtualECU::new(
+            "ECU",
+            DiagProtocol::UDS,
+            0x7E0,
+            0x7E8,
+        )])
+        .open_test_iface();
+        match periodic {
+            Some((interval, task)) => RequestExecutor::with_periodic("Test", iface, interval, task),
+            None => RequestExecutor::new("Test", iface),
+        }
+    }
+
+    fn tester_present(iface: &mut Box<dyn Interface>) -> ProtocolResult<Vec<u8>> {
+        let tx = InterfacePayload::new(0x7E0, &[0x3E, 0x00]);
+        Ok(iface.send_recv_data(tx, 0, 1000)?.data)
+    }
+
+    #[test]
+    fn requests_complete() {
+        let executor = start_executor(None);
+        assert_eq!(executor.run(tester_present).unwrap(), vec![0x7E, 0x00]);
+
+        let (tx, rx) = channel();
+        executor
+            .submit(tester_present)
+            .on_complete(move |res| tx.send(res).unwrap());
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(1)).unwrap().unwrap(),
+            vec![0x7E, 0x00]
+        );
+
+        executor.stop();
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!executor.is_running());
+        assert!(executor.run(tester_present).is_err());
+    }
+
+    #[test]
+    fn cancel_and_timeout() {
+        let executor = start_executor(None);
+        // Hold the executor up, so that the following requests stay queued
+        let blocker = executor.submit(|_| {
+            std::thread::sleep(Duration::from_millis(100));
+            Ok(())
+        });
+        let (tx, rx) = channel();
+        let tx_t = tx.clone();
+        let cancelled = executor.submit(move |_| {
+            tx_t.send(()).unwrap();
+            Ok(())
+        });
+        let expired = executor.submit_timeout(Duration::from_millis(10), move |_| {
+            tx.send(()).unwrap();
+            Ok(())
+        });
+        cancelled.cancel();
+        assert!(cancelled.try_take().unwrap().is_err());
+        assert!(expired.wait().unwrap_err().is_timeout());
+        blocker.wait().unwrap();
+        assert_eq!(executor.run(|_| Ok(1)).unwrap(), 1);
+        // Neither request was sent
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn timeout_restarts_when_sent() {
+        let executor = start_executor(None);
+        let blocker = executor.submit(|_| {
+            std::thread::sleep(Duration::from_millis(100));
+            Ok(())
+        });
+        // Sent after 100ms, and completes after 250ms. Past its first deadline,
+        // but within the timeout once it was sent
+        let slow = executor.submit_timeout(Duration::from_millis(200), |_| {
+            std::thread::sleep(Duration::from_millis(150));
+            Ok(1)
+        });
+        assert_eq!(slow.wait().unwrap(), 1);
+        blocker.wait().unwrap();
+    }
+
+    #[test]
+    fn spawned_task_sends_several_requests() {
+        let executor = start_executor(None);
+        let executor_t = executor.clone();
+        assert!(executor.is_shared());
+        let task = RequestHandle::spawn(move || {
+            let first = executor_t.run(tester_present)?;
+            let second = executor_t.run(tester_present)?;
+            Ok(first.len() + second.len())
+        });
+        assert_eq!(task.wait().unwrap(), 4);
+        assert!(!executor.is_shared());
+
+        let panicked: RequestHandle<()> = RequestHandle::spawn(|| panic!("Task panicked"));
+        assert!(panicked.wait().is_err());
+        executor.stop();
+    }
+
+    #[test]
+    fn periodic_task_runs_when_idle() {
+        let (tx, rx) = channel();
+        let executor = start_executor(Some((
+            Duration::from_millis(20),
+            Box::new(move |iface| {
+                let _ = tx.send(tester_present(iface).is_ok());
+                true
+            }),
+        )));
+        assert!(rx.recv_timeout(Duration::from_secs(1)).unwrap());
+        executor.stop();
+    }
+}
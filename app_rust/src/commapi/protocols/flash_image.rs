@@ -0,0 +1,255 @@
+use std::path::Path;
+
+use super::{ProtocolError, ProtocolResult};
+
+/// A contiguous block of data to be written to an ECU
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlashSegment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+impl FlashSegment {
+    pub fn end_address(&self) -> u32 {
+        self.address + self.data.len() as u32
+    }
+}
+
+/// A flash image, split into segments sorted by address
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FlashImage {
+    pub segments: Vec<FlashSegment>,
+}
+
+fn parse_error(line: usize, desc: &str) -> ProtocolError {
+    ProtocolError::CustomError(format!("Flash image line {}: {}", line, desc))
+}
+
+fn decode_hex_line(line: usize, s: &str) -> ProtocolResult<Vec<u8>> {
+    hex::decode(s).map_err(|_| parse_error(line, "Invalid hex data"))
+}
+
+impl FlashImage {
+    /// Loads an image from a file. The format is picked from the file extension:
+    /// * .hex, .ihex - Intel HEX
+    /// * .s19, .s28, .s37, .srec, .mot - Motorola S-record
+    /// * Anything else - Raw binary, written starting at `bin_address`
+    pub fn load_file(path: &str, bin_address: u32) -> ProtocolResult<Self> {
+        let read_err =
+            |e: std::io::Error| ProtocolError::CustomError(format!("Cannot read {}: {}", path, e));
+        let ext = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+        match ext.as_deref() {
+            Some("hex") | Some("ihex") => {
+                Self::from_intel_hex(&std::fs::read_to_string(path).map_err(read_err)?)
+            }
+            Some("s19") | Some("s28") | Some("s37") | Some("srec") | Some("mot") => {
+                Self::from_srec(&std::fs::read_to_string(path).map_err(read_err)?)
+            }
+            _ => Ok(Self::from_binary(
+                bin_address,
+                &std::fs::read(path).map_err(read_err)?,
+            )),
+        }
+    }
+
+    /// Creates an image from a raw binary, starting at `address`
+    pub fn from_binary(address: u32, data: &[u8]) -> Self {
+        let mut res = Self::default();
+        res.add_data(address, data);
+        res
+    }
+
+    /// Parses an Intel HEX file
+    pub fn from_intel_hex(text: &str) -> ProtocolResult<Self> {
+        let mut res = Self::default();
+        let mut base_address: u32 = 0;
+        for (idx, line) in text.lines().enumerate() {
+            let line_no = idx + 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if !line.starts_with(':') {
+                return Err(parse_error(line_no, "Record does not start with ':'"));
+            }
+            let bytes = decode_hex_line(line_no, &line[1..])?;
+            if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+                return Err(parse_error(line_no, "Invalid record length"));
+            }
+            if bytes.iter().fold(0u8, |sum, x| sum.wrapping_add(*x)) != 0 {
+                return Err(parse_error(line_no, "Checksum mismatch"));
+            }
+            let offset = (bytes[1] as u32) << 8 | bytes[2] as u32;
+            let data = &bytes[4..bytes.len() - 1];
+            match bytes[3] {
+                0x00 => res.add_data(base_address.wrapping_add(offset), data),
+                0x01 => break, // End of file
+                0x02 if data.len() == 2 => {
+                    base_address = ((data[0] as u32) << 8 | data[1] as u32) << 4
+                }
+                0x04 if data.len() == 2 => {
+                    base_address = ((data[0] as u32) << 8 | data[1] as u32) << 16
+                }
+                0x03 | 0x05 => {} // Start address, not needed for flashing
+                _ => return Err(parse_error(line_no, "Invalid record type")),
+            }
+        }
+        Ok(res)
+    }
+
+    /// Parses a Motorola S-record file
+    pub fn from_srec(text: &str) -> ProtocolResult<Self> {
+        let mut res = Self::default();
+        for (idx, line) in text.lines().enumerate() {
+            let line_no = idx + 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if !line.starts_with('S') || line.len() < 4 {
+                return Err(parse_error(line_no, "Record does not start with 'S'"));
+            }
+            let bytes = decode_hex_line(line_no, &line[2..])?;
+            if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+                return Err(parse_error(line_no, "Invalid record length"));
+            }
+            if bytes.iter().fold(0u8, |sum, x| sum.wrapping_add(*x)) != 0xFF {
+                return Err(parse_error(line_no, "Checksum mismatch"));
+            }
+            let addr_len = match &line[1..2] {
+                "1" => 2,
+                "2" => 3,
+                "3" => 4,
+                "0" | "5" | "6" | "7" | "8" | "9" => continue, // Header, count and start address
+                _ => return Err(parse_error(line_no, "Invalid record type")),
+            };
+            if bytes.len() < addr_len + 2 {
+                return Err(parse_error(line_no, "Invalid record length"));
+            }
+            let address = bytes[1..1 + addr_len]
+                .iter()
+                .fold(0u32, |addr, x| addr << 8 | *x as u32);
+            res.add_data(address, &bytes[1 + addr_len..bytes.len() - 1]);
+        }
+        Ok(res)
+    }
+
+    /// Adds data to the image, joining it onto an existing segment if it follows on from one
+    pub fn add_data(&mut self, address: u32, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        match self
+            .segments
+            .iter_mut()
+            .find(|s| s.end_address() == address)
+        {
+            Some(s) => s.data.extend_from_slice(data),
+            None => {
+                self.segments.push(FlashSegment {
+                    address,
+                    data: Vec::from(data),
+                });
+                self.segments.sort_by_key(|s| s.address);
+            }
+        }
+        // Adding data may have closed the gap between two segments
+        let mut i = 0;
+        while i + 1 < self.segments.len() {
+            if self.segments[i].end_address() == self.segments[i + 1].address {
+                let next = self.segments.remove(i + 1);
+                self.segments[i].data.extend_from_slice(&next.data);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Reads `size` bytes starting at `address`. Returns None if any part of the range
+    /// is not in the image
+    pub fn read(&self, address: u32, size: usize) -> Option<Vec<u8>> {
+        // Contiguous data is always merged into one segment
+        let s = self
+            .segments
+            .iter()
+            .find(|s| s.address <= address && address < s.end_address())?;
+        let start = (address - s.address) as usize;
+        s.data.get(start..start + size).map(Vec::from)
+    }
+
+    /// Total number of bytes in the image
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|s| s.data.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// CRC32 (IEEE 802.3) of every segment's data, in address order
+    pub fn crc32(&self) -> u32 {
+        let mut crc = 0xFFFFFFFF;
+        for s in &self.segments {
+            crc = crc32_update(crc, &s.data);
+        }
+        !crc
+    }
+}
+
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intel_hex() {
+        let text = ":020000040001F9\n\
+                    :0400000001020304F2\n\
+                    :0400040005060708DE\n\
+                    :00000001FF\n";
+        let img = FlashImage::from_intel_hex(text).unwrap();
+        assert_eq!(
+            img.segments,
+            vec![FlashSegment {
+                address: 0x00010000,
+                data: vec![1, 2, 3, 4, 5, 6, 7, 8]
+            }]
+        );
+        assert!(FlashImage::from_intel_hex(":0400000001020304F3").is_err());
+    }
+
+    #[test]
+    fn srec() {
+        let text = "S00600004844521B\n\
+                    S1070000AABBCCDDEA\n\
+                    S30900001000112233443C\n\
+                    S9030000FC\n";
+        let img = FlashImage::from_srec(text).unwrap();
+        assert_eq!(img.segments.len(), 2);
+        assert_eq!(img.segments[0].data, vec![0xAA, 0xBB, 0xCC, 0xDD]);
+        assert_eq!(img.segments[1].address, 0x1000);
+        assert_eq!(img.segments[1].data, vec![0x11, 0x22, 0x33, 0x44]);
+    }
+
+    #[test]
+    fn crc() {
+        let img = FlashImage::from_binary(0, b"123456789");
+        assert_eq!(img.crc32(), 0xCBF43926);
+    }
+}
@@ -0,0 +1,141 @@
+use crate::commapi::protocols::{ProtocolError, ProtocolResult, ProtocolServer};
+
+use super::{Service, KWP2000ECU};
+
+// The service, Input Output Control By Local Identifier ($30), lets the tester take control
+// of an input or output of the ECU, such as a fan or a valve. Whilst under control, the ECU
+// no longer drives the component itself, so control must always be given back with
+// returnControlToECU once the tester is done.
+//
+// Every local identifier under control is tracked in the KWP2000ECU, and released when the
+// diagnostic session is exited, or when the ECU stops responding. An identifier is tracked
+// before the request taking control is sent, as the ECU may act on it even if its response
+// is lost, and is only forgotten once the ECU confirms it has control again.
+
+/// inputOutputControlParameter of the request
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IOControlParameter {
+    /// Give control of the component back to the ECU
+    ReturnControlToECU,
+    /// Read the current state of the component, without taking control of it
+    ReportCurrentState,
+    /// Set the component to its default value
+    ResetToDefault,
+    /// Hold the component at its current value
+    FreezeCurrentState,
+    /// Set the component to a value given by the tester
+    ShortTermAdjustment,
+    /// Store a new value for the component in the ECU's memory
+    LongTermAdjustment,
+}
+
+impl Into<u8> for IOControlParameter {
+    fn into(self) -> u8 {
+        match self {
+            IOControlParameter::ReturnControlToECU => 0x00,
+            IOControlParameter::ReportCurrentState => 0x01,
+            IOControlParameter::ResetToDefault => 0x04,
+            IOControlParameter::FreezeCurrentState => 0x05,
+            IOControlParameter::ShortTermAdjustment => 0x07,
+            IOControlParameter::LongTermAdjustment => 0x08,
+        }
+    }
+}
+
+impl IOControlParameter {
+    pub fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            0x00 => Some(Self::ReturnControlToECU),
+            0x01 => Some(Self::ReportCurrentState),
+            0x04 => Some(Self::ResetToDefault),
+            0x05 => Some(Self::FreezeCurrentState),
+            0x07 => Some(Self::ShortTermAdjustment),
+            0x08 => Some(Self::LongTermAdjustment),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the tester has control of the component after sending this parameter
+    fn takes_control(&self) -> bool {
+        matches!(
+            self,
+            IOControlParameter::FreezeCurrentState | IOControlParameter::ShortTermAdjustment
+        )
+    }
+}
+
+/// Sends an IO control request to the ECU.
+///
+/// ## Params
+/// * `local_id` - Local identifier of the component
+/// * `param` - What to do with the component
+/// * `control_state` - controlState to send. Only used for adjustments
+///
+/// ## Returns
+/// The controlStatus the ECU responded with
+pub fn io_control(
+    ecu: &KWP2000ECU,
+    local_id: u8,
+    param: IOControlParameter,
+    control_state: &[u8],
+) -> ProtocolResult<Vec<u8>> {
+    let mut args = vec![local_id, param.into()];
+    if param == IOControlParameter::ShortTermAdjustment
+        || param == IOControlParameter::LongTermAdjustment
+    {
+        args.extend_from_slice(control_state);
+    }
+    if param.takes_control() {
+        let mut active = ecu.io_controls.write().unwrap();
+        if !active.contains(&local_id) {
+            active.push(local_id);
+        }
+    }
+    let res = ecu.run_command(Service::IOCTLByLocalID.into(), &args)?;
+    if res.len() < 3 || res[1..3] != args[0..2] {
+        return Err(ProtocolError::CustomError(
+            "ECU responded with a different identifier or control parameter".into(),
+        ));
+    }
+    if param == IOControlParameter::ReturnControlToECU {
+        ecu.io_controls.write().unwrap().retain(|x| *x != local_id);
+    }
+    Ok(Vec::from(&res[3..]))
+}
+
+/// Sets a component to a value given by the tester, until control is returned to the ECU
+pub fn short_term_adjustment(
+    ecu: &KWP2000ECU,
+    local_id: u8,
+    control_state: &[u8],
+) -> ProtocolResult<Vec<u8>> {
+    io_control(
+        ecu,
+        local_id,
+        IOControlParameter::ShortTermAdjustment,
+        control_state,
+    )
+}
+
+/// Gives control of a component back to the ECU
+pub fn return_control_to_ecu(ecu: &KWP2000ECU, local_id: u8) -> ProtocolResult<()> {
+    io_control(ecu, local_id, IOControlParameter::ReturnControlToECU, &[])?;
+    Ok(())
+}
+
+/// Gives control of every component the tester is controlling back to the ECU.
+/// All components are attempted, even if one fails
+pub fn return_all_control_to_ecu(ecu: &KWP2000ECU) -> ProtocolResult<()> {
+    let active = ecu.get_active_io_controls();
+    let mut res = Ok(());
+    for local_id in active {
+        if let Err(e) = return_control_to_ecu(ecu, local_id) {
+            eprintln!(
+                "Could not return control of 0x{:02X} to ECU: {:?}",
+                local_id, e
+            );
+            res = Err(e);
+        }
+    }
+    res
+}
@@ -1,15 +1,10 @@
 use commapi::{
     comm_api::{ComServer, ISO15765Config},
-    protocols::DTCState,
+    protocols::dtc::KwpDTCStatus,
 };
-use std::sync::atomic::Ordering::Relaxed;
 use std::{
-    sync::{
-        atomic::AtomicBool,
-        mpsc::{self, Receiver, Sender},
-        Arc, Mutex, RwLock,
-    },
-    time::Instant,
+    sync::{Arc, RwLock},
+    time::Duration,
 };
 
 use self::start_diag_session::DiagSession;
@@ -22,15 +17,21 @@ use crate::commapi::{
 };
 
 use super::{
-    CautionLevel, CommandError, DiagCfg, ECUCommand, ProtocolError, ProtocolResult, ProtocolServer,
-    Selectable, DTC,
+    executor::{RequestExecutor, RequestHandle},
+    timing::DiagTiming,
+    CautionLevel, CommandError, DiagCfg, ECUCommand, FunctionalResponses, ProtocolError,
+    ProtocolResult, ProtocolServer, Selectable, DTC,
 };
 
 pub mod clear_diag_information;
 pub mod ecu_reset;
+pub mod io_control;
 pub mod read_ecu_identification;
 pub mod read_status_dtc;
+pub mod routine_control;
+pub mod security_access;
 pub mod start_diag_session;
+pub mod transfer;
 
 // Developed using Daimler's KWP2000 documentation
 // http://read.pudn.com/downloads554/ebook/2284613/KWP2000_release2_2.pdf
@@ -345,17 +346,52 @@ impl CommandError for KwpNegativeCode {
             _ => Self::Unknown(b),
         }
     }
+
+    fn to_byte(&self) -> u8 {
+        match self {
+            KwpNegativeCode::GeneralReject => 0x10,
+            KwpNegativeCode::ServiceNotSupported => 0x11,
+            KwpNegativeCode::SubFunctionNotSupported => 0x12,
+            KwpNegativeCode::Busy => 0x21,
+            KwpNegativeCode::RequestSequenceError => 0x22,
+            KwpNegativeCode::RoutineNotComplete => 0x23,
+            KwpNegativeCode::RequestOutOfRange => 0x31,
+            KwpNegativeCode::SecurityAccessDenied => 0x33,
+            KwpNegativeCode::InvalidKey => 0x35,
+            KwpNegativeCode::ExceededAttempts => 0x36,
+            KwpNegativeCode::TimeDelayNotExpired => 0x37,
+            KwpNegativeCode::DownloadNotAccepted => 0x40,
+            KwpNegativeCode::UploadNotAccepted => 0x50,
+            KwpNegativeCode::TransferSuspended => 0x71,
+            KwpNegativeCode::ResponsePending => 0x78,
+            KwpNegativeCode::ServiceNotSupportedActiveSession => 0x80,
+            KwpNegativeCode::DataDecompressionFailed => 0x9A,
+            KwpNegativeCode::DataDecryptionFailed => 0x9B,
+            KwpNegativeCode::ECUNotResponding => 0xA0,
+            KwpNegativeCode::ECUAddressUnknown => 0xA1,
+            KwpNegativeCode::CustomDaimler(b) => *b,
+            KwpNegativeCode::Reserved(b) => *b,
+            KwpNegativeCode::Unknown(b) => *b,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct KWP2000ECU {
-    should_run: Arc<AtomicBool>,
+    executor: RequestExecutor,
     last_error: Arc<RwLock<Option<ProtocolError>>>,
-    cmd_tx: Sender<(u8, Vec<u8>, bool)>,
-    cmd_rx: Arc<Receiver<ProtocolResult<Vec<u8>>>>,
     curr_session_type: Arc<RwLock<DiagSession>>,
+    /// Unlocked SecurityAccess level (Request seed sub function)
+    security_level: Arc<RwLock<Option<u8>>>,
+    /// Local identifiers the tester has taken control of with IO control
+    io_controls: Arc<RwLock<Vec<u8>>>,
     send_id: u32,
-    cmd_mutex: Arc<Mutex<()>>,
+    recv_id: u32,
+    /// Functional request ID of the connection
+    global_id: Option<u32>,
+    iface_type: InterfaceType,
+    tx_flags: Option<Vec<PayloadFlag>>,
+    timing: DiagTiming,
 }
 
 #[derive(Debug, Clone)]
@@ -404,6 +440,7 @@ impl KWP2000ECU {
         match start_diag_session::set_diag_session(&self, mode) {
             Ok(_) => {
                 *self.curr_session_type.write().unwrap() = mode; // Switch diagnostic modes!
+                *self.security_level.write().unwrap() = None; // ECU locks itself on session change
                 Ok(())
             }
             Err(e) => {
@@ -416,6 +453,86 @@ impl KWP2000ECU {
     pub fn get_session_type(&self) -> DiagSession {
         *self.curr_session_type.read().unwrap()
     }
+
+    /// Returns the currently unlocked security level, if any
+    pub fn get_security_level(&self) -> Option<u8> {
+        *self.security_level.read().unwrap()
+    }
+
+    /// Returns the local identifiers of all components the tester is controlling
+    pub fn get_active_io_controls(&self) -> Vec<u8> {
+        self.io_controls.read().unwrap().clone()
+    }
+
+    /// Returns true if other copies of this server are still using its session
+    pub fn is_shared(&self) -> bool {
+        self.executor.is_shared()
+    }
+
+    /// Queues a request to the ECU, without waiting for its response. The request is
+    /// given up on if the ECU has not responded within [DiagTiming::get_request_timeout]
+    pub fn run_command_async(&self, cmd: u8, args: &[u8]) -> RequestHandle<Vec<u8>> {
+        self.run_command_async_timeout(cmd, args, self.timing.get_request_timeout())
+    }
+
+    /// Queues a request to the ECU, without waiting for its response. The request is
+    /// given up on if the ECU has not responded within `timeout`
+    pub fn run_command_async_timeout(
+        &self,
+        cmd: u8,
+        args: &[u8],
+        timeout: Duration,
+    ) -> RequestHandle<Vec<u8>> {
+        let args = Vec::from(args);
+        let tx_flags = self.tx_flags.clone();
+        let send_id = self.send_id;
+        let timing = self.timing;
+        self.executor.submit_timeout(timeout, move |iface| {
+            let resp =
+                Self::run_command_resp(iface, &tx_flags, send_id, cmd, &args, true, &timing)?;
+            if resp[0] == 0x7F {
+                let neg_code = KwpNegativeCode::from_byte(resp[2]);
+                Err(ProtocolError::ProtocolError(Box::new(neg_code)))
+            } else {
+                Ok(resp)
+            }
+        })
+    }
+
+    /// Queues a request on the functional ID of the connection ([DiagCfg::global_id]),
+    /// without waiting for the responses. The response of the ECU and of every ECU in
+    /// `others` (Request ID, Response ID) is collected, by response ID
+    pub fn run_command_functional_async(
+        &self,
+        cmd: u8,
+        args: &[u8],
+        others: &[(u32, u32)],
+    ) -> RequestHandle<FunctionalResponses> {
+        let args = Vec::from(args);
+        let others = Vec::from(others);
+        let tx_flags = self.tx_flags.clone();
+        let global_id = self.global_id;
+        let recv_id = self.recv_id;
+        let iface_type = self.iface_type;
+        let timing = self.timing;
+        self.executor
+            .submit_timeout(timing.get_request_timeout(), move |iface| {
+                let functional_id = global_id.ok_or_else(|| {
+                    ProtocolError::CustomError("Connection has no functional request ID".into())
+                })?;
+                Self::run_functional_command_ecus(
+                    iface,
+                    iface_type,
+                    &tx_flags,
+                    functional_id,
+                    cmd,
+                    &args,
+                    &timing,
+                    recv_id,
+                    &others,
+                )
+            })
+    }
 }
 
 impl ProtocolServer for KWP2000ECU {
@@ -443,139 +560,130 @@ impl ProtocolServer for KWP2000ECU {
                 fc: diag_cfg.send_id,
             })?;
         } else {
-            return Err(ProtocolError::CustomError(
-                "KWP2000 over ISO14230 is a WIP".into(),
-            ));
+            // K-Line ECUs respond with their own address as the source
+            dyn_interface.add_filter(FilterType::Pass {
+                id: diag_cfg.recv_id,
+                mask: 0xFF,
+            })?;
         }
 
-        let should_run = Arc::new(AtomicBool::new(true));
-        let should_run_t = should_run.clone();
-
         let last_error = Arc::new(RwLock::new(None));
         let last_error_t = last_error.clone();
 
-        let (channel_tx_sender, channel_tx_receiver): (
-            Sender<(u8, Vec<u8>, bool)>,
-            Receiver<(u8, Vec<u8>, bool)>,
-        ) = mpsc::channel();
-        let (channel_rx_sender, channel_rx_receiver): (
-            Sender<ProtocolResult<Vec<u8>>>,
-            Receiver<ProtocolResult<Vec<u8>>>,
-        ) = mpsc::channel();
-
         let session_type = Arc::new(RwLock::new(DiagSession::Default));
         let session_type_t = session_type.clone();
 
-        // Enter extended diagnostic session (Full features)
+        let io_controls = Arc::new(RwLock::new(Vec::new()));
+        let io_controls_t = io_controls.clone();
+
         let s_id = diag_cfg.send_id;
-        std::thread::spawn(move || {
-            println!("KWP2000 Diag server start!");
-            let mut timer = Instant::now();
-            while should_run_t.load(Relaxed) {
-                if let Ok(data) = channel_tx_receiver.try_recv() {
-                    let res = Self::run_command_resp(
-                        &mut dyn_interface,
-                        &tx_flags,
-                        s_id,
-                        data.0,
-                        &data.1,
-                        data.2,
-                    );
-                    if channel_rx_sender.send(res).is_err() {
-                        *last_error_t.write().unwrap() =
-                            Some(ProtocolError::CustomError("Sender channel died".into()));
-                        break;
-                    }
+        let timing = diag_cfg.timing;
+        let tx_flags_t = tx_flags.clone();
+        // Tester present, to stay out of the default session
+        let executor = RequestExecutor::with_periodic(
+            "KWP2000",
+            dyn_interface,
+            Duration::from_millis(timing.s3 as u64),
+            Box::new(move |interface| {
+                if *session_type_t.read().unwrap() == DiagSession::Default {
+                    return true;
                 }
-                if timer.elapsed().as_millis() >= 2000
-                    && *session_type_t.read().unwrap() != DiagSession::Default
-                {
-                    timer = Instant::now();
-                    //if let Err(e) = Self::run_command_iso_tp(comm_server.as_ref(), 0x001C, Service::TesterPresent.into(), &[0x02], false) {
-
-                    let tp_cmd = match diag_cfg.global_id {
-                        // Global tester present - No response from ECU
-                        Some(x) => Self::run_command_resp(
-                            &mut dyn_interface,
-                            &tx_flags,
-                            x,
-                            Service::TesterPresent.into(),
-                            &[0x02],
-                            false,
-                        ),
-                        None => Self::run_command_resp(
-                            &mut dyn_interface,
-                            &tx_flags,
-                            s_id,
-                            Service::TesterPresent.into(),
-                            &[0x01],
-                            true,
-                        ),
-                    };
-                    if let Err(e) = tp_cmd {
-                        if e.is_timeout() {
-                            println!("Lost connection with ECU! - {:?}", e);
-                            // Try to regain connection
-                            if Self::run_command_resp(
-                                &mut dyn_interface,
-                                &tx_flags,
+                let tp_cmd = match diag_cfg.global_id {
+                    // Global tester present - No response from ECU
+                    Some(x) => Self::run_command_resp(
+                        interface,
+                        &tx_flags_t,
+                        x,
+                        Service::TesterPresent.into(),
+                        &[0x02],
+                        false,
+                        &timing,
+                    ),
+                    None => Self::run_command_resp(
+                        interface,
+                        &tx_flags_t,
+                        s_id,
+                        Service::TesterPresent.into(),
+                        &[0x01],
+                        true,
+                        &timing,
+                    ),
+                };
+                if let Err(e) = tp_cmd {
+                    if e.is_timeout() {
+                        println!("Lost connection with ECU! - {:?}", e);
+                        // The ECU releases components itself once the session times out,
+                        // but try to release them now in case it can still hear us
+                        for local_id in io_controls_t.write().unwrap().drain(..) {
+                            let _ = Self::run_command_resp(
+                                interface,
+                                &tx_flags_t,
                                 s_id,
-                                Service::StartDiagSession.into(),
-                                &[0x92],
+                                Service::IOCTLByLocalID.into(),
+                                &[
+                                    local_id,
+                                    io_control::IOControlParameter::ReturnControlToECU.into(),
+                                ],
                                 true,
-                            )
-                            .is_err()
-                            {
-                                println!("Cannot re-establish ECU connection!");
-                                should_run_t.store(false, Relaxed);
-                            } else {
-                                println!("Regained connection to the ECU!");
-                            }
-                        } else {
-                            println!("Warning. ECU did not approve of tester present - {:?}", e);
+                                &timing,
+                            );
                         }
+                        // Try to regain connection
+                        if let Err(e) = Self::run_command_resp(
+                            interface,
+                            &tx_flags_t,
+                            s_id,
+                            Service::StartDiagSession.into(),
+                            &[0x92],
+                            true,
+                            &timing,
+                        ) {
+                            println!("Cannot re-establish ECU connection!");
+                            *last_error_t.write().unwrap() = Some(e);
+                            return false;
+                        }
+                        println!("Regained connection to the ECU!");
+                    } else {
+                        println!("Warning. ECU did not approve of tester present - {:?}", e);
                     }
                 }
-                std::thread::sleep(std::time::Duration::from_micros(100))
-            }
-            println!("Diag server stop!");
-            let _res = dyn_interface.close();
-        });
+                true
+            }),
+        );
 
+        // Enter extended diagnostic session (Full features)
         let mut ecu = KWP2000ECU {
-            should_run,
+            executor,
             last_error,
-            cmd_tx: channel_tx_sender,
-            cmd_rx: Arc::new(channel_rx_receiver),
             send_id: diag_cfg.send_id,
+            recv_id: diag_cfg.recv_id,
+            global_id: diag_cfg.global_id,
+            iface_type: interface_type,
             curr_session_type: session_type, // Assumed,
-            cmd_mutex: Arc::new(Mutex::new(())),
+            security_level: Arc::new(RwLock::new(None)),
+            io_controls,
+            tx_flags,
+            timing,
         };
 
         if let Err(e) = ecu.set_diag_session_mode(DiagSession::Extended) {
             println!("KWP2000 - Couldn't set the ECU in extended diag mode!");
-            ecu.should_run.store(false, Relaxed);
+            ecu.executor.stop();
             return Err(e);
         }
         Ok(ecu)
     }
 
     fn exit_diag_session(&mut self) {
-        self.should_run.store(false, Relaxed);
+        if self.is_in_diag_session() {
+            // Never leave a component under the tester's control
+            let _ = io_control::return_all_control_to_ecu(self);
+        }
+        self.executor.stop();
     }
 
     fn run_command(&self, cmd: u8, args: &[u8]) -> ProtocolResult<Vec<u8>> {
-        let _guard = self.cmd_mutex.lock().unwrap(); // We are allowed to send / receive!
-        if self.cmd_tx.send((cmd, Vec::from(args), true)).is_err() {
-            return Err(ProtocolError::CustomError("Channel Tx failed".into()));
-        }
-        let resp = self.cmd_rx.recv().unwrap()?;
-        if resp[0] == 0x7F {
-            let neg_code = KwpNegativeCode::from_byte(resp[2]);
-            Err(ProtocolError::ProtocolError(Box::new(neg_code)))
-        } else {
-            Ok(resp)
-        }
+        self.run_command_async(cmd, args).wait()
     }
 
     fn read_errors(&self) -> ProtocolResult<Vec<DTC>> {
@@ -589,34 +697,15 @@ impl ProtocolServer for KWP2000ECU {
 
         let mut res: Vec<DTC> = Vec::new();
         for _ in 0..count {
-            let name = format!("{:02X}{:02X}", bytes[0], bytes[1]);
-            let status = bytes[2];
-            //let flag = (status >> 4 & 0b00000001) > 0;
-            //0b011
-            let storage_state = (status >> 5) & 0b0000011;
-
-            let state = match storage_state {
-                1 => DTCState::Stored,
-                2 => DTCState::Pending,
-                3 => DTCState::Permanent,
-                _ => DTCState::None,
-            };
-
-            let mil = (status >> 7 & 0b00000001) > 0;
-
-            res.push(DTC {
-                error: name,
-                state,
-                check_engine_on: mil,
-                id: ((bytes[0] as u32) << 8) | bytes[1] as u32,
-            });
+            let id = (bytes[0] as u16) << 8 | bytes[1] as u16;
+            res.push(DTC::from_kwp(id, KwpDTCStatus::from_byte(bytes[2])));
             bytes.drain(0..3); // DTC is 3 bytes (1 for status, 2 for the ID)
         }
         Ok(res)
     }
 
     fn is_in_diag_session(&self) -> bool {
-        self.should_run.load(Relaxed) // Diag server self-terminates upon ECU Session error
+        self.executor.is_running() // Diag server self-terminates upon ECU Session error
     }
 
     fn get_last_error(&self) -> Option<String> {
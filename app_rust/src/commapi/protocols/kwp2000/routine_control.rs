@@ -0,0 +1,70 @@
+use crate::commapi::protocols::{ProtocolError, ProtocolResult, ProtocolServer};
+
+use super::{Service, KWP2000ECU};
+
+// Routines are started with Start Routine By Local Identifier ($31), stopped with
+// Stop Routine By Local Identifier ($32), and their results are read with
+// Request Routine Results By Local Identifier ($33). Each routine is identified by a 1 byte
+// routineLocalIdentifier. What the optional routineEntryOption and routineExitOption records
+// contain is up to the ECU manufacturer.
+
+/// Positive response to a routine request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutineResult {
+    pub local_id: u8,
+    /// Full ECU response, so that output params can be decoded from it
+    pub raw: Vec<u8>,
+}
+
+impl RoutineResult {
+    /// Returns the routineEntryStatus, routineExitStatus or routineResults of the response
+    pub fn get_status_record(&self) -> &[u8] {
+        &self.raw[2..]
+    }
+}
+
+fn run_routine_command(
+    ecu: &KWP2000ECU,
+    service: Service,
+    local_id: u8,
+    option_record: &[u8],
+) -> ProtocolResult<RoutineResult> {
+    let mut args = vec![local_id];
+    args.extend_from_slice(option_record);
+    let res = ecu.run_command(service.into(), &args)?;
+    if res.len() < 2 {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: 2,
+            actual: res.len(),
+        });
+    }
+    if res[1] != local_id {
+        return Err(ProtocolError::CustomError(
+            "ECU responded with a different routine".into(),
+        ));
+    }
+    Ok(RoutineResult { local_id, raw: res })
+}
+
+/// Starts a routine on the ECU, with an optional routineEntryOption record
+pub fn start_routine(
+    ecu: &KWP2000ECU,
+    local_id: u8,
+    option_record: &[u8],
+) -> ProtocolResult<RoutineResult> {
+    run_routine_command(ecu, Service::StartRoutineByLocalID, local_id, option_record)
+}
+
+/// Stops a running routine, with an optional routineExitOption record
+pub fn stop_routine(
+    ecu: &KWP2000ECU,
+    local_id: u8,
+    option_record: &[u8],
+) -> ProtocolResult<RoutineResult> {
+    run_routine_command(ecu, Service::StopRoutineByLocalID, local_id, option_record)
+}
+
+/// Requests the results of a routine
+pub fn request_routine_results(ecu: &KWP2000ECU, local_id: u8) -> ProtocolResult<RoutineResult> {
+    run_routine_command(ecu, Service::RequestRoutineResultsByLocalID, local_id, &[])
+}
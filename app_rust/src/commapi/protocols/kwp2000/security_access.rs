@@ -0,0 +1,39 @@
+use crate::commapi::protocols::{
+    security::{self, SeedKeyAlgorithm},
+    ProtocolError, ProtocolResult,
+};
+
+use super::{Service, KWP2000ECU};
+
+// The service, Security Access ($27), is used to unlock services that are
+// restricted to authorised testers, such as flashing or coding. The ECU sends a seed
+// for the requested level, and the tester must reply with the matching key.
+
+/// Unlocks a security level on the ECU.
+///
+/// ## Params
+/// * `level` - Request seed sub function (0x01, 0x03, ...)
+/// * `algo` - Algorithm used to calculate the key from the ECU's seed
+pub fn unlock(ecu: &KWP2000ECU, level: u8, algo: &dyn SeedKeyAlgorithm) -> ProtocolResult<()> {
+    match security::unlock_ecu(ecu, Service::SecurityAccess.into(), level, algo) {
+        Ok(_) => {
+            *ecu.security_level.write().unwrap() = Some(level);
+            Ok(())
+        }
+        Err(e) => {
+            *ecu.security_level.write().unwrap() = None; // Failed attempt relocks the ECU
+            Err(e)
+        }
+    }
+}
+
+/// Unlocks a security level on the ECU using an algorithm from the registry
+pub fn unlock_with(ecu: &KWP2000ECU, level: u8, algo_name: &str) -> ProtocolResult<()> {
+    match security::get_algorithm(algo_name) {
+        Some(algo) => unlock(ecu, level, algo.as_ref()),
+        None => Err(ProtocolError::CustomError(format!(
+            "No seed/key algorithm named '{}'",
+            algo_name
+        ))),
+    }
+}
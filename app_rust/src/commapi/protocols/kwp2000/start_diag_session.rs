@@ -19,5 +19,7 @@ pub enum DiagSession {
 /// Attempts to set the diagnostic session type of the ECU
 pub fn set_diag_session(ecu: &KWP2000ECU, mode: DiagSession) -> ProtocolResult<()> {
     ecu.run_command(super::Service::StartDiagSession.into(), &[mode as u8])?;
+    // The ECU returns control of all components when the session changes
+    ecu.io_controls.write().unwrap().clear();
     Ok(())
 }
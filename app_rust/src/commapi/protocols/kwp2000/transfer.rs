@@ -0,0 +1,225 @@
+use crate::commapi::protocols::{
+    flash_image::FlashImage, ProtocolError, ProtocolResult, ProtocolServer,
+};
+
+use super::{Service, KWP2000ECU};
+
+// Memory is written to the ECU by Request Download ($34), and read out of it by
+// Request Upload ($35). Both tell the ECU the memory address, the format of the data
+// and how many bytes will be moved. The data is then moved with Transfer Data ($36)
+// blocks no bigger than the ECU's maxNumberOfBlockLength, and Request Transfer Exit ($37)
+// ends the transfer.
+//
+// The ECU must normally be in its flash session (0x85) and unlocked with
+// Security Access ($27) before it accepts either request.
+
+/// Largest memory address (and size) KWP2000 can address. Both are sent as 3 bytes
+pub const MAX_MEMORY_ADDRESS: u32 = 0xFFFFFF;
+
+/// dataFormatIdentifier of a transfer. What each compression and encryption
+/// method is, is up to the ECU manufacturer. 0 is always uncompressed / unencrypted
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct DataFormat {
+    pub compression: u8,
+    pub encryption: u8,
+}
+
+impl DataFormat {
+    pub fn new(compression: u8, encryption: u8) -> Self {
+        Self {
+            compression,
+            encryption,
+        }
+    }
+}
+
+impl Into<u8> for DataFormat {
+    fn into(self) -> u8 {
+        (self.compression & 0x0F) << 4 | (self.encryption & 0x0F)
+    }
+}
+
+/// Builds the memoryAddress, dataFormatIdentifier and memorySize arguments
+/// used by both Request Download and Request Upload
+fn transfer_request_args(address: u32, size: usize, format: DataFormat) -> ProtocolResult<Vec<u8>> {
+    if address > MAX_MEMORY_ADDRESS {
+        return Err(ProtocolError::CustomError(format!(
+            "Memory address 0x{:08X} is out of range",
+            address
+        )));
+    }
+    if size == 0 || size > MAX_MEMORY_ADDRESS as usize {
+        return Err(ProtocolError::CustomError(format!(
+            "Cannot transfer {} bytes",
+            size
+        )));
+    }
+    let mut args = Vec::from(&address.to_be_bytes()[1..]);
+    args.push(format.into());
+    args.extend_from_slice(&(size as u32).to_be_bytes()[1..]);
+    Ok(args)
+}
+
+/// Reads maxNumberOfBlockLength out of a Request Download/Upload response, and
+/// returns how many bytes of data fit in each Transfer Data message
+fn read_block_length(res: &[u8]) -> ProtocolResult<usize> {
+    if res.len() < 2 {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: 2,
+            actual: res.len(),
+        });
+    }
+    // Some ECUs report the length as 1 byte, others as 2
+    let max_len = res[1..].iter().fold(0usize, |n, x| n << 8 | *x as usize);
+    if max_len <= 1 {
+        return Err(ProtocolError::CustomError(format!(
+            "ECU reported an invalid max block length of {}",
+            max_len
+        )));
+    }
+    Ok(max_len - 1) // Block length includes the SID
+}
+
+/// Writes data to the ECU's memory.
+///
+/// ## Params
+/// * `address` - Memory address to start writing at
+/// * `data` - Data to write. If `format` is compressed or encrypted, this should already be
+///   compressed or encrypted
+/// * `format` - Format of `data`
+/// * `on_progress` - Called after each block with the number of bytes written, and the total
+pub fn download<F: FnMut(usize, usize)>(
+    ecu: &KWP2000ECU,
+    address: u32,
+    data: &[u8],
+    format: DataFormat,
+    mut on_progress: F,
+) -> ProtocolResult<()> {
+    let args = transfer_request_args(address, data.len(), format)?;
+    let res = ecu.run_command(Service::RequestDownload.into(), &args)?;
+    let block_len = read_block_length(&res)?;
+    let mut done = 0;
+    for chunk in data.chunks(block_len) {
+        ecu.run_command(Service::TransferData.into(), chunk)?;
+        done += chunk.len();
+        on_progress(done, data.len());
+    }
+    ecu.run_command(Service::RequestTransferExit.into(), &[])?;
+    Ok(())
+}
+
+/// Writes every segment of a flash image to the ECU's memory.
+/// `on_progress` is given the bytes written and total for the whole image
+pub fn download_image<F: FnMut(usize, usize)>(
+    ecu: &KWP2000ECU,
+    image: &FlashImage,
+    format: DataFormat,
+    mut on_progress: F,
+) -> ProtocolResult<()> {
+    let total = image.len();
+    let mut written = 0;
+    for s in &image.segments {
+        download(ecu, s.address, &s.data, format, |done, _| {
+            on_progress(written + done, total)
+        })?;
+        written += s.data.len();
+    }
+    Ok(())
+}
+
+/// Reads data out of the ECU's memory, such as a calibration.
+///
+/// ## Params
+/// * `address` - Memory address to start reading from
+/// * `size` - Number of bytes to read
+/// * `format` - Format to ask the ECU to send the data in. If compressed, the data is
+///   returned as the ECU sent it
+/// * `on_progress` - Called after each block with the number of bytes read, and the total
+pub fn upload<F: FnMut(usize, usize)>(
+    ecu: &KWP2000ECU,
+    address: u32,
+    size: usize,
+    format: DataFormat,
+    mut on_progress: F,
+) -> ProtocolResult<Vec<u8>> {
+    let args = transfer_request_args(address, size, format)?;
+    let res = ecu.run_command(Service::RequestUpload.into(), &args)?;
+    let block_len = read_block_length(&res)?;
+    let compressed = format != DataFormat::default();
+    let mut data = Vec::with_capacity(size);
+    while data.len() < size {
+        let res = ecu.run_command(Service::TransferData.into(), &[])?;
+        if res.len() < 2 {
+            // No data would mean we loop forever
+            return Err(ProtocolError::InvalidResponseSize {
+                expect: 2,
+                actual: res.len(),
+            });
+        }
+        data.extend_from_slice(&res[1..]);
+        on_progress(std::cmp::min(data.len(), size), size);
+        if compressed && res.len() - 1 < block_len {
+            // Compressed data is shorter than `size`, so a short block is the last one
+            break;
+        }
+    }
+    ecu.run_command(Service::RequestTransferExit.into(), &[])?;
+    if !compressed && data.len() != size {
+        return Err(ProtocolError::CustomError(format!(
+            "ECU sent {} bytes, expected {}",
+            data.len(),
+            size
+        )));
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commapi::{
+        protocols::{
+            kwp2000::{security_access, start_diag_session},
+            security::SeedKeyAlgorithm,
+            DiagProtocol,
+        },
+        simulator_api::{SimulatorAPI, VirtualECU},
+    };
+
+    struct AnyKey;
+
+    impl SeedKeyAlgorithm for AnyKey {
+        fn get_name(&self) -> String {
+            "any key".into()
+        }
+
+        fn calculate_key(&self, _level: u8, seed: &[u8]) -> ProtocolResult<Vec<u8>> {
+            Ok(Vec::from(seed))
+        }
+    }
+
+    #[test]
+    fn download_and_upload() {
+        let mut sim_ecu = VirtualECU::new("KWP", DiagProtocol::KWP2000, 0x7E0, 0x7E8);
+        sim_ecu.max_block_length = 0x21;
+        let mut ecu: KWP2000ECU = SimulatorAPI::new(vec![sim_ecu]).start_test_session();
+        start_diag_session::set_diag_session(&ecu, start_diag_session::DiagSession::Flash).unwrap();
+        security_access::unlock(&ecu, 0x01, &AnyKey).unwrap();
+
+        let calibration: Vec<u8> = (0..200).map(|x| x as u8).collect();
+        let mut last = (0, 0);
+        download(
+            &ecu,
+            0x10000,
+            &calibration,
+            DataFormat::default(),
+            |d, t| last = (d, t),
+        )
+        .unwrap();
+        assert_eq!(last, (200, 200));
+        let read = upload(&ecu, 0x10000, 200, DataFormat::default(), |_, _| {}).unwrap();
+        assert_eq!(read, calibration);
+        assert!(upload(&ecu, 0x20000, 10, DataFormat::default(), |_, _| {}).is_err());
+        ecu.exit_diag_session();
+    }
+}
@@ -1,18 +1,41 @@
-use std::fmt::Display;
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    time::{Duration, Instant},
+};
 
 use comm_api::ComServerError;
 use kwp2000::KWP2000ECU;
 use uds::UDSECU;
 
-use self::{kwp2000::read_ecu_identification, uds::read_data};
+use common::schema::diag::service::Parameter;
+
+use self::{
+    dtc::{DTCRecord, DTCRecordType, DTCStatusMask, KwpDTCStatus},
+    executor::RequestHandle,
+    kwp2000::read_ecu_identification,
+    timing::DiagTiming,
+    uds::{
+        read_data,
+        read_dtc_info::{DTCSeverity, DTCStatus},
+    },
+};
 
 use super::{
-    comm_api::{self, ComServer},
-    iface::{Interface, InterfaceConfig, InterfacePayload, InterfaceType, PayloadFlag},
+    comm_api::{self, ComServer, FilterType},
+    iface::{BufferType, Interface, InterfaceConfig, InterfacePayload, InterfaceType, PayloadFlag},
 };
 
+pub mod actuation;
+pub mod adjustment;
+pub mod dtc;
+pub mod executor;
+pub mod flash_image;
 pub mod kwp2000;
 pub mod obd2;
+pub mod routine;
+pub mod security;
+pub mod timing;
 pub mod uds;
 pub mod vin;
 
@@ -35,6 +58,14 @@ impl ProtocolError {
             ProtocolError::Timeout => true,
         }
     }
+
+    /// Returns the negative response code the ECU replied with, if the error came from the ECU
+    pub fn get_nrc(&self) -> Option<u8> {
+        match &self {
+            ProtocolError::ProtocolError(e) => Some(e.to_byte()),
+            _ => None,
+        }
+    }
 }
 
 impl From<ComServerError> for ProtocolError {
@@ -62,6 +93,9 @@ impl ProtocolError {
 
 pub type ProtocolResult<T> = std::result::Result<T, ProtocolError>;
 
+/// Responses to a functionally addressed request, by the ID of the ECU that sent them
+pub type FunctionalResponses = BTreeMap<u32, Vec<u8>>;
+
 pub trait Selectable: Into<u8> {
     fn get_desc(&self) -> String;
     fn get_name(&self) -> String;
@@ -88,6 +122,8 @@ pub trait CommandError {
     fn from_byte(b: u8) -> Self
     where
         Self: Sized;
+    /// Returns the negative response code byte the error was created from
+    fn to_byte(&self) -> u8;
 }
 
 impl std::fmt::Debug for Box<dyn CommandError> {
@@ -106,10 +142,22 @@ pub enum DTCState {
 
 #[derive(Debug, Clone)]
 pub struct DTC {
+    /// SAE J2012 formatted code (EG: P2001). UDS DTCs have their failure type appended (EG: P2001-1C)
     pub(crate) error: String,
     pub(crate) state: DTCState,
     pub(crate) check_engine_on: bool,
+    /// Raw DTC number. 3 bytes for UDS, 2 bytes for KWP2000 and OBD-II
     pub(crate) id: u32,
+    /// Every status bit the ECU reported for the DTC
+    pub(crate) status: DTCStatusMask,
+    /// Severity of the DTC (UDS only)
+    pub(crate) severity: Option<DTCSeverity>,
+    /// Functional unit the DTC belongs to (UDS only)
+    pub(crate) functional_unit: Option<u8>,
+    /// How many times the fault has occurred, if the ECU reports it
+    pub(crate) occurrence_count: Option<u32>,
+    /// Freeze frame and extended data records. These are read by [DiagServer::get_dtc_env_data]
+    pub(crate) records: Vec<DTCRecord>,
 }
 
 impl Display for DTC {
@@ -127,6 +175,7 @@ pub struct DiagCfg {
     pub send_id: u32,
     pub recv_id: u32,
     pub global_id: Option<u32>,
+    pub timing: DiagTiming,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -189,6 +238,68 @@ impl DiagServer {
         }
     }
 
+    /// Queues a request to the ECU, returning a handle to its response straight away
+    /// so that the caller is never blocked by the ECU
+    pub fn run_cmd_async(&self, cmd: u8, args: &[u8]) -> RequestHandle<Vec<u8>> {
+        match self {
+            Self::KWP2000(s) => s.run_command_async(cmd, args),
+            Self::UDS(s) => s.run_command_async(cmd, args),
+        }
+    }
+
+    /// Same as [DiagServer::run_cmd_async], but the request is given up on if the
+    /// ECU has not responded within `timeout`
+    pub fn run_cmd_async_timeout(
+        &self,
+        cmd: u8,
+        args: &[u8],
+        timeout: Duration,
+    ) -> RequestHandle<Vec<u8>> {
+        match self {
+            Self::KWP2000(s) => s.run_command_async_timeout(cmd, args, timeout),
+            Self::UDS(s) => s.run_command_async_timeout(cmd, args, timeout),
+        }
+    }
+
+    /// Sends a request on the functional ID of the connection ([DiagCfg::global_id]), and
+    /// collects the response of the ECU and of every ECU in `others` (Request ID, Response ID),
+    /// by response ID. Negative responses are kept, so the caller can tell which ECUs
+    /// rejected the request
+    pub fn run_cmd_functional(
+        &self,
+        cmd: u8,
+        args: &[u8],
+        others: &[(u32, u32)],
+    ) -> ProtocolResult<FunctionalResponses> {
+        self.run_cmd_functional_async(cmd, args, others).wait()
+    }
+
+    /// Same as [DiagServer::run_cmd_functional], but returns a handle to the responses
+    /// straight away
+    pub fn run_cmd_functional_async(
+        &self,
+        cmd: u8,
+        args: &[u8],
+        others: &[(u32, u32)],
+    ) -> RequestHandle<FunctionalResponses> {
+        match self {
+            Self::KWP2000(s) => s.run_command_functional_async(cmd, args, others),
+            Self::UDS(s) => s.run_command_functional_async(cmd, args, others),
+        }
+    }
+
+    /// Runs `f` with a copy of the server on its own thread, returning a handle to its
+    /// result straight away. This is for tasks that send several requests, so that the
+    /// caller is never blocked by the ECU
+    pub fn spawn_task<T, F>(&self, f: F) -> RequestHandle<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut DiagServer) -> ProtocolResult<T> + Send + 'static,
+    {
+        let mut server = self.clone();
+        RequestHandle::spawn(move || f(&mut server))
+    }
+
     pub fn into_kwp(&mut self) -> Option<&mut KWP2000ECU> {
         match self {
             Self::KWP2000(s) => Some(s),
@@ -196,6 +307,13 @@ impl DiagServer {
         }
     }
 
+    pub fn into_uds(&mut self) -> Option<&mut UDSECU> {
+        match self {
+            Self::KWP2000(_) => None,
+            Self::UDS(s) => Some(s),
+        }
+    }
+
     pub fn read_errors(&self) -> ProtocolResult<Vec<DTC>> {
         match self {
             Self::KWP2000(s) => s.read_errors(),
@@ -219,18 +337,65 @@ impl DiagServer {
         }
     }
 
-    pub fn get_dtc_env_data(&self, dtc: &DTC) -> ProtocolResult<Vec<u8>> {
+    /// Reads the data the ECU stored with a DTC, decoding it with `envs` from the ECU's
+    /// definition. Returns the DTC with its records, and any status, severity and
+    /// counters the ECU reported alongside them
+    pub fn get_dtc_env_data(&self, dtc: &DTC, envs: &[Parameter]) -> ProtocolResult<DTC> {
+        let mut res = dtc.clone();
+        res.records.clear();
         match self {
-            Self::KWP2000(s) => kwp2000::read_status_dtc::read_status_dtc(s, dtc),
-            Self::UDS(s) => uds::read_dtc_info::read_dtc_information(s, dtc)
+            Self::KWP2000(s) => {
+                // 0x57, number of DTCs, DTC (2 bytes), status, environment data
+                let raw = kwp2000::read_status_dtc::read_status_dtc(s, dtc)?;
+                if let Some(status) = raw.get(4) {
+                    res.status = DTCStatusMask::Kwp(KwpDTCStatus::from_byte(*status));
+                }
+                res.add_record(DTCRecord::new(DTCRecordType::FreezeFrame, None, raw, envs));
+            }
+            Self::UDS(s) => {
+                // 0x59, 0x06, DTC (3 bytes), status, records
+                let raw = uds::read_dtc_info::read_dtc_information(s, dtc)?;
+                if let Some(status) = raw.get(5) {
+                    res.status = DTCStatusMask::Uds(DTCStatus::from_byte(*status));
+                }
+                res.add_record(DTCRecord::new(
+                    DTCRecordType::ExtendedData,
+                    raw.get(6).copied(),
+                    raw,
+                    envs,
+                ));
+                // Not every ECU supports snapshots or severity, so these are optional
+                if let Ok(raw) = uds::read_dtc_info::read_dtc_snapshot_information(s, dtc) {
+                    if raw.len() > 6 {
+                        res.add_record(DTCRecord::new(
+                            DTCRecordType::FreezeFrame,
+                            raw.get(6).copied(),
+                            raw,
+                            &[],
+                        ));
+                    }
+                }
+                if let Ok(Some(sev)) = uds::read_dtc_info::read_dtc_severity(s, dtc.id) {
+                    res.severity = Some(sev.severity);
+                    res.functional_unit = Some(sev.functional_unit);
+                }
+            }
         }
+        Ok(res)
     }
 }
 
 impl Drop for DiagServer {
     fn drop(&mut self) {
         println!("Drop for Diag Server called!");
-        self.kill_diag_server()
+        let shared = match self {
+            Self::KWP2000(s) => s.is_shared(),
+            Self::UDS(s) => s.is_shared(),
+        };
+        // Copies of the server are given to background tasks, only the last one ends the session
+        if !shared {
+            self.kill_diag_server()
+        }
     }
 }
 
@@ -257,6 +422,7 @@ pub trait ProtocolServer: Sized {
         cmd: u8,
         args: &[u8],
         receive_require: bool,
+        timing: &DiagTiming,
     ) -> std::result::Result<Vec<u8>, ProtocolError> {
         let mut tx_data = vec![cmd];
         tx_data.extend_from_slice(args);
@@ -270,22 +436,31 @@ pub trait ProtocolServer: Sized {
                 .map(|_| vec![])
                 .map_err(ProtocolError::CommError)
         } else {
-            // Await max 1 second for response
-            let mut res = interface.send_recv_data(tx, 0, 2000)?;
-            if res.data[0] == 0x7F && res.data[2] == 0x78 {
-                // ResponsePending
-                println!("DIAG - ECU is processing request - Waiting!");
-                match interface.recv_data(1, 2000) {
-                    Ok(data) => {
-                        if let Some(d) = data.get(0) {
-                            res = d.clone();
-                        } else {
-                            return Err(ProtocolError::ProtocolError(Box::new(
-                                Self::Error::from_byte(res.data[2]),
-                            )));
+            // Await max P2 for response
+            let mut res = interface.send_recv_data(tx.clone(), 0, timing.p2)?;
+            let mut busy_attempts = 0;
+            let mut pending_count = 0;
+            while res.data.len() >= 3 && res.data[0] == 0x7F {
+                match res.data[2] {
+                    0x78 if pending_count < timing::MAX_RESPONSE_PENDING => {
+                        // ResponsePending. P2* restarts every time the ECU sends this
+                        println!("DIAG - ECU is processing request - Waiting!");
+                        pending_count += 1;
+                        match interface.recv_data(1, timing.p2_ext) {
+                            Ok(data) => match data.first() {
+                                Some(d) => res = d.clone(),
+                                None => return Err(ProtocolError::Timeout),
+                            },
+                            Err(e) => return Err(ProtocolError::CommError(e)),
                         }
                     }
-                    Err(e) => return Err(ProtocolError::CommError(e)),
+                    0x21 if busy_attempts < timing::BUSY_REPEAT_RETRIES => {
+                        // BusyRepeatRequest. Back off, then send the request again
+                        std::thread::sleep(timing.get_busy_backoff(busy_attempts));
+                        busy_attempts += 1;
+                        res = interface.send_recv_data(tx.clone(), 0, timing.p2)?;
+                    }
+                    _ => break,
                 }
             }
             if res.data[0] == 0x7F {
@@ -304,4 +479,180 @@ pub trait ProtocolServer: Sized {
             }
         }
     }
+
+    /// Sends a request on a functional (broadcast) ID, and collects the response of every ECU
+    /// the interface can hear that replies within P2. ECUs that reply with ResponsePending are
+    /// waited for up to P2*, for up to [timing::MAX_RESPONSE_PENDING] replies. Negative
+    /// responses are kept, so the caller can tell which ECUs rejected the request.
+    ///
+    /// If `expected` lists the IDs of ECUs known to be present, collecting stops as soon as
+    /// all of them have responded, rather than waiting for P2 to pass
+    fn run_functional_command_resp(
+        interface: &mut Box<dyn Interface>,
+        flags: &Option<Vec<PayloadFlag>>,
+        functional_id: u32,
+        cmd: u8,
+        args: &[u8],
+        timing: &DiagTiming,
+        expected: &[u32],
+    ) -> ProtocolResult<FunctionalResponses> {
+        let mut tx_data = vec![cmd];
+        tx_data.extend_from_slice(args);
+        let mut tx = InterfacePayload::new(functional_id, &tx_data);
+        if let Some(f) = flags {
+            tx.flags = f.clone();
+        }
+        interface.clear_buffer(BufferType::RX)?;
+        interface.send_data(&[tx], 0)?;
+
+        let mut res = FunctionalResponses::new();
+        let window_end = Instant::now() + Duration::from_millis(timing.p2 as u64);
+        // ECUs that are still processing the request, when they have to respond by
+        // and how many ResponsePending replies they have sent
+        let mut pending: BTreeMap<u32, (Instant, u32)> = BTreeMap::new();
+        loop {
+            let wait_until = pending.values().fold(window_end, |a, b| a.max(b.0));
+            let now = Instant::now();
+            if now >= wait_until {
+                break;
+            }
+            for msg in interface.recv_data(10, (wait_until - now).as_millis() as u32)? {
+                let nrc = match msg.data.as_slice() {
+                    [0x7F, sid, nrc, ..] if *sid == cmd => Some(*nrc),
+                    [sid, ..] if *sid == cmd + 0x40 => None,
+                    _ => continue, // Not a response to this request
+                };
+                let count = pending.get(&msg.id).map(|p| p.1).unwrap_or(0);
+                if nrc == Some(0x78) && count < timing::MAX_RESPONSE_PENDING {
+                    // ResponsePending
+                    let reply_by = Instant::now() + Duration::from_millis(timing.p2_ext as u64);
+                    pending.insert(msg.id, (reply_by, count + 1));
+                } else {
+                    pending.remove(&msg.id);
+                    res.insert(msg.id, msg.data);
+                }
+            }
+            if !expected.is_empty() && expected.iter().all(|id| res.contains_key(id)) {
+                break;
+            }
+        }
+        Ok(res)
+    }
+
+    /// Sends a request on a functional ID, and collects the response of the ECU the interface
+    /// is listening to (`recv_id`), and of every ECU in `others` (Request ID, Response ID).
+    ///
+    /// The interface only hears the ECU the server is connected to, so filters for the
+    /// other ECUs are added whilst their responses are collected
+    #[allow(clippy::too_many_arguments)]
+    fn run_functional_command_ecus(
+        interface: &mut Box<dyn Interface>,
+        interface_type: InterfaceType,
+        flags: &Option<Vec<PayloadFlag>>,
+        functional_id: u32,
+        cmd: u8,
+        args: &[u8],
+        timing: &DiagTiming,
+        recv_id: u32,
+        others: &[(u32, u32)],
+    ) -> ProtocolResult<FunctionalResponses> {
+        let mut filters = Vec::new();
+        for (send_id, resp_id) in others {
+            let f = match interface_type {
+                InterfaceType::IsoTp => FilterType::IsoTP {
+                    id: *resp_id,
+                    mask: 0xFFFF,
+                    fc: *send_id,
+                },
+                _ => FilterType::Pass {
+                    id: *resp_id,
+                    mask: 0xFFFF,
+                },
+            };
+            match interface.add_filter(f) {
+                Ok(id) => filters.push(id),
+                Err(e) => {
+                    for id in filters {
+                        let _ = interface.rem_filter(id);
+                    }
+                    return Err(ProtocolError::CommError(e));
+                }
+            }
+        }
+        let mut expected = vec![recv_id];
+        expected.extend(others.iter().map(|(_, resp_id)| *resp_id));
+        let res = Self::run_functional_command_resp(
+            interface,
+            flags,
+            functional_id,
+            cmd,
+            args,
+            timing,
+            &expected,
+        );
+        for id in filters {
+            let _ = interface.rem_filter(id);
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commapi::simulator_api::{SimulatorAPI, VirtualECU};
+
+    fn functional_ecu(protocol: DiagProtocol, request_id: u32, response_id: u32) -> VirtualECU {
+        let mut ecu = VirtualECU::new("ECU", protocol, request_id, response_id);
+        ecu.functional_id = Some(0x7DF);
+        ecu
+    }
+
+    #[test]
+    fn functional_requests() {
+        for protocol in &[DiagProtocol::UDS, DiagProtocol::KWP2000] {
+            let mut ecu = functional_ecu(*protocol, 0x7E0, 0x7E8);
+            ecu.custom_responses
+                .push((vec![0x22, 0xF1, 0x90], vec![0x62, 0xF1, 0x90, 0x01]));
+            let mut other = functional_ecu(*protocol, 0x7E1, 0x7E9);
+            other
+                .custom_responses
+                .push((vec![0x22, 0xF1, 0x90], vec![0x7F, 0x22, 0x31]));
+            // Not listed as a responder, so never heard
+            let unlisted = functional_ecu(*protocol, 0x7E2, 0x7EA);
+            let sim = SimulatorAPI::new(vec![ecu, other, unlisted]);
+            let mut server = sim.start_test_server(Default::default());
+
+            let res = server
+                .run_cmd_functional(0x22, &[0xF1, 0x90], &[(0x7E1, 0x7E9)])
+                .unwrap();
+            assert_eq!(
+                res.keys().copied().collect::<Vec<u32>>(),
+                vec![0x7E8, 0x7E9]
+            );
+            assert_eq!(res[&0x7E8], vec![0x62, 0xF1, 0x90, 0x01]);
+            assert_eq!(res[&0x7E9], vec![0x7F, 0x22, 0x31]);
+
+            // Filters for the other ECUs are removed afterwards
+            let res = server.run_cmd_functional(0x22, &[0xF1, 0x90], &[]).unwrap();
+            assert_eq!(res.keys().copied().collect::<Vec<u32>>(), vec![0x7E8]);
+            assert_eq!(
+                server.run_cmd(0x22, &[0xF1, 0x90]).unwrap(),
+                vec![0x62, 0xF1, 0x90, 0x01]
+            );
+            server.kill_diag_server();
+        }
+    }
+
+    #[test]
+    fn functional_request_without_id() {
+        let sim = SimulatorAPI::new(vec![VirtualECU::new(
+            "ECU",
+            DiagProtocol::UDS,
+            0x7E0,
+            0x7E8,
+        )]);
+        let server = sim.start_test_server(Default::default());
+        assert!(server.run_cmd_functional(0x3E, &[0x00], &[]).is_err());
+    }
 }
@@ -1,9 +1,6 @@
 use std::{
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        mpsc::{self, Receiver, Sender},
-        Arc, Mutex, RwLock,
-    },
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, RwLock},
     vec,
 };
 
@@ -11,8 +8,11 @@ use commapi::protocols::ProtocolError;
 
 use crate::commapi::{
     self,
-    comm_api::{ComServer, FilterType},
-    iface::{DynamicInterface, Interface, InterfaceConfig, InterfaceType, PayloadFlag},
+    comm_api::{Capability, ComServer, FilterType},
+    iface::{
+        BufferType, DynamicInterface, Interface, InterfaceConfig, InterfacePayload, InterfaceType,
+        KLineInitMode, PayloadFlag, IFACE_CFG,
+    },
 };
 
 use self::{
@@ -22,7 +22,10 @@ use self::{
 };
 
 use super::{
-    CommandError, DTCState, DiagCfg, ECUCommand, ProtocolResult, ProtocolServer, Selectable, DTC,
+    executor::RequestExecutor,
+    timing::{self, DiagTiming},
+    CommandError, DTCState, DiagCfg, ECUCommand, FunctionalResponses, ProtocolResult,
+    ProtocolServer, Selectable, DTC,
 };
 
 pub mod codes;
@@ -36,9 +39,15 @@ pub mod service07;
 pub mod service08;
 pub mod service09;
 pub mod service10;
+pub mod unit_scaling;
 
 pub type OBDError<T> = ProtocolResult<T>;
 
+/// ISO15765-4 functional request ID. Every emissions ECU on CAN listens to it
+pub const OBD_FUNCTIONAL_ID: u32 = 0x07DF;
+/// ISO15765-4 response IDs of emissions ECUs. Each ECU's physical request ID is 8 lower
+const OBD_RESPONSE_IDS: std::ops::RangeInclusive<u32> = 0x07E8..=0x07EF;
+
 // Helper function to get bits from byte array, in order MSB to LSB
 pub(crate) fn get_obd_bits(src: &[u8]) -> Vec<bool> {
     let mut res = Vec::new();
@@ -62,22 +71,48 @@ where
 #[derive(Debug, Clone, Copy)]
 pub enum ObdError {
     CmdNotSupported,
+    /// ECU rejected the request with a negative response code other than 'Service not supported'
+    NegativeResponse(u8),
 }
 
 impl CommandError for ObdError {
     fn get_desc(&self) -> String {
-        "OBD Command not supported by ECU".into()
+        match self {
+            Self::CmdNotSupported => "OBD Command not supported by ECU".into(),
+            Self::NegativeResponse(0x10) => "General reject".into(),
+            Self::NegativeResponse(0x12) => "Sub function not supported or invalid format".into(),
+            Self::NegativeResponse(0x21) => "ECU is busy".into(),
+            Self::NegativeResponse(0x22) => "Conditions not correct".into(),
+            Self::NegativeResponse(0x78) => "ECU is still processing the request".into(),
+            Self::NegativeResponse(x) => format!("ECU rejected the request (0x{:02X})", x),
+        }
     }
 
     fn get_help(&self) -> Option<String> {
-        Some("OBD Command not supported".into())
+        match self {
+            Self::CmdNotSupported => Some("OBD Command not supported".into()),
+            Self::NegativeResponse(0x22) => {
+                Some("Check the ignition is on, and the engine state required".into())
+            }
+            Self::NegativeResponse(_) => None,
+        }
     }
 
-    fn from_byte(_b: u8) -> Self
+    fn from_byte(b: u8) -> Self
     where
         Self: Sized,
     {
-        Self::CmdNotSupported
+        match b {
+            0x11 => Self::CmdNotSupported,
+            x => Self::NegativeResponse(x),
+        }
+    }
+
+    fn to_byte(&self) -> u8 {
+        match self {
+            Self::CmdNotSupported => 0x11, // Service not supported
+            Self::NegativeResponse(x) => *x,
+        }
     }
 }
 
@@ -103,7 +138,7 @@ impl Selectable for OBDCmd {
             OBDCmd::Service03 => "Show DTCs",
             OBDCmd::Service04 => "Clear DTCs",
             OBDCmd::Service05 => "Test results, O2 sensor monitoring (Non CAN)",
-            OBDCmd::Service06 => "Test results, 02 sensor monitoring (CAN only)",
+            OBDCmd::Service06 => "Test results, on-board monitoring",
             OBDCmd::Service07 => "Show pending DTCs",
             OBDCmd::Service08 => "Control operation of on-board systems",
             OBDCmd::Service09 => "Request vehicle information",
@@ -136,7 +171,11 @@ impl Into<u8> for OBDCmd {
 
 impl ECUCommand for OBDCmd {
     fn get_caution_level(&self) -> super::CautionLevel {
-        super::CautionLevel::None // Always for OBD - Read only protocol so nothing bad can happen :)
+        match self {
+            OBDCmd::Service04 => super::CautionLevel::Warn, // Also resets the readiness monitors
+            OBDCmd::Service08 => super::CautionLevel::Alert, // See Service08::get_caution_level
+            _ => super::CautionLevel::None, // Read only, so nothing bad can happen :)
+        }
     }
 
     fn get_cmd_list() -> Vec<Self> {
@@ -157,10 +196,13 @@ impl ECUCommand for OBDCmd {
 
 #[derive(Debug, Clone)]
 pub struct ObdServer {
-    should_run: Arc<AtomicBool>,
-    cmd_tx: Sender<(u8, Vec<u8>, bool)>,
-    cmd_rx: Arc<Receiver<ProtocolResult<Vec<u8>>>>,
-    cmd_mutex: Arc<Mutex<()>>,
+    iface_type: InterfaceType,
+    executor: RequestExecutor,
+    send_id: u32,
+    tx_flags: Option<Vec<PayloadFlag>>,
+    timing: DiagTiming,
+    /// IDs of the emissions ECUs that responded when the session was started
+    ecus: Arc<RwLock<Vec<u32>>>,
     s01: Option<Service01>,
     s02: Option<Service02>,
     s03: Option<Service03>,
@@ -174,6 +216,71 @@ pub struct ObdServer {
 }
 
 impl ObdServer {
+    pub fn req_service01<T, F: Fn(&Service01) -> ProtocolResult<T>>(
+        &self,
+        func: F,
+    ) -> ProtocolResult<T> {
+        if let Some(s) = &self.s01 {
+            func(s)
+        } else {
+            Err(ProtocolError::CustomError(
+                "Service not supported by ECU".into(),
+            ))
+        }
+    }
+
+    pub fn req_service02<T, F: Fn(&Service02) -> ProtocolResult<T>>(
+        &self,
+        func: F,
+    ) -> ProtocolResult<T> {
+        if let Some(s) = &self.s02 {
+            func(s)
+        } else {
+            Err(ProtocolError::CustomError(
+                "Service not supported by ECU".into(),
+            ))
+        }
+    }
+
+    pub fn req_service05<T, F: Fn(&Service05) -> ProtocolResult<T>>(
+        &self,
+        func: F,
+    ) -> ProtocolResult<T> {
+        if let Some(s) = &self.s05 {
+            func(s)
+        } else {
+            Err(ProtocolError::CustomError(
+                "Service not supported by ECU".into(),
+            ))
+        }
+    }
+
+    pub fn req_service06<T, F: Fn(&Service06) -> ProtocolResult<T>>(
+        &self,
+        func: F,
+    ) -> ProtocolResult<T> {
+        if let Some(s) = &self.s06 {
+            func(s)
+        } else {
+            Err(ProtocolError::CustomError(
+                "Service not supported by ECU".into(),
+            ))
+        }
+    }
+
+    pub fn req_service08<T, F: Fn(&Service08) -> ProtocolResult<T>>(
+        &self,
+        func: F,
+    ) -> ProtocolResult<T> {
+        if let Some(s) = &self.s08 {
+            func(s)
+        } else {
+            Err(ProtocolError::CustomError(
+                "Service not supported by ECU".into(),
+            ))
+        }
+    }
+
     pub fn req_service09<T, F: Fn(&Service09) -> ProtocolResult<T>>(
         &self,
         func: F,
@@ -210,44 +317,382 @@ impl ObdServer {
         return res;
     }
 
-    // Used for services 03, 07 and 0A
-    fn decode_dtc_resp(&self, bytes: &[u8], state: DTCState, res: &mut Vec<DTC>) {
-        let num_dtcs = bytes[0];
-        if num_dtcs == 0 {
-            return;
-        }
-        for idx in 0..num_dtcs as usize {
-            let a = bytes[idx * 2 + 1];
-            let b = bytes[idx * 2 + 2];
-            let char = match (a & 0b11000000) >> 6 {
-                0 => 'P',
-                1 => 'C',
-                2 => 'B',
-                _ => 'U',
-            };
+    // Used for services 03, 07 and 0A. Bytes are the number of DTCs, then 2 bytes per DTC
+    fn decode_dtc_resp(bytes: &[u8], state: DTCState, res: &mut Vec<DTC>) {
+        let num_dtcs = match bytes.first() {
+            Some(n) => *n as usize,
+            None => return,
+        };
+        for dtc in bytes[1..].chunks_exact(2).take(num_dtcs) {
+            res.push(DTC::from_obd((dtc[0] as u16) << 8 | dtc[1] as u16, state))
+        }
+    }
 
-            let second = match (a & 0b00001100) >> 4 {
-                0 => '0',
-                1 => '1',
-                2 => '2',
-                _ => '3',
-            };
-            // STD Hex representation
-            let third = format!("{:1X}", a & 0b00001111);
-            let fourth = format!("{:02X}", b);
-
-            res.push(DTC {
-                error: format!("{}{}{}{}", char, second, third, fourth),
-                state: state,
-                check_engine_on: state == DTCState::Stored || state == DTCState::Permanent,
-                id: (a as u32) << 8 | b as u32,
+    /// Reads the DTCs of every emissions ECU with Service 03, 07 or 0A, keyed by the ID of the ECU
+    pub(crate) fn read_dtcs(&self, sid: u8, state: DTCState) -> OBDError<BTreeMap<u32, Vec<DTC>>> {
+        Ok(self
+            .run_command_all(sid, &[])?
+            .into_iter()
+            .map(|(id, resp)| {
+                let mut dtcs = Vec::new();
+                Self::decode_dtc_resp(&resp[1..], state, &mut dtcs);
+                (id, dtcs)
             })
-        }
+            .collect())
     }
 
     pub fn get_dtc_desc(dtc: &DTC) -> String {
         codes::get_dtc_desc(dtc)
     }
+
+    /// Returns the interface type the vehicle is being talked to over
+    pub fn get_interface_type(&self) -> InterfaceType {
+        self.iface_type
+    }
+
+    /// Returns the IDs of every emissions ECU found on the vehicle
+    pub fn get_ecu_ids(&self) -> Vec<u32> {
+        self.ecus.read().unwrap().clone()
+    }
+
+    /// Sends a request to every emissions ECU on the vehicle.
+    ///
+    /// ## Returns
+    /// The positive response of each ECU that supports the request, by ECU ID
+    pub fn run_command_all(&self, cmd: u8, args: &[u8]) -> ProtocolResult<FunctionalResponses> {
+        let args = Vec::from(args);
+        let iface_type = self.iface_type;
+        let tx_flags = self.tx_flags.clone();
+        let send_id = self.send_id;
+        let timing = self.timing;
+        let ecus = self.ecus.clone();
+        let resp = self
+            .executor
+            .submit_timeout(timing.get_request_timeout(), move |iface| {
+                // Requests are always sent to every ECU, as more than one may be
+                // responsible for emissions
+                if iface_type == InterfaceType::IsoTp {
+                    Self::run_functional_command_resp(
+                        iface,
+                        &tx_flags,
+                        send_id,
+                        cmd,
+                        &args,
+                        &timing,
+                        &ecus.read().unwrap(),
+                    )
+                } else {
+                    Self::run_non_can_command_resp(iface, send_id, cmd, &args, &timing)
+                }
+            })
+            .wait()?;
+        if resp.is_empty() {
+            return Err(ProtocolError::Timeout);
+        }
+        // Every ECU rejected the request, so return the reason the first one gave
+        let nrc = resp.values().find_map(|r| match r.as_slice() {
+            [0x7F, _, nrc, ..] => Some(*nrc),
+            _ => None,
+        });
+        let res: FunctionalResponses = resp.into_iter().filter(|(_, r)| r[0] != 0x7F).collect();
+        match (res.is_empty(), nrc) {
+            (false, _) => Ok(res),
+            (true, Some(nrc)) => Err(ProtocolError::ProtocolError(Box::new(ObdError::from_byte(
+                nrc,
+            )))),
+            (true, None) => Err(ProtocolError::Timeout),
+        }
+    }
+
+    /// Attempts to start an OBD-II session over every bus the adapter supports, stopping at the
+    /// first one an emissions ECU responds on. The protocols are tried in order:
+    /// 1. ISO15765-4 (CAN)
+    /// 2. ISO9141-2 and ISO14230-4 (K-Line), see [start_kline_session](Self::start_kline_session)
+    /// 3. SAE J1850 VPW and PWM, see [start_j1850_session](Self::start_j1850_session)
+    pub fn start_auto_session(comm_server: &Box<dyn ComServer>) -> ProtocolResult<Self> {
+        let caps = comm_server.get_capabilities();
+        let mut last_err = ProtocolError::CustomError(
+            "Adapter does not support ISO15765, ISO9141, ISO14230 or J1850".into(),
+        );
+        if caps.supports_iso15765() == Capability::Yes {
+            match Self::start_can_session(comm_server) {
+                Ok(server) => return Ok(server),
+                Err(e) => last_err = e,
+            }
+        }
+        if caps.supports_iso9141() == Capability::Yes || caps.supports_iso14230() == Capability::Yes
+        {
+            match Self::start_kline_session(comm_server) {
+                Ok(server) => return Ok(server),
+                Err(e) => last_err = e,
+            }
+        }
+        if caps.supports_j1850vpw() == Capability::Yes
+            || caps.supports_j1850pwm() == Capability::Yes
+        {
+            match Self::start_j1850_session(comm_server) {
+                Ok(server) => return Ok(server),
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Attempts to start an OBD-II session over ISO15765-4 (CAN) at 500kbps. Requests are sent
+    /// functionally, so every emissions ECU responds
+    pub fn start_can_session(comm_server: &Box<dyn ComServer>) -> ProtocolResult<Self> {
+        let mut cfg = InterfaceConfig::new();
+        cfg.add_param(IFACE_CFG::BAUDRATE, 500_000);
+        cfg.add_param(IFACE_CFG::EXT_CAN_ADDR, 0);
+        cfg.add_param(IFACE_CFG::EXT_ISOTP_ADDR, 0);
+
+        let diag_cfg = DiagCfg {
+            send_id: OBD_FUNCTIONAL_ID,
+            recv_id: 0x00, // Any ECU may respond
+            global_id: None,
+            timing: Default::default(),
+        };
+        let server = Self::start_diag_session(
+            comm_server,
+            InterfaceType::IsoTp,
+            cfg,
+            Some(vec![PayloadFlag::ISOTP_PAD_FRAME]),
+            diag_cfg,
+        )?;
+        if server.get_ecu_ids().is_empty() {
+            println!("OBD2 - ISO15765 failed: No ECU responded");
+            return Err(ProtocolError::CustomError("No ECU responded".into()));
+        }
+        println!("OBD2 - Connected using {:?}", InterfaceType::IsoTp);
+        Ok(server)
+    }
+
+    /// Attempts to start an OBD-II session over K-Line. The protocols are tried in order:
+    /// 1. ISO9141-2 (5 baud init)
+    /// 2. ISO14230-4 (Fast init)
+    /// 3. ISO14230-4 (5 baud init)
+    pub fn start_kline_session(comm_server: &Box<dyn ComServer>) -> ProtocolResult<Self> {
+        let caps = comm_server.get_capabilities();
+        let mut attempts = Vec::new();
+        if caps.supports_iso9141() == Capability::Yes {
+            attempts.push((InterfaceType::Iso9141, KLineInitMode::FiveBaud));
+        }
+        if caps.supports_iso14230() == Capability::Yes {
+            attempts.push((InterfaceType::Iso14230, KLineInitMode::FastInit));
+            attempts.push((InterfaceType::Iso14230, KLineInitMode::FiveBaud));
+        }
+        let mut last_err =
+            ProtocolError::CustomError("Adapter does not support ISO9141 or ISO14230".into());
+        for (iface_type, init_mode) in attempts {
+            let mut cfg = InterfaceConfig::new();
+            cfg.add_param(IFACE_CFG::BAUDRATE, 10400);
+            cfg.add_param(IFACE_CFG::KLINE_INIT_MODE, init_mode as u32);
+            cfg.add_param(IFACE_CFG::KLINE_SOURCE_ADDR, 0xF1);
+            cfg.add_param(IFACE_CFG::KLINE_TARGET_ADDR, 0x33); // OBD-II functional address
+            cfg.add_param(IFACE_CFG::KLINE_FUNC_ADDR, 0x33);
+            cfg.add_param(IFACE_CFG::KLINE_MAX_SEGMENT_SIZE, 7);
+
+            let diag_cfg = DiagCfg {
+                send_id: 0x33,
+                recv_id: 0x00, // Any ECU may respond
+                global_id: None,
+                timing: Default::default(),
+            };
+            match Self::start_diag_session(comm_server, iface_type, cfg, None, diag_cfg) {
+                Ok(server) => {
+                    println!("OBD2 - Connected using {:?} ({:?})", iface_type, init_mode);
+                    return Ok(server);
+                }
+                Err(e) => {
+                    println!("OBD2 - {:?} ({:?}) failed: {:?}", iface_type, init_mode, e);
+                    last_err = e
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Attempts to start an OBD-II session over SAE J1850. VPW is tried first, then PWM
+    pub fn start_j1850_session(comm_server: &Box<dyn ComServer>) -> ProtocolResult<Self> {
+        let caps = comm_server.get_capabilities();
+        let mut attempts = Vec::new();
+        if caps.supports_j1850vpw() == Capability::Yes {
+            attempts.push((InterfaceType::J1850Vpw, 0x68));
+        }
+        if caps.supports_j1850pwm() == Capability::Yes {
+            attempts.push((InterfaceType::J1850Pwm, 0x61));
+        }
+        let mut last_err =
+            ProtocolError::CustomError("Adapter does not support J1850 VPW or PWM".into());
+        for (iface_type, priority) in attempts {
+            let mut cfg = InterfaceConfig::new();
+            cfg.add_param(IFACE_CFG::J1850_PRIORITY, priority);
+            cfg.add_param(IFACE_CFG::J1850_SOURCE_ADDR, 0xF1);
+            cfg.add_param(IFACE_CFG::J1850_RESPONSE_ADDR, 0x6B);
+
+            let diag_cfg = DiagCfg {
+                send_id: 0x6A, // OBD-II functional address
+                recv_id: 0x00, // Any ECU may respond
+                global_id: None,
+                timing: Default::default(),
+            };
+            match Self::start_diag_session(comm_server, iface_type, cfg, None, diag_cfg) {
+                // J1850 has no wake up, so an ECU answering Service 01 is the only
+                // way of telling if anything is on the bus
+                Ok(server) if server.s01.is_some() => {
+                    println!("OBD2 - Connected using {:?}", iface_type);
+                    return Ok(server);
+                }
+                Ok(_) => {
+                    println!("OBD2 - {:?} failed: No ECU responded", iface_type);
+                    last_err = ProtocolError::Timeout
+                }
+                Err(e) => {
+                    println!("OBD2 - {:?} failed: {:?}", iface_type, e);
+                    last_err = e
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Runs an OBD-II request over K-Line or J1850. Unlike CAN, responses that do not fit in
+    /// a single message are split over multiple messages, so all responses are collected,
+    /// and each ECU's are merged back into the layout CAN would use.
+    fn run_non_can_command_resp(
+        interface: &mut Box<dyn Interface>,
+        send_id: u32,
+        cmd: u8,
+        args: &[u8],
+        timing: &DiagTiming,
+    ) -> ProtocolResult<FunctionalResponses> {
+        let mut tx_data = vec![cmd];
+        tx_data.extend_from_slice(args);
+        let tx = InterfacePayload::new(send_id, &tx_data);
+        interface.clear_buffer(BufferType::RX)?;
+        interface.send_data(&[tx], 0)?;
+        let mut msgs: BTreeMap<u32, Vec<Vec<u8>>> = BTreeMap::new();
+        let mut timeout = timing.p2;
+        let mut pending_count = 0;
+        // ECUs which replied with ResponsePending, and have not sent their response yet
+        let mut pending: BTreeSet<u32> = BTreeSet::new();
+        loop {
+            let read = interface.recv_data(10, timeout)?;
+            if read.is_empty() {
+                break;
+            }
+            for m in read {
+                match m.data.first() {
+                    Some(0x7F)
+                        if m.data.get(1) == Some(&cmd)
+                            && m.data.get(2) == Some(&0x78)
+                            && pending_count < timing::MAX_RESPONSE_PENDING =>
+                    {
+                        pending_count += 1;
+                        pending.insert(m.id);
+                    }
+                    Some(&sid)
+                        if sid == cmd + 0x40 || (sid == 0x7F && m.data.get(1) == Some(&cmd)) =>
+                    {
+                        pending.remove(&m.id);
+                        msgs.entry(m.id).or_default().push(m.data)
+                    }
+                    sid => eprintln!(
+                        "OBD2 - Command response did not match request? Send: {:02X} - Recv: {:02X?}",
+                        cmd, sid
+                    ),
+                }
+            }
+            // ECUs that are still processing the request have up to P2* to respond.
+            // Otherwise, remaining messages follow within P2 max (50ms)
+            timeout = if pending.is_empty() {
+                100
+            } else {
+                timing.p2_ext
+            };
+        }
+        Ok(msgs
+            .into_iter()
+            .map(|(id, m)| {
+                let positive: Vec<Vec<u8>> = m.iter().filter(|x| x[0] != 0x7F).cloned().collect();
+                if positive.is_empty() {
+                    (id, m[0].clone())
+                } else {
+                    (id, Self::merge_non_can_responses(positive))
+                }
+            })
+            .collect())
+    }
+
+    /// Merges multi-message K-Line or J1850 responses (SAE J1979 non-CAN format) into the
+    /// single response format used by ISO15765-4
+    pub(crate) fn merge_non_can_responses(msgs: Vec<Vec<u8>>) -> Vec<u8> {
+        let sid = match msgs.get(0).and_then(|m| m.get(0)) {
+            Some(sid) => *sid,
+            None => return Vec::new(),
+        };
+        match sid {
+            0x43 | 0x47 | 0x4A => {
+                // Each message holds up to 3 DTCs, padded with 0x0000.
+                // CAN has the number of DTCs at the start instead
+                let mut dtcs = Vec::new();
+                for m in &msgs {
+                    for dtc in m[1..].chunks(2) {
+                        if dtc.len() == 2 && dtc != [0x00, 0x00] {
+                            dtcs.extend_from_slice(dtc);
+                        }
+                    }
+                }
+                let mut res = vec![sid, (dtcs.len() / 2) as u8];
+                res.extend(dtcs);
+                res
+            }
+            0x49 if msgs[0].len() > 3 && msgs[0][1] % 2 == 0 => {
+                // Even PIDs have a message sequence number rather than
+                // the number of data items CAN uses
+                let pid = msgs[0][1];
+                // Every message needs the PID, a sequence number and data to be put in order.
+                // Anything else is corrupt, or for a different request
+                let mut sorted: Vec<Vec<u8>> = msgs
+                    .into_iter()
+                    .filter(|m| m.len() > 3 && m[1] == pid)
+                    .collect();
+                sorted.sort_by_key(|m| m[2]);
+                let mut data: Vec<u8> = sorted.iter().flat_map(|m| m[3..].to_vec()).collect();
+                let item_size = match pid {
+                    0x02 => {
+                        // VIN is padded to fit in 5 messages
+                        while data.len() > 17 && data[0] == 0x00 {
+                            data.remove(0);
+                        }
+                        17
+                    }
+                    0x04 => 16, // Calibration ID
+                    0x06 => 4,  // CVN
+                    0x08 => 2,  // In use performance tracking
+                    0x0A => 20, // ECU name
+                    _ => 0,     // Supported PIDs
+                };
+                let mut res = vec![sid, pid];
+                if item_size != 0 {
+                    res.push((data.len() / item_size) as u8);
+                }
+                res.extend(data);
+                res
+            }
+            0x46 if msgs[0].len() > 2 && msgs[0][1] % 0x20 != 0 => {
+                // Each message is the result of one component. Keep a single SID and TID,
+                // followed by every component's result
+                let tid = msgs[0][1];
+                let mut res = vec![sid, tid];
+                for m in msgs.iter().filter(|m| m.len() > 2 && m[1] == tid) {
+                    res.extend_from_slice(&m[2..]);
+                }
+                res
+            }
+            _ => msgs[0].clone(),
+        }
+    }
 }
 
 impl ProtocolServer for ObdServer {
@@ -262,71 +707,40 @@ impl ProtocolServer for ObdServer {
         tx_flags: Option<Vec<PayloadFlag>>,
         diag_cfg: DiagCfg,
     ) -> super::ProtocolResult<Self> {
-        if interface_type != InterfaceType::IsoTp && interface_type != InterfaceType::Iso9141 {
+        if interface_type == InterfaceType::Can || interface_type == InterfaceType::DoIp {
             return Err(ProtocolError::CustomError(
-                "OBD-II Can only be executed over ISO-TP or ISO9141".into(),
+                "OBD-II Can only be executed over ISO-TP, ISO9141, ISO14230 or J1850".into(),
             ));
         }
 
         let mut dyn_interface =
             DynamicInterface::new(comm_server, interface_type, &interface_cfg)?.clone_box();
-        if interface_type == InterfaceType::IsoTp {
+        if interface_type == InterfaceType::IsoTp && diag_cfg.recv_id == 0x00 {
+            // Listen to every emissions ECU, sending flow control to its physical request ID
+            for id in OBD_RESPONSE_IDS {
+                dyn_interface.add_filter(FilterType::IsoTP {
+                    id,
+                    mask: 0xFFFF,
+                    fc: id - 8,
+                })?;
+            }
+        } else if interface_type == InterfaceType::IsoTp {
             dyn_interface.add_filter(FilterType::IsoTP {
                 id: diag_cfg.recv_id,
                 mask: 0xFFFF,
                 fc: diag_cfg.send_id,
             })?;
-        } else {
-            return Err(ProtocolError::CustomError(
-                "OBD-II over ISO9141 is a WIP".into(),
-            ));
         }
+        // No filter on K-Line or J1850, ECUs reply with their own address
 
-        let should_run = Arc::new(AtomicBool::new(true));
-        let should_run_t = should_run.clone();
-
-        let last_error = Arc::new(RwLock::new(None));
-        let last_error_t = last_error.clone();
-
-        let (channel_tx_sender, channel_tx_receiver): (
-            Sender<(u8, Vec<u8>, bool)>,
-            Receiver<(u8, Vec<u8>, bool)>,
-        ) = mpsc::channel();
-        let (channel_rx_sender, channel_rx_receiver): (
-            Sender<ProtocolResult<Vec<u8>>>,
-            Receiver<ProtocolResult<Vec<u8>>>,
-        ) = mpsc::channel();
-
-        let s_id = diag_cfg.send_id;
-        std::thread::spawn(move || {
-            println!("OBD2 server start!");
-            while should_run_t.load(Ordering::Relaxed) {
-                if let Ok(data) = channel_tx_receiver.try_recv() {
-                    let res = Self::run_command_resp(
-                        &mut dyn_interface,
-                        &tx_flags,
-                        s_id,
-                        data.0,
-                        &data.1,
-                        data.2,
-                    );
-                    if channel_rx_sender.send(res).is_err() {
-                        *last_error_t.write().unwrap() =
-                            Some(ProtocolError::CustomError("Sender channel died".into()));
-                        break;
-                    }
-                }
-                std::thread::sleep(std::time::Duration::from_micros(100))
-            }
-            println!("OBD2 Server stop!");
-            let _res = dyn_interface.close();
-        });
-
+        let executor = RequestExecutor::new("OBD2", dyn_interface);
         let mut server = ObdServer {
-            should_run,
-            cmd_mutex: Arc::new(Mutex::new(())),
-            cmd_rx: Arc::new(channel_rx_receiver),
-            cmd_tx: channel_tx_sender,
+            iface_type: interface_type,
+            executor,
+            send_id: diag_cfg.send_id,
+            tx_flags,
+            timing: diag_cfg.timing,
+            ecus: Arc::new(RwLock::new(Vec::new())),
             s01: None,
             s02: None,
             s03: None,
@@ -338,12 +752,37 @@ impl ProtocolServer for ObdServer {
             s09: None,
             s10: None,
         };
-        std::thread::sleep(std::time::Duration::from_millis(100)); // Wait for diag server to start
 
+        // Every emissions ECU must support Service 01 PID 00
+        match server.run_command_all(0x01, &[0x00]) {
+            Ok(res) => {
+                *server.ecus.write().unwrap() = res.keys().copied().collect();
+                println!("OBD2 - Found ECUs {:04X?}", server.get_ecu_ids());
+            }
+            // Nothing on the bus, so don't wait for every other service to time out as well.
+            // Callers check for no ECUs or no Service 01
+            Err(_) => return Ok(server),
+        }
         if let Some(r) = Service01::init(&server) {
             server.s01 = Some(r)
         }
-        server.s03 = Some(service03::Service03);
+        if let Some(r) = Service02::init(&server) {
+            server.s02 = Some(r)
+        }
+        // Services 03, 07 and 0A have no supported list. ECUs without permanent DTC support
+        // won't respond to 0A, which is handled when reading the DTCs
+        server.s03 = Some(Service03);
+        server.s07 = Some(Service07);
+        server.s10 = Some(Service0A);
+        if let Some(r) = Service05::init(&server) {
+            server.s05 = Some(r)
+        }
+        if let Some(r) = Service06::init(&server) {
+            server.s06 = Some(r)
+        }
+        if let Some(r) = Service08::init(&server) {
+            server.s08 = Some(r)
+        }
         if let Some(r) = Service09::init(&server) {
             server.s09 = Some(r)
         }
@@ -351,42 +790,28 @@ impl ProtocolServer for ObdServer {
     }
 
     fn exit_diag_session(&mut self) {
-        self.should_run.store(false, Ordering::Relaxed);
+        self.executor.stop();
     }
 
+    /// Returns the response of the ECU with the lowest ID that supports the request.
+    /// Use [ObdServer::run_command_all] to get the response of every ECU
     fn run_command(&self, cmd: u8, args: &[u8]) -> super::ProtocolResult<Vec<u8>> {
-        let _guard = self.cmd_mutex.lock().unwrap(); // We are allowed to send / receive!
-        if self.cmd_tx.send((cmd, Vec::from(args), true)).is_err() {
-            return Err(ProtocolError::CustomError("Channel Tx failed".into()));
-        }
-        let resp = self.cmd_rx.recv().unwrap()?;
-        if resp[0] == 0x7F {
-            Err(ProtocolError::ProtocolError(Box::new(ObdError::from_byte(
-                0,
-            ))))
-        } else {
-            Ok(resp)
-        }
+        let mut res = self.run_command_all(cmd, args)?;
+        let first = *res.keys().next().unwrap();
+        Ok(res.remove(&first).unwrap())
     }
 
     fn read_errors(&self) -> super::ProtocolResult<Vec<super::DTC>> {
-        let mut res: Vec<DTC> = Vec::new();
-        if let Ok(resp) = self.run_command(0x03, &[]) {
-            //  Stored DTCs
-            println!("S03: {:02X?}", resp);
-            self.decode_dtc_resp(&resp[1..], DTCState::Stored, &mut res);
-        }
-        if let Ok(resp) = self.run_command(0x07, &[]) {
-            // Pending DTCs
-            println!("S07: {:02X?}", resp);
-            self.decode_dtc_resp(&resp[1..], DTCState::Pending, &mut res);
+        // Stored DTCs from every emissions ECU
+        let mut res = Service03::read_dtcs(self)?;
+        // Pending and permanent DTCs. Not every ECU supports permanent DTCs
+        if let Ok(mut pending) = Service07::read_dtcs(self) {
+            res.append(&mut pending);
         }
-        if let Ok(resp) = self.run_command(0x0A, &[]) {
-            // Permanent DTCs
-            println!("S0A: {:02X?}", resp);
-            self.decode_dtc_resp(&resp[1..], DTCState::Permanent, &mut res);
+        if let Ok(mut permanent) = Service0A::read_dtcs(self) {
+            res.append(&mut permanent);
         }
-        return Ok(res);
+        Ok(res)
     }
 
     fn is_in_diag_session(&self) -> bool {
@@ -400,6 +825,192 @@ impl ProtocolServer for ObdServer {
 
 impl Drop for ObdServer {
     fn drop(&mut self) {
-        self.exit_diag_session();
+        // Copies of the server are given to background tasks, only the last one ends the session
+        if !self.executor.is_shared() {
+            self.exit_diag_session();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::service01::MonitorState;
+    use super::*;
+    use crate::commapi::{
+        protocols::DiagProtocol,
+        simulator_api::{SimulatorAPI, VirtualECU},
+    };
+
+    /// Emissions ECU that supports Service 01 PID 00, and no other PIDs
+    fn emissions_ecu(protocol: DiagProtocol, request_id: u32, response_id: u32) -> VirtualECU {
+        let mut ecu = VirtualECU::new("ECM", protocol, request_id, response_id);
+        ecu.custom_responses
+            .push((vec![0x01, 0x00], vec![0x41, 0x00, 0x00, 0x00, 0x00, 0x00]));
+        ecu
+    }
+
+    #[test]
+    fn merge_non_can_dtcs() {
+        let msgs = vec![
+            vec![0x43, 0x01, 0x23, 0x00, 0x00, 0x00, 0x00],
+            vec![0x43, 0x04, 0x56, 0x00, 0x00, 0x00, 0x00],
+        ];
+        assert_eq!(
+            ObdServer::merge_non_can_responses(msgs),
+            vec![0x43, 0x02, 0x01, 0x23, 0x04, 0x56]
+        );
+    }
+
+    #[test]
+    fn merge_non_can_vin() {
+        let vin = b"1G1JC5444R7252367";
+        // The VIN is padded with 3 0s so it fits in 5 messages of 4 bytes
+        let mut padded = vec![0x00; 3];
+        padded.extend_from_slice(vin);
+        let mut msgs: Vec<Vec<u8>> = padded
+            .chunks(4)
+            .enumerate()
+            .map(|(idx, c)| {
+                let mut m = vec![0x49, 0x02, idx as u8 + 1];
+                m.extend_from_slice(c);
+                m
+            })
+            .collect();
+        // Messages can arrive in any order
+        msgs.swap(0, 3);
+        msgs.swap(1, 4);
+        let mut expected = vec![0x49, 0x02, 0x01];
+        expected.extend_from_slice(vin);
+        assert_eq!(ObdServer::merge_non_can_responses(msgs.clone()), expected);
+
+        // Truncated messages, or messages for another PID are dropped rather than panicking
+        msgs.insert(2, vec![0x49, 0x02]);
+        msgs.push(vec![0x49]);
+        msgs.push(vec![0x49, 0x02, 0x06]);
+        msgs.push(vec![0x49, 0x04, 0x01, 0x41, 0x42, 0x43, 0x44]);
+        assert_eq!(ObdServer::merge_non_can_responses(msgs), expected);
+    }
+
+    #[test]
+    fn merge_non_can_cvn() {
+        let msgs = vec![
+            vec![0x49, 0x06, 0x02, 0x55, 0x66, 0x77, 0x88],
+            vec![0x49, 0x06, 0x01, 0x11, 0x22, 0x33, 0x44],
+        ];
+        assert_eq!(
+            ObdServer::merge_non_can_responses(msgs),
+            vec![0x49, 0x06, 0x02, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88]
+        );
+        assert!(ObdServer::merge_non_can_responses(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn auto_session_prefers_can() {
+        let mut ecu = emissions_ecu(DiagProtocol::UDS, 0x7E0, 0x7E8);
+        ecu.functional_id = Some(OBD_FUNCTIONAL_ID);
+        let kline_ecu = emissions_ecu(DiagProtocol::KWP2000, 0x33, 0x10);
+        let server: Box<dyn ComServer> = Box::new(SimulatorAPI::new(vec![ecu, kline_ecu]));
+
+        let obd = ObdServer::start_auto_session(&server).unwrap();
+        assert_eq!(obd.get_interface_type(), InterfaceType::IsoTp);
+        assert_eq!(obd.get_ecu_ids(), vec![0x7E8]);
+    }
+
+    #[test]
+    fn auto_session_falls_back_to_kline() {
+        let kline_ecu = emissions_ecu(DiagProtocol::KWP2000, 0x33, 0x10);
+        let server: Box<dyn ComServer> = Box::new(SimulatorAPI::new(vec![kline_ecu]));
+
+        assert!(ObdServer::start_can_session(&server).is_err());
+        let obd = ObdServer::start_auto_session(&server).unwrap();
+        assert_eq!(obd.get_interface_type(), InterfaceType::Iso14230);
+        assert_eq!(obd.get_ecu_ids(), vec![0x10]);
+    }
+
+    #[test]
+    fn freeze_frame_support() {
+        let mut ecu = emissions_ecu(DiagProtocol::UDS, 0x7E0, 0x7E8);
+        ecu.functional_id = Some(OBD_FUNCTIONAL_ID);
+        // PIDs 01-20 supported, PIDs 21-40 response is cut short
+        ecu.custom_responses.push((
+            vec![0x02, 0x00, 0x00],
+            vec![0x42, 0x00, 0x00, 0xC0, 0x00, 0x00, 0x01],
+        ));
+        ecu.custom_responses
+            .push((vec![0x02, 0x20, 0x00], vec![0x42, 0x20, 0x00, 0x80]));
+        let server: Box<dyn ComServer> = Box::new(SimulatorAPI::new(vec![ecu.clone()]));
+        let obd = ObdServer::start_can_session(&server).unwrap();
+        assert!(obd.s02.is_none());
+
+        ecu.custom_responses[2].1 = vec![0x42, 0x20, 0x00, 0x80, 0x00, 0x00, 0x00];
+        let server: Box<dyn ComServer> = Box::new(SimulatorAPI::new(vec![ecu.clone()]));
+        let obd = ObdServer::start_can_session(&server).unwrap();
+        let s02 = obd.s02.as_ref().unwrap();
+        assert_eq!(s02.get_supported_pids(), vec![0x01, 0x21]);
+
+        // Supported PIDs are missing from the response
+        ecu.custom_responses[1].1 = vec![0x42, 0x00, 0x00];
+        let server: Box<dyn ComServer> = Box::new(SimulatorAPI::new(vec![ecu]));
+        let obd = ObdServer::start_can_session(&server).unwrap();
+        assert!(obd.s02.is_none());
+    }
+
+    #[test]
+    fn rejected_by_every_ecu() {
+        let mut ecu = emissions_ecu(DiagProtocol::UDS, 0x7E0, 0x7E8);
+        ecu.functional_id = Some(OBD_FUNCTIONAL_ID);
+        ecu.custom_responses
+            .push((vec![0x09, 0x02], vec![0x7F, 0x09, 0x22]));
+        let server: Box<dyn ComServer> = Box::new(SimulatorAPI::new(vec![ecu]));
+        let obd = ObdServer::start_can_session(&server).unwrap();
+        let err = obd.run_command_all(0x09, &[0x02]).unwrap_err();
+        assert_eq!(err.get_nrc(), Some(0x22));
+    }
+
+    #[test]
+    fn readiness_of_every_ecu() {
+        let mut ecm = emissions_ecu(DiagProtocol::UDS, 0x7E0, 0x7E8);
+        ecm.functional_id = Some(OBD_FUNCTIONAL_ID);
+        // PIDs 01, 20, 40 and 41 supported
+        ecm.custom_responses[0].1 = vec![0x41, 0x00, 0x80, 0x00, 0x00, 0x01];
+        ecm.custom_responses
+            .push((vec![0x01, 0x20], vec![0x41, 0x20, 0x00, 0x00, 0x00, 0x01]));
+        ecm.custom_responses
+            .push((vec![0x01, 0x40], vec![0x41, 0x40, 0x80, 0x00, 0x00, 0x00]));
+        let mut tcm = ecm.clone();
+        tcm.name = "TCM".into();
+        tcm.request_id = 0x7E1;
+        tcm.response_id = 0x7E9;
+        // ECM has completed its catalyst monitor, but not this drive cycle.
+        // TCM's PID 01 response is cut short, and it has not completed EVAP this drive cycle
+        ecm.custom_responses
+            .push((vec![0x01, 0x01], vec![0x41, 0x01, 0x00, 0x00, 0x01, 0x00]));
+        ecm.custom_responses
+            .push((vec![0x01, 0x41], vec![0x41, 0x41, 0x00, 0x00, 0x01, 0x01]));
+        tcm.custom_responses.push((vec![0x01, 0x01], vec![0x41]));
+        tcm.custom_responses
+            .push((vec![0x01, 0x41], vec![0x41, 0x41, 0x00, 0x00, 0x04, 0x04]));
+        let server: Box<dyn ComServer> = Box::new(SimulatorAPI::new(vec![ecm, tcm]));
+        let obd = ObdServer::start_can_session(&server).unwrap();
+        assert_eq!(obd.get_ecu_ids().len(), 2);
+
+        let readiness = obd.req_service01(|s| s.get_readiness(&obd)).unwrap();
+        assert!(readiness.get_incomplete_monitors().is_empty());
+
+        let monitors = obd
+            .req_service01(|s| s.get_drive_cycle_monitors(&obd, readiness.ignition))
+            .unwrap();
+        let incomplete: Vec<&str> = monitors
+            .iter()
+            .filter(|m| m.state == MonitorState::Incomplete)
+            .map(|m| m.name)
+            .collect();
+        assert_eq!(incomplete, vec!["Catalyst", "Evaporative system"]);
+    }
+
+    #[test]
+    fn auto_session_no_ecu() {
+        let server: Box<dyn ComServer> = Box::new(SimulatorAPI::new(Vec::new()));
+        assert!(ObdServer::start_auto_session(&server).is_err());
     }
 }
@@ -7,7 +7,7 @@ use crate::commapi::protocols::{ProtocolError, ProtocolServer};
 use super::{get_obd_bits, OBDError, ObdError, ObdServer, ObdService};
 
 lazy_static! {
-    static ref PID_LIST: PidList = PidList::init_list();
+    pub(crate) static ref PID_LIST: PidList = PidList::init_list();
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -86,6 +86,17 @@ pub struct PidResult<'a> {
     bounds: (f32, f32),
 }
 
+impl<'a> PidResult<'a> {
+    pub fn get_desc(&self) -> &'a str {
+        self.desc
+    }
+
+    /// Returns the value, with its unit
+    pub fn get_value_string(&self) -> String {
+        format!("{:.2} {}", self.res, self.unit)
+    }
+}
+
 pub struct PidList {
     pids: Vec<Option<PidConvert>>,
 }
@@ -964,6 +975,48 @@ impl Service01 {
         Ok(PID_LIST.parse_pid(pid, &bytes[2..]))
     }
 
+    /// Reads the readiness monitor status since DTCs were last cleared (PID 01).
+    /// The status of every emissions ECU is combined, so a monitor is only complete
+    /// once every ECU supporting it says it is complete
+    pub fn get_readiness(&self, s: &ObdServer) -> OBDError<ReadinessStatus> {
+        self.check_service_supported(0x01)?;
+        s.run_command_all(0x01, &[0x01])?
+            .values()
+            .filter(|res| res.get(1) == Some(&0x01))
+            .filter_map(|res| ReadinessStatus::from_pid01(res.get(2..)?))
+            .fold(None, |acc: Option<ReadinessStatus>, x| match acc {
+                Some(acc) => Some(acc.combine(&x)),
+                None => Some(x),
+            })
+            .ok_or(ProtocolError::InvalidResponseSize {
+                expect: 6,
+                actual: 0,
+            })
+    }
+
+    /// Reads the monitor status of this drive cycle (PID 41). Monitors which are
+    /// disabled for the rest of this drive cycle are [MonitorState::Unsupported].
+    /// Like [Service01::get_readiness], the monitors of every emissions ECU are combined
+    pub fn get_drive_cycle_monitors(
+        &self,
+        s: &ObdServer,
+        ignition: IgnitionType,
+    ) -> OBDError<Vec<Monitor>> {
+        self.check_service_supported(0x41)?;
+        s.run_command_all(0x01, &[0x41])?
+            .values()
+            .filter(|res| res.len() >= 6 && res[1] == 0x41)
+            .map(|res| parse_monitors(&res[3..6], ignition))
+            .fold(None, |acc: Option<Vec<Monitor>>, x| match acc {
+                Some(acc) => Some(combine_monitors(&acc, &x)),
+                None => Some(x),
+            })
+            .ok_or(ProtocolError::InvalidResponseSize {
+                expect: 6,
+                actual: 0,
+            })
+    }
+
     pub fn get_supported_chartable_pids(&self) -> Vec<(u8, Vec<&'static str>)> {
         (0x01..0xFF as u8)
             .filter(|x| self.check_service_supported(*x).is_ok())
@@ -973,3 +1026,225 @@ impl Service01 {
             .collect()
     }
 }
+
+/// Most monitors allowed to be incomplete when being inspected. US EPA
+/// inspections allow 1 incomplete monitor on 2001 and newer vehicles
+pub const MAX_INCOMPLETE_MONITORS: usize = 1;
+
+/// Spark ignition monitors (PID 01 byte C/D bits 0-7)
+const SPARK_MONITORS: [Option<&str>; 8] = [
+    Some("Catalyst"),
+    Some("Heated catalyst"),
+    Some("Evaporative system"),
+    Some("Secondary air system"),
+    Some("A/C refrigerant"),
+    Some("Oxygen sensor"),
+    Some("Oxygen sensor heater"),
+    Some("EGR system"),
+];
+
+/// Compression ignition monitors (PID 01 byte C/D bits 0-7)
+const COMPRESSION_MONITORS: [Option<&str>; 8] = [
+    Some("NMHC catalyst"),
+    Some("NOx/SCR aftertreatment"),
+    None,
+    Some("Boost pressure"),
+    None,
+    Some("Exhaust gas sensor"),
+    Some("PM filter"),
+    Some("EGR and/or VVT system"),
+];
+
+/// Continuous monitors (PID 01 byte B bits 0-2)
+const CONTINUOUS_MONITORS: [&str; 3] = ["Misfire", "Fuel system", "Comprehensive components"];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IgnitionType {
+    Spark,
+    Compression,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MonitorState {
+    Complete,
+    Incomplete,
+    Unsupported,
+}
+
+#[derive(Debug, Clone)]
+pub struct Monitor {
+    pub name: &'static str,
+    /// Continuous monitors run all the time, rather than once per drive cycle
+    pub continuous: bool,
+    pub state: MonitorState,
+}
+
+/// Parses PID 01 or 41 bytes B, C and D. Bits of B and C say if each monitor is
+/// supported, and bits of B and D say if it is incomplete
+fn parse_monitors(bytes: &[u8], ignition: IgnitionType) -> Vec<Monitor> {
+    let state = |supported: bool, incomplete: bool| match (supported, incomplete) {
+        (false, _) => MonitorState::Unsupported,
+        (true, true) => MonitorState::Incomplete,
+        (true, false) => MonitorState::Complete,
+    };
+    let mut res: Vec<Monitor> = CONTINUOUS_MONITORS
+        .iter()
+        .enumerate()
+        .map(|(bit, name)| Monitor {
+            name,
+            continuous: true,
+            state: state(bytes[0] & 1 << bit != 0, bytes[0] & 1 << (bit + 4) != 0),
+        })
+        .collect();
+    let names = match ignition {
+        IgnitionType::Spark => SPARK_MONITORS,
+        IgnitionType::Compression => COMPRESSION_MONITORS,
+    };
+    for (bit, name) in names.iter().enumerate() {
+        if let Some(name) = name {
+            res.push(Monitor {
+                name,
+                continuous: false,
+                state: state(bytes[1] & 1 << bit != 0, bytes[2] & 1 << bit != 0),
+            })
+        }
+    }
+    res
+}
+
+/// Combines the monitors of 2 ECUs by name. A monitor is only complete once every
+/// ECU supporting it says it is complete
+fn combine_monitors(a: &[Monitor], b: &[Monitor]) -> Vec<Monitor> {
+    let mut res = a.to_vec();
+    for m in b {
+        match res.iter_mut().find(|x| x.name == m.name) {
+            Some(x) => {
+                x.state = match (x.state, m.state) {
+                    (MonitorState::Unsupported, s) | (s, MonitorState::Unsupported) => s,
+                    (MonitorState::Incomplete, _) | (_, MonitorState::Incomplete) => {
+                        MonitorState::Incomplete
+                    }
+                    _ => MonitorState::Complete,
+                }
+            }
+            None => res.push(m.clone()),
+        }
+    }
+    res
+}
+
+#[derive(Debug, Clone)]
+pub struct ReadinessStatus {
+    pub mil_on: bool,
+    /// Number of stored emissions related DTCs
+    pub dtc_count: u8,
+    pub ignition: IgnitionType,
+    pub monitors: Vec<Monitor>,
+}
+
+impl ReadinessStatus {
+    /// Parses the 4 data bytes of PID 01
+    pub fn from_pid01(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 4 {
+            return None;
+        }
+        let ignition = match bytes[1] & 0b0000_1000 {
+            0 => IgnitionType::Spark,
+            _ => IgnitionType::Compression,
+        };
+        Some(ReadinessStatus {
+            mil_on: bytes[0] & 0b1000_0000 != 0,
+            dtc_count: bytes[0] & 0b0111_1111,
+            ignition,
+            monitors: parse_monitors(&bytes[1..4], ignition),
+        })
+    }
+
+    /// Combines the status of 2 ECUs. Monitors are matched by name, so if the ECUs
+    /// report different ignition types, the monitors of both are kept
+    fn combine(&self, other: &Self) -> Self {
+        ReadinessStatus {
+            mil_on: self.mil_on || other.mil_on,
+            dtc_count: self.dtc_count.saturating_add(other.dtc_count),
+            ignition: self.ignition,
+            monitors: combine_monitors(&self.monitors, &other.monitors),
+        }
+    }
+
+    /// Returns the supported monitors which have not completed
+    pub fn get_incomplete_monitors(&self) -> Vec<&Monitor> {
+        self.monitors
+            .iter()
+            .filter(|m| m.state == MonitorState::Incomplete)
+            .collect()
+    }
+
+    /// Returns true if the vehicle would pass an emissions inspection. The MIL must be off
+    /// and at most [MAX_INCOMPLETE_MONITORS] monitors can be incomplete
+    pub fn is_ready_for_inspection(&self) -> bool {
+        !self.mil_on && self.get_incomplete_monitors().len() <= MAX_INCOMPLETE_MONITORS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readiness() {
+        // MIL on with 2 DTCs. Spark ignition, continuous monitors complete.
+        // Catalyst, EVAP and O2 sensor supported, EVAP incomplete
+        let status = ReadinessStatus::from_pid01(&[0x82, 0x07, 0x25, 0x04]).unwrap();
+        assert!(status.mil_on);
+        assert_eq!(status.dtc_count, 2);
+        assert_eq!(status.ignition, IgnitionType::Spark);
+        let incomplete: Vec<&str> = status
+            .get_incomplete_monitors()
+            .iter()
+            .map(|m| m.name)
+            .collect();
+        assert_eq!(incomplete, vec!["Evaporative system"]);
+        assert_eq!(
+            status
+                .monitors
+                .iter()
+                .find(|m| m.name == "Catalyst")
+                .unwrap()
+                .state,
+            MonitorState::Complete
+        );
+        assert!(!status.is_ready_for_inspection());
+
+        // Compression ignition, no reserved monitors
+        let diesel = ReadinessStatus::from_pid01(&[0x00, 0x0F, 0xE9, 0x60]).unwrap();
+        assert_eq!(diesel.ignition, IgnitionType::Compression);
+        assert_eq!(diesel.monitors.len(), 9);
+        assert_eq!(diesel.get_incomplete_monitors().len(), 2);
+        assert!(!diesel.is_ready_for_inspection());
+
+        // Second ECU has the MIL off and has completed its EVAP monitor
+        let combined =
+            status.combine(&ReadinessStatus::from_pid01(&[0x00, 0x07, 0x04, 0x00]).unwrap());
+        assert!(combined.mil_on);
+        assert_eq!(combined.get_incomplete_monitors().len(), 1);
+        assert_eq!(combined.monitors.len(), status.monitors.len());
+
+        // A diesel ECU's monitors are not mixed up with the spark ignition ones
+        let mixed = status.combine(&diesel);
+        assert_eq!(mixed.ignition, IgnitionType::Spark);
+        assert_eq!(mixed.monitors.len(), 17);
+        let state = |name: &str| {
+            mixed
+                .monitors
+                .iter()
+                .find(|m| m.name == name)
+                .unwrap()
+                .state
+        };
+        assert_eq!(state("Catalyst"), MonitorState::Complete);
+        assert_eq!(state("Evaporative system"), MonitorState::Incomplete);
+        assert_eq!(state("NMHC catalyst"), MonitorState::Complete);
+        assert_eq!(state("PM filter"), MonitorState::Incomplete);
+        assert_eq!(mixed.get_incomplete_monitors().len(), 3);
+    }
+}
@@ -1,2 +1,144 @@
+use crate::commapi::protocols::{DTCState, ProtocolError, ProtocolServer, DTC};
+
+use super::{
+    get_obd_bits,
+    service01::{PidReturnType, PID_LIST},
+    OBDError, ObdError, ObdServer, ObdService,
+};
+
+// Service 02 reads freeze frame data. When an emissions related DTC is stored, the ECU takes
+// a snapshot of its Service 01 PIDs, so that the conditions at the time of the fault can be
+// seen later on. Requests are the PID followed by the frame number, and PID 0x02 of a frame is
+// the DTC which caused the frame to be stored (0x0000 if the frame is empty).
+//
+// PIDs are decoded the same way as Service 01 PIDs.
+
+/// PID containing the DTC which caused the freeze frame to be stored
+const PID_FREEZE_FRAME_DTC: u8 = 0x02;
+
 #[derive(Debug, Clone)]
-pub struct Service02;
+pub struct Service02 {
+    supported_pids: Vec<bool>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FreezeFrame {
+    pub frame: u8,
+    /// DTC which caused the frame to be stored
+    pub dtc: DTC,
+    /// Decoded value of each PID stored in the frame
+    pub values: Vec<(u8, PidReturnType<'static>)>,
+}
+
+impl FreezeFrame {
+    /// Returns the name and value of everything stored in the frame
+    pub fn get_display_values(&self) -> Vec<(String, String)> {
+        let mut res = Vec::new();
+        for (pid, value) in &self.values {
+            match value {
+                PidReturnType::Number(r) => res.push((r.get_desc().into(), r.get_value_string())),
+                PidReturnType::MultiNumber(list) => {
+                    for r in list {
+                        res.push((r.get_desc().into(), r.get_value_string()))
+                    }
+                }
+                PidReturnType::String(s) => {
+                    let name = PID_LIST
+                        .get_desc_pid(*pid)
+                        .map(|(_, desc)| desc[0].to_string())
+                        .unwrap_or(format!("PID {:02X}", pid));
+                    res.push((name, s.clone()))
+                }
+            }
+        }
+        res
+    }
+}
+
+impl ObdService for Service02 {
+    fn init(s: &ObdServer) -> Option<Self> {
+        println!("Attempt init service 02!");
+        // Response is SID, PID, frame number then 4 bytes of supported PIDs
+        let read_range = |range: u8| match s.run_command(0x02, &[range, 0x00]) {
+            Ok(res) if res.len() >= 7 => Some(get_obd_bits(&res[3..7])),
+            _ => None,
+        };
+        let mut s02 = Service02 {
+            supported_pids: read_range(0x00)?,
+        };
+        // Check the rest of the PID ranges (21-40, 41-60...)
+        for range in (0x20..=0xC0).step_by(0x20) {
+            if s02.check_service_supported(range).is_err() {
+                break;
+            }
+            s02.supported_pids.append(&mut read_range(range)?);
+        }
+        Some(s02)
+    }
+}
+
+impl Service02 {
+    fn check_service_supported(&self, pid: u8) -> OBDError<()> {
+        if let Some(r) = self.supported_pids.get(pid as usize - 1) {
+            // -1 as pid 0x00 is not here
+            match r {
+                true => Ok(()),
+                false => Err(ProtocolError::ProtocolError(Box::new(
+                    ObdError::CmdNotSupported,
+                ))),
+            }
+        } else {
+            Err(ProtocolError::ProtocolError(Box::new(
+                ObdError::CmdNotSupported,
+            )))
+        }
+    }
+
+    /// Returns the PIDs that can be stored in a freeze frame
+    pub fn get_supported_pids(&self) -> Vec<u8> {
+        (0x01..0xFF as u8)
+            .filter(|x| x % 0x20 != 0 && *x != PID_FREEZE_FRAME_DTC)
+            .filter(|x| self.check_service_supported(*x).is_ok())
+            .collect()
+    }
+
+    /// Returns the DTC which caused a freeze frame to be stored,
+    /// or None if the frame is empty
+    pub fn get_freeze_frame_dtc(&self, s: &ObdServer, frame: u8) -> OBDError<Option<DTC>> {
+        self.check_service_supported(PID_FREEZE_FRAME_DTC)?;
+        let res = s.run_command(0x02, &[PID_FREEZE_FRAME_DTC, frame])?;
+        if res.len() < 5 {
+            return Err(ProtocolError::InvalidResponseSize {
+                expect: 5,
+                actual: res.len(),
+            });
+        }
+        match (res[3] as u16) << 8 | res[4] as u16 {
+            0x0000 => Ok(None),
+            id => Ok(Some(DTC::from_obd(id, DTCState::Stored))),
+        }
+    }
+
+    /// Reads a freeze frame, decoding every PID stored in it.
+    /// Returns None if the frame is empty
+    pub fn read_freeze_frame(&self, s: &ObdServer, frame: u8) -> OBDError<Option<FreezeFrame>> {
+        let dtc = match self.get_freeze_frame_dtc(s, frame)? {
+            Some(dtc) => dtc,
+            None => return Ok(None),
+        };
+        let mut values = Vec::new();
+        for pid in self.get_supported_pids() {
+            match s.run_command(0x02, &[pid, frame]) {
+                // Response is SID, PID, frame number then the PID's data
+                Ok(res) if res.len() > 3 && res[1] == pid => {
+                    if let Some(v) = PID_LIST.parse_pid(pid, &res[3..]) {
+                        values.push((pid, v))
+                    }
+                }
+                Ok(_) => eprintln!("Invalid freeze frame response for PID {:02X}", pid),
+                Err(e) => eprintln!("Could not read freeze frame PID {:02X}: {:?}", pid, e),
+            }
+        }
+        Ok(Some(FreezeFrame { frame, dtc, values }))
+    }
+}
@@ -1,36 +1,49 @@
-use crate::commapi::protocols::{DTCState, ProtocolServer, DTC};
+use std::collections::BTreeMap;
+
+use crate::commapi::protocols::{DTCState, DTC};
 
 use super::{OBDError, ObdServer};
 
+// Service 03 reads the emissions related DTCs which are stored (confirmed) by each ECU,
+// and cause the MIL to turn on.
+//
+// On ISO15765-4 (CAN) each ECU responds with the number of DTCs followed by 2 bytes per DTC.
+// On K-Line and J1850 each ECU responds with up to 3 DTCs per message, padded with 0x0000.
+// These messages are merged into the CAN format before being decoded.
+
 #[derive(Debug, Clone)]
 pub struct Service03;
 
 impl Service03 {
+    /// Reads the stored DTCs of every emissions ECU
     pub fn read_dtcs(s: &ObdServer) -> OBDError<Vec<DTC>> {
-        let mut bytes = s.run_command(0x07, &[])?;
-        let num_dtcs = bytes[1];
-        bytes.drain(0..2);
+        Ok(Self::read_dtcs_by_ecu(s)?.into_values().flatten().collect())
+    }
+
+    /// Reads the stored DTCs of every emissions ECU, keyed by the ID of the ECU
+    pub fn read_dtcs_by_ecu(s: &ObdServer) -> OBDError<BTreeMap<u32, Vec<DTC>>> {
+        s.read_dtcs(0x03, DTCState::Stored)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_dtcs() {
         let mut res = Vec::new();
-        for _ in 0..num_dtcs {
-            let prefix = match (bytes[0] >> 6) & 0b0000011 {
-                1 => 'C', // Chassis
-                2 => 'B', // Body
-                3 => 'U', // Network
-                _ => 'P', // Powertrain
-            };
-            let n1 = (bytes[0] >> 4) & 0b0000011;
-            let n2 = (bytes[0] >> 2) & 0b0000011;
-            let dtc = DTC {
-                error: format!("{}{:1X}{:1X}{:2X}", prefix, n1, n2, bytes[1]),
-                state: DTCState::Stored, // TODO Fix this
-                check_engine_on: true,
-                id: bytes[1] as u32,
-            };
-            bytes.drain(0..2);
-            res.push(dtc);
-        }
-        // TODO
-        println!("DTC BYTES: {:02X?}", res);
-        Ok(res)
+        // CAN - Number of DTCs, then the DTCs
+        ObdServer::decode_dtc_resp(&[0x02, 0x01, 0x05, 0xC1, 0x00], DTCState::Stored, &mut res);
+        // K-Line - 3 DTCs per message, padded with 0x0000
+        let merged = ObdServer::merge_non_can_responses(vec![
+            vec![0x43, 0x01, 0x33, 0x00, 0x00, 0x00, 0x00],
+            vec![0x43, 0x41, 0x01, 0x00, 0x00, 0x00, 0x00],
+        ]);
+        ObdServer::decode_dtc_resp(&merged[1..], DTCState::Stored, &mut res);
+        // Truncated response
+        ObdServer::decode_dtc_resp(&[0x02, 0x03, 0x00], DTCState::Stored, &mut res);
+        let codes: Vec<String> = res.iter().map(|d| d.error.clone()).collect();
+        assert_eq!(codes, vec!["P0105", "U0100", "P0133", "C0101", "P0300"]);
     }
 }
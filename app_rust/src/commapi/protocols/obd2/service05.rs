@@ -1,2 +1,208 @@
+use crate::commapi::{
+    iface::InterfaceType,
+    protocols::{ProtocolError, ProtocolServer},
+};
+
+use super::{get_obd_bits, service06::get_tid_name, OBDError, ObdError, ObdServer, ObdService};
+
+// Service 05 reads the results of the oxygen sensor monitoring tests. This service is only on
+// K-Line and J1850, ISO15765-4 (CAN) vehicles report these tests with Service 06 instead.
+//
+// Requests are a Test ID (TID) followed by the O2 sensor. Sensors are a single bit, in the
+// same layout as Service 01 PID 13 (bits 0-3 are bank 1 sensors 1-4, 4-7 are bank 2).
+// Each response is the TID, sensor, test value, then optionally the minimum and maximum limits.
+//
+// TIDs 01-0A are defined by SAE J1979, the rest are up to the manufacturer.
+
+/// Last TID defined by SAE J1979
+const MAX_STANDARD_TID: u8 = 0x0A;
+
 #[derive(Debug, Clone)]
-pub struct Service05;
+pub struct Service05 {
+    supported_tids: Vec<bool>,
+    /// O2 sensors present, one bit each
+    sensors: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct O2SensorTestResult {
+    pub tid: u8,
+    /// O2 sensor bit
+    pub sensor: u8,
+    pub value: u8,
+    pub min: Option<u8>,
+    pub max: Option<u8>,
+}
+
+impl O2SensorTestResult {
+    /// Converts a raw value into physical units
+    fn convert(&self, raw: u8) -> f32 {
+        match get_tid_scaling(self.tid) {
+            Some((scale, _)) => raw as f32 * scale,
+            None => raw as f32,
+        }
+    }
+
+    /// Converts a raw value into a string, in physical units if possible
+    pub fn format_value(&self, raw: u8) -> String {
+        match get_tid_scaling(self.tid) {
+            Some((_, unit)) => format!("{:.3} {}", self.convert(raw), unit),
+            None => format!("{} (Raw)", raw),
+        }
+    }
+
+    /// Returns true if the test value is within its limits
+    pub fn is_passed(&self) -> bool {
+        let above_min = self.min.map(|m| self.value >= m).unwrap_or(true);
+        let below_max = self.max.map(|m| self.value <= m).unwrap_or(true);
+        above_min && below_max
+    }
+
+    /// Returns the bank and position of the O2 sensor
+    pub fn get_sensor_name(&self) -> String {
+        let bit = self.sensor.trailing_zeros();
+        format!("Bank {} - Sensor {}", bit / 4 + 1, bit % 4 + 1)
+    }
+
+    /// Returns the name of the test
+    pub fn get_test_name(&self) -> String {
+        get_tid_name(self.tid)
+    }
+}
+
+impl ObdService for Service05 {
+    fn init(s: &ObdServer) -> Option<Self> {
+        if s.get_interface_type() == InterfaceType::IsoTp {
+            return None; // Service 06 is used on CAN
+        }
+        println!("Attempt init service 05!");
+        // Find which O2 sensors are present (Service 01 PID 13)
+        let sensors: Vec<u8> = match s.run_command(0x01, &[0x13]) {
+            Ok(res) if res.len() > 2 => {
+                (0..8).map(|b| 1 << b).filter(|b| res[2] & b != 0).collect()
+            }
+            _ => vec![0x01], // Assume only bank 1 sensor 1
+        };
+        let res = s.run_command(0x05, &[0x00, *sensors.first()?]).ok()?;
+        if res.len() < 7 {
+            return None;
+        }
+        Some(Service05 {
+            supported_tids: get_obd_bits(&res[3..7]), // Drop SID, TID and sensor
+            sensors,
+        })
+    }
+}
+
+impl Service05 {
+    fn check_service_supported(&self, tid: u8) -> OBDError<()> {
+        if let Some(r) = self.supported_tids.get(tid as usize - 1) {
+            // -1 as tid 0x00 is not here
+            match r {
+                true => Ok(()),
+                false => Err(ProtocolError::ProtocolError(Box::new(
+                    ObdError::CmdNotSupported,
+                ))),
+            }
+        } else {
+            Err(ProtocolError::ProtocolError(Box::new(
+                ObdError::CmdNotSupported,
+            )))
+        }
+    }
+
+    /// Returns the standard TIDs (01-0A) the ECU supports
+    pub fn get_supported_tids(&self) -> Vec<u8> {
+        (0x01..=MAX_STANDARD_TID)
+            .filter(|x| self.check_service_supported(*x).is_ok())
+            .collect()
+    }
+
+    /// Returns the bit of each O2 sensor present
+    pub fn get_sensors(&self) -> Vec<u8> {
+        self.sensors.clone()
+    }
+
+    /// Reads the result of a single test of an O2 sensor
+    pub fn get_test_result(
+        &self,
+        s: &ObdServer,
+        tid: u8,
+        sensor: u8,
+    ) -> OBDError<O2SensorTestResult> {
+        self.check_service_supported(tid)?;
+        let res = s.run_command(0x05, &[tid, sensor])?;
+        Self::parse_result(tid, sensor, &res)
+    }
+
+    /// Reads the result of every supported test, for every O2 sensor
+    pub fn get_all_test_results(&self, s: &ObdServer) -> Vec<O2SensorTestResult> {
+        let mut res = Vec::new();
+        for sensor in &self.sensors {
+            for tid in self.get_supported_tids() {
+                match self.get_test_result(s, tid, *sensor) {
+                    Ok(r) => res.push(r),
+                    Err(e) => eprintln!(
+                        "Could not read test {:02X} of O2 sensor {:02X}: {:?}",
+                        tid, sensor, e
+                    ),
+                }
+            }
+        }
+        res
+    }
+
+    fn parse_result(tid: u8, sensor: u8, res: &[u8]) -> OBDError<O2SensorTestResult> {
+        if res.len() < 4 {
+            return Err(ProtocolError::InvalidResponseSize {
+                expect: 4,
+                actual: res.len(),
+            });
+        }
+        if res[1] != tid || res[2] != sensor {
+            return Err(ProtocolError::CustomError(
+                "ECU responded with a different test".into(),
+            ));
+        }
+        Ok(O2SensorTestResult {
+            tid,
+            sensor,
+            value: res[3],
+            min: res.get(4).copied(),
+            max: res.get(5).copied(),
+        })
+    }
+}
+
+/// Returns the scale and unit of a SAE J1979 O2 sensor TID
+fn get_tid_scaling(tid: u8) -> Option<(f32, &'static str)> {
+    match tid {
+        0x01..=0x04 | 0x07 | 0x08 => Some((0.005, "V")),
+        0x05 | 0x06 => Some((0.004, "s")),
+        0x09 | 0x0A => Some((0.04, "s")),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn o2_sensor_results() {
+        // Bank 2 sensor 1, 0.450V with limits of 0.400-0.500V
+        let res =
+            Service05::parse_result(0x01, 0x10, &[0x45, 0x01, 0x10, 0x5A, 0x50, 0x64]).unwrap();
+        assert_eq!(res.format_value(res.value), "0.450 V");
+        assert_eq!(res.get_sensor_name(), "Bank 2 - Sensor 1");
+        assert!(res.is_passed());
+
+        // Switch time without limits
+        let res = Service05::parse_result(0x05, 0x02, &[0x45, 0x05, 0x02, 0x19]).unwrap();
+        assert_eq!(res.format_value(res.value), "0.100 s");
+        assert_eq!(res.get_sensor_name(), "Bank 1 - Sensor 2");
+        assert_eq!((res.min, res.max), (None, None));
+
+        assert!(Service05::parse_result(0x01, 0x01, &[0x45, 0x02, 0x01, 0x00]).is_err());
+    }
+}
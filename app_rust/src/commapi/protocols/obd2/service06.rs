@@ -1,2 +1,292 @@
+use crate::commapi::{
+    iface::InterfaceType,
+    protocols::{ProtocolError, ProtocolServer},
+};
+
+use super::{get_obd_bits, unit_scaling::UnitScaling, OBDError, ObdError, ObdServer, ObdService};
+
+// Service 06 reads the results of the on-board monitoring tests, such as the catalyst,
+// oxygen sensor and EVAP tests. Each result has a test value and its limits, so that it can
+// be seen how close a monitor is to failing.
+//
+// There are 2 formats of this service:
+// * ISO15765-4 (CAN) - Requests are an OBD Monitor ID (OBDMID). Each OBDMID responds with
+//   one or more 9 byte test results: OBDMID, Test ID (TID), Unit and Scaling ID, then the
+//   test value, minimum limit and maximum limit as 2 bytes each.
+// * Non-CAN (K-Line and J1850) - Requests are a Test ID. Each component tested responds with
+//   a message of Component ID (CID), then the test value and a single limit as 2 bytes each.
+//   The top bit of the CID says if the limit is a minimum (1) or maximum (0). Scaling of these
+//   values is up to the manufacturer.
+
+/// Size of a test result record on CAN
+const CAN_RECORD_SIZE: usize = 9;
+/// Size of a test result record (once merged) on K-Line and J1850
+const NON_CAN_RECORD_SIZE: usize = 5;
+
 #[derive(Debug, Clone)]
-pub struct Service06;
+pub struct Service06 {
+    /// OBDMIDs on CAN, Test IDs on K-Line and J1850
+    supported_ids: Vec<bool>,
+    is_can: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct MonitorTestResult {
+    /// OBDMID on CAN, Test ID on K-Line and J1850
+    pub monitor_id: u8,
+    /// Test ID on CAN, Component ID on K-Line and J1850
+    pub test_id: u8,
+    /// How to convert the values into physical units. None means the values are raw
+    pub scaling: Option<UnitScaling>,
+    pub value: u16,
+    pub min: Option<u16>,
+    pub max: Option<u16>,
+    /// Only CAN IDs are standardised
+    is_can: bool,
+}
+
+impl MonitorTestResult {
+    fn convert(&self, raw: u16) -> f32 {
+        match self.scaling {
+            Some(s) => s.convert(raw),
+            None => raw as f32,
+        }
+    }
+
+    /// Converts a raw value into a string, in physical units if possible
+    pub fn format_value(&self, raw: u16) -> String {
+        match self.scaling {
+            Some(s) => s.format(raw),
+            None => format!("{} (Raw)", raw),
+        }
+    }
+
+    /// Returns true if the test value is within its limits
+    pub fn is_passed(&self) -> bool {
+        let value = self.convert(self.value);
+        let above_min = self.min.map(|m| value >= self.convert(m)).unwrap_or(true);
+        let below_max = self.max.map(|m| value <= self.convert(m)).unwrap_or(true);
+        above_min && below_max
+    }
+
+    /// Returns the name of the monitor the test belongs to
+    pub fn get_monitor_name(&self) -> String {
+        if self.is_can {
+            get_obdmid_name(self.monitor_id)
+        } else {
+            format!("Test ID {:02X}", self.monitor_id)
+        }
+    }
+
+    /// Returns the name of the test
+    pub fn get_test_name(&self) -> String {
+        if self.is_can {
+            get_tid_name(self.test_id)
+        } else {
+            format!("Component ID {:02X}", self.test_id)
+        }
+    }
+}
+
+impl ObdService for Service06 {
+    fn init(s: &ObdServer) -> Option<Self> {
+        println!("Attempt init service 06!");
+        let mut s06 = Service06 {
+            supported_ids: Vec::new(),
+            is_can: s.get_interface_type() == InterfaceType::IsoTp,
+        };
+        // Check each range of IDs (01-20, 21-40...) whilst the next range is supported
+        for range in (0x00..=0xE0).step_by(0x20) {
+            if range != 0x00 && s06.check_service_supported(range).is_err() {
+                break;
+            }
+            let res = s.run_command(0x06, &[range]).ok()?;
+            if res.len() < 6 {
+                return None;
+            }
+            // Support bits are always the last 4 bytes, non-CAN responses may have a CID
+            s06.supported_ids
+                .append(&mut get_obd_bits(&res[res.len() - 4..]));
+        }
+        Some(s06)
+    }
+}
+
+impl Service06 {
+    fn check_service_supported(&self, id: u8) -> OBDError<()> {
+        if let Some(r) = self.supported_ids.get(id as usize - 1) {
+            // -1 as id 0x00 is not here
+            match r {
+                true => Ok(()),
+                false => Err(ProtocolError::ProtocolError(Box::new(
+                    ObdError::CmdNotSupported,
+                ))),
+            }
+        } else {
+            Err(ProtocolError::ProtocolError(Box::new(
+                ObdError::CmdNotSupported,
+            )))
+        }
+    }
+
+    /// Returns the OBDMIDs (CAN) or Test IDs (K-Line and J1850) with test results
+    pub fn get_supported_ids(&self) -> Vec<u8> {
+        (0x01..0xFF as u8)
+            .filter(|x| x % 0x20 != 0)
+            .filter(|x| self.check_service_supported(*x).is_ok())
+            .collect()
+    }
+
+    /// Reads the test results of a single OBDMID (CAN) or Test ID (K-Line and J1850)
+    pub fn get_test_results(&self, s: &ObdServer, id: u8) -> OBDError<Vec<MonitorTestResult>> {
+        self.check_service_supported(id)?;
+        let res = s.run_command(0x06, &[id])?;
+        if res.len() < 2 || res[1] != id {
+            return Err(ProtocolError::CustomError(
+                "ECU responded with a different test".into(),
+            ));
+        }
+        if self.is_can {
+            // Every result starts with the OBDMID
+            Ok(Self::parse_can_results(id, &res[1..]))
+        } else {
+            Ok(Self::parse_non_can_results(id, &res[2..]))
+        }
+    }
+
+    /// Reads the test results of every supported monitor
+    pub fn get_all_test_results(&self, s: &ObdServer) -> Vec<MonitorTestResult> {
+        let mut res = Vec::new();
+        for id in self.get_supported_ids() {
+            match self.get_test_results(s, id) {
+                Ok(mut results) => res.append(&mut results),
+                Err(e) => eprintln!("Could not read test results of {:02X}: {:?}", id, e),
+            }
+        }
+        res
+    }
+
+    fn parse_can_results(mid: u8, bytes: &[u8]) -> Vec<MonitorTestResult> {
+        bytes
+            .chunks_exact(CAN_RECORD_SIZE)
+            .filter(|r| r[0] == mid)
+            .map(|r| MonitorTestResult {
+                monitor_id: mid,
+                test_id: r[1],
+                scaling: UnitScaling::from_id(r[2]),
+                value: (r[3] as u16) << 8 | r[4] as u16,
+                min: Some((r[5] as u16) << 8 | r[6] as u16),
+                max: Some((r[7] as u16) << 8 | r[8] as u16),
+                is_can: true,
+            })
+            .collect()
+    }
+
+    fn parse_non_can_results(tid: u8, bytes: &[u8]) -> Vec<MonitorTestResult> {
+        bytes
+            .chunks_exact(NON_CAN_RECORD_SIZE)
+            .map(|r| {
+                let limit = (r[3] as u16) << 8 | r[4] as u16;
+                let is_min_limit = r[0] & 0x80 != 0;
+                MonitorTestResult {
+                    monitor_id: tid,
+                    test_id: r[0] & 0x7F,
+                    scaling: None,
+                    value: (r[1] as u16) << 8 | r[2] as u16,
+                    min: if is_min_limit { Some(limit) } else { None },
+                    max: if is_min_limit { None } else { Some(limit) },
+                    is_can: false,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Returns the name of a SAE J1979 OBD Monitor ID
+pub fn get_obdmid_name(mid: u8) -> String {
+    match mid {
+        0x01..=0x10 => format!(
+            "Oxygen sensor monitor Bank {} - Sensor {}",
+            (mid - 1) / 4 + 1,
+            (mid - 1) % 4 + 1
+        ),
+        0x21..=0x24 => format!("Catalyst monitor Bank {}", mid - 0x20),
+        0x31..=0x38 => format!("EGR / VVT monitor Bank {}", mid - 0x30),
+        0x39 => "EVAP monitor (Cap off / 0.150\")".into(),
+        0x3A => "EVAP monitor (0.090\")".into(),
+        0x3B => "EVAP monitor (0.040\")".into(),
+        0x3C => "EVAP monitor (0.020\")".into(),
+        0x3D => "Purge flow monitor".into(),
+        0x41..=0x50 => format!(
+            "Oxygen sensor heater monitor Bank {} - Sensor {}",
+            (mid - 0x41) / 4 + 1,
+            (mid - 0x41) % 4 + 1
+        ),
+        0x61..=0x64 => format!("Heated catalyst monitor Bank {}", mid - 0x60),
+        0x71..=0x74 => format!("Secondary air monitor {}", mid - 0x70),
+        0x81..=0x84 => format!("Fuel system monitor Bank {}", mid - 0x80),
+        0x85..=0x86 => format!("Boost pressure control monitor Bank {}", mid - 0x84),
+        0x90..=0x91 => format!("NOx adsorber monitor Bank {}", mid - 0x8F),
+        0x98..=0x99 => format!("NOx catalyst monitor Bank {}", mid - 0x97),
+        0xA1 => "Misfire monitor general data".into(),
+        0xA2..=0xAD => format!("Misfire cylinder {} data", mid - 0xA1),
+        0xB0..=0xB1 => format!("PM filter monitor Bank {}", mid - 0xAF),
+        _ => format!("Monitor {:02X}", mid),
+    }
+}
+
+/// Returns the name of a SAE J1979 Test ID. Test IDs above 0x0A are manufacturer defined
+pub fn get_tid_name(tid: u8) -> String {
+    match tid {
+        0x01 => "Rich to lean sensor threshold voltage",
+        0x02 => "Lean to rich sensor threshold voltage",
+        0x03 => "Low sensor voltage for switch time calculation",
+        0x04 => "High sensor voltage for switch time calculation",
+        0x05 => "Rich to lean sensor switch time",
+        0x06 => "Lean to rich sensor switch time",
+        0x07 => "Minimum sensor voltage for test cycle",
+        0x08 => "Maximum sensor voltage for test cycle",
+        0x09 => "Time between sensor transitions",
+        0x0A => "Sensor period",
+        _ => return format!("Test {:02X}", tid),
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_results() {
+        let res = Service06::parse_can_results(
+            0x01,
+            &[
+                0x01, 0x01, 0x0A, 0x0D, 0x05, 0x0C, 0x00, 0x0F, 0x00, // 406mV, 374-468mV
+                0x01, 0x05, 0x10, 0x00, 0x80, 0x00, 0x00, 0x00, 0x48, // 128ms, 0-72ms
+            ],
+        );
+        assert_eq!(res.len(), 2);
+        assert!(res[0].is_passed());
+        assert_eq!(res[0].format_value(res[0].value), "406.626 mV");
+        assert_eq!(
+            res[0].get_test_name(),
+            "Rich to lean sensor threshold voltage"
+        );
+        assert!(!res[1].is_passed());
+    }
+
+    #[test]
+    fn non_can_results() {
+        let merged = ObdServer::merge_non_can_responses(vec![
+            vec![0x46, 0x05, 0x11, 0x00, 0x20, 0x00, 0x30], // Below maximum
+            vec![0x46, 0x05, 0x92, 0x00, 0x20, 0x00, 0x30], // Below minimum
+        ]);
+        let res = Service06::parse_non_can_results(0x05, &merged[2..]);
+        assert_eq!(res.len(), 2);
+        assert_eq!((res[0].test_id, res[0].max), (0x11, Some(0x30)));
+        assert!(res[0].is_passed());
+        assert_eq!((res[1].test_id, res[1].min), (0x12, Some(0x30)));
+        assert!(!res[1].is_passed());
+    }
+}
@@ -1,2 +1,26 @@
+use std::collections::BTreeMap;
+
+use crate::commapi::protocols::{DTCState, DTC};
+
+use super::{OBDError, ObdServer};
+
+// Service 07 reads the emissions related DTCs which are pending. A pending DTC has been
+// detected during the current or last drive cycle, but has not yet been confirmed, so the
+// MIL is not on. This can be used to check a repair straight after clearing DTCs.
+//
+// Requests and responses are the same format as Service 03.
+
 #[derive(Debug, Clone)]
 pub struct Service07;
+
+impl Service07 {
+    /// Reads the pending DTCs of every emissions ECU
+    pub fn read_dtcs(s: &ObdServer) -> OBDError<Vec<DTC>> {
+        Ok(Self::read_dtcs_by_ecu(s)?.into_values().flatten().collect())
+    }
+
+    /// Reads the pending DTCs of every emissions ECU, keyed by the ID of the ECU
+    pub fn read_dtcs_by_ecu(s: &ObdServer) -> OBDError<BTreeMap<u32, Vec<DTC>>> {
+        s.read_dtcs(0x07, DTCState::Pending)
+    }
+}
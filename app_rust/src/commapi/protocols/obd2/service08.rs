@@ -1,2 +1,122 @@
+use crate::commapi::{
+    iface::InterfaceType,
+    protocols::{CautionLevel, ProtocolError, ProtocolServer},
+};
+
+use super::{get_obd_bits, OBDError, ObdError, ObdServer, ObdService};
+
+// Service 08 controls on-board systems, tests or components. Unlike every other OBD service,
+// this can change how the vehicle behaves, so each test has a caution level which must be
+// accepted by the caller before the test is run.
+//
+// Requests are a Test ID (TID). On K-Line and J1850 the TID is always followed by 5 data
+// bytes (0x00 if unused). Responses are the TID, followed by any data the test returns.
+//
+// Only TID 01 (EVAP system leak test) is defined by SAE J1979, the rest are up to the
+// manufacturer.
+
+/// TID of the EVAP system leak test
+pub const TID_EVAP_LEAK_TEST: u8 = 0x01;
+
 #[derive(Debug, Clone)]
-pub struct Service08;
+pub struct Service08 {
+    supported_tids: Vec<bool>,
+    is_can: bool,
+}
+
+impl ObdService for Service08 {
+    fn init(s: &ObdServer) -> Option<Self> {
+        println!("Attempt init service 08!");
+        let mut s08 = Service08 {
+            supported_tids: Vec::new(),
+            is_can: s.get_interface_type() == InterfaceType::IsoTp,
+        };
+        // Check each range of TIDs (01-20, 21-40...) whilst the next range is supported
+        for range in (0x00..=0xE0).step_by(0x20) {
+            if range != 0x00 && s08.check_service_supported(range).is_err() {
+                break;
+            }
+            let res = s.run_command(0x08, &s08.build_request(range)).ok()?;
+            if res.len() < 6 {
+                return None;
+            }
+            s08.supported_tids.append(&mut get_obd_bits(&res[2..6]));
+        }
+        Some(s08)
+    }
+}
+
+impl Service08 {
+    fn check_service_supported(&self, tid: u8) -> OBDError<()> {
+        if let Some(r) = self.supported_tids.get(tid as usize - 1) {
+            // -1 as tid 0x00 is not here
+            match r {
+                true => Ok(()),
+                false => Err(ProtocolError::ProtocolError(Box::new(
+                    ObdError::CmdNotSupported,
+                ))),
+            }
+        } else {
+            Err(ProtocolError::ProtocolError(Box::new(
+                ObdError::CmdNotSupported,
+            )))
+        }
+    }
+
+    fn build_request(&self, tid: u8) -> Vec<u8> {
+        if self.is_can {
+            vec![tid]
+        } else {
+            vec![tid, 0x00, 0x00, 0x00, 0x00, 0x00]
+        }
+    }
+
+    /// Returns the TIDs the ECU supports
+    pub fn get_supported_tids(&self) -> Vec<u8> {
+        (0x01..0xFF as u8)
+            .filter(|x| x % 0x20 != 0)
+            .filter(|x| self.check_service_supported(*x).is_ok())
+            .collect()
+    }
+
+    /// Returns the name of a test
+    pub fn get_tid_name(tid: u8) -> String {
+        match tid {
+            TID_EVAP_LEAK_TEST => "EVAP system leak test".into(),
+            _ => format!("Manufacturer test {:02X}", tid),
+        }
+    }
+
+    /// Returns how cautious the user should be before running a test
+    pub fn get_caution_level(tid: u8) -> CautionLevel {
+        match tid {
+            // Seals the EVAP system. The ECU only runs this if the vehicle is stationary
+            TID_EVAP_LEAK_TEST => CautionLevel::Warn,
+            // What manufacturer tests do is unknown
+            _ => CautionLevel::Alert,
+        }
+    }
+
+    /// Runs a test, returning any data the ECU responded with.
+    ///
+    /// `accepted` is the caution level the user has confirmed. If the test needs a
+    /// higher level than this, it is not run.
+    pub fn run_test(&self, s: &ObdServer, tid: u8, accepted: CautionLevel) -> OBDError<Vec<u8>> {
+        self.check_service_supported(tid)?;
+        let required = Self::get_caution_level(tid);
+        if required > accepted {
+            return Err(ProtocolError::CustomError(format!(
+                "{} needs {:?} caution level to be accepted before running",
+                Self::get_tid_name(tid),
+                required
+            )));
+        }
+        let res = s.run_command(0x08, &self.build_request(tid))?;
+        if res.len() < 2 || res[1] != tid {
+            return Err(ProtocolError::CustomError(
+                "ECU responded with a different test".into(),
+            ));
+        }
+        Ok(res[2..].to_vec())
+    }
+}
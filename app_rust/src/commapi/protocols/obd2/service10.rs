@@ -1,2 +1,26 @@
+use std::collections::BTreeMap;
+
+use crate::commapi::protocols::{DTCState, DTC};
+
+use super::{OBDError, ObdServer};
+
+// Service 0A reads the emissions related DTCs which are permanent. A permanent DTC cannot be
+// cleared with Service 04, only the ECU can clear it once its monitor has run and passed.
+// Only vehicles from 2010 onwards have to support this service, so older ECUs won't respond.
+//
+// Requests and responses are the same format as Service 03.
+
 #[derive(Debug, Clone)]
 pub struct Service0A;
+
+impl Service0A {
+    /// Reads the permanent DTCs of every emissions ECU
+    pub fn read_dtcs(s: &ObdServer) -> OBDError<Vec<DTC>> {
+        Ok(Self::read_dtcs_by_ecu(s)?.into_values().flatten().collect())
+    }
+
+    /// Reads the permanent DTCs of every emissions ECU, keyed by the ID of the ECU
+    pub fn read_dtcs_by_ecu(s: &ObdServer) -> OBDError<BTreeMap<u32, Vec<DTC>>> {
+        s.read_dtcs(0x0A, DTCState::Permanent)
+    }
+}
@@ -0,0 +1,187 @@
+// SAE J1979 Unit and Scaling IDs (Appendix E).
+//
+// On ISO15765-4, Service 06 test results come with a Unit and Scaling ID, saying how to
+// convert the raw 2 byte value, and its limits, into physical units. IDs 0x01-0x7F are
+// unsigned values, and IDs 0x81-0xFE are signed (two's complement) values.
+
+/// Scaling of a Unit and Scaling ID
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct UnitScaling {
+    pub id: u8,
+    /// Physical value per bit
+    pub scale: f32,
+    /// Added to the value once scaled
+    pub offset: f32,
+    pub unit: &'static str,
+}
+
+// (ID, scale, offset, unit)
+const UNIT_SCALING_TABLE: &[(u8, f32, f32, &str)] = &[
+    // Unsigned
+    (0x01, 1.0, 0.0, ""),
+    (0x02, 0.1, 0.0, ""),
+    (0x03, 0.01, 0.0, ""),
+    (0x04, 0.001, 0.0, ""),
+    (0x05, 0.0000305, 0.0, ""),
+    (0x06, 0.000305, 0.0, ""),
+    (0x07, 0.25, 0.0, "rpm"),
+    (0x08, 0.01, 0.0, "km/h"),
+    (0x09, 1.0, 0.0, "km/h"),
+    (0x0A, 0.122, 0.0, "mV"),
+    (0x0B, 0.001, 0.0, "V"),
+    (0x0C, 0.01, 0.0, "V"),
+    (0x0D, 0.00390625, 0.0, "mA"),
+    (0x0E, 0.001, 0.0, "A"),
+    (0x0F, 0.01, 0.0, "A"),
+    (0x10, 1.0, 0.0, "ms"),
+    (0x11, 100.0, 0.0, "ms"),
+    (0x12, 1.0, 0.0, "s"),
+    (0x13, 1.0, 0.0, "m\u{03A9}"),
+    (0x14, 1.0, 0.0, "\u{03A9}"),
+    (0x15, 1.0, 0.0, "k\u{03A9}"),
+    (0x16, 0.1, -40.0, "\u{00B0}C"),
+    (0x17, 0.01, 0.0, "kPa"),
+    (0x18, 0.0117, 0.0, "kPa"),
+    (0x19, 0.079, 0.0, "kPa"),
+    (0x1A, 1.0, 0.0, "kPa"),
+    (0x1B, 10.0, 0.0, "kPa"),
+    (0x1C, 0.01, 0.0, "\u{00B0}"),
+    (0x1D, 0.5, 0.0, "\u{00B0}"),
+    (0x1E, 0.0000305, 0.0, "lambda"),
+    (0x1F, 0.05, 0.0, "A/F ratio"),
+    (0x20, 0.0039062, 0.0, ""),
+    (0x21, 1.0, 0.0, "mHz"),
+    (0x22, 1.0, 0.0, "Hz"),
+    (0x23, 1.0, 0.0, "kHz"),
+    (0x24, 1.0, 0.0, "counts"),
+    (0x25, 1.0, 0.0, "km"),
+    (0x26, 0.0001, 0.0, "V/ms"),
+    (0x27, 0.01, 0.0, "g/s"),
+    (0x28, 1.0, 0.0, "g/s"),
+    (0x29, 0.25, 0.0, "Pa/s"),
+    (0x2A, 0.001, 0.0, "kg/h"),
+    (0x2B, 1.0, 0.0, "switches"),
+    (0x2C, 0.01, 0.0, "g/cyl"),
+    (0x2D, 0.01, 0.0, "mg/stroke"),
+    (0x2E, 1.0, 0.0, "true/false"),
+    (0x2F, 0.01, 0.0, "%"),
+    (0x30, 0.001526, 0.0, "%"),
+    (0x31, 0.001, 0.0, "L"),
+    (0x32, 0.0007747, 0.0, "mm"),
+    (0x33, 0.00024414, 0.0, "lambda"),
+    (0x34, 1.0, 0.0, "min"),
+    (0x35, 10.0, 0.0, "ms"),
+    (0x36, 0.01, 0.0, "g"),
+    (0x37, 0.1, 0.0, "g"),
+    (0x38, 1.0, 0.0, "g"),
+    (0x39, 0.01, -327.68, "%"),
+    (0x3A, 0.001, 0.0, "g"),
+    (0x3B, 0.0001, 0.0, "g"),
+    (0x3C, 0.1, 0.0, "\u{00B5}s"),
+    (0x3D, 0.01, 0.0, "mA"),
+    (0x3E, 0.00006103516, 0.0, "mm\u{00B2}"),
+    (0x3F, 0.01, 0.0, "L"),
+    (0x40, 1.0, 0.0, "ppm"),
+    (0x41, 0.01, 0.0, "\u{00B5}A"),
+    // Signed
+    (0x81, 1.0, 0.0, ""),
+    (0x82, 0.1, 0.0, ""),
+    (0x83, 0.01, 0.0, ""),
+    (0x84, 0.001, 0.0, ""),
+    (0x85, 0.0000305, 0.0, ""),
+    (0x86, 0.000305, 0.0, ""),
+    (0x87, 1.0, 0.0, "ppm"),
+    (0x8A, 0.122, 0.0, "mV"),
+    (0x8B, 0.001, 0.0, "V"),
+    (0x8C, 0.01, 0.0, "V"),
+    (0x8D, 0.00390625, 0.0, "mA"),
+    (0x8E, 0.001, 0.0, "A"),
+    (0x90, 1.0, 0.0, "ms"),
+    (0x96, 0.1, 0.0, "\u{00B0}C"),
+    (0x9C, 0.01, 0.0, "\u{00B0}"),
+    (0x9D, 0.5, 0.0, "\u{00B0}"),
+    (0xA8, 1.0, 0.0, "g/s"),
+    (0xA9, 0.25, 0.0, "Pa/s"),
+    (0xAD, 0.01, 0.0, "mg/stroke"),
+    (0xAE, 0.1, 0.0, "mg/stroke"),
+    (0xAF, 0.01, 0.0, "%"),
+    (0xB0, 0.003052, 0.0, "%"),
+    (0xB1, 2.0, 0.0, "mV/s"),
+    (0xFC, 0.01, 0.0, "kPa"),
+    (0xFD, 0.001, 0.0, "kPa"),
+    (0xFE, 0.25, 0.0, "Pa"),
+];
+
+impl UnitScaling {
+    /// Looks up a Unit and Scaling ID. Returns None if the ID is reserved
+    pub fn from_id(id: u8) -> Option<Self> {
+        UNIT_SCALING_TABLE
+            .iter()
+            .find(|(x, _, _, _)| *x == id)
+            .map(|(id, scale, offset, unit)| UnitScaling {
+                id: *id,
+                scale: *scale,
+                offset: *offset,
+                unit,
+            })
+    }
+
+    /// Returns true if values of this ID are signed
+    pub fn is_signed(&self) -> bool {
+        self.id & 0x80 != 0
+    }
+
+    /// Converts a raw value into physical units
+    pub fn convert(&self, raw: u16) -> f32 {
+        let value = if self.is_signed() {
+            raw as i16 as f32
+        } else {
+            raw as f32
+        };
+        value * self.scale + self.offset
+    }
+
+    /// Returns how many decimal places a value of this ID has
+    fn get_decimal_places(&self) -> usize {
+        (0..8)
+            .find(|d| {
+                let x = self.scale * 10f32.powi(*d as i32);
+                (x - x.round()).abs() < 0.0001
+            })
+            .unwrap_or(8)
+    }
+
+    /// Converts a raw value into physical units, formatted with its unit
+    pub fn format(&self, raw: u16) -> String {
+        let value = format!("{:.*}", self.get_decimal_places(), self.convert(raw));
+        if self.unit.is_empty() {
+            value
+        } else {
+            format!("{} {}", value, self.unit)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversion() {
+        let temp = UnitScaling::from_id(0x16).unwrap();
+        assert!(!temp.is_signed());
+        assert!((temp.convert(1000) - 60.0).abs() < 0.001);
+
+        let volts = UnitScaling::from_id(0x8C).unwrap();
+        assert!(volts.is_signed());
+        assert!((volts.convert(0xFF9C) + 1.0).abs() < 0.001);
+        assert_eq!(UnitScaling::from_id(0x0B).unwrap().format(450), "0.450 V");
+        assert_eq!(
+            UnitScaling::from_id(0x07).unwrap().format(3000),
+            "750.00 rpm"
+        );
+
+        assert!(UnitScaling::from_id(0x00).is_none());
+        assert!(UnitScaling::from_id(0x80).is_none());
+    }
+}
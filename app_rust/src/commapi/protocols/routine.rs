@@ -0,0 +1,284 @@
+use std::time::{Duration, Instant};
+
+use common::schema::diag::service::Service;
+
+use super::{
+    adjustment, kwp2000,
+    uds::{self, routine_control::RoutineControlType, UDSCommand},
+    DiagServer, ProtocolError, ProtocolResult,
+};
+
+// Routines are services from the ECU definition that start a routine on the ECU, with
+// RoutineControl ($31) on UDS, or StartRoutineByLocalIdentifier ($31) on KWP2000.
+//
+// Once started, the routine runs on the ECU by itself. Its results are polled for until the
+// ECU reports it has completed. Whilst running, the ECU either responds with busyRepeatRequest
+// (0x21) or routineNotComplete (0x23, KWP2000 only). ECUs that do not support requesting
+// results complete the routine within the start request, so its response is the result.
+
+/// Default time to wait for a routine to complete
+pub const DEFAULT_ROUTINE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Negative responses meaning the routine is still running
+const NRC_BUSY_REPEAT_REQUEST: u8 = 0x21;
+const NRC_ROUTINE_NOT_COMPLETE: u8 = 0x23;
+/// Negative responses meaning the ECU has no results to request
+const NRC_SERVICE_NOT_SUPPORTED: u8 = 0x11;
+const NRC_SUB_FUNCTION_NOT_SUPPORTED: u8 = 0x12;
+
+/// A routine request, parsed out of a routine service
+enum RoutineRequest {
+    Uds { id: u16, options: Vec<u8> },
+    Kwp { local_id: u8, options: Vec<u8> },
+}
+
+fn parse_request(server: &DiagServer, payload: &[u8]) -> ProtocolResult<RoutineRequest> {
+    let uds_sid: u8 = UDSCommand::RoutineControl.into();
+    let uds_start: u8 = RoutineControlType::StartRoutine.into();
+    let kwp_sid: u8 = kwp2000::Service::StartRoutineByLocalID.into();
+    match server {
+        DiagServer::UDS(_)
+            if payload.len() >= 4 && payload[0] == uds_sid && payload[1] == uds_start =>
+        {
+            Ok(RoutineRequest::Uds {
+                id: (payload[2] as u16) << 8 | payload[3] as u16,
+                options: Vec::from(&payload[4..]),
+            })
+        }
+        DiagServer::KWP2000(_) if payload.len() >= 2 && payload[0] == kwp_sid => {
+            Ok(RoutineRequest::Kwp {
+                local_id: payload[1],
+                options: Vec::from(&payload[2..]),
+            })
+        }
+        _ => Err(ProtocolError::CustomError(format!(
+            "{:02X?} is not a start routine request for {}",
+            payload,
+            server.get_name()
+        ))),
+    }
+}
+
+/// State of a running routine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoutineState {
+    Running,
+    /// Routine completed, with the ECU's full response
+    Complete(Vec<u8>),
+}
+
+/// A routine that has been started on the ECU
+#[derive(Debug, Clone)]
+pub struct RoutineRun {
+    service: Service,
+    started: Instant,
+    timeout: Duration,
+    start_response: Vec<u8>,
+}
+
+impl RoutineRun {
+    /// Starts a routine.
+    ///
+    /// ## Params
+    /// * `service` - Routine service from the ECU definition
+    /// * `inputs` - A value for each input param of `service`, used to build the option record.
+    ///   Empty inputs are left as they are in the service's payload
+    /// * `timeout` - How long to wait for the routine to complete
+    pub fn start(
+        server: &DiagServer,
+        service: &Service,
+        inputs: &[String],
+        timeout: Duration,
+    ) -> ProtocolResult<Self> {
+        let payload = adjustment::encode_values(service, &service.payload, inputs)?;
+        let start_response = match (parse_request(server, &payload)?, server) {
+            (RoutineRequest::Uds { id, options }, DiagServer::UDS(ecu)) => {
+                uds::routine_control::start_routine(ecu, id, &options)?.raw
+            }
+            (RoutineRequest::Kwp { local_id, options }, DiagServer::KWP2000(ecu)) => {
+                kwp2000::routine_control::start_routine(ecu, local_id, &options)?.raw
+            }
+            _ => unreachable!(),
+        };
+        Ok(Self {
+            service: service.clone(),
+            started: Instant::now(),
+            timeout,
+            start_response,
+        })
+    }
+
+    /// Requests the results of the routine. If the routine has not completed
+    /// within the timeout, it is stopped and an error is returned
+    pub fn poll(&self, server: &DiagServer) -> ProtocolResult<RoutineState> {
+        let res = match (parse_request(server, &self.service.payload)?, server) {
+            (RoutineRequest::Uds { id, .. }, DiagServer::UDS(ecu)) => {
+                uds::routine_control::request_routine_results(ecu, id).map(|r| r.raw)
+            }
+            (RoutineRequest::Kwp { local_id, .. }, DiagServer::KWP2000(ecu)) => {
+                kwp2000::routine_control::request_routine_results(ecu, local_id).map(|r| r.raw)
+            }
+            _ => unreachable!(),
+        };
+        match res {
+            Ok(raw) => Ok(RoutineState::Complete(raw)),
+            Err(e) => match e.get_nrc() {
+                Some(NRC_SERVICE_NOT_SUPPORTED) | Some(NRC_SUB_FUNCTION_NOT_SUPPORTED) => {
+                    Ok(RoutineState::Complete(self.start_response.clone()))
+                }
+                Some(NRC_BUSY_REPEAT_REQUEST) | Some(NRC_ROUTINE_NOT_COMPLETE) => {
+                    if self.started.elapsed() < self.timeout {
+                        Ok(RoutineState::Running)
+                    } else {
+                        let _ = self.stop(server);
+                        Err(ProtocolError::CustomError(format!(
+                            "{} did not complete within {} seconds",
+                            self.service.name,
+                            self.timeout.as_secs()
+                        )))
+                    }
+                }
+                _ => Err(e),
+            },
+        }
+    }
+
+    /// Stops the routine, returning the ECU's response
+    pub fn stop(&self, server: &DiagServer) -> ProtocolResult<Vec<u8>> {
+        match (parse_request(server, &self.service.payload)?, server) {
+            (RoutineRequest::Uds { id, .. }, DiagServer::UDS(ecu)) => {
+                Ok(uds::routine_control::stop_routine(ecu, id, &[])?.raw)
+            }
+            (RoutineRequest::Kwp { local_id, .. }, DiagServer::KWP2000(ecu)) => {
+                Ok(kwp2000::routine_control::stop_routine(ecu, local_id, &[])?.raw)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Starts a routine, and polls it until it completes.
+    /// `on_progress` is called after each poll with the percentage of the timeout that has passed
+    ///
+    /// ## Returns
+    /// The ECU's response containing the routine's results
+    pub fn run_to_completion<F: FnMut(f32)>(
+        server: &DiagServer,
+        service: &Service,
+        inputs: &[String],
+        timeout: Duration,
+        poll_interval: Duration,
+        mut on_progress: F,
+    ) -> ProtocolResult<Vec<u8>> {
+        let run = Self::start(server, service, inputs, timeout)?;
+        loop {
+            if let RoutineState::Complete(res) = run.poll(server)? {
+                on_progress(100.0);
+                return Ok(res);
+            }
+            on_progress(run.get_progress());
+            std::thread::sleep(poll_interval);
+        }
+    }
+
+    /// Returns the percentage of the timeout that has passed. Routines do not report
+    /// how far along they are, so this is only an estimate
+    pub fn get_progress(&self) -> f32 {
+        let percent =
+            self.started.elapsed().as_millis() as f32 / self.timeout.as_millis() as f32 * 100.0;
+        percent.min(99.0)
+    }
+
+    pub fn get_service(&self) -> &Service {
+        &self.service
+    }
+}
+
+/// Decodes the output params of `service` out of a routine's results
+pub fn decode_results(service: &Service, raw: &[u8]) -> Vec<(String, String)> {
+    service
+        .output_params
+        .iter()
+        .map(|p| {
+            let value = match p.decode_value_to_string(raw) {
+                Ok(v) => v,
+                Err(e) => format!("{:?}", e),
+            };
+            (p.name.clone(), value)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commapi::{
+        protocols::DiagProtocol,
+        simulator_api::{SimRoutine, SimulatorAPI, VirtualECU},
+    };
+    use common::schema::diag::{
+        service::{ParamByteOrder, Parameter},
+        DataFormat,
+    };
+
+    #[test]
+    fn polled_until_complete() {
+        // busyRepeatRequest is repeated by the diagnostic server itself,
+        // so the UDS routine completes on the first poll
+        for (protocol, payload, id, result_start, polls) in &[
+            (
+                DiagProtocol::UDS,
+                vec![0x31, 0x01, 0x02, 0x03, 0x00],
+                0x0203,
+                4,
+                1,
+            ),
+            (DiagProtocol::KWP2000, vec![0x31, 0x03, 0x00], 0x03, 2, 3),
+        ] {
+            let mut ecu = VirtualECU::new("ECU", *protocol, 0x7E0, 0x7E8);
+            ecu.routines
+                .insert(*id, SimRoutine::new(vec![0x00, 0x2A], 2));
+            let sim = SimulatorAPI::new(vec![ecu]);
+            let server = sim.start_test_server(Default::default());
+
+            let param = |name: &str, start_bit: usize| Parameter {
+                name: name.into(),
+                unit: "".into(),
+                start_bit,
+                length_bits: 8,
+                byte_order: ParamByteOrder::BigEndian,
+                data_format: DataFormat::Identical,
+                valid_bounds: None,
+            };
+            let service = Service {
+                name: "RT_SELF_TEST".into(),
+                description: "Self test".into(),
+                payload: payload.clone(),
+                input_params: vec![param("Test mode", (payload.len() - 1) * 8)],
+                output_params: vec![param("Result", (result_start + 1) * 8)],
+            };
+
+            let mut progress = Vec::new();
+            let res = RoutineRun::run_to_completion(
+                &server,
+                &service,
+                &["5".into()],
+                DEFAULT_ROUTINE_TIMEOUT,
+                Duration::from_millis(1),
+                |p| progress.push(p),
+            )
+            .unwrap();
+            assert_eq!(&res[*result_start..], &[0x00, 0x2A]);
+            assert_eq!(progress.len(), *polls);
+            assert_eq!(progress.last(), Some(&100.0));
+            assert_eq!(
+                decode_results(&service, &res),
+                vec![("Result".to_string(), "42".to_string())]
+            );
+
+            // A stopped routine has no results to request
+            let run = RoutineRun::start(&server, &service, &[], DEFAULT_ROUTINE_TIMEOUT).unwrap();
+            run.stop(&server).unwrap();
+            assert_eq!(run.poll(&server).unwrap_err().get_nrc(), Some(0x24));
+        }
+    }
+}
@@ -0,0 +1,416 @@
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    path::Path,
+    process::Command,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use lazy_static::lazy_static;
+use libloading::Library;
+
+use super::{ProtocolError, ProtocolResult, ProtocolServer};
+
+// Shared by UDS and KWP2000. Both use service 0x27, with odd sub functions requesting
+// the seed for a level, and the following even sub function sending the key back
+
+/// Negative response codes used by both UDS and KWP2000 for SecurityAccess
+pub const NRC_INVALID_KEY: u8 = 0x35;
+pub const NRC_EXCEEDED_ATTEMPTS: u8 = 0x36;
+pub const NRC_TIME_DELAY_NOT_EXPIRED: u8 = 0x37;
+
+/// How long ECUs lock out security access for after a failed attempt or power up.
+/// ISO14229-1 recommends at least 10 seconds
+pub const SECURITY_DELAY_MS: u64 = 10000;
+
+/// Highest request seed sub function. 0x7E and 0x7F are reserved by ISO14229-1
+pub const MAX_SECURITY_LEVEL: u8 = 0x7D;
+
+/// An algorithm that calculates the key an ECU expects for a given seed
+pub trait SeedKeyAlgorithm: Send + Sync {
+    /// Name of the algorithm, as shown to the user
+    fn get_name(&self) -> String;
+    /// Calculates the key for `seed`. `level` is the request seed sub function (0x01, 0x03, ...)
+    fn calculate_key(&self, level: u8, seed: &[u8]) -> ProtocolResult<Vec<u8>>;
+}
+
+impl std::fmt::Debug for dyn SeedKeyAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SeedKeyAlgorithm {}", self.get_name())
+    }
+}
+
+type GenerateKeyExFn = unsafe extern "C" fn(
+    seed: *const u8,
+    seed_size: u32,
+    security_level: u32,
+    variant: *const libc::c_char,
+    key: *mut u8,
+    max_key_size: u32,
+    key_size: *mut u32,
+) -> i32;
+
+/// Largest key accepted from a seed/key library
+const MAX_KEY_SIZE: usize = 256;
+
+/// Seed/key algorithm in a dynamic library (.dll / .so / .dylib), exporting
+/// the commonly used `GenerateKeyEx` function:
+///
+/// ```c
+/// int GenerateKeyEx(const unsigned char* seed, unsigned int seed_size,
+///                   unsigned int security_level, const char* variant,
+///                   unsigned char* key, unsigned int max_key_size, unsigned int* key_size);
+/// ```
+/// A return value of 0 means the key was generated
+#[derive(Clone)]
+pub struct LibraryAlgorithm {
+    name: String,
+    variant: CString,
+    lib: Arc<Library>,
+    generate_key_fn: GenerateKeyExFn,
+}
+
+impl LibraryAlgorithm {
+    pub fn load_lib(name: String, path: &Path, variant: &str) -> ProtocolResult<Self> {
+        let load_err = |e: libloading::Error| {
+            ProtocolError::CustomError(format!(
+                "Cannot load seed/key library {}: {}",
+                path.display(),
+                e
+            ))
+        };
+        let lib = unsafe { Library::new(path).map_err(load_err)? };
+        let generate_key_fn = unsafe {
+            *lib.get::<GenerateKeyExFn>(b"GenerateKeyEx\0")
+                .map_err(load_err)?
+                .into_raw()
+        };
+        Ok(Self {
+            name,
+            variant: CString::new(variant).unwrap_or_default(),
+            lib: Arc::new(lib),
+            generate_key_fn,
+        })
+    }
+}
+
+impl SeedKeyAlgorithm for LibraryAlgorithm {
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn calculate_key(&self, level: u8, seed: &[u8]) -> ProtocolResult<Vec<u8>> {
+        let mut key = [0u8; MAX_KEY_SIZE];
+        let mut key_size: u32 = 0;
+        let res = unsafe {
+            (self.generate_key_fn)(
+                seed.as_ptr(),
+                seed.len() as u32,
+                level as u32,
+                self.variant.as_ptr(),
+                key.as_mut_ptr(),
+                MAX_KEY_SIZE as u32,
+                &mut key_size,
+            )
+        };
+        if res != 0 {
+            return Err(ProtocolError::CustomError(format!(
+                "{} failed to generate a key (Error {})",
+                self.name, res
+            )));
+        }
+        Ok(Vec::from(
+            &key[0..std::cmp::min(key_size as usize, MAX_KEY_SIZE)],
+        ))
+    }
+}
+
+/// Seed/key algorithm run as an external program or script. It is called as
+/// `<path> <level> <seed as hex>` and must print the key as hex to stdout
+#[derive(Debug, Clone)]
+pub struct ScriptAlgorithm {
+    name: String,
+    path: String,
+}
+
+impl ScriptAlgorithm {
+    pub fn new(name: String, path: String) -> Self {
+        Self { name, path }
+    }
+}
+
+impl SeedKeyAlgorithm for ScriptAlgorithm {
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn calculate_key(&self, level: u8, seed: &[u8]) -> ProtocolResult<Vec<u8>> {
+        let output = Command::new(&self.path)
+            .arg(format!("{:02X}", level))
+            .arg(hex::encode_upper(seed))
+            .output()
+            .map_err(|e| {
+                ProtocolError::CustomError(format!(
+                    "Cannot run seed/key script {}: {}",
+                    self.path, e
+                ))
+            })?;
+        if !output.status.success() {
+            return Err(ProtocolError::CustomError(format!(
+                "Seed/key script {} failed: {}",
+                self.path,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        let key: String = String::from_utf8_lossy(&output.stdout)
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect();
+        hex::decode(&key).map_err(|_| {
+            ProtocolError::CustomError(format!(
+                "Seed/key script {} returned an invalid key '{}'",
+                self.path, key
+            ))
+        })
+    }
+}
+
+lazy_static! {
+    static ref ALGORITHMS: RwLock<HashMap<String, Arc<dyn SeedKeyAlgorithm>>> =
+        RwLock::new(HashMap::new());
+}
+
+/// Adds an algorithm to the registry, replacing any algorithm with the same name
+pub fn register_algorithm(algo: Arc<dyn SeedKeyAlgorithm>) {
+    ALGORITHMS.write().unwrap().insert(algo.get_name(), algo);
+}
+
+/// Loads an algorithm from a file and adds it to the registry. Files with a
+/// dynamic library extension are loaded as a [LibraryAlgorithm], anything else
+/// is treated as a [ScriptAlgorithm]
+pub fn load_algorithm(name: String, path: &str) -> ProtocolResult<()> {
+    let p = Path::new(path);
+    let algo: Arc<dyn SeedKeyAlgorithm> = match p.extension().and_then(|e| e.to_str()) {
+        Some("dll") | Some("so") | Some("dylib") => {
+            Arc::new(LibraryAlgorithm::load_lib(name, p, "")?)
+        }
+        _ => Arc::new(ScriptAlgorithm::new(name, path.into())),
+    };
+    register_algorithm(algo);
+    Ok(())
+}
+
+pub fn get_algorithm(name: &str) -> Option<Arc<dyn SeedKeyAlgorithm>> {
+    ALGORITHMS.read().unwrap().get(name).cloned()
+}
+
+/// Returns the names of all registered algorithms
+pub fn get_algorithm_names() -> Vec<String> {
+    let mut names: Vec<String> = ALGORITHMS.read().unwrap().keys().cloned().collect();
+    names.sort();
+    names
+}
+
+/// Runs the seed/key exchange with an ECU.
+///
+/// If the ECU reports the security delay has not expired, this waits for
+/// [SECURITY_DELAY_MS] and tries once more, so this can block for over 10 seconds.
+/// It must never be called on the GUI thread. An invalid key or too many attempts
+/// is returned to the caller as is, as trying again with the same algorithm
+/// would only lock the ECU out for longer
+///
+/// ## Params
+/// * `sid` - Service ID of SecurityAccess for the protocol
+/// * `level` - Request seed sub function. Must be odd, and no higher than [MAX_SECURITY_LEVEL]
+pub(crate) fn unlock_ecu<P: ProtocolServer>(
+    ecu: &P,
+    sid: u8,
+    level: u8,
+    algo: &dyn SeedKeyAlgorithm,
+) -> ProtocolResult<()> {
+    unlock_ecu_with_delay(
+        ecu,
+        sid,
+        level,
+        algo,
+        Duration::from_millis(SECURITY_DELAY_MS),
+    )
+}
+
+fn unlock_ecu_with_delay<P: ProtocolServer>(
+    ecu: &P,
+    sid: u8,
+    level: u8,
+    algo: &dyn SeedKeyAlgorithm,
+    delay: Duration,
+) -> ProtocolResult<()> {
+    if level % 2 == 0 || level > MAX_SECURITY_LEVEL {
+        return Err(ProtocolError::CustomError(format!(
+            "0x{:02X} is not a request seed security level",
+            level
+        )));
+    }
+    match try_unlock(ecu, sid, level, algo) {
+        Err(e) if e.get_nrc() == Some(NRC_TIME_DELAY_NOT_EXPIRED) => {
+            std::thread::sleep(delay);
+            try_unlock(ecu, sid, level, algo)
+        }
+        res => res,
+    }
+}
+
+fn try_unlock<P: ProtocolServer>(
+    ecu: &P,
+    sid: u8,
+    level: u8,
+    algo: &dyn SeedKeyAlgorithm,
+) -> ProtocolResult<()> {
+    let res = ecu.run_command(sid, &[level])?;
+    if res.len() < 3 {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: 3,
+            actual: res.len(),
+        });
+    }
+    let seed = &res[2..];
+    if seed.iter().all(|x| *x == 0) {
+        // A seed of all 0s means the level is already unlocked
+        return Ok(());
+    }
+    let key = algo.calculate_key(level, seed)?;
+    let mut args = vec![level + 1];
+    args.extend_from_slice(&key);
+    ecu.run_command(sid, &args)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commapi::{
+        protocols::{uds::UDSECU, DiagProtocol},
+        simulator_api::{SimulatorAPI, VirtualECU},
+    };
+    use std::time::Instant;
+
+    /// Key is the seed XOR'ed with the level
+    struct XorLevel;
+
+    impl SeedKeyAlgorithm for XorLevel {
+        fn get_name(&self) -> String {
+            "security test xor".into()
+        }
+
+        fn calculate_key(&self, level: u8, seed: &[u8]) -> ProtocolResult<Vec<u8>> {
+            Ok(seed.iter().map(|x| x ^ level).collect())
+        }
+    }
+
+    /// Always returns the same key, which the ECU will not accept
+    struct WrongKey;
+
+    impl SeedKeyAlgorithm for WrongKey {
+        fn get_name(&self) -> String {
+            "security test wrong key".into()
+        }
+
+        fn calculate_key(&self, _level: u8, _seed: &[u8]) -> ProtocolResult<Vec<u8>> {
+            Ok(vec![0x00; 4])
+        }
+    }
+
+    /// Writes an executable shell script to the temp directory, returning its path
+    #[cfg(unix)]
+    fn write_script(name: &str, body: &str) -> String {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("ovd_{}_{}.sh", name, std::process::id()));
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn script_algorithm() {
+        let run = |name: &str, body: &str, seed: &[u8]| {
+            let path = write_script(name, body);
+            let res = ScriptAlgorithm::new(name.into(), path.clone()).calculate_key(0x03, seed);
+            std::fs::remove_file(path).unwrap();
+            res
+        };
+        assert_eq!(
+            run("echo", "echo \"$1 $2\"", &[0xAB, 0xCD]).unwrap(),
+            vec![0x03, 0xAB, 0xCD]
+        );
+        assert!(run("fail", "exit 1", &[0x00]).is_err());
+        assert!(run("not_hex", "echo KEY", &[0x00]).is_err());
+
+        let algo = ScriptAlgorithm::new("missing".into(), "/nonexistent/ovd_key.sh".into());
+        assert!(algo.calculate_key(0x01, &[0x00]).is_err());
+    }
+
+    #[test]
+    fn algorithm_registry() {
+        register_algorithm(Arc::new(XorLevel));
+        let algo = get_algorithm("security test xor").unwrap();
+        assert_eq!(algo.calculate_key(0x01, &[0x10]).unwrap(), vec![0x11]);
+        assert!(get_algorithm_names().contains(&"security test xor".to_string()));
+        assert!(get_algorithm("security test missing").is_none());
+
+        // Anything that is not a dynamic library is run as a script
+        load_algorithm("security test script".into(), "/nonexistent/key.py").unwrap();
+        assert!(get_algorithm("security test script").is_some());
+        assert!(load_algorithm("security test lib".into(), "/nonexistent/key.so").is_err());
+        assert!(get_algorithm("security test lib").is_none());
+    }
+
+    #[test]
+    fn unlock_levels() {
+        let mut sim_ecu = VirtualECU::new("ECU", DiagProtocol::UDS, 0x7E0, 0x7E8);
+        sim_ecu.security_algorithm = Some(Arc::new(XorLevel));
+        let mut ecu: UDSECU = SimulatorAPI::new(vec![sim_ecu]).start_test_session();
+        for level in &[0x00, 0x02, 0x7E, 0x7F, 0xFF] {
+            assert!(unlock_ecu(&ecu, 0x27, *level, &XorLevel).is_err());
+        }
+        unlock_ecu(&ecu, 0x27, 0x7D, &XorLevel).unwrap();
+        // Already unlocked, so the ECU sends a seed of 0s and no key is needed
+        unlock_ecu(&ecu, 0x27, 0x7D, &WrongKey).unwrap();
+        ecu.exit_diag_session();
+    }
+
+    #[test]
+    fn unlock_nrcs() {
+        let mut sim_ecu = VirtualECU::new("ECU", DiagProtocol::UDS, 0x7E0, 0x7E8);
+        sim_ecu.security_algorithm = Some(Arc::new(XorLevel));
+        sim_ecu.custom_responses.push((
+            vec![0x27, 0x03],
+            vec![0x7F, 0x27, NRC_TIME_DELAY_NOT_EXPIRED],
+        ));
+        let mut ecu: UDSECU = SimulatorAPI::new(vec![sim_ecu]).start_test_session();
+
+        let nrc = |res: ProtocolResult<()>| res.err().and_then(|e| e.get_nrc());
+        assert_eq!(
+            nrc(unlock_ecu(&ecu, 0x27, 0x01, &WrongKey)),
+            Some(NRC_INVALID_KEY)
+        );
+        assert_eq!(
+            nrc(unlock_ecu(&ecu, 0x27, 0x01, &WrongKey)),
+            Some(NRC_INVALID_KEY)
+        );
+        assert_eq!(
+            nrc(unlock_ecu(&ecu, 0x27, 0x01, &WrongKey)),
+            Some(NRC_EXCEEDED_ATTEMPTS)
+        );
+
+        // The delay is waited out once, then the error is returned if the ECU is still locked
+        let start = Instant::now();
+        let delay = Duration::from_millis(100);
+        assert_eq!(
+            nrc(unlock_ecu_with_delay(&ecu, 0x27, 0x03, &XorLevel, delay)),
+            Some(NRC_TIME_DELAY_NOT_EXPIRED)
+        );
+        assert!(start.elapsed() >= delay);
+        ecu.exit_diag_session();
+    }
+}
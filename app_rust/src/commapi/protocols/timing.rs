@@ -0,0 +1,167 @@
+use std::time::Duration;
+
+// Timing parameters of a diagnostic connection, in milliseconds.
+//
+// * P2 - How long the ECU has to respond to a request.
+// * P2* (P2 extended) - How long the ECU has to send its next response once it has replied
+//   with ResponsePending (0x78). P2* restarts with every ResponsePending, up to
+//   MAX_RESPONSE_PENDING replies. If the ECU is still pending after that, the request fails
+//   with the ResponsePending error.
+// * S3 - How often tester present is sent to stop the ECU dropping out of its
+//   diagnostic session.
+//
+// If the ECU replies with BusyRepeatRequest (0x21), the request is sent again after a delay
+// that doubles with each attempt, up to BUSY_REPEAT_RETRIES times.
+//
+// Both limits mean a request can never take longer than [DiagTiming::get_request_timeout],
+// which is used as the timeout of the request once it has been sent.
+
+/// Default P2 timeout
+pub const DEFAULT_P2: u32 = 2000;
+/// Default P2* timeout
+pub const DEFAULT_P2_EXT: u32 = 5000;
+/// Default S3 (tester present) interval
+pub const DEFAULT_S3: u32 = 2000;
+
+/// Number of times a request is repeated if the ECU is busy
+pub const BUSY_REPEAT_RETRIES: u32 = 3;
+/// Delay before the first repeat of a request the ECU was too busy for
+const BUSY_REPEAT_BACKOFF: u64 = 50;
+/// Number of ResponsePending replies allowed before a request is given up on
+pub const MAX_RESPONSE_PENDING: u32 = 10;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DiagTiming {
+    /// P2 timeout (ms)
+    pub p2: u32,
+    /// P2* timeout (ms)
+    pub p2_ext: u32,
+    /// S3 tester present interval (ms)
+    pub s3: u32,
+}
+
+impl Default for DiagTiming {
+    fn default() -> Self {
+        Self {
+            p2: DEFAULT_P2,
+            p2_ext: DEFAULT_P2_EXT,
+            s3: DEFAULT_S3,
+        }
+    }
+}
+
+impl DiagTiming {
+    /// Creates timing parameters from the values of a connection.
+    /// Missing or 0 values are replaced with their default
+    pub fn new(p2: Option<u32>, p2_ext: Option<u32>, s3: Option<u32>) -> Self {
+        Self {
+            p2: p2.filter(|x| *x > 0).unwrap_or(DEFAULT_P2),
+            p2_ext: p2_ext.filter(|x| *x > 0).unwrap_or(DEFAULT_P2_EXT),
+            s3: s3.filter(|x| *x > 0).unwrap_or(DEFAULT_S3),
+        }
+    }
+
+    /// Returns how long to wait before repeating a request the ECU was busy for
+    ///
+    /// ## Params
+    /// * `attempt` - Number of times the request has already been repeated
+    pub fn get_busy_backoff(&self, attempt: u32) -> Duration {
+        Duration::from_millis(BUSY_REPEAT_BACKOFF << attempt.min(8))
+    }
+
+    /// Returns the longest time a single request can take before it is given up on
+    pub fn get_request_timeout(&self) -> Duration {
+        let attempts = BUSY_REPEAT_RETRIES + 1;
+        let backoff: Duration = (0..BUSY_REPEAT_RETRIES)
+            .map(|x| self.get_busy_backoff(x))
+            .sum();
+        Duration::from_millis(
+            self.p2 as u64 * attempts as u64 + self.p2_ext as u64 * MAX_RESPONSE_PENDING as u64,
+        ) + backoff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commapi::{
+        protocols::{uds::UDSECU, DiagProtocol, ProtocolServer},
+        simulator_api::{SimulatorAPI, VirtualECU},
+    };
+    use std::time::Instant;
+
+    #[test]
+    fn defaults() {
+        assert_eq!(DiagTiming::new(None, None, None), DiagTiming::default());
+        let timing = DiagTiming::new(Some(150), Some(0), Some(4000));
+        assert_eq!(timing.p2, 150);
+        assert_eq!(timing.p2_ext, DEFAULT_P2_EXT);
+        assert_eq!(timing.s3, 4000);
+        assert!(timing.get_busy_backoff(1) > timing.get_busy_backoff(0));
+        assert!(timing.get_request_timeout() > Duration::from_millis(timing.p2_ext as u64));
+    }
+
+    #[test]
+    fn repeated_response_pending() {
+        let mut ecu = VirtualECU::new("ECU", DiagProtocol::UDS, 0x7E0, 0x7E8);
+        ecu.custom_responses
+            .push((vec![0x22, 0xF1, 0x90], vec![0x62, 0xF1, 0x90, 0x01]));
+        ecu.response_pending_count = 5;
+        let mut server = SimulatorAPI::new(vec![ecu]).start_test_server(DiagTiming::default());
+        assert_eq!(
+            server.run_cmd(0x22, &[0xF1, 0x90]).unwrap(),
+            vec![0x62, 0xF1, 0x90, 0x01]
+        );
+    }
+
+    #[test]
+    fn response_pending_restarts_p2_ext() {
+        let mut ecu = VirtualECU::new("ECU", DiagProtocol::UDS, 0x7E0, 0x7E8);
+        ecu.custom_responses
+            .push((vec![0x31, 0x01, 0xFF, 0x00], vec![0x71, 0x01, 0xFF, 0x00]));
+        // 4 pendings, 150ms apart. 600ms in total, but each is within P2*
+        ecu.response_pending_count = 4;
+        ecu.response_pending_interval = 150;
+        let timing = DiagTiming::new(None, Some(300), None);
+        let mut server = SimulatorAPI::new(vec![ecu]).start_test_server(timing);
+        let start = Instant::now();
+        assert_eq!(
+            server.run_cmd(0x31, &[0x01, 0xFF, 0x00]).unwrap(),
+            vec![0x71, 0x01, 0xFF, 0x00]
+        );
+        assert!(start.elapsed() > Duration::from_millis(timing.p2_ext as u64));
+    }
+
+    #[test]
+    fn too_many_response_pending() {
+        let mut ecu = VirtualECU::new("ECU", DiagProtocol::UDS, 0x7E0, 0x7E8);
+        ecu.custom_responses
+            .push((vec![0x22, 0xF1, 0x90], vec![0x62, 0xF1, 0x90, 0x01]));
+        // Every request is answered slowly, so starting a session would fail too
+        ecu.response_pending_count = MAX_RESPONSE_PENDING + 1;
+        let mut iface = SimulatorAPI::new(vec![ecu]).open_test_iface();
+        let timing = DiagTiming::default();
+        let start = Instant::now();
+        let err =
+            UDSECU::run_command_resp(&mut iface, &None, 0x7E0, 0x22, &[0xF1, 0x90], true, &timing)
+                .unwrap_err();
+        assert_eq!(err.get_nrc(), Some(0x78));
+        assert!(start.elapsed() < timing.get_request_timeout());
+    }
+
+    #[test]
+    fn busy_repeat_request() {
+        let mut ecu = VirtualECU::new("ECU", DiagProtocol::UDS, 0x7E0, 0x7E8);
+        ecu.custom_responses
+            .push((vec![0x22, 0xF1, 0x90], vec![0x7F, 0x22, 0x21]));
+        let timing = DiagTiming::default();
+        let mut server = SimulatorAPI::new(vec![ecu]).start_test_server(timing);
+        let start = Instant::now();
+        let err = server.run_cmd(0x22, &[0xF1, 0x90]).unwrap_err();
+        assert_eq!(err.get_nrc(), Some(0x21));
+        let min_wait: Duration = (0..BUSY_REPEAT_RETRIES)
+            .map(|x| timing.get_busy_backoff(x))
+            .sum();
+        assert!(start.elapsed() >= min_wait);
+    }
+}
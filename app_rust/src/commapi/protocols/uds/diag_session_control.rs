@@ -36,5 +36,7 @@ pub fn set_diag_session(ecu: &UDSECU, mode: DiagSession) -> ProtocolResult<()> {
         super::UDSCommand::DiagnosticSessionControl.into(),
         &[mode.to_byte()],
     )?;
+    // The ECU returns control of all components when the session changes
+    ecu.io_controls.write().unwrap().clear();
     Ok(())
 }
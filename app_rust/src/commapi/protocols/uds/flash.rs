@@ -0,0 +1,397 @@
+use serde::{Deserialize, Serialize};
+
+use crate::commapi::{
+    comm_api::ComServer,
+    iface::{InterfaceConfig, InterfaceType, IFACE_CFG},
+    protocols::{
+        flash_image::FlashImage, security, DiagCfg, DiagProtocol, ProtocolError, ProtocolResult,
+        ProtocolServer,
+    },
+    simulator_api::{SimulatorAPI, VirtualECU},
+};
+
+use super::{
+    diag_session_control::DiagSession,
+    routine_control::{self, RoutineResult},
+    security_access, UDSCommand, UDSECU,
+};
+
+// Reprogramming an ECU is done with RequestDownload ($34), which tells the ECU
+// where the data will go and how big it is, followed by TransferData ($36) blocks
+// containing the data, and RequestTransferExit ($37) once each memory region is
+// written. Erasing and verifying memory are done with RoutineControl ($31).
+
+/// eraseMemory routine (ISO14229-1 Annex F)
+pub const ERASE_MEMORY_ROUTINE: u16 = 0xFF00;
+/// checkMemory routine, commonly used to verify a CRC32 of the written data
+pub const CHECK_MEMORY_ROUTINE: u16 = 0x0202;
+
+/// Settings for a flash job. These depend on the ECU being flashed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlashCfg {
+    /// SecurityAccess request seed level to unlock before programming
+    pub security_level: u8,
+    /// Name of the seed/key algorithm in the [security] registry.
+    /// If None, SecurityAccess is skipped
+    pub security_algorithm: Option<String>,
+    /// Routine to erase each memory region before it is written. If None, memory is not erased
+    pub erase_routine: Option<u16>,
+    /// Routine to check the CRC32 of the written image. If None, no check is done
+    pub check_routine: Option<u16>,
+    /// RequestDownload dataFormatIdentifier. 0x00 = No compression or encryption
+    pub data_format: u8,
+    /// addressAndLengthFormatIdentifier. High nibble = size bytes, low nibble = address bytes
+    pub address_length_format: u8,
+    /// ECUReset type to send once flashing is done. If None, the ECU is not reset
+    pub reset_type: Option<u8>,
+}
+
+impl Default for FlashCfg {
+    fn default() -> Self {
+        Self {
+            security_level: 0x01,
+            security_algorithm: None,
+            erase_routine: Some(ERASE_MEMORY_ROUTINE),
+            check_routine: Some(CHECK_MEMORY_ROUTINE),
+            data_format: 0x00,
+            address_length_format: 0x44,
+            reset_type: Some(0x01), // Hard reset
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FlashStage {
+    NotStarted,
+    ProgrammingSession,
+    SecurityAccess,
+    Erase,
+    Transfer,
+    Check,
+    Reset,
+    Complete,
+}
+
+/// Progress of a flash job. This can be saved, and given back to [FlashJob::resume]
+/// to carry on from where a failed job stopped
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlashProgress {
+    pub stage: FlashStage,
+    /// Memory has been erased
+    pub erased: bool,
+    /// Segment of the image currently being transferred
+    pub segment: usize,
+    /// Bytes of the current segment the ECU has accepted
+    pub segment_offset: usize,
+    /// Bytes of the whole image the ECU has accepted
+    pub bytes_done: usize,
+    pub bytes_total: usize,
+}
+
+impl FlashProgress {
+    fn new(image: &FlashImage) -> Self {
+        Self {
+            stage: FlashStage::NotStarted,
+            erased: false,
+            segment: 0,
+            segment_offset: 0,
+            bytes_done: 0,
+            bytes_total: image.len(),
+        }
+    }
+
+    /// Returns the transfer progress as a percentage
+    pub fn get_percent(&self) -> f32 {
+        if self.bytes_total == 0 {
+            100.0
+        } else {
+            self.bytes_done as f32 * 100.0 / self.bytes_total as f32
+        }
+    }
+}
+
+/// Writes a [FlashImage] to an ECU
+#[derive(Debug, Clone)]
+pub struct FlashJob {
+    image: FlashImage,
+    cfg: FlashCfg,
+    progress: FlashProgress,
+}
+
+/// Encodes `value` as a big endian number of `len` bytes
+fn encode_be(value: u32, len: u8) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let len = std::cmp::min(len as usize, 4);
+    Vec::from(&bytes[4 - len..])
+}
+
+impl FlashJob {
+    pub fn new(image: FlashImage, cfg: FlashCfg) -> Self {
+        let progress = FlashProgress::new(&image);
+        Self {
+            image,
+            cfg,
+            progress,
+        }
+    }
+
+    /// Creates a job that carries on from a previously saved [FlashProgress].
+    /// Memory is not erased again if the previous job already erased it
+    pub fn resume(
+        image: FlashImage,
+        cfg: FlashCfg,
+        progress: FlashProgress,
+    ) -> ProtocolResult<Self> {
+        if progress.bytes_total != image.len() || progress.segment > image.segments.len() {
+            return Err(ProtocolError::CustomError(
+                "Saved progress does not belong to this image".into(),
+            ));
+        }
+        Ok(Self {
+            image,
+            cfg,
+            progress,
+        })
+    }
+
+    pub fn get_progress(&self) -> &FlashProgress {
+        &self.progress
+    }
+
+    fn memory_record(&self, address: u32, size: u32) -> Vec<u8> {
+        let fmt = self.cfg.address_length_format;
+        let mut res = vec![fmt];
+        res.extend(encode_be(address, fmt & 0x0F));
+        res.extend(encode_be(size, fmt >> 4));
+        res
+    }
+
+    fn set_stage<F: FnMut(&FlashProgress)>(&mut self, stage: FlashStage, on_progress: &mut F) {
+        self.progress.stage = stage;
+        on_progress(&self.progress);
+    }
+
+    /// Runs the job. `on_progress` is called each time a stage starts, and after
+    /// each block of data is accepted by the ECU.
+    ///
+    /// If this fails, [FlashJob::get_progress] shows how far the job got, and calling
+    /// run again carries on from there
+    pub fn run<F: FnMut(&FlashProgress)>(
+        &mut self,
+        ecu: &UDSECU,
+        mut on_progress: F,
+    ) -> ProtocolResult<()> {
+        if self.image.is_empty() {
+            return Err(ProtocolError::CustomError("Flash image is empty".into()));
+        }
+        self.set_stage(FlashStage::ProgrammingSession, &mut on_progress);
+        ecu.set_diag_session_mode(DiagSession::Programming)?;
+
+        if let Some(algo) = self.cfg.security_algorithm.clone() {
+            self.set_stage(FlashStage::SecurityAccess, &mut on_progress);
+            security_access::unlock_with(ecu, self.cfg.security_level, &algo)?;
+        }
+
+        if let (Some(routine), false) = (self.cfg.erase_routine, self.progress.erased) {
+            self.set_stage(FlashStage::Erase, &mut on_progress);
+            for s in &self.image.segments {
+                let record = self.memory_record(s.address, s.data.len() as u32);
+                Self::check_routine_result(routine_control::start_routine(ecu, routine, &record)?)?;
+            }
+            self.progress.erased = true;
+        }
+
+        self.set_stage(FlashStage::Transfer, &mut on_progress);
+        while self.progress.segment < self.image.segments.len() {
+            self.transfer_segment(ecu, &mut on_progress)?;
+            self.progress.segment += 1;
+            self.progress.segment_offset = 0;
+        }
+
+        if let Some(routine) = self.cfg.check_routine {
+            self.set_stage(FlashStage::Check, &mut on_progress);
+            let crc = self.image.crc32().to_be_bytes();
+            Self::check_routine_result(routine_control::start_routine(ecu, routine, &crc)?)?;
+        }
+
+        if let Some(reset_type) = self.cfg.reset_type {
+            self.set_stage(FlashStage::Reset, &mut on_progress);
+            ecu.run_command(UDSCommand::ECUReset.into(), &[reset_type])?;
+            // ECU restarts in its default session, locked
+            *ecu.curr_session_type.write().unwrap() = DiagSession::Default;
+            *ecu.security_level.write().unwrap() = None;
+        }
+        self.set_stage(FlashStage::Complete, &mut on_progress);
+        Ok(())
+    }
+
+    /// Checks the routineStatusRecord of a RoutineControl response. 0x00 means the routine passed
+    fn check_routine_result(res: RoutineResult) -> ProtocolResult<()> {
+        match res.get_status_record().first() {
+            None | Some(0x00) => Ok(()),
+            Some(x) => Err(ProtocolError::CustomError(format!(
+                "Routine 0x{:04X} failed with status 0x{:02X}",
+                res.routine_id, x
+            ))),
+        }
+    }
+
+    fn transfer_segment<F: FnMut(&FlashProgress)>(
+        &mut self,
+        ecu: &UDSECU,
+        on_progress: &mut F,
+    ) -> ProtocolResult<()> {
+        let segment = &self.image.segments[self.progress.segment];
+        let offset = self.progress.segment_offset;
+        let data = &segment.data[offset..];
+
+        let mut args = vec![self.cfg.data_format];
+        args.extend(self.memory_record(segment.address + offset as u32, data.len() as u32));
+        let res = ecu.run_command(UDSCommand::RequestDownload.into(), &args)?;
+        // lengthFormatIdentifier, then maxNumberOfBlockLength
+        let len_bytes = (*res.get(1).unwrap_or(&0) >> 4) as usize;
+        if len_bytes == 0 || res.len() < 2 + len_bytes {
+            return Err(ProtocolError::InvalidResponseSize {
+                expect: 2 + std::cmp::max(len_bytes, 1),
+                actual: res.len(),
+            });
+        }
+        let max_block_len = res[2..2 + len_bytes]
+            .iter()
+            .fold(0usize, |n, x| n << 8 | *x as usize);
+        if max_block_len <= 2 {
+            return Err(ProtocolError::CustomError(format!(
+                "ECU reported an invalid max block length of {}",
+                max_block_len
+            )));
+        }
+        let chunk_size = max_block_len - 2; // SID and block sequence counter
+
+        let mut bsc: u8 = 0x01;
+        for chunk in data.chunks(chunk_size) {
+            let mut args = vec![bsc];
+            args.extend_from_slice(chunk);
+            let res = ecu.run_command(UDSCommand::TransferData.into(), &args)?;
+            if res.get(1) != Some(&bsc) {
+                return Err(ProtocolError::CustomError(format!(
+                    "ECU acknowledged the wrong block sequence counter (Sent 0x{:02X})",
+                    bsc
+                )));
+            }
+            bsc = bsc.wrapping_add(1); // Wraps 0xFF -> 0x00
+            self.progress.segment_offset += chunk.len();
+            self.progress.bytes_done += chunk.len();
+            on_progress(&self.progress);
+        }
+        ecu.run_command(UDSCommand::TransferExit.into(), &[])?;
+        Ok(())
+    }
+
+    /// Runs the job against a simulated ECU rather than a real one, then checks the
+    /// simulated ECU's memory contains exactly the image. The job's own progress is not touched.
+    ///
+    /// ## Params
+    /// * `send_id` - CAN ID requests are sent to
+    /// * `recv_id` - CAN ID the ECU responds on
+    pub fn dry_run<F: FnMut(&FlashProgress)>(
+        &self,
+        send_id: u32,
+        recv_id: u32,
+        on_progress: F,
+    ) -> ProtocolResult<()> {
+        let mut sim_ecu = VirtualECU::new("Flash dry run", DiagProtocol::UDS, send_id, recv_id);
+        if let Some(name) = &self.cfg.security_algorithm {
+            sim_ecu.security_algorithm = Some(security::get_algorithm(name).ok_or_else(|| {
+                ProtocolError::CustomError(format!("No seed/key algorithm named '{}'", name))
+            })?);
+        }
+        if let Some(r) = self.cfg.erase_routine {
+            sim_ecu.erase_routine = r;
+        }
+        if let Some(r) = self.cfg.check_routine {
+            sim_ecu.check_routine = r;
+        }
+        let sim = SimulatorAPI::new(vec![sim_ecu]);
+        let server: Box<dyn ComServer> = Box::new(sim.clone());
+
+        let mut cfg = InterfaceConfig::new();
+        cfg.add_param(IFACE_CFG::BAUDRATE, 500000);
+        let mut ecu = UDSECU::start_diag_session(
+            &server,
+            InterfaceType::IsoTp,
+            cfg,
+            None,
+            DiagCfg {
+                send_id,
+                recv_id,
+                global_id: None,
+                timing: Default::default(),
+            },
+        )?;
+        let mut job = Self::new(self.image.clone(), self.cfg.clone());
+        let res = job.run(&ecu, on_progress);
+        ecu.exit_diag_session();
+        res?;
+
+        let written = sim
+            .get_ecus()
+            .first()
+            .map(|e| e.flash_memory.clone())
+            .unwrap_or_default();
+        if written != self.image {
+            return Err(ProtocolError::CustomError(
+                "Simulated ECU memory does not match the image after flashing".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::commapi::protocols::{flash_image::FlashSegment, security::SeedKeyAlgorithm};
+
+    struct XorAlgorithm;
+
+    impl SeedKeyAlgorithm for XorAlgorithm {
+        fn get_name(&self) -> String {
+            "flash test xor".into()
+        }
+
+        fn calculate_key(&self, _level: u8, seed: &[u8]) -> ProtocolResult<Vec<u8>> {
+            Ok(seed.iter().map(|x| x ^ 0x5A).collect())
+        }
+    }
+
+    #[test]
+    fn dry_run() {
+        security::register_algorithm(Arc::new(XorAlgorithm));
+        // Large enough for the block sequence counter to wrap around
+        let image = FlashImage {
+            segments: vec![
+                FlashSegment {
+                    address: 0x8000,
+                    data: (0..0x2000).map(|x| x as u8).collect(),
+                },
+                FlashSegment {
+                    address: 0x20000,
+                    data: vec![0xAA; 300],
+                },
+            ],
+        };
+        let cfg = FlashCfg {
+            security_algorithm: Some("flash test xor".into()),
+            ..Default::default()
+        };
+        let job = FlashJob::new(image, cfg);
+        let mut last = None;
+        job.dry_run(0x7E0, 0x7E8, |p| last = Some(p.clone()))
+            .unwrap();
+        let last = last.unwrap();
+        assert_eq!(last.stage, FlashStage::Complete);
+        assert_eq!(last.bytes_done, last.bytes_total);
+    }
+}
@@ -0,0 +1,120 @@
+use crate::commapi::protocols::{ProtocolError, ProtocolResult, ProtocolServer};
+
+use super::{UDSCommand, UDSECU};
+
+// The service, InputOutputControlByIdentifier ($2F), lets the tester take control of
+// an input or output of the ECU, such as a fan or a valve. Whilst under control, the ECU
+// no longer drives the component itself, so control must always be given back with
+// returnControlToECU once the tester is done.
+//
+// Every data identifier under control is tracked in the UDSECU, and released when the
+// diagnostic session is exited, or when the ECU stops responding. An identifier is tracked
+// before the request taking control is sent, as the ECU may act on it even if its response
+// is lost, and is only forgotten once the ECU confirms it has control again.
+
+/// inputOutputControlParameter of the request
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IOControlParameter {
+    /// Give control of the component back to the ECU
+    ReturnControlToECU,
+    /// Set the component to its default value
+    ResetToDefault,
+    /// Hold the component at its current value
+    FreezeCurrentState,
+    /// Set the component to a value given by the tester
+    ShortTermAdjustment,
+}
+
+impl Into<u8> for IOControlParameter {
+    fn into(self) -> u8 {
+        match self {
+            IOControlParameter::ReturnControlToECU => 0x00,
+            IOControlParameter::ResetToDefault => 0x01,
+            IOControlParameter::FreezeCurrentState => 0x02,
+            IOControlParameter::ShortTermAdjustment => 0x03,
+        }
+    }
+}
+
+impl IOControlParameter {
+    pub fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            0x00 => Some(Self::ReturnControlToECU),
+            0x01 => Some(Self::ResetToDefault),
+            0x02 => Some(Self::FreezeCurrentState),
+            0x03 => Some(Self::ShortTermAdjustment),
+            _ => None,
+        }
+    }
+}
+
+/// Sends an IO control request to the ECU.
+///
+/// ## Params
+/// * `did` - Data identifier of the component
+/// * `param` - What to do with the component
+/// * `control_state` - controlState and controlMask to send. Only used for [IOControlParameter::ShortTermAdjustment]
+///
+/// ## Returns
+/// The controlStatusRecord the ECU responded with
+pub fn io_control(
+    ecu: &UDSECU,
+    did: u16,
+    param: IOControlParameter,
+    control_state: &[u8],
+) -> ProtocolResult<Vec<u8>> {
+    let mut args = vec![(did >> 8) as u8, did as u8, param.into()];
+    if param == IOControlParameter::ShortTermAdjustment {
+        args.extend_from_slice(control_state);
+    }
+    if param != IOControlParameter::ReturnControlToECU {
+        let mut active = ecu.io_controls.write().unwrap();
+        if !active.contains(&did) {
+            active.push(did);
+        }
+    }
+    let res = ecu.run_command(UDSCommand::IOCTLById.into(), &args)?;
+    if res.len() < 4 || res[1..4] != args[0..3] {
+        return Err(ProtocolError::CustomError(
+            "ECU responded with a different identifier or control parameter".into(),
+        ));
+    }
+    if param == IOControlParameter::ReturnControlToECU {
+        ecu.io_controls.write().unwrap().retain(|x| *x != did);
+    }
+    Ok(Vec::from(&res[4..]))
+}
+
+/// Sets a component to a value given by the tester
+pub fn short_term_adjustment(
+    ecu: &UDSECU,
+    did: u16,
+    control_state: &[u8],
+) -> ProtocolResult<Vec<u8>> {
+    io_control(
+        ecu,
+        did,
+        IOControlParameter::ShortTermAdjustment,
+        control_state,
+    )
+}
+
+/// Gives control of a component back to the ECU
+pub fn return_control_to_ecu(ecu: &UDSECU, did: u16) -> ProtocolResult<()> {
+    io_control(ecu, did, IOControlParameter::ReturnControlToECU, &[])?;
+    Ok(())
+}
+
+/// Gives control of every component the tester is controlling back to the ECU.
+/// All components are attempted, even if one fails
+pub fn return_all_control_to_ecu(ecu: &UDSECU) -> ProtocolResult<()> {
+    let active = ecu.get_active_io_controls();
+    let mut res = Ok(());
+    for did in active {
+        if let Err(e) = return_control_to_ecu(ecu, did) {
+            eprintln!("Could not return control of 0x{:04X} to ECU: {:?}", did, e);
+            res = Err(e);
+        }
+    }
+    res
+}
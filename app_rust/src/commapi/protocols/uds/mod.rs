@@ -1,22 +1,23 @@
 use self::diag_session_control::DiagSession;
 use super::{
-    CautionLevel, CommandError, DiagCfg, ECUCommand, ProtocolError, ProtocolResult, ProtocolServer,
-    Selectable, DTC,
+    executor::{RequestExecutor, RequestHandle},
+    timing::DiagTiming,
+    CautionLevel, CommandError, DiagCfg, ECUCommand, FunctionalResponses, ProtocolError,
+    ProtocolResult, ProtocolServer, Selectable, DTC,
 };
-use crate::commapi::{comm_api::{ComServer, FilterType}, iface::{InterfaceConfig, InterfaceType, IsoTPInterface, PayloadFlag}, protocols::DTCState};
-use std::sync::atomic::Ordering::Relaxed;
+use crate::commapi::{comm_api::{ComServer, FilterType}, iface::{DynamicInterface, Interface, InterfaceConfig, InterfaceType, PayloadFlag}};
 use std::{
-    sync::{
-        atomic::AtomicBool,
-        mpsc::{self, Receiver, Sender},
-        Arc, Mutex, RwLock,
-    },
-    time::Instant,
+    sync::{Arc, RwLock},
+    time::Duration,
 };
 
 pub mod diag_session_control;
+pub mod flash;
+pub mod io_control;
 pub mod read_data;
 pub mod read_dtc_info;
+pub mod routine_control;
+pub mod security_access;
 
 #[derive(Copy, Clone, Debug, Eq, PartialOrd, PartialEq)]
 /// UDS Commands AKA SID (Service identifiers)
@@ -392,17 +393,71 @@ impl CommandError for UDSNegativeCode {
             _ => Self::Reserved(b),
         }
     }
+
+    fn to_byte(&self) -> u8 {
+        match &self {
+            UDSNegativeCode::GeneralReject => 0x10,
+            UDSNegativeCode::ServiceNotSupported => 0x11,
+            UDSNegativeCode::SubFunctionNotSupported => 0x12,
+            UDSNegativeCode::IncorrectMessageLength => 0x13,
+            UDSNegativeCode::ResponseTooLong => 0x14,
+            UDSNegativeCode::BusyRepeatRequest => 0x21,
+            UDSNegativeCode::ConditionsNotCorrect => 0x22,
+            UDSNegativeCode::RequestSequenceError => 0x24,
+            UDSNegativeCode::NoResponseSubnetComponent => 0x25,
+            UDSNegativeCode::FailurePreventsExecutionOfRequestedAction => 0x26,
+            UDSNegativeCode::RequestOutOfRange => 0x31,
+            UDSNegativeCode::SecurityAccessDenied => 0x33,
+            UDSNegativeCode::InvalidKey => 0x35,
+            UDSNegativeCode::ExceedNumberOfAttempts => 0x36,
+            UDSNegativeCode::RequiredTimeDelayNotExpired => 0x37,
+            UDSNegativeCode::UploadDownloadNotAccepted => 0x70,
+            UDSNegativeCode::TransferDataSuspended => 0x71,
+            UDSNegativeCode::GeneralProgrammingFailure => 0x72,
+            UDSNegativeCode::WrongBlockSequenceCounter => 0x73,
+            UDSNegativeCode::ResponsePending => 0x78,
+            UDSNegativeCode::SubFunctionNotSupportedActiveSession => 0x7E,
+            UDSNegativeCode::ServiceNotSupportedActiveSession => 0x7F,
+            UDSNegativeCode::RpmTooHigh => 0x81,
+            UDSNegativeCode::RpmTooLow => 0x82,
+            UDSNegativeCode::EngineIsRunning => 0x83,
+            UDSNegativeCode::EngineIsNotRunning => 0x84,
+            UDSNegativeCode::EngineRunTimeTooLow => 0x85,
+            UDSNegativeCode::TempTooHigh => 0x86,
+            UDSNegativeCode::TempTooLow => 0x87,
+            UDSNegativeCode::SpeedTooHigh => 0x88,
+            UDSNegativeCode::SpeedTooLow => 0x89,
+            UDSNegativeCode::ThrottleTooHigh => 0x8A,
+            UDSNegativeCode::ThrottleTooLow => 0x8B,
+            UDSNegativeCode::TransmissionNotInNeutral => 0x8C,
+            UDSNegativeCode::TransmissionNotInGear => 0x8D,
+            UDSNegativeCode::BrakeNotApplied => 0x8F,
+            UDSNegativeCode::ShifterNotInPark => 0x90,
+            UDSNegativeCode::TorqueConverterClutchLocked => 0x91,
+            UDSNegativeCode::VoltageTooHigh => 0x92,
+            UDSNegativeCode::VoltageTooLow => 0x93,
+            UDSNegativeCode::ReservedSpecificConditionsIncorrect => 0x94,
+            UDSNegativeCode::Reserved(b) => *b,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct UDSECU {
-    should_run: Arc<AtomicBool>,
+    executor: RequestExecutor,
     last_error: Arc<RwLock<Option<ProtocolError>>>,
-    cmd_tx: Sender<(u8, Vec<u8>, bool)>,
-    cmd_rx: Arc<Receiver<ProtocolResult<Vec<u8>>>>,
     curr_session_type: Arc<RwLock<DiagSession>>,
+    /// Unlocked SecurityAccess level (Request seed sub function)
+    security_level: Arc<RwLock<Option<u8>>>,
+    /// Data identifiers the tester has taken control of with IO control
+    io_controls: Arc<RwLock<Vec<u16>>>,
     send_id: u32,
-    cmd_mutex: Arc<Mutex<()>>,
+    recv_id: u32,
+    /// Functional request ID of the connection
+    global_id: Option<u32>,
+    iface_type: InterfaceType,
+    tx_flags: Option<Vec<PayloadFlag>>,
+    timing: DiagTiming,
 }
 
 impl UDSECU {
@@ -412,12 +467,13 @@ impl UDSECU {
     }
 
     fn set_diag_session_mode(
-        &mut self,
+        &self,
         mode: DiagSession,
     ) -> std::result::Result<(), ProtocolError> {
         match diag_session_control::set_diag_session(&self, mode) {
             Ok(_) => {
                 *self.curr_session_type.write().unwrap() = mode; // Switch diagnostic modes!
+                *self.security_level.write().unwrap() = None; // ECU locks itself on session change
                 Ok(())
             }
             Err(e) => {
@@ -430,6 +486,86 @@ impl UDSECU {
     pub fn get_session_type(&self) -> DiagSession {
         *self.curr_session_type.read().unwrap()
     }
+
+    /// Returns the currently unlocked security level, if any
+    pub fn get_security_level(&self) -> Option<u8> {
+        *self.security_level.read().unwrap()
+    }
+
+    /// Returns the data identifiers of all components the tester is controlling
+    pub fn get_active_io_controls(&self) -> Vec<u16> {
+        self.io_controls.read().unwrap().clone()
+    }
+
+    /// Returns true if other copies of this server are still using its session
+    pub fn is_shared(&self) -> bool {
+        self.executor.is_shared()
+    }
+
+    /// Queues a request to the ECU, without waiting for its response. The request is
+    /// given up on if the ECU has not responded within [DiagTiming::get_request_timeout]
+    pub fn run_command_async(&self, cmd: u8, args: &[u8]) -> RequestHandle<Vec<u8>> {
+        self.run_command_async_timeout(cmd, args, self.timing.get_request_timeout())
+    }
+
+    /// Queues a request to the ECU, without waiting for its response. The request is
+    /// given up on if the ECU has not responded within `timeout`
+    pub fn run_command_async_timeout(
+        &self,
+        cmd: u8,
+        args: &[u8],
+        timeout: Duration,
+    ) -> RequestHandle<Vec<u8>> {
+        let args = Vec::from(args);
+        let tx_flags = self.tx_flags.clone();
+        let send_id = self.send_id;
+        let timing = self.timing;
+        self.executor.submit_timeout(timeout, move |iface| {
+            let resp =
+                Self::run_command_resp(iface, &tx_flags, send_id, cmd, &args, true, &timing)?;
+            if resp[0] == 0x7F {
+                let neg_code = UDSNegativeCode::from_byte(resp[2]);
+                Err(ProtocolError::ProtocolError(Box::new(neg_code)))
+            } else {
+                Ok(resp)
+            }
+        })
+    }
+
+    /// Queues a request on the functional ID of the connection ([DiagCfg::global_id]),
+    /// without waiting for the responses. The response of the ECU and of every ECU in
+    /// `others` (Request ID, Response ID) is collected, by response ID
+    pub fn run_command_functional_async(
+        &self,
+        cmd: u8,
+        args: &[u8],
+        others: &[(u32, u32)],
+    ) -> RequestHandle<FunctionalResponses> {
+        let args = Vec::from(args);
+        let others = Vec::from(others);
+        let tx_flags = self.tx_flags.clone();
+        let global_id = self.global_id;
+        let recv_id = self.recv_id;
+        let iface_type = self.iface_type;
+        let timing = self.timing;
+        self.executor
+            .submit_timeout(timing.get_request_timeout(), move |iface| {
+                let functional_id = global_id.ok_or_else(|| {
+                    ProtocolError::CustomError("Connection has no functional request ID".into())
+                })?;
+                Self::run_functional_command_ecus(
+                    iface,
+                    iface_type,
+                    &tx_flags,
+                    functional_id,
+                    cmd,
+                    &args,
+                    &timing,
+                    recv_id,
+                    &others,
+                )
+            })
+    }
 }
 
 impl ProtocolServer for UDSECU {
@@ -442,159 +578,123 @@ impl ProtocolServer for UDSECU {
         tx_flags: Option<Vec<PayloadFlag>>,
         diag_cfg: DiagCfg,
     ) -> ProtocolResult<Self> {
-        if interface_type != InterfaceType::IsoTp {
+        if interface_type != InterfaceType::IsoTp && interface_type != InterfaceType::DoIp {
             return Err(ProtocolError::CustomError(
-                "UDS Can only be executed over ISO-TP".into(),
+                "UDS Can only be executed over ISO-TP or DoIP".into(),
             ));
         }
 
-        let mut interface = IsoTPInterface::new(comm_server.clone_box())?;
-
-        interface.setup(&interface_cfg)?;
-        interface.add_filter(FilterType::IsoTP {
-            id: diag_cfg.recv_id,
-            mask: 0xFFFF,
-            fc: diag_cfg.send_id,
-        })?;
-
-        let should_run = Arc::new(AtomicBool::new(true));
-        let should_run_t = should_run.clone();
+        let mut interface =
+            DynamicInterface::new(comm_server, interface_type, &interface_cfg)?.clone_box();
+        if interface_type == InterfaceType::IsoTp {
+            interface.add_filter(FilterType::IsoTP {
+                id: diag_cfg.recv_id,
+                mask: 0xFFFF,
+                fc: diag_cfg.send_id,
+            })?;
+        } else {
+            // DoIP has no flow control, only accept responses from the target ECU
+            interface.add_filter(FilterType::Pass {
+                id: diag_cfg.recv_id,
+                mask: 0xFFFF,
+            })?;
+        }
 
         let last_error = Arc::new(RwLock::new(None));
         let last_error_t = last_error.clone();
 
-        let (channel_tx_sender, channel_tx_receiver): (
-            Sender<(u8, Vec<u8>, bool)>,
-            Receiver<(u8, Vec<u8>, bool)>,
-        ) = mpsc::channel();
-        let (channel_rx_sender, channel_rx_receiver): (
-            Sender<ProtocolResult<Vec<u8>>>,
-            Receiver<ProtocolResult<Vec<u8>>>,
-        ) = mpsc::channel();
-
         let session_type = Arc::new(RwLock::new(DiagSession::Default));
         let session_type_t = session_type.clone();
 
-        // Enter extended diagnostic session (Full features)
+        let io_controls = Arc::new(RwLock::new(Vec::new()));
+        let io_controls_t = io_controls.clone();
+
         let s_id = diag_cfg.send_id;
-        std::thread::spawn(move || {
-            println!("UDS Diag server start!");
-            let mut timer = Instant::now();
-            while should_run_t.load(Relaxed) {
-                if let Ok(data) = channel_tx_receiver.try_recv() {
-                    let res = Self::run_command_resp(
-                        &mut interface,
-                        &tx_flags,
-                        s_id,
-                        data.0,
-                        &data.1,
-                        data.2,
-                    );
-                    if channel_rx_sender.send(res).is_err() {
-                        *last_error_t.write().unwrap() =
-                            Some(ProtocolError::CustomError("Sender channel died".into()));
-                        break;
-                    }
+        let timing = diag_cfg.timing;
+        let tx_flags_t = tx_flags.clone();
+        // Tester present, to stay out of the default session
+        let executor = RequestExecutor::with_periodic(
+            "UDS",
+            interface,
+            Duration::from_millis(timing.s3 as u64),
+            Box::new(move |interface| {
+                if *session_type_t.read().unwrap() == DiagSession::Default {
+                    return true;
                 }
-                if timer.elapsed().as_millis() >= 2000
-                    && *session_type_t.read().unwrap() != DiagSession::Default
-                {
-                    if Self::run_command_resp(
-                        &mut interface,
-                        &tx_flags,
-                        s_id,
-                        UDSCommand::TesterPresent.into(),
-                        &[0x00],
-                        true,
-                    )
-                    .is_err()
-                    {
-                        println!("Lost connection with ECU!");
+                if let Err(e) = Self::run_command_resp(
+                    interface,
+                    &tx_flags_t,
+                    s_id,
+                    UDSCommand::TesterPresent.into(),
+                    &[0x00],
+                    true,
+                    &timing,
+                ) {
+                    println!("Lost connection with ECU!");
+                    *last_error_t.write().unwrap() = Some(e);
+                    // The ECU releases components itself once the session times out,
+                    // but try to release them now in case it can still hear us
+                    for did in io_controls_t.write().unwrap().drain(..) {
+                        let _ = Self::run_command_resp(
+                            interface,
+                            &tx_flags_t,
+                            s_id,
+                            UDSCommand::IOCTLById.into(),
+                            &[
+                                (did >> 8) as u8,
+                                did as u8,
+                                io_control::IOControlParameter::ReturnControlToECU.into(),
+                            ],
+                            true,
+                            &timing,
+                        );
                     }
-                    timer = Instant::now();
                 }
-                std::thread::sleep(std::time::Duration::from_micros(100))
-            }
-            println!("UDS Diag server stop!");
-            let _res = interface.close();
-        });
+                true
+            }),
+        );
 
-        let mut ecu = UDSECU {
-            should_run,
+        // Enter extended diagnostic session (Full features)
+        let ecu = UDSECU {
+            executor,
             last_error,
-            cmd_tx: channel_tx_sender,
-            cmd_rx: Arc::new(channel_rx_receiver),
             send_id: diag_cfg.send_id,
+            recv_id: diag_cfg.recv_id,
+            global_id: diag_cfg.global_id,
+            iface_type: interface_type,
             curr_session_type: session_type, // Assumed,
-            cmd_mutex: Arc::new(Mutex::new(())),
+            security_level: Arc::new(RwLock::new(None)),
+            io_controls,
+            tx_flags,
+            timing,
         };
 
         if let Err(e) = ecu.set_diag_session_mode(DiagSession::Extended) {
-            ecu.should_run.store(false, Relaxed);
+            ecu.executor.stop();
             return Err(e);
         }
         Ok(ecu)
     }
 
     fn exit_diag_session(&mut self) {
-        self.should_run.store(false, Relaxed);
+        if self.is_in_diag_session() {
+            // Never leave a component under the tester's control
+            let _ = io_control::return_all_control_to_ecu(self);
+        }
+        self.executor.stop();
     }
 
     fn run_command(&self, cmd: u8, args: &[u8]) -> ProtocolResult<Vec<u8>> {
-        let _guard = self.cmd_mutex.lock().unwrap(); // We are allowed to send / receive!
-        if self.cmd_tx.send((cmd, Vec::from(args), true)).is_err() {
-            return Err(ProtocolError::CustomError("Channel Tx failed".into()));
-        }
-        let resp = self.cmd_rx.recv().unwrap()?;
-        if resp[0] == 0x7F {
-            let neg_code = UDSNegativeCode::from_byte(resp[2]);
-            Err(ProtocolError::ProtocolError(Box::new(neg_code)))
-        } else {
-            Ok(resp)
-        }
+        self.run_command_async(cmd, args).wait()
     }
 
     fn read_errors(&self) -> ProtocolResult<Vec<DTC>> {
-        // Response is list of bytes starting in 0x59, 0x02 0xFF
-        let mut response = self.run_command(UDSCommand::ReadDTCInformation as u8, &[0x02, 0xFF])?;
-        response.drain(0..3); // Remove first 3 bytes
-        if response.len() % 4 != 0 {
-            // Message is of invalid length. Each DTC should be 3 bytes + Status byte = 4 bytes!
-            return Err(ProtocolError::InvalidResponseSize { expect: (((response.len() + 3) / 4) * 4) + 4, actual: response.len() + 3  })
-        }
-
-        let mut res : Vec<DTC> = Vec::new();
-
-        // Response OK, each DTC = 4 bytes
-        for i in (0..response.len()).step_by(4) {
-            let dtc_raw: u32 = (response[i] as u32) << 16 | (response[i+1] as u32) << 8 | response[i+2] as u32;
-
-            let dtc_string = format!("{:06X}", dtc_raw);
-
-            let status = response[i+3];
-            
-            let dtc_state = if status & 0b00000100 != 0 {
-                DTCState::Pending
-            } else if status & 0b00001000 != 0 {
-                DTCState::Permanent
-            } else if status & 0b00000001 != 0 {
-                DTCState::Stored
-            } else {
-                DTCState::None
-            };
-
-            res.push(DTC {
-                error: dtc_string,
-                state: dtc_state,
-                check_engine_on: status & 0b10000000 != 0, // TODO
-                id: dtc_raw,
-            });
-        }
-        Ok(res)
+        let list = read_dtc_info::read_dtc_by_status_mask(self, 0xFF)?;
+        Ok(list.dtcs.iter().map(|d| DTC::from_uds(d.dtc, d.status)).collect())
     }
 
     fn is_in_diag_session(&self) -> bool {
-        self.should_run.load(Relaxed) // Diag server self-terminates upon ECU Session error
+        self.executor.is_running()
     }
 
     fn get_last_error(&self) -> Option<String> {
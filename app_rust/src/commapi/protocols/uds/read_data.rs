@@ -1,10 +1,329 @@
-use crate::commapi::protocols::{ProtocolResult, ProtocolServer};
+use std::collections::HashMap;
 
-use super::UDSECU;
+use crate::commapi::protocols::{ProtocolError, ProtocolResult, ProtocolServer};
 
+use super::{UDSCommand, UDSECU};
+
+// The service, Read Data By Identifier ($22), reads one or more data records from the ECU,
+// each identified by a 2 byte data identifier (DID). The response contains each DID followed
+// by its data, in the order they were requested. As the response does not say how long each
+// record is, the length of every DID except the last must be known to split the response up.
+
+/// Identifier the ECU's diagnostic variant is read from
+pub const VARIANT_ID_DID: u16 = 0xF100;
+
+/// How the data of a DID is shown to the user
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DidFormat {
+    /// Text. Shown as hex if it contains unprintable characters
+    Ascii,
+    /// Raw bytes
+    Hex,
+    /// Big endian number
+    Numeric,
+    /// 3 byte BCD date, YY MM DD
+    BcdDate,
+    /// First byte is the number of software modules, followed by the ID of each module
+    ModuleList,
+}
+
+/// A data identifier the ECU can be queried for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DidDefinition {
+    pub id: u16,
+    pub name: String,
+    /// Length of the data in bytes. None if the length is not fixed
+    pub length: Option<usize>,
+    pub format: DidFormat,
+}
+
+impl DidDefinition {
+    pub fn new(id: u16, name: &str, length: Option<usize>, format: DidFormat) -> Self {
+        Self {
+            id,
+            name: name.into(),
+            length,
+            format,
+        }
+    }
+
+    /// Decodes the data of this DID to a readable string
+    pub fn decode(&self, data: &[u8]) -> String {
+        match self.format {
+            DidFormat::Ascii => decode_ascii(data),
+            DidFormat::Hex => format!("{:02X?}", data),
+            DidFormat::Numeric => data
+                .iter()
+                .fold(0u64, |n, x| n << 8 | *x as u64)
+                .to_string(),
+            DidFormat::BcdDate if data.len() == 3 => {
+                format!("{:02X}/{:02X}/{:02X}", data[2], data[1], data[0]) // DD/MM/YY
+            }
+            DidFormat::BcdDate => format!("{:02X?}", data),
+            DidFormat::ModuleList => match data.split_first() {
+                Some((count, ids)) => format!("{} module(s): {}", count, decode_ascii(ids)),
+                None => String::new(),
+            },
+        }
+    }
+}
+
+fn decode_ascii(data: &[u8]) -> String {
+    let trimmed: &[u8] = match data.iter().rposition(|x| *x != 0x00 && *x != 0xFF) {
+        Some(end) => &data[..=end],
+        None => &[],
+    };
+    if trimmed.iter().all(|x| x.is_ascii_graphic() || *x == b' ') {
+        String::from_utf8_lossy(trimmed).trim().to_string()
+    } else {
+        format!("{:02X?}", data)
+    }
+}
+
+/// Known data identifiers, used to split up and decode ReadDataByIdentifier responses
+#[derive(Debug, Clone)]
+pub struct DidDatabase {
+    dids: HashMap<u16, DidDefinition>,
+}
+
+impl Default for DidDatabase {
+    /// Creates a database containing the standard identifiers from ISO14229-1 Annex C
+    fn default() -> Self {
+        use DidFormat::*;
+        let mut db = Self {
+            dids: HashMap::new(),
+        };
+        for d in vec![
+            DidDefinition::new(0xF180, "Boot software identification", None, ModuleList),
+            DidDefinition::new(
+                0xF181,
+                "Application software identification",
+                None,
+                ModuleList,
+            ),
+            DidDefinition::new(0xF182, "Application data identification", None, ModuleList),
+            DidDefinition::new(0xF183, "Boot software fingerprint", None, Hex),
+            DidDefinition::new(0xF184, "Application software fingerprint", None, Hex),
+            DidDefinition::new(0xF185, "Application data fingerprint", None, Hex),
+            DidDefinition::new(0xF186, "Active diagnostic session", Some(1), Numeric),
+            DidDefinition::new(0xF187, "Manufacturer spare part number", None, Ascii),
+            DidDefinition::new(0xF188, "Manufacturer ECU software number", None, Ascii),
+            DidDefinition::new(0xF189, "Manufacturer ECU software version", None, Ascii),
+            DidDefinition::new(0xF18A, "System supplier identifier", None, Ascii),
+            DidDefinition::new(
+                0xF18B,
+                "ECU manufacturing date (DD/MM/YY)",
+                Some(3),
+                BcdDate,
+            ),
+            DidDefinition::new(0xF18C, "ECU serial number", None, Ascii),
+            DidDefinition::new(0xF18D, "Supported functional units", None, Hex),
+            DidDefinition::new(0xF18E, "Manufacturer kit assembly part number", None, Ascii),
+            DidDefinition::new(0xF190, "VIN", Some(17), Ascii),
+            DidDefinition::new(0xF191, "Manufacturer ECU hardware number", None, Ascii),
+            DidDefinition::new(0xF192, "Supplier ECU hardware number", None, Ascii),
+            DidDefinition::new(0xF193, "Supplier ECU hardware version", None, Ascii),
+            DidDefinition::new(0xF194, "Supplier ECU software number", None, Ascii),
+            DidDefinition::new(0xF195, "Supplier ECU software version", None, Ascii),
+            DidDefinition::new(
+                0xF196,
+                "Exhaust regulation / type approval number",
+                None,
+                Ascii,
+            ),
+            DidDefinition::new(0xF197, "System name or engine type", None, Ascii),
+            DidDefinition::new(
+                0xF198,
+                "Repair shop code / tester serial number",
+                None,
+                Ascii,
+            ),
+            DidDefinition::new(0xF199, "Programming date (DD/MM/YY)", Some(3), BcdDate),
+            DidDefinition::new(0xF19A, "Calibration equipment serial number", None, Ascii),
+            DidDefinition::new(0xF19B, "Calibration date (DD/MM/YY)", Some(3), BcdDate),
+            DidDefinition::new(0xF19C, "Calibration equipment software number", None, Ascii),
+            DidDefinition::new(0xF19D, "ECU installation date (DD/MM/YY)", Some(3), BcdDate),
+            DidDefinition::new(0xF19E, "ODX file", None, Ascii),
+            DidDefinition::new(0xF19F, "Entity", None, Hex),
+        ] {
+            db.add_did(d);
+        }
+        db
+    }
+}
+
+impl DidDatabase {
+    /// Adds a DID to the database, replacing any existing definition with the same ID
+    pub fn add_did(&mut self, did: DidDefinition) {
+        self.dids.insert(did.id, did);
+    }
+
+    pub fn get_did(&self, id: u16) -> Option<&DidDefinition> {
+        self.dids.get(&id)
+    }
+
+    /// Returns the name of a DID, or its ID in hex if it is not known
+    pub fn get_name(&self, id: u16) -> String {
+        match self.get_did(id) {
+            Some(d) => d.name.clone(),
+            None => format!("DID 0x{:04X}", id),
+        }
+    }
+
+    /// Decodes the data of a DID to a readable string. Unknown DIDs are shown as hex
+    pub fn decode(&self, value: &DidValue) -> String {
+        match self.get_did(value.id) {
+            Some(d) => d.decode(&value.data),
+            None => format!("{:02X?}", value.data),
+        }
+    }
+
+    /// Splits a ReadDataByIdentifier response into the data of each requested DID
+    pub fn parse_response(&self, ids: &[u16], res: &[u8]) -> ProtocolResult<Vec<DidValue>> {
+        let mut values = Vec::with_capacity(ids.len());
+        let mut pos = 1; // Skip the SID
+        for (idx, id) in ids.iter().enumerate() {
+            if res.len() < pos + 2 {
+                return Err(ProtocolError::InvalidResponseSize {
+                    expect: pos + 2,
+                    actual: res.len(),
+                });
+            }
+            let resp_id = (res[pos] as u16) << 8 | res[pos + 1] as u16;
+            if resp_id != *id {
+                return Err(ProtocolError::CustomError(format!(
+                    "ECU responded with DID 0x{:04X}, expected 0x{:04X}",
+                    resp_id, id
+                )));
+            }
+            pos += 2;
+            let len = match (self.get_did(*id).and_then(|d| d.length), ids.get(idx + 1)) {
+                (Some(len), _) => len,
+                (None, None) => res.len() - pos, // Last DID has the rest of the response
+                (None, Some(next)) => {
+                    // Best effort. Data ends where the next DID we asked for starts
+                    let next = next.to_be_bytes();
+                    res[pos..]
+                        .windows(2)
+                        .position(|w| w == next)
+                        .ok_or_else(|| {
+                            ProtocolError::CustomError(format!(
+                                "Cannot find the end of DID 0x{:04X} in the response",
+                                id
+                            ))
+                        })?
+                }
+            };
+            if res.len() < pos + len {
+                return Err(ProtocolError::InvalidResponseSize {
+                    expect: pos + len,
+                    actual: res.len(),
+                });
+            }
+            values.push(DidValue {
+                id: *id,
+                data: Vec::from(&res[pos..pos + len]),
+            });
+            pos += len;
+        }
+        Ok(values)
+    }
+}
+
+/// Data read from a DID
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DidValue {
+    pub id: u16,
+    pub data: Vec<u8>,
+}
+
+/// Reads one or more DIDs from the ECU in a single request, using `db` to split up the response
+pub fn read_data_by_identifier_with(
+    ecu: &UDSECU,
+    db: &DidDatabase,
+    ids: &[u16],
+) -> ProtocolResult<Vec<DidValue>> {
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let args: Vec<u8> = ids.iter().flat_map(|id| id.to_be_bytes()).collect();
+    let res = ecu.run_command(UDSCommand::ReadDataByID.into(), &args)?;
+    db.parse_response(ids, &res)
+}
+
+/// Reads one or more DIDs from the ECU in a single request, using the standard DIDs to
+/// split up the response
+pub fn read_data_by_identifier(ecu: &UDSECU, ids: &[u16]) -> ProtocolResult<Vec<DidValue>> {
+    read_data_by_identifier_with(ecu, &DidDatabase::default(), ids)
+}
+
+/// Reads the ECU identification DIDs (0xF180 - 0xF19F) the ECU supports, returning the name
+/// and decoded value of each. All DIDs are asked for in one request first. If the ECU
+/// rejects that, each DID is read on its own, skipping any the ECU does not support
+pub fn read_ecu_identification(ecu: &UDSECU) -> Vec<(String, String)> {
+    let db = DidDatabase::default();
+    let ids: Vec<u16> = (0xF180..=0xF19F)
+        .filter(|id| *id != 0xF186 && db.get_did(*id).is_some()) // Session is not identification
+        .collect();
+    let values = match read_data_by_identifier_with(ecu, &db, &ids) {
+        Ok(v) => v,
+        Err(_) => ids
+            .iter()
+            .filter_map(|id| read_data_by_identifier_with(ecu, &db, &[*id]).ok())
+            .flatten()
+            .collect(),
+    };
+    values
+        .iter()
+        .map(|v| (db.get_name(v.id), db.decode(v)))
+        .collect()
+}
+
+/// Reads the diagnostic variant ID of the ECU
 pub fn read_variant_id(ecu: &UDSECU) -> ProtocolResult<u32> {
-    let mut res = ecu.run_command(super::UDSCommand::ReadDataByID.into(), &[0xF1, 0x00])?;
-    println!("{:02X?}", res);
-    res.drain(0..2);
-    Ok((res[0] as u32) << 24 | (res[1] as u32) << 16 | (res[2] as u32) << 8 | res[3] as u32)
+    let res = read_data_by_identifier(ecu, &[VARIANT_ID_DID])?;
+    match res.first() {
+        Some(v) if v.data.len() >= 3 => {
+            Ok((v.data[0] as u32) << 16 | (v.data[1] as u32) << 8 | v.data[2] as u32)
+        }
+        Some(v) => Err(ProtocolError::InvalidResponseSize {
+            expect: 6,
+            actual: v.data.len() + 3,
+        }),
+        None => Err(ProtocolError::InvalidResponseSize {
+            expect: 6,
+            actual: 0,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multi_did_response() {
+        let db = DidDatabase::default();
+        let mut res = vec![0x62, 0xF1, 0x90];
+        res.extend_from_slice(b"WDD2030461A123456");
+        res.extend_from_slice(&[0xF1, 0x8C]);
+        res.extend_from_slice(b"SN1234\0\0");
+        res.extend_from_slice(&[0xF1, 0x99, 0x21, 0x03, 0x15]);
+        res.extend_from_slice(&[0xF1, 0x87]);
+        res.extend_from_slice(b"A0009002101");
+
+        let values = db
+            .parse_response(&[0xF190, 0xF18C, 0xF199, 0xF187], &res)
+            .unwrap();
+        assert_eq!(values.len(), 4);
+        assert_eq!(db.decode(&values[0]), "WDD2030461A123456");
+        assert_eq!(db.decode(&values[1]), "SN1234");
+        assert_eq!(db.decode(&values[2]), "15/03/21");
+        assert_eq!(db.decode(&values[3]), "A0009002101");
+
+        // DIDs must come back in the order they were asked for
+        assert!(db.parse_response(&[0xF18C, 0xF190], &res).is_err());
+        // Truncated data
+        assert!(db.parse_response(&[0xF190], &res[0..10]).is_err());
+    }
 }
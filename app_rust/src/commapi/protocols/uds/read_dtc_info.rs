@@ -1,15 +1,521 @@
-use crate::commapi::protocols::{DTC, ProtocolResult, ProtocolServer};
+use crate::commapi::protocols::{ProtocolError, ProtocolResult, ProtocolServer, DTC};
 
-use super::UDSECU;
+use super::{UDSCommand, UDSECU};
 
+// The service, Read DTC Information ($19), reads DTCs and the data stored alongside
+// them out of the ECU. What is reported is chosen by the sub function (reportType).
+// Every DTC the ECU reports is 3 bytes, followed by its status byte.
+
+/// Sub functions of ReadDTCInformation
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReportType {
+    NumberOfDTCByStatusMask = 0x01,
+    DTCByStatusMask = 0x02,
+    DTCSnapshotIdentification = 0x03,
+    DTCSnapshotRecordByDTCNumber = 0x04,
+    DTCExtDataRecordByDTCNumber = 0x06,
+    DTCBySeverityMaskRecord = 0x08,
+    SeverityInformationOfDTC = 0x09,
+    SupportedDTC = 0x0A,
+    FirstTestFailedDTC = 0x0B,
+    FirstConfirmedDTC = 0x0C,
+    MostRecentTestFailedDTC = 0x0D,
+    MostRecentConfirmedDTC = 0x0E,
+    MirrorMemoryDTCByStatusMask = 0x0F,
+    MirrorMemoryDTCExtDataRecordByDTCNumber = 0x10,
+    NumberOfMirrorMemoryDTCByStatusMask = 0x11,
+    DTCWithPermanentStatus = 0x15,
+}
+
+impl Into<u8> for ReportType {
+    fn into(self) -> u8 {
+        self as u8
+    }
+}
+
+/// DTC status byte, with all 8 status bits (ISO14229-1 Annex D)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct DTCStatus {
+    /// Bit 0 - The most recent test failed
+    pub test_failed: bool,
+    /// Bit 1 - A test failed during the current operation cycle
+    pub test_failed_this_operation_cycle: bool,
+    /// Bit 2 - A test failed during the current or last operation cycle
+    pub pending_dtc: bool,
+    /// Bit 3 - The DTC was confirmed and stored
+    pub confirmed_dtc: bool,
+    /// Bit 4 - A test has not completed since DTCs were last cleared
+    pub test_not_completed_since_last_clear: bool,
+    /// Bit 5 - A test failed at least once since DTCs were last cleared
+    pub test_failed_since_last_clear: bool,
+    /// Bit 6 - A test has not completed during the current operation cycle
+    pub test_not_completed_this_operation_cycle: bool,
+    /// Bit 7 - The ECU is asking for a warning indicator (Such as the MIL) to be on
+    pub warning_indicator_requested: bool,
+}
+
+impl DTCStatus {
+    pub fn from_byte(b: u8) -> Self {
+        Self {
+            test_failed: b & 0x01 != 0,
+            test_failed_this_operation_cycle: b & 0x02 != 0,
+            pending_dtc: b & 0x04 != 0,
+            confirmed_dtc: b & 0x08 != 0,
+            test_not_completed_since_last_clear: b & 0x10 != 0,
+            test_failed_since_last_clear: b & 0x20 != 0,
+            test_not_completed_this_operation_cycle: b & 0x40 != 0,
+            warning_indicator_requested: b & 0x80 != 0,
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        [
+            self.test_failed,
+            self.test_failed_this_operation_cycle,
+            self.pending_dtc,
+            self.confirmed_dtc,
+            self.test_not_completed_since_last_clear,
+            self.test_failed_since_last_clear,
+            self.test_not_completed_this_operation_cycle,
+            self.warning_indicator_requested,
+        ]
+        .iter()
+        .enumerate()
+        .fold(0u8, |b, (bit, set)| b | (*set as u8) << bit)
+    }
+}
+
+/// DTC severity byte. Only the upper 3 bits are defined (ISO14229-1 Annex D)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct DTCSeverity {
+    /// Bit 5 - Fault only needs fixing at the next service
+    pub maintenance_only: bool,
+    /// Bit 6 - Fault should be checked at the next stop
+    pub check_at_next_halt: bool,
+    /// Bit 7 - Fault should be checked immediately
+    pub check_immediately: bool,
+}
+
+impl DTCSeverity {
+    pub fn from_byte(b: u8) -> Self {
+        Self {
+            maintenance_only: b & 0x20 != 0,
+            check_at_next_halt: b & 0x40 != 0,
+            check_immediately: b & 0x80 != 0,
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        (self.maintenance_only as u8) << 5
+            | (self.check_at_next_halt as u8) << 6
+            | (self.check_immediately as u8) << 7
+    }
+}
+
+/// Format of the DTCs the ECU reports
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DTCFormat {
+    ISO15031_6,
+    ISO14229_1,
+    SAEJ1939_73,
+    ISO11992_4,
+    SAEJ2012WWHOBD,
+    Unknown(u8),
+}
+
+impl DTCFormat {
+    pub fn from_byte(b: u8) -> Self {
+        match b {
+            0x00 => Self::ISO15031_6,
+            0x01 => Self::ISO14229_1,
+            0x02 => Self::SAEJ1939_73,
+            0x03 => Self::ISO11992_4,
+            0x04 => Self::SAEJ2012WWHOBD,
+            x => Self::Unknown(x),
+        }
+    }
+}
+
+/// A DTC reported by the ECU
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DTCStatusRecord {
+    /// 3 byte DTC number
+    pub dtc: u32,
+    pub status: DTCStatus,
+}
+
+/// Number of DTCs matching a status mask
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DTCCount {
+    /// Status bits the ECU supports
+    pub availability_mask: u8,
+    pub format: DTCFormat,
+    pub count: u16,
+}
+
+/// List of DTCs reported by the ECU
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DTCList {
+    /// Status bits the ECU supports
+    pub availability_mask: u8,
+    pub dtcs: Vec<DTCStatusRecord>,
+}
+
+/// Severity and functional unit of a DTC
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DTCSeverityRecord {
+    pub dtc: DTCStatusRecord,
+    pub severity: DTCSeverity,
+    /// Function of the ECU the DTC belongs to. What each unit is, is up to the ECU manufacturer
+    pub functional_unit: u8,
+}
+
+/// A DTC that has a snapshot record stored for it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DTCSnapshotIdentification {
+    pub dtc: u32,
+    pub record_number: u8,
+}
+
+/// Snapshot or extended data records stored with a DTC.
+///
+/// The layout of the data depends on the ECU, so it is returned as is. For snapshot records,
+/// `data` starts with the number of data identifiers in the record, followed by each
+/// identifier and its data. If all records were requested (record number 0xFF), `data`
+/// contains the remaining records, each starting with their record number
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DTCRecordData {
+    pub dtc: DTCStatusRecord,
+    /// Number of the first record. None if the ECU has no record stored
+    pub record_number: Option<u8>,
+    pub data: Vec<u8>,
+}
+
+/// First or most recent DTC to fail, see [read_dtc_by_occurrence]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DTCOccurrence {
+    FirstTestFailed,
+    FirstConfirmed,
+    MostRecentTestFailed,
+    MostRecentConfirmed,
+}
+
+fn run_report(ecu: &UDSECU, report: ReportType, args: &[u8]) -> ProtocolResult<Vec<u8>> {
+    let mut req = vec![report.into()];
+    req.extend_from_slice(args);
+    let res = ecu.run_command(UDSCommand::ReadDTCInformation.into(), &req)?;
+    if res.len() < 2 {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: 2,
+            actual: res.len(),
+        });
+    }
+    Ok(res)
+}
+
+fn dtc_bytes(dtc: u32) -> [u8; 3] {
+    [(dtc >> 16) as u8, (dtc >> 8) as u8, dtc as u8]
+}
+
+fn read_dtc_number(b: &[u8]) -> u32 {
+    (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32
+}
+
+/// Parses a response containing the availability mask followed by DTC and status records
+fn parse_dtc_list(res: &[u8]) -> ProtocolResult<DTCList> {
+    // 0x59, reportType, availability mask
+    if res.len() < 3 || (res.len() - 3) % 4 != 0 {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: std::cmp::max(3, ((res.len() + 1) / 4) * 4 + 3),
+            actual: res.len(),
+        });
+    }
+    Ok(DTCList {
+        availability_mask: res[2],
+        dtcs: res[3..]
+            .chunks(4)
+            .map(|c| DTCStatusRecord {
+                dtc: read_dtc_number(c),
+                status: DTCStatus::from_byte(c[3]),
+            })
+            .collect(),
+    })
+}
+
+fn parse_severity_list(res: &[u8]) -> ProtocolResult<Vec<DTCSeverityRecord>> {
+    // 0x59, reportType, availability mask, then severity, functional unit, DTC and status
+    if res.len() < 3 || (res.len() - 3) % 6 != 0 {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: std::cmp::max(3, ((res.len() + 3) / 6) * 6 + 3),
+            actual: res.len(),
+        });
+    }
+    Ok(res[3..]
+        .chunks(6)
+        .map(|c| DTCSeverityRecord {
+            severity: DTCSeverity::from_byte(c[0]),
+            functional_unit: c[1],
+            dtc: DTCStatusRecord {
+                dtc: read_dtc_number(&c[2..5]),
+                status: DTCStatus::from_byte(c[5]),
+            },
+        })
+        .collect())
+}
+
+fn parse_dtc_count(res: &[u8]) -> ProtocolResult<DTCCount> {
+    // 0x59, reportType, availability mask, format, count (2 bytes)
+    if res.len() != 6 {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: 6,
+            actual: res.len(),
+        });
+    }
+    Ok(DTCCount {
+        availability_mask: res[2],
+        format: DTCFormat::from_byte(res[3]),
+        count: (res[4] as u16) << 8 | res[5] as u16,
+    })
+}
+
+fn parse_record_data(res: &[u8]) -> ProtocolResult<DTCRecordData> {
+    // 0x59, reportType, DTC (3 bytes), status, records...
+    if res.len() < 6 {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: 6,
+            actual: res.len(),
+        });
+    }
+    Ok(DTCRecordData {
+        dtc: DTCStatusRecord {
+            dtc: read_dtc_number(&res[2..5]),
+            status: DTCStatus::from_byte(res[5]),
+        },
+        record_number: res.get(6).copied(),
+        data: res.get(7..).map(Vec::from).unwrap_or_default(),
+    })
+}
+
+/// Reads how many DTCs match `status_mask`
+pub fn read_number_of_dtc_by_status_mask(
+    ecu: &UDSECU,
+    status_mask: u8,
+) -> ProtocolResult<DTCCount> {
+    parse_dtc_count(&run_report(
+        ecu,
+        ReportType::NumberOfDTCByStatusMask,
+        &[status_mask],
+    )?)
+}
+
+/// Reads all DTCs with any of the status bits in `status_mask` set
+pub fn read_dtc_by_status_mask(ecu: &UDSECU, status_mask: u8) -> ProtocolResult<DTCList> {
+    parse_dtc_list(&run_report(
+        ecu,
+        ReportType::DTCByStatusMask,
+        &[status_mask],
+    )?)
+}
+
+/// Reads which DTCs have snapshot records stored, and their record numbers
+pub fn read_dtc_snapshot_identification(
+    ecu: &UDSECU,
+) -> ProtocolResult<Vec<DTCSnapshotIdentification>> {
+    let res = run_report(ecu, ReportType::DTCSnapshotIdentification, &[])?;
+    if (res.len() - 2) % 4 != 0 {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: ((res.len() + 2) / 4) * 4 + 2,
+            actual: res.len(),
+        });
+    }
+    Ok(res[2..]
+        .chunks(4)
+        .map(|c| DTCSnapshotIdentification {
+            dtc: read_dtc_number(c),
+            record_number: c[3],
+        })
+        .collect())
+}
+
+/// Reads a snapshot record of a DTC. A `record_number` of 0xFF reads all records
+pub fn read_dtc_snapshot_record(
+    ecu: &UDSECU,
+    dtc: u32,
+    record_number: u8,
+) -> ProtocolResult<DTCRecordData> {
+    let mut args = Vec::from(&dtc_bytes(dtc)[..]);
+    args.push(record_number);
+    parse_record_data(&run_report(
+        ecu,
+        ReportType::DTCSnapshotRecordByDTCNumber,
+        &args,
+    )?)
+}
+
+/// Reads an extended data record of a DTC. A `record_number` of 0xFF reads all records
+pub fn read_dtc_ext_data_record(
+    ecu: &UDSECU,
+    dtc: u32,
+    record_number: u8,
+) -> ProtocolResult<DTCRecordData> {
+    let mut args = Vec::from(&dtc_bytes(dtc)[..]);
+    args.push(record_number);
+    parse_record_data(&run_report(
+        ecu,
+        ReportType::DTCExtDataRecordByDTCNumber,
+        &args,
+    )?)
+}
+
+/// Reads the severity of all DTCs with any of the bits in `severity_mask` and `status_mask` set
+pub fn read_dtc_by_severity_mask(
+    ecu: &UDSECU,
+    severity_mask: u8,
+    status_mask: u8,
+) -> ProtocolResult<Vec<DTCSeverityRecord>> {
+    parse_severity_list(&run_report(
+        ecu,
+        ReportType::DTCBySeverityMaskRecord,
+        &[severity_mask, status_mask],
+    )?)
+}
+
+/// Reads the severity and functional unit of a DTC. Returns None if the ECU does not have the DTC
+pub fn read_dtc_severity(ecu: &UDSECU, dtc: u32) -> ProtocolResult<Option<DTCSeverityRecord>> {
+    Ok(parse_severity_list(&run_report(
+        ecu,
+        ReportType::SeverityInformationOfDTC,
+        &dtc_bytes(dtc),
+    )?)?
+    .first()
+    .copied())
+}
+
+/// Reads every DTC the ECU supports, regardless of status
+pub fn read_supported_dtc(ecu: &UDSECU) -> ProtocolResult<DTCList> {
+    parse_dtc_list(&run_report(ecu, ReportType::SupportedDTC, &[])?)
+}
+
+/// Reads the first or most recent DTC to fail, since DTCs were last cleared.
+/// Returns None if no DTC has failed
+pub fn read_dtc_by_occurrence(
+    ecu: &UDSECU,
+    occurrence: DTCOccurrence,
+) -> ProtocolResult<Option<DTCStatusRecord>> {
+    let report = match occurrence {
+        DTCOccurrence::FirstTestFailed => ReportType::FirstTestFailedDTC,
+        DTCOccurrence::FirstConfirmed => ReportType::FirstConfirmedDTC,
+        DTCOccurrence::MostRecentTestFailed => ReportType::MostRecentTestFailedDTC,
+        DTCOccurrence::MostRecentConfirmed => ReportType::MostRecentConfirmedDTC,
+    };
+    Ok(parse_dtc_list(&run_report(ecu, report, &[])?)?
+        .dtcs
+        .first()
+        .copied())
+}
+
+/// Reads the number of DTCs in mirror memory matching `status_mask`
+pub fn read_number_of_mirror_memory_dtc_by_status_mask(
+    ecu: &UDSECU,
+    status_mask: u8,
+) -> ProtocolResult<DTCCount> {
+    parse_dtc_count(&run_report(
+        ecu,
+        ReportType::NumberOfMirrorMemoryDTCByStatusMask,
+        &[status_mask],
+    )?)
+}
+
+/// Reads DTCs in mirror memory with any of the status bits in `status_mask` set.
+/// Mirror memory is a copy of the DTC memory which is not erased by ClearDTCInformation
+pub fn read_mirror_memory_dtc_by_status_mask(
+    ecu: &UDSECU,
+    status_mask: u8,
+) -> ProtocolResult<DTCList> {
+    parse_dtc_list(&run_report(
+        ecu,
+        ReportType::MirrorMemoryDTCByStatusMask,
+        &[status_mask],
+    )?)
+}
+
+/// Reads an extended data record of a DTC in mirror memory. A `record_number` of 0xFF reads all records
+pub fn read_mirror_memory_dtc_ext_data_record(
+    ecu: &UDSECU,
+    dtc: u32,
+    record_number: u8,
+) -> ProtocolResult<DTCRecordData> {
+    let mut args = Vec::from(&dtc_bytes(dtc)[..]);
+    args.push(record_number);
+    parse_record_data(&run_report(
+        ecu,
+        ReportType::MirrorMemoryDTCExtDataRecordByDTCNumber,
+        &args,
+    )?)
+}
+
+/// Reads DTCs stored as permanent. These cannot be cleared by the tester, only by the
+/// ECU once it sees the fault has gone
+pub fn read_permanent_dtc(ecu: &UDSECU) -> ProtocolResult<DTCList> {
+    parse_dtc_list(&run_report(ecu, ReportType::DTCWithPermanentStatus, &[])?)
+}
+
+/// Reads all snapshot records of a DTC, returning the raw response
+pub fn read_dtc_snapshot_information(ecu: &UDSECU, dtc: &DTC) -> ProtocolResult<Vec<u8>> {
+    let mut args = Vec::from(&dtc_bytes(dtc.id)[..]);
+    args.push(0xFF);
+    run_report(ecu, ReportType::DTCSnapshotRecordByDTCNumber, &args)
+}
+
+/// Reads all extended data records of a DTC, returning the raw response
 pub fn read_dtc_information(ecu: &UDSECU, dtc: &DTC) -> ProtocolResult<Vec<u8>> {
-    ecu.run_command(
-        super::UDSCommand::ReadDTCInformation.into(),
-        &[
-            0x06, // reportDTCExtendedDataRecordByDTCNumber
-            (dtc.id >> 16) as u8, // High byte
-            (dtc.id >> 8) as u8, // Mid byte
-            (dtc.id) as u8 // Low byte
-        ],
-    )
-}
\ No newline at end of file
+    let mut args = Vec::from(&dtc_bytes(dtc.id)[..]);
+    args.push(0xFF);
+    run_report(ecu, ReportType::DTCExtDataRecordByDTCNumber, &args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_bits() {
+        for b in 0..=0xFF {
+            assert_eq!(DTCStatus::from_byte(b).to_byte(), b);
+        }
+        let s = DTCStatus::from_byte(0x89);
+        assert!(s.test_failed && s.confirmed_dtc && s.warning_indicator_requested);
+        assert!(!s.pending_dtc);
+    }
+
+    #[test]
+    fn parse_responses() {
+        let list = parse_dtc_list(&[
+            0x59, 0x02, 0xFF, 0x12, 0x34, 0x56, 0x08, 0xC0, 0x01, 0x00, 0x24,
+        ])
+        .unwrap();
+        assert_eq!(list.availability_mask, 0xFF);
+        assert_eq!(list.dtcs.len(), 2);
+        assert_eq!(list.dtcs[0].dtc, 0x123456);
+        assert!(list.dtcs[0].status.confirmed_dtc);
+        assert_eq!(list.dtcs[1].dtc, 0xC00100);
+        assert!(parse_dtc_list(&[0x59, 0x02, 0xFF, 0x12]).is_err());
+
+        let count = parse_dtc_count(&[0x59, 0x01, 0x7F, 0x01, 0x00, 0x03]).unwrap();
+        assert_eq!(count.format, DTCFormat::ISO14229_1);
+        assert_eq!(count.count, 3);
+
+        let rec = parse_record_data(&[0x59, 0x06, 0x12, 0x34, 0x56, 0x08, 0x01, 0xAA]).unwrap();
+        assert_eq!(rec.record_number, Some(0x01));
+        assert_eq!(rec.data, vec![0xAA]);
+        let rec = parse_record_data(&[0x59, 0x06, 0x12, 0x34, 0x56, 0x08]).unwrap();
+        assert_eq!(rec.record_number, None);
+
+        let sev =
+            parse_severity_list(&[0x59, 0x09, 0xFF, 0x40, 0x10, 0x12, 0x34, 0x56, 0x09]).unwrap();
+        assert_eq!(sev.len(), 1);
+        assert!(sev[0].severity.check_at_next_halt);
+        assert_eq!(sev[0].functional_unit, 0x10);
+        assert_eq!(sev[0].dtc.dtc, 0x123456);
+    }
+}
@@ -0,0 +1,112 @@
+use crate::commapi::protocols::{ProtocolError, ProtocolResult, ProtocolServer};
+
+use super::{UDSCommand, UDSECU};
+
+// The service, RoutineControl ($31), is used to start a routine on the ECU, such as
+// erasing memory or running a self test, stop it, and request its results.
+// Each routine is identified by a 2 byte routineIdentifier. What the optional
+// routineControlOptionRecord and routineStatusRecord contain is up to the ECU manufacturer.
+
+/// Sub functions of RoutineControl
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RoutineControlType {
+    StartRoutine,
+    StopRoutine,
+    RequestRoutineResults,
+}
+
+impl Into<u8> for RoutineControlType {
+    fn into(self) -> u8 {
+        match self {
+            RoutineControlType::StartRoutine => 0x01,
+            RoutineControlType::StopRoutine => 0x02,
+            RoutineControlType::RequestRoutineResults => 0x03,
+        }
+    }
+}
+
+/// Positive response to a RoutineControl request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutineResult {
+    pub routine_id: u16,
+    /// Full ECU response, so that output params can be decoded from it
+    pub raw: Vec<u8>,
+}
+
+impl RoutineResult {
+    /// Returns the routineInfo and routineStatusRecord of the response
+    pub fn get_status_record(&self) -> &[u8] {
+        &self.raw[4..]
+    }
+}
+
+/// Sends a RoutineControl request.
+///
+/// ## Params
+/// * `control_type` - What to do with the routine
+/// * `routine_id` - Routine identifier
+/// * `option_record` - routineControlOptionRecord to send with the request
+pub fn routine_control(
+    ecu: &UDSECU,
+    control_type: RoutineControlType,
+    routine_id: u16,
+    option_record: &[u8],
+) -> ProtocolResult<RoutineResult> {
+    let mut args = vec![control_type.into()];
+    args.extend_from_slice(&routine_id.to_be_bytes());
+    args.extend_from_slice(option_record);
+    let res = ecu.run_command(UDSCommand::RoutineControl.into(), &args)?;
+    if res.len() < 4 {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: 4,
+            actual: res.len(),
+        });
+    }
+    if res[1..4] != args[0..3] {
+        return Err(ProtocolError::CustomError(
+            "ECU responded with a different routine".into(),
+        ));
+    }
+    Ok(RoutineResult {
+        routine_id,
+        raw: res,
+    })
+}
+
+/// Starts a routine on the ECU
+pub fn start_routine(
+    ecu: &UDSECU,
+    routine_id: u16,
+    option_record: &[u8],
+) -> ProtocolResult<RoutineResult> {
+    routine_control(
+        ecu,
+        RoutineControlType::StartRoutine,
+        routine_id,
+        option_record,
+    )
+}
+
+/// Stops a running routine
+pub fn stop_routine(
+    ecu: &UDSECU,
+    routine_id: u16,
+    option_record: &[u8],
+) -> ProtocolResult<RoutineResult> {
+    routine_control(
+        ecu,
+        RoutineControlType::StopRoutine,
+        routine_id,
+        option_record,
+    )
+}
+
+/// Requests the results of a routine
+pub fn request_routine_results(ecu: &UDSECU, routine_id: u16) -> ProtocolResult<RoutineResult> {
+    routine_control(
+        ecu,
+        RoutineControlType::RequestRoutineResults,
+        routine_id,
+        &[],
+    )
+}
@@ -0,0 +1,1743 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Condvar, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use common::schema::{diag::service::Service, OvdECU, ServerType};
+
+use crate::commapi::comm_api::{
+    CanFrame, Capability, ComServer, ComServerError, DeviceCapabilities, FilterType, ISO15765Data,
+    J1850Data, KLineData,
+};
+
+use super::{
+    iface::Iso14230Header,
+    protocols::{
+        flash_image::FlashImage,
+        security::SeedKeyAlgorithm,
+        uds::flash::{CHECK_MEMORY_ROUTINE, ERASE_MEMORY_ROUTINE},
+        DiagProtocol,
+    },
+};
+#[cfg(test)]
+use super::{
+    iface::{Interface, InterfaceConfig, InterfaceType, IsoTPInterface, IFACE_CFG},
+    protocols::{timing::DiagTiming, DiagCfg, DiagServer, ProtocolServer},
+};
+
+/// A DTC that is stored in a [VirtualECU]
+#[derive(Debug, Clone)]
+pub struct SimDTC {
+    /// Raw DTC ID. 2 bytes for KWP2000, 3 bytes for UDS
+    pub id: u32,
+    /// Raw status byte the ECU reports for the DTC
+    pub status: u8,
+    /// Environment (Freeze frame) data returned when the DTC is queried
+    pub env_data: Vec<u8>,
+}
+
+/// A routine that can be started on a [VirtualECU]
+#[derive(Debug, Clone)]
+pub struct SimRoutine {
+    /// Results the ECU responds with once the routine completes
+    pub result: Vec<u8>,
+    /// Number of result requests that are answered with 'not complete' before the routine completes
+    pub busy_polls: u32,
+    polls_left: Option<u32>,
+}
+
+impl SimRoutine {
+    pub fn new(result: Vec<u8>, busy_polls: u32) -> Self {
+        Self {
+            result,
+            busy_polls,
+            polls_left: None,
+        }
+    }
+}
+
+/// Download started by RequestDownload, that is being filled by TransferData
+#[derive(Debug, Clone)]
+struct SimDownload {
+    address: u32,
+    size: usize,
+    data: Vec<u8>,
+    next_bsc: u8,
+}
+
+/// Memory being read out of the ECU with RequestUpload (KWP2000)
+#[derive(Debug, Clone)]
+struct SimUpload {
+    data: Vec<u8>,
+    offset: usize,
+}
+
+/// A simulated ECU which answers ISO-TP and K-Line (ISO14230) requests in-process.
+///
+/// Behaviour for the standard diagnostic services (Session control, DTCs, identification)
+/// is built in, everything else is answered from the services defined in the loaded
+/// [OvdECU](common::schema::OvdECU) variant, or from [custom_responses](VirtualECU::custom_responses)
+#[derive(Debug, Clone)]
+pub struct VirtualECU {
+    /// Name of the ECU
+    pub name: String,
+    /// Diagnostic protocol the ECU speaks
+    pub protocol: DiagProtocol,
+    /// CAN ID (Or K-Line address) the ECU listens to for requests
+    pub request_id: u32,
+    /// CAN ID (Or K-Line address) the ECU sends responses on
+    pub response_id: u32,
+    /// Optional functional (broadcast) request ID the ECU also listens to
+    pub functional_id: Option<u32>,
+    /// Variant ID reported when the tester asks for identification
+    pub variant_id: u32,
+    /// DTCs currently stored in the ECU
+    pub dtcs: Vec<SimDTC>,
+    /// Services from the ECU variant definition
+    pub services: Vec<Service>,
+    /// Request -> Response overrides. These are checked before anything else.
+    /// An empty response makes the ECU stay silent
+    pub custom_responses: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Number of ResponsePending (0x78) responses to send before each positive response
+    pub response_pending_count: u32,
+    /// Delay (ms) between each ResponsePending, and before the final response (ISO-TP only)
+    pub response_pending_interval: u32,
+    /// Algorithm used to check SecurityAccess keys. If None, any key is accepted
+    pub security_algorithm: Option<Arc<dyn SeedKeyAlgorithm>>,
+    /// maxNumberOfBlockLength reported in RequestDownload responses
+    pub max_block_length: u16,
+    /// RoutineControl ID that erases [flash_memory](VirtualECU::flash_memory)
+    pub erase_routine: u16,
+    /// RoutineControl ID that compares the CRC32 of [flash_memory](VirtualECU::flash_memory)
+    pub check_routine: u16,
+    /// Memory written with RequestDownload and TransferData
+    pub flash_memory: FlashImage,
+    /// Values of the data identifiers (UDS) or local identifiers (KWP2000) that
+    /// can be read and written by the tester
+    pub data_identifiers: HashMap<u16, Vec<u8>>,
+    /// Identifiers of the components currently under the tester's control (IO control)
+    pub io_controls: Vec<u16>,
+    /// Routines that can be started, by routine identifier (UDS) or local identifier (KWP2000)
+    pub routines: HashMap<u16, SimRoutine>,
+    session: u8,
+    security_level: Option<u8>,
+    pending_seed: Option<(u8, Vec<u8>)>,
+    seed_counter: u32,
+    failed_key_attempts: u32,
+    download: Option<SimDownload>,
+    upload: Option<SimUpload>,
+}
+
+impl VirtualECU {
+    pub fn new(name: &str, protocol: DiagProtocol, request_id: u32, response_id: u32) -> Self {
+        Self {
+            name: name.into(),
+            protocol,
+            request_id,
+            response_id,
+            functional_id: None,
+            variant_id: 0,
+            dtcs: Vec::new(),
+            services: Vec::new(),
+            custom_responses: Vec::new(),
+            response_pending_count: 0,
+            response_pending_interval: 0,
+            security_algorithm: None,
+            max_block_length: 0x0802,
+            erase_routine: ERASE_MEMORY_ROUTINE,
+            check_routine: CHECK_MEMORY_ROUTINE,
+            flash_memory: FlashImage::default(),
+            data_identifiers: HashMap::new(),
+            io_controls: Vec::new(),
+            routines: HashMap::new(),
+            session: Self::default_session(protocol),
+            security_level: None,
+            pending_seed: None,
+            seed_counter: 0,
+            failed_key_attempts: 0,
+            download: None,
+            upload: None,
+        }
+    }
+
+    /// Creates a virtual ECU from an OVD JSON ECU.
+    ///
+    /// ## Params
+    /// * ecu - The ECU definition
+    /// * variant_idx - Which variant of the ECU to simulate
+    /// * connection_idx - Which connection of the ECU to simulate
+    pub fn from_ovd_ecu(
+        ecu: &OvdECU,
+        variant_idx: usize,
+        connection_idx: usize,
+    ) -> Result<Self, ComServerError> {
+        let variant = ecu.variants.get(variant_idx).ok_or(ComServerError {
+            err_code: 1,
+            err_desc: format!("ECU {} has no variant {}", ecu.name, variant_idx),
+        })?;
+        let connection = ecu.connections.get(connection_idx).ok_or(ComServerError {
+            err_code: 1,
+            err_desc: format!("ECU {} has no connection {}", ecu.name, connection_idx),
+        })?;
+        let protocol = match connection.server_type {
+            ServerType::UDS => DiagProtocol::UDS,
+            ServerType::KWP2000 => DiagProtocol::KWP2000,
+        };
+
+        let mut res = Self::new(&ecu.name, protocol, connection.send_id, connection.recv_id);
+        res.functional_id = connection.global_send_id;
+        res.variant_id = variant.patterns.first().map(|p| p.vendor_id).unwrap_or(0);
+
+        // Every DTC in the variant definition is reported as stored
+        let id_len = match protocol {
+            DiagProtocol::KWP2000 => 4,
+            DiagProtocol::UDS => 6,
+        };
+        for e in &variant.errors {
+            let name = e.error_name.as_str();
+            // Names too short to hold an ID, or which do not end with ASCII characters, are skipped
+            let id_str = match name.len().checked_sub(id_len).and_then(|i| name.get(i..)) {
+                Some(s) => s,
+                None => continue,
+            };
+            if let Ok(id) = u32::from_str_radix(id_str, 16) {
+                let env_len = e
+                    .envs
+                    .iter()
+                    .map(|p| (p.start_bit + p.length_bits).div_ceil(8))
+                    .max()
+                    .unwrap_or(0);
+                res.dtcs.push(SimDTC {
+                    id,
+                    status: match protocol {
+                        DiagProtocol::KWP2000 => 0xA0, // Stored, MIL on
+                        DiagProtocol::UDS => 0x09,     // testFailed, confirmedDTC
+                    },
+                    env_data: vec![0x00; env_len],
+                })
+            }
+        }
+
+        res.services.extend_from_slice(&variant.downloads);
+        res.services.extend_from_slice(&variant.functions);
+        res.services.extend_from_slice(&variant.routines);
+        res.services.extend_from_slice(&variant.adjustments);
+        res.services.extend_from_slice(&variant.actuations);
+        Ok(res)
+    }
+
+    fn default_session(protocol: DiagProtocol) -> u8 {
+        match protocol {
+            DiagProtocol::KWP2000 => 0x81,
+            DiagProtocol::UDS => 0x01,
+        }
+    }
+
+    /// Returns true if the ECU will respond to a request sent on this CAN ID
+    pub fn listens_to(&self, id: u32) -> bool {
+        self.request_id == id || self.functional_id == Some(id)
+    }
+
+    /// Processes a request from the tester, returning all the responses the ECU would send.
+    /// An empty list means the ECU stays silent
+    pub fn handle_request(&mut self, req: &[u8]) -> Vec<Vec<u8>> {
+        if req.is_empty() {
+            return vec![];
+        }
+        let sid = req[0];
+        let resp = match self
+            .custom_responses
+            .iter()
+            .find(|(r, _)| r.as_slice() == req)
+        {
+            Some((_, resp)) => Some(resp.clone()),
+            // Bit 6 marks a positive response, so no request has it set. Rejecting these
+            // here also means that building a positive response (SID + 0x40) cannot overflow
+            None if sid & 0x40 != 0 => Self::neg_response(sid, 0x11),
+            None => match self.protocol {
+                DiagProtocol::KWP2000 => self.handle_kwp2000(req),
+                DiagProtocol::UDS => self.handle_uds(req),
+            },
+        };
+        let resp = match resp {
+            Some(r) if !r.is_empty() => r,
+            _ => return vec![], // Suppressed response
+        };
+        let mut res = Vec::new();
+        if resp.first() != Some(&0x7F) {
+            for _ in 0..self.response_pending_count {
+                res.push(vec![0x7F, sid, 0x78])
+            }
+        }
+        res.push(resp);
+        res
+    }
+
+    fn neg_response(sid: u8, code: u8) -> Option<Vec<u8>> {
+        Some(vec![0x7F, sid, code])
+    }
+
+    /// Returns true if the ECU is in its programming session and unlocked
+    fn can_program(&self) -> bool {
+        let prog_session = match self.protocol {
+            DiagProtocol::KWP2000 => 0x85,
+            DiagProtocol::UDS => 0x02,
+        };
+        self.session == prog_session && self.security_level.is_some()
+    }
+
+    fn reset_state(&mut self) {
+        self.session = Self::default_session(self.protocol);
+        self.security_level = None;
+        self.pending_seed = None;
+        self.download = None;
+        self.upload = None;
+        self.io_controls.clear();
+    }
+
+    fn handle_security_access(&mut self, req: &[u8]) -> Option<Vec<u8>> {
+        let sid = req[0];
+        let level = match req.get(1) {
+            Some(l) => *l,
+            None => return Self::neg_response(sid, 0x13),
+        };
+        if level % 2 == 1 {
+            // Request seed
+            if self.security_level == Some(level) {
+                return Some(vec![sid + 0x40, level, 0x00, 0x00, 0x00, 0x00]);
+            }
+            self.seed_counter = self.seed_counter.wrapping_add(1);
+            let seed = (self.seed_counter.wrapping_mul(0x9E3779B9) | 1).to_be_bytes();
+            self.pending_seed = Some((level, seed.to_vec()));
+            let mut res = vec![sid + 0x40, level];
+            res.extend_from_slice(&seed);
+            Some(res)
+        } else {
+            // Send key
+            let (seed_level, seed) = match self.pending_seed.take() {
+                Some(s) if s.0.wrapping_add(1) == level => s,
+                _ => return Self::neg_response(sid, 0x24),
+            };
+            let key_ok = match &self.security_algorithm {
+                Some(algo) => algo
+                    .calculate_key(seed_level, &seed)
+                    .map(|k| k.as_slice() == &req[2..])
+                    .unwrap_or(false),
+                None => true,
+            };
+            if key_ok {
+                self.security_level = Some(seed_level);
+                self.failed_key_attempts = 0;
+                Some(vec![sid + 0x40, level])
+            } else {
+                self.failed_key_attempts += 1;
+                if self.failed_key_attempts >= 3 {
+                    Self::neg_response(sid, 0x36)
+                } else {
+                    Self::neg_response(sid, 0x35)
+                }
+            }
+        }
+    }
+
+    /// Handles the built in erase and check memory routines
+    fn handle_flash_routine(&mut self, req: &[u8]) -> Option<Vec<u8>> {
+        let sid = req[0];
+        if req.len() < 4 || req[1] != 0x01 {
+            return self.handle_json_service(req);
+        }
+        let id = (req[2] as u16) << 8 | req[3] as u16;
+        let status = if id == self.erase_routine {
+            if !self.can_program() {
+                return Self::neg_response(sid, 0x33);
+            }
+            self.flash_memory = FlashImage::default();
+            0x00
+        } else if id == self.check_routine {
+            if req.len() < 8 {
+                return Self::neg_response(sid, 0x13);
+            }
+            let crc = u32::from_be_bytes([req[4], req[5], req[6], req[7]]);
+            if crc == self.flash_memory.crc32() {
+                0x00
+            } else {
+                0x01
+            }
+        } else {
+            return self.handle_json_service(req);
+        };
+        Some(vec![sid + 0x40, req[1], req[2], req[3], status])
+    }
+
+    fn handle_request_download(&mut self, req: &[u8]) -> Option<Vec<u8>> {
+        let sid = req[0];
+        if !self.can_program() {
+            return Self::neg_response(sid, 0x33);
+        }
+        if req.len() < 3 {
+            return Self::neg_response(sid, 0x13);
+        }
+        let addr_len = (req[2] & 0x0F) as usize;
+        let size_len = (req[2] >> 4) as usize;
+        if addr_len == 0 || addr_len > 4 || size_len == 0 || size_len > 4 {
+            return Self::neg_response(sid, 0x31);
+        }
+        if req.len() != 3 + addr_len + size_len {
+            return Self::neg_response(sid, 0x13);
+        }
+        let read_num = |b: &[u8]| b.iter().fold(0u32, |n, x| n << 8 | *x as u32);
+        self.download = Some(SimDownload {
+            address: read_num(&req[3..3 + addr_len]),
+            size: read_num(&req[3 + addr_len..]) as usize,
+            data: Vec::new(),
+            next_bsc: 0x01,
+        });
+        let max_len = self.max_block_length.to_be_bytes();
+        Some(vec![sid + 0x40, 0x20, max_len[0], max_len[1]])
+    }
+
+    fn handle_transfer_data(&mut self, req: &[u8]) -> Option<Vec<u8>> {
+        let sid = req[0];
+        let max_block_length = self.max_block_length as usize;
+        let download = match self.download.as_mut() {
+            Some(d) => d,
+            None => return Self::neg_response(sid, 0x24),
+        };
+        if req.len() < 2 || req.len() > max_block_length {
+            return Self::neg_response(sid, 0x13);
+        }
+        if req[1] != download.next_bsc {
+            return Self::neg_response(sid, 0x73);
+        }
+        if download.data.len() + req.len() - 2 > download.size {
+            return Self::neg_response(sid, 0x71);
+        }
+        download.data.extend_from_slice(&req[2..]);
+        download.next_bsc = download.next_bsc.wrapping_add(1);
+        Some(vec![sid + 0x40, req[1]])
+    }
+
+    fn handle_transfer_exit(&mut self, req: &[u8]) -> Option<Vec<u8>> {
+        let sid = req[0];
+        if let Some(u) = self.upload.take() {
+            return if u.offset == u.data.len() {
+                Some(vec![sid + 0x40])
+            } else {
+                Self::neg_response(sid, 0x24)
+            };
+        }
+        match self.download.take() {
+            Some(d) if d.data.len() == d.size => {
+                self.flash_memory.add_data(d.address, &d.data);
+                Some(vec![sid + 0x40])
+            }
+            _ => Self::neg_response(sid, 0x24),
+        }
+    }
+
+    /// Handles KWP2000 RequestDownload and RequestUpload. Both use a 3 byte
+    /// address and size, with the dataFormatIdentifier between them
+    fn handle_kwp_request_transfer(&mut self, req: &[u8]) -> Option<Vec<u8>> {
+        let sid = req[0];
+        if !self.can_program() {
+            return Self::neg_response(sid, 0x33);
+        }
+        if req.len() != 8 {
+            return Self::neg_response(sid, 0x12);
+        }
+        if req[4] != 0x00 {
+            return Self::neg_response(sid, 0x31); // Only uncompressed, unencrypted data
+        }
+        if self.max_block_length == 0 {
+            return Self::neg_response(sid, 0x22); // A block could not even hold the SID
+        }
+        let address = (req[1] as u32) << 16 | (req[2] as u32) << 8 | req[3] as u32;
+        let size = ((req[5] as u32) << 16 | (req[6] as u32) << 8 | req[7] as u32) as usize;
+        if sid == 0x34 {
+            self.download = Some(SimDownload {
+                address,
+                size,
+                data: Vec::new(),
+                next_bsc: 0x00, // No block sequence counter in KWP2000
+            });
+        } else {
+            match self.flash_memory.read(address, size) {
+                Some(data) => self.upload = Some(SimUpload { data, offset: 0 }),
+                None => return Self::neg_response(sid, 0x31),
+            }
+        }
+        let max_len = std::cmp::min(self.max_block_length, 0xFF) as u8;
+        Some(vec![sid + 0x40, max_len])
+    }
+
+    fn handle_kwp_transfer_data(&mut self, req: &[u8]) -> Option<Vec<u8>> {
+        let sid = req[0];
+        let max_len = std::cmp::min(self.max_block_length, 0xFF) as usize;
+        if let Some(u) = self.upload.as_mut() {
+            let end = std::cmp::min(u.offset + max_len - 1, u.data.len());
+            if u.offset == end {
+                return Self::neg_response(sid, 0x24);
+            }
+            let mut res = vec![sid + 0x40];
+            res.extend_from_slice(&u.data[u.offset..end]);
+            u.offset = end;
+            return Some(res);
+        }
+        let download = match self.download.as_mut() {
+            Some(d) => d,
+            None => return Self::neg_response(sid, 0x24),
+        };
+        if req.len() < 2 || req.len() > max_len {
+            return Self::neg_response(sid, 0x12);
+        }
+        if download.data.len() + req.len() - 1 > download.size {
+            return Self::neg_response(sid, 0x71);
+        }
+        download.data.extend_from_slice(&req[1..]);
+        Some(vec![sid + 0x40])
+    }
+
+    /// Reads a value from [data_identifiers](VirtualECU::data_identifiers).
+    /// `id_len` is the length of the identifier in the request
+    fn handle_read_identifier(&self, req: &[u8], id_len: usize) -> Option<Vec<u8>> {
+        let id = req.get(1..1 + id_len)?;
+        let value = self
+            .data_identifiers
+            .get(&id.iter().fold(0u16, |n, x| n << 8 | *x as u16))?;
+        let mut res = vec![req[0] + 0x40];
+        res.extend_from_slice(id);
+        res.extend_from_slice(value);
+        Some(res)
+    }
+
+    /// Writes a value to [data_identifiers](VirtualECU::data_identifiers).
+    /// `id_len` is the length of the identifier in the request
+    fn handle_write_identifier(&mut self, req: &[u8], id_len: usize) -> Option<Vec<u8>> {
+        let id = req.get(1..1 + id_len)?;
+        let value = self
+            .data_identifiers
+            .get_mut(&id.iter().fold(0u16, |n, x| n << 8 | *x as u16))?;
+        if req.len() - 1 - id_len != value.len() {
+            return Self::neg_response(req[0], 0x13);
+        }
+        value.copy_from_slice(&req[1 + id_len..]);
+        let mut res = vec![req[0] + 0x40];
+        res.extend_from_slice(id);
+        Some(res)
+    }
+
+    /// Takes or returns control of a component.
+    /// `id_len` is the length of the identifier in the request, `return_control`
+    /// and `report_state` are the protocol's control parameters that release the component
+    /// and leave it unchanged
+    fn handle_io_control(
+        &mut self,
+        req: &[u8],
+        id_len: usize,
+        return_control: u8,
+        report_state: Option<u8>,
+    ) -> Option<Vec<u8>> {
+        if req.len() < 2 + id_len {
+            return Self::neg_response(req[0], 0x13);
+        }
+        let id = req[1..1 + id_len]
+            .iter()
+            .fold(0u16, |n, x| n << 8 | *x as u16);
+        let param = req[1 + id_len];
+        if param == return_control {
+            self.io_controls.retain(|x| *x != id);
+        } else if Some(param) != report_state && !self.io_controls.contains(&id) {
+            self.io_controls.push(id);
+        }
+        let mut res = vec![req[0] + 0x40];
+        res.extend_from_slice(&req[1..]);
+        Some(res)
+    }
+
+    /// Starts, stops and returns the results of a routine in [routines](VirtualECU::routines)
+    fn handle_routine(&mut self, req: &[u8]) -> Option<Vec<u8>> {
+        let sid = req[0];
+        // UDS has a sub function and 2 byte ID, KWP2000 uses a SID for each action and a 1 byte ID
+        let (control, id_start, header_len, busy_nrc) = match self.protocol {
+            DiagProtocol::UDS => (*req.get(1)?, 2, 4, 0x21),
+            DiagProtocol::KWP2000 => (sid - 0x30, 1, 2, 0x23),
+        };
+        let id = req
+            .get(id_start..header_len)?
+            .iter()
+            .fold(0u16, |n, x| n << 8 | *x as u16);
+        let routine = self.routines.get_mut(&id)?;
+        let mut res = vec![sid + 0x40];
+        res.extend_from_slice(&req[1..header_len]);
+        match control {
+            0x01 => routine.polls_left = Some(routine.busy_polls),
+            0x02 => routine.polls_left = None,
+            0x03 => match routine.polls_left {
+                None => return Self::neg_response(sid, 0x24),
+                Some(0) => res.extend_from_slice(&routine.result),
+                Some(x) => {
+                    routine.polls_left = Some(x - 1);
+                    return Self::neg_response(sid, busy_nrc);
+                }
+            },
+            _ => return Self::neg_response(sid, 0x12),
+        }
+        Some(res)
+    }
+
+    fn handle_kwp2000(&mut self, req: &[u8]) -> Option<Vec<u8>> {
+        let sid = req[0];
+        match sid {
+            // StartDiagSession
+            0x10 => match req.get(1) {
+                Some(mode @ (0x81 | 0x85 | 0x89 | 0x90 | 0x92)) => {
+                    self.session = *mode;
+                    self.security_level = None;
+                    self.io_controls.clear();
+                    Some(vec![0x50, *mode])
+                }
+                _ => Self::neg_response(sid, 0x12),
+            },
+            // ECUReset
+            0x11 => {
+                self.reset_state();
+                Some(vec![0x51, *req.get(1).unwrap_or(&0x01)])
+            }
+            // ClearDiagnosticInformation
+            0x14 => {
+                self.dtcs.clear();
+                let mut res = vec![0x54];
+                res.extend_from_slice(&req[1..]);
+                Some(res)
+            }
+            // ReadDTCStatus
+            0x17 => {
+                if req.len() != 3 {
+                    return Self::neg_response(sid, 0x12);
+                }
+                let id = (req[1] as u32) << 8 | req[2] as u32;
+                match self.dtcs.iter().find(|d| d.id == id) {
+                    Some(dtc) => {
+                        let mut res = vec![0x57, 0x01, req[1], req[2], dtc.status];
+                        res.extend_from_slice(&dtc.env_data);
+                        Some(res)
+                    }
+                    None => Self::neg_response(sid, 0x31),
+                }
+            }
+            // ReadDTCByStatus
+            0x18 => {
+                let mut res = vec![0x58, self.dtcs.len() as u8];
+                for dtc in &self.dtcs {
+                    res.push((dtc.id >> 8) as u8);
+                    res.push(dtc.id as u8);
+                    res.push(dtc.status);
+                }
+                Some(res)
+            }
+            // ReadECUID
+            0x1A => match req.get(1) {
+                Some(0x86) => {
+                    let mut res = vec![0x5A, 0x86];
+                    res.extend_from_slice(&[0x00; 9]);
+                    res.push(0x00); // Supplier
+                    res.push((self.variant_id >> 8) as u8);
+                    res.push(self.variant_id as u8);
+                    res.extend_from_slice(&[0x00; 4]);
+                    Some(res)
+                }
+                Some(0x87) => {
+                    let mut res = vec![0x5A, 0x87, 0x00, 0x00];
+                    res.push((self.variant_id >> 8) as u8);
+                    res.push(self.variant_id as u8);
+                    res.extend_from_slice(&[0x00; 6]);
+                    res.extend_from_slice(b"0000000000");
+                    Some(res)
+                }
+                _ => self.handle_json_service(req),
+            },
+            // ReadDataByLocalIdentifier
+            0x21 => self
+                .handle_read_identifier(req, 1)
+                .or_else(|| self.handle_json_service(req)),
+            // SecurityAccess
+            0x27 => self.handle_security_access(req),
+            // InputOutputControlByLocalIdentifier
+            0x30 => self.handle_io_control(req, 1, 0x00, Some(0x01)),
+            // Start / Stop / RequestRoutineResults ByLocalIdentifier
+            0x31..=0x33 => self
+                .handle_routine(req)
+                .or_else(|| self.handle_json_service(req)),
+            // WriteDataByLocalIdentifier
+            0x3B => self
+                .handle_write_identifier(req, 1)
+                .or_else(|| self.handle_json_service(req)),
+            // RequestDownload / RequestUpload
+            0x34 | 0x35 => self.handle_kwp_request_transfer(req),
+            // TransferData
+            0x36 => self.handle_kwp_transfer_data(req),
+            // RequestTransferExit
+            0x37 => self.handle_transfer_exit(req),
+            // TesterPresent
+            0x3E => match req.get(1) {
+                Some(0x02) => None, // Response not required
+                _ => Some(vec![0x7E]),
+            },
+            _ => self.handle_json_service(req),
+        }
+    }
+
+    fn handle_uds(&mut self, req: &[u8]) -> Option<Vec<u8>> {
+        let sid = req[0];
+        match sid {
+            // DiagnosticSessionControl
+            0x10 => match req.get(1) {
+                Some(mode @ 0x01..=0x04) => {
+                    self.session = *mode;
+                    self.security_level = None;
+                    self.io_controls.clear();
+                    // P2 = 50ms, P2* = 5000ms
+                    Some(vec![0x50, *mode, 0x00, 0x32, 0x01, 0xF4])
+                }
+                _ => Self::neg_response(sid, 0x12),
+            },
+            // ECUReset
+            0x11 => {
+                self.reset_state();
+                Some(vec![0x51, *req.get(1).unwrap_or(&0x01)])
+            }
+            // ClearDTCInformation
+            0x14 => {
+                self.dtcs.clear();
+                Some(vec![0x54])
+            }
+            // ReadDTCInformation
+            0x19 => match req.get(1) {
+                Some(0x02) => {
+                    let mask = *req.get(2).unwrap_or(&0xFF);
+                    let mut res = vec![0x59, 0x02, 0xFF];
+                    for dtc in self.dtcs.iter().filter(|d| d.status & mask != 0) {
+                        res.push((dtc.id >> 16) as u8);
+                        res.push((dtc.id >> 8) as u8);
+                        res.push(dtc.id as u8);
+                        res.push(dtc.status);
+                    }
+                    Some(res)
+                }
+                Some(0x06) => {
+                    if req.len() < 5 {
+                        return Self::neg_response(sid, 0x13);
+                    }
+                    let id = (req[2] as u32) << 16 | (req[3] as u32) << 8 | req[4] as u32;
+                    match self.dtcs.iter().find(|d| d.id == id) {
+                        Some(dtc) => {
+                            let mut res = vec![0x59, 0x06, req[2], req[3], req[4], dtc.status];
+                            res.extend_from_slice(&dtc.env_data);
+                            Some(res)
+                        }
+                        None => Self::neg_response(sid, 0x31),
+                    }
+                }
+                _ => Self::neg_response(sid, 0x12),
+            },
+            // ReadDataByID
+            0x22 => match req.get(1..3) {
+                Some([0xF1, 0x00]) => Some(vec![
+                    0x62,
+                    0xF1,
+                    0x00,
+                    (self.variant_id >> 16) as u8,
+                    (self.variant_id >> 8) as u8,
+                    self.variant_id as u8,
+                    0x00,
+                ]),
+                _ => self
+                    .handle_read_identifier(req, 2)
+                    .or_else(|| self.handle_json_service(req)),
+            },
+            // SecurityAccess
+            0x27 => self.handle_security_access(req),
+            // WriteDataByID
+            0x2E => self
+                .handle_write_identifier(req, 2)
+                .or_else(|| self.handle_json_service(req)),
+            // InputOutputControlByIdentifier
+            0x2F => self.handle_io_control(req, 2, 0x00, None),
+            // RoutineControl
+            0x31 => self
+                .handle_routine(req)
+                .or_else(|| self.handle_flash_routine(req)),
+            // RequestDownload
+            0x34 => self.handle_request_download(req),
+            // TransferData
+            0x36 => self.handle_transfer_data(req),
+            // RequestTransferExit
+            0x37 => self.handle_transfer_exit(req),
+            // TesterPresent
+            0x3E => match req.get(1) {
+                Some(0x80) => None, // suppressPosRspMsgIndicationBit
+                _ => Some(vec![0x7E, 0x00]),
+            },
+            _ => self.handle_json_service(req),
+        }
+    }
+
+    /// Answers a request from the services in the ECU variant definition.
+    /// The response is the positive SID, followed by the request arguments, padded out
+    /// with zeros to fit all the service's output parameters
+    fn handle_json_service(&self, req: &[u8]) -> Option<Vec<u8>> {
+        let sid = req[0];
+        let service = self.services.iter().find(|s| {
+            if s.payload.is_empty() {
+                false
+            } else if s.input_params.is_empty() {
+                s.payload.as_slice() == req
+            } else {
+                // Input arguments are OR'ed into the payload, so only compare the identifier
+                let cmp_len = std::cmp::min(s.payload.len(), 3);
+                req.len() >= cmp_len && s.payload[0..cmp_len] == req[0..cmp_len]
+            }
+        });
+        match service {
+            Some(s) => {
+                let resp_len = s
+                    .output_params
+                    .iter()
+                    .map(|p| (p.start_bit + p.length_bits).div_ceil(8))
+                    .max()
+                    .unwrap_or(0);
+                let mut res = vec![sid + 0x40];
+                res.extend_from_slice(&req[1..]);
+                if res.len() < resp_len {
+                    res.resize(resp_len, 0x00);
+                }
+                Some(res)
+            }
+            None => Self::neg_response(sid, 0x11), // Service not supported
+        }
+    }
+}
+
+/// Settings used to create a [VirtualECU] from an OVD JSON ECU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtualECUConfig {
+    /// Which variant of the ECU to simulate
+    pub variant_idx: usize,
+    /// Which connection of the ECU to simulate. This also decides if the ECU speaks UDS or KWP2000
+    pub connection_idx: usize,
+    /// If true, every DTC in the variant definition is stored in the ECU, otherwise it starts with none
+    pub store_dtcs: bool,
+    /// Number of ResponsePending (0x78) responses to send before each positive response
+    pub response_pending_count: u32,
+    /// Delay (ms) between each ResponsePending
+    pub response_pending_interval: u32,
+}
+
+impl Default for VirtualECUConfig {
+    fn default() -> Self {
+        Self {
+            variant_idx: 0,
+            connection_idx: 0,
+            store_dtcs: true,
+            response_pending_count: 0,
+            response_pending_interval: 0,
+        }
+    }
+}
+
+impl VirtualECUConfig {
+    /// Creates a virtual ECU from an OVD JSON ECU using these settings
+    pub fn build(&self, ecu: &OvdECU) -> Result<VirtualECU, ComServerError> {
+        let mut res = VirtualECU::from_ovd_ecu(ecu, self.variant_idx, self.connection_idx)?;
+        if !self.store_dtcs {
+            res.dtcs.clear();
+        }
+        res.response_pending_count = self.response_pending_count;
+        res.response_pending_interval = self.response_pending_interval;
+        Ok(res)
+    }
+}
+
+type RxQueue<T> = Arc<(Mutex<VecDeque<T>>, Condvar)>;
+
+/// Simulates one or more ECUs in-process, so that OVD can be used without
+/// a vehicle or adapter being present.
+#[derive(Debug, Clone)]
+pub struct SimulatorAPI {
+    ecus: Arc<RwLock<Vec<VirtualECU>>>,
+    can_open: Arc<RwLock<bool>>,
+    isotp_open: Arc<RwLock<bool>>,
+    can_filters: Arc<RwLock<[Option<FilterType>; 10]>>,
+    isotp_filters: Arc<RwLock<[Option<FilterType>; 10]>>,
+    can_rx: RxQueue<CanFrame>,
+    isotp_rx: RxQueue<ISO15765Data>,
+    kline_open: Arc<RwLock<bool>>,
+    kline_rx: RxQueue<KLineData>,
+}
+
+impl SimulatorAPI {
+    pub fn new(ecus: Vec<VirtualECU>) -> Self {
+        Self {
+            ecus: Arc::new(RwLock::new(ecus)),
+            can_open: Arc::new(RwLock::new(false)),
+            isotp_open: Arc::new(RwLock::new(false)),
+            can_filters: Arc::new(RwLock::new([None; 10])),
+            isotp_filters: Arc::new(RwLock::new([None; 10])),
+            can_rx: Arc::new((Mutex::new(VecDeque::new()), Condvar::new())),
+            isotp_rx: Arc::new((Mutex::new(VecDeque::new()), Condvar::new())),
+            kline_open: Arc::new(RwLock::new(false)),
+            kline_rx: Arc::new((Mutex::new(VecDeque::new()), Condvar::new())),
+        }
+    }
+
+    /// Adds a new ECU to the simulated vehicle
+    pub fn add_ecu(&self, ecu: VirtualECU) {
+        self.ecus.write().unwrap().push(ecu)
+    }
+
+    /// Returns a copy of the simulated ECUs, in their current state
+    pub fn get_ecus(&self) -> Vec<VirtualECU> {
+        self.ecus.read().unwrap().clone()
+    }
+
+    fn filter_matches(filters: &[Option<FilterType>], id: u32) -> bool {
+        let mut pass = false;
+        for f in filters.iter().flatten() {
+            match f {
+                FilterType::Pass { id: f_id, mask } | FilterType::IsoTP { id: f_id, mask, .. } => {
+                    if id & mask == f_id & mask {
+                        pass = true
+                    }
+                }
+                FilterType::Block { id: f_id, mask } => {
+                    if id & mask == f_id & mask {
+                        return false;
+                    }
+                }
+            }
+        }
+        pass
+    }
+
+    fn add_filter(
+        filters: &RwLock<[Option<FilterType>; 10]>,
+        f: FilterType,
+    ) -> Result<u32, ComServerError> {
+        let mut filters = filters.write().unwrap();
+        match filters.iter().position(|x| x.is_none()) {
+            Some(pos) => {
+                filters[pos] = Some(f);
+                Ok(pos as u32)
+            }
+            None => Err(ComServerError {
+                err_code: 98,
+                err_desc: "No free filters were found".into(),
+            }),
+        }
+    }
+
+    fn rem_filter(
+        filters: &RwLock<[Option<FilterType>; 10]>,
+        filter_idx: u32,
+    ) -> Result<(), ComServerError> {
+        match filters.write().unwrap().get_mut(filter_idx as usize) {
+            Some(f) => {
+                *f = None;
+                Ok(())
+            }
+            None => Err(ComServerError {
+                err_code: 99,
+                err_desc: format!("Invalid filter ID {}", filter_idx),
+            }),
+        }
+    }
+
+    /// Waits up to timeout_ms for data to arrive in a queue, then returns up to max_msgs of it
+    fn read_queue<T>(queue: &RxQueue<T>, timeout_ms: u32, max_msgs: usize) -> Vec<T> {
+        let (lock, cvar) = &**queue;
+        let mut q = lock.lock().unwrap();
+        let start = Instant::now();
+        let timeout = Duration::from_millis(timeout_ms as u64);
+        while q.is_empty() && start.elapsed() < timeout {
+            q = cvar.wait_timeout(q, timeout - start.elapsed()).unwrap().0;
+        }
+        let count = std::cmp::min(max_msgs, q.len());
+        q.drain(0..count).collect()
+    }
+
+    fn push_queue<T>(queue: &RxQueue<T>, data: T) {
+        let (lock, cvar) = &**queue;
+        lock.lock().unwrap().push_back(data);
+        cvar.notify_all();
+    }
+
+    /// Pushes the first message to a queue straight away, then each of the rest
+    /// interval_ms apart, like an ECU that is slowly processing a request
+    fn push_queue_delayed<T: Send + 'static>(queue: &RxQueue<T>, data: Vec<T>, interval_ms: u32) {
+        let mut data = data.into_iter();
+        if let Some(first) = data.next() {
+            Self::push_queue(queue, first)
+        }
+        if interval_ms == 0 {
+            data.for_each(|x| Self::push_queue(queue, x));
+            return;
+        }
+        let queue = queue.clone();
+        let rest: Vec<T> = data.collect();
+        std::thread::spawn(move || {
+            for x in rest {
+                std::thread::sleep(Duration::from_millis(interval_ms as u64));
+                Self::push_queue(&queue, x)
+            }
+        });
+    }
+
+    fn iso9141_unsupported() -> ComServerError {
+        ComServerError {
+            err_code: 1,
+            err_desc: "Virtual ECUs do not speak ISO9141".into(),
+        }
+    }
+
+    fn j1850_unsupported() -> ComServerError {
+        ComServerError {
+            err_code: 1,
+            err_desc: "Virtual ECUs do not speak J1850".into(),
+        }
+    }
+
+    fn kline_timeout_err() -> ComServerError {
+        ComServerError {
+            err_code: 2,
+            err_desc: "No ECU responded to the K-Line wake up".into(),
+        }
+    }
+
+    fn not_open_err(iface: &str) -> ComServerError {
+        ComServerError {
+            err_code: 2,
+            err_desc: format!("Simulator {} interface not open", iface),
+        }
+    }
+}
+
+impl ComServer for SimulatorAPI {
+    fn open_device(&mut self) -> Result<(), ComServerError> {
+        Ok(()) // Nothing to open, the vehicle is in memory
+    }
+
+    fn close_device(&mut self) -> Result<(), ComServerError> {
+        self.close_can_interface()?;
+        self.close_iso15765_interface()?;
+        self.close_iso14230_interface()
+    }
+
+    fn send_can_packets(
+        &mut self,
+        data: &[CanFrame],
+        _timeout_ms: u32,
+    ) -> Result<usize, ComServerError> {
+        // Virtual ECUs only talk ISO-TP or K-Line, so raw frames go nowhere
+        if !*self.can_open.read().unwrap() {
+            return Err(Self::not_open_err("CAN"));
+        }
+        Ok(data.len())
+    }
+
+    fn is_connected(&self) -> bool {
+        *self.can_open.read().unwrap()
+            || *self.isotp_open.read().unwrap()
+            || *self.kline_open.read().unwrap()
+    }
+
+    fn read_can_packets(
+        &self,
+        timeout_ms: u32,
+        max_msgs: usize,
+    ) -> Result<Vec<CanFrame>, ComServerError> {
+        if !*self.can_open.read().unwrap() {
+            return Err(Self::not_open_err("CAN"));
+        }
+        Ok(Self::read_queue(&self.can_rx, timeout_ms, max_msgs))
+    }
+
+    fn send_iso15765_data(
+        &self,
+        data: &[ISO15765Data],
+        _timeout_ms: u32,
+    ) -> Result<usize, ComServerError> {
+        if !*self.isotp_open.read().unwrap() {
+            return Err(Self::not_open_err("ISO15765"));
+        }
+        for msg in data {
+            let mut ecus = self.ecus.write().unwrap();
+            for ecu in ecus.iter_mut().filter(|e| e.listens_to(msg.id)) {
+                let resps = ecu.handle_request(&msg.data);
+                if !Self::filter_matches(&*self.isotp_filters.read().unwrap(), ecu.response_id) {
+                    continue;
+                }
+                let frames = resps
+                    .into_iter()
+                    .map(|resp| ISO15765Data {
+                        id: ecu.response_id,
+                        data: resp,
+                        pad_frame: false,
+                        ext_addressing: msg.ext_addressing,
+                    })
+                    .collect();
+                Self::push_queue_delayed(&self.isotp_rx, frames, ecu.response_pending_interval)
+            }
+        }
+        Ok(data.len())
+    }
+
+    fn read_iso15765_packets(
+        &self,
+        timeout_ms: u32,
+        max_msgs: usize,
+    ) -> Result<Vec<ISO15765Data>, ComServerError> {
+        if !*self.isotp_open.read().unwrap() {
+            return Err(Self::not_open_err("ISO15765"));
+        }
+        Ok(Self::read_queue(&self.isotp_rx, timeout_ms, max_msgs))
+    }
+
+    fn open_can_interface(
+        &mut self,
+        _bus_speed: u32,
+        _is_ext_can: bool,
+    ) -> Result<(), ComServerError> {
+        // Physically impossible to have both CAN and ISOTP enabled at the same time
+        self.close_iso15765_interface()?;
+        *self.can_open.write().unwrap() = true;
+        Ok(())
+    }
+
+    fn close_can_interface(&mut self) -> Result<(), ComServerError> {
+        *self.can_open.write().unwrap() = false;
+        *self.can_filters.write().unwrap() = [None; 10];
+        self.clear_can_rx_buffer()
+    }
+
+    fn open_iso15765_interface(
+        &mut self,
+        _bus_speed: u32,
+        _is_ext_can: bool,
+        _ext_addressing: bool,
+    ) -> Result<(), ComServerError> {
+        self.close_can_interface()?;
+        *self.isotp_open.write().unwrap() = true;
+        Ok(())
+    }
+
+    fn close_iso15765_interface(&mut self) -> Result<(), ComServerError> {
+        *self.isotp_open.write().unwrap() = false;
+        *self.isotp_filters.write().unwrap() = [None; 10];
+        self.clear_iso15765_rx_buffer()
+    }
+
+    fn add_can_filter(&mut self, f: FilterType) -> Result<u32, ComServerError> {
+        if let FilterType::IsoTP { .. } = f {
+            return Err(ComServerError {
+                err_code: 99,
+                err_desc: "Cannot apply a FlowControl filter to CAN".into(),
+            });
+        }
+        Self::add_filter(&self.can_filters, f)
+    }
+
+    fn rem_can_filter(&mut self, filter_idx: u32) -> Result<(), ComServerError> {
+        Self::rem_filter(&self.can_filters, filter_idx)
+    }
+
+    fn add_iso15765_filter(&mut self, f: FilterType) -> Result<u32, ComServerError> {
+        if let FilterType::IsoTP { .. } = f {
+            Self::add_filter(&self.isotp_filters, f)
+        } else {
+            Err(ComServerError {
+                err_code: 99,
+                err_desc: "Cannot apply a pass/block filter to ISOTP".into(),
+            })
+        }
+    }
+
+    fn rem_iso15765_filter(&mut self, filter_idx: u32) -> Result<(), ComServerError> {
+        Self::rem_filter(&self.isotp_filters, filter_idx)
+    }
+
+    fn set_iso15765_params(
+        &mut self,
+        _separation_time_min: u32,
+        _block_size: u32,
+    ) -> Result<(), ComServerError> {
+        Ok(()) // No segmentation takes place in the simulator
+    }
+
+    fn set_iso15765_fd_params(&mut self, _tx_dl: u32, _brs: bool) -> Result<(), ComServerError> {
+        Ok(()) // No segmentation takes place in the simulator
+    }
+
+    fn open_iso14230_interface(&mut self, _baud: u32) -> Result<(), ComServerError> {
+        *self.kline_open.write().unwrap() = true;
+        Ok(())
+    }
+
+    fn close_iso14230_interface(&mut self) -> Result<(), ComServerError> {
+        *self.kline_open.write().unwrap() = false;
+        self.clear_iso14230_rx_buffer()
+    }
+
+    fn iso14230_fast_init(&mut self, init_msg: &[u8]) -> Result<Vec<u8>, ComServerError> {
+        if !*self.kline_open.read().unwrap() {
+            return Err(Self::not_open_err("ISO14230"));
+        }
+        // Every virtual ECU supports all the header formats
+        match Iso14230Header::parse(init_msg) {
+            Some((Some((target, source)), data)) if data == [0x81] => self
+                .ecus
+                .read()
+                .unwrap()
+                .iter()
+                .find(|e| e.listens_to(target as u32))
+                .map(|e| {
+                    Iso14230Header::default().build(
+                        source,
+                        e.response_id as u8,
+                        false,
+                        &[0xC1, 0xEF, 0x8F],
+                    )
+                })
+                .unwrap_or_else(|| Err(Self::kline_timeout_err())),
+            _ => Err(Self::kline_timeout_err()),
+        }
+    }
+
+    fn iso14230_five_baud_init(&mut self, address: u8) -> Result<Vec<u8>, ComServerError> {
+        if !*self.kline_open.read().unwrap() {
+            return Err(Self::not_open_err("ISO14230"));
+        }
+        match self
+            .ecus
+            .read()
+            .unwrap()
+            .iter()
+            .any(|e| e.listens_to(address as u32))
+        {
+            true => Ok(vec![0xEF, 0x8F]),
+            false => Err(Self::kline_timeout_err()),
+        }
+    }
+
+    fn send_iso14230_data(
+        &self,
+        data: &[KLineData],
+        _timeout_ms: u32,
+    ) -> Result<usize, ComServerError> {
+        if !*self.kline_open.read().unwrap() {
+            return Err(Self::not_open_err("ISO14230"));
+        }
+        for msg in data {
+            // Virtual ECUs need to know who the request is for
+            let (target, source, req) = match Iso14230Header::parse(&msg.data) {
+                Some((Some((target, source)), req)) => (target, source, req),
+                _ => continue,
+            };
+            let mut ecus = self.ecus.write().unwrap();
+            for ecu in ecus.iter_mut().filter(|e| e.listens_to(target as u32)) {
+                for resp in ecu.handle_request(&req) {
+                    if let Ok(resp) =
+                        Iso14230Header::default().build(source, ecu.response_id as u8, false, &resp)
+                    {
+                        Self::push_queue(&self.kline_rx, KLineData { data: resp })
+                    }
+                }
+            }
+        }
+        Ok(data.len())
+    }
+
+    fn read_iso14230_packets(
+        &self,
+        timeout_ms: u32,
+        max_msgs: usize,
+    ) -> Result<Vec<KLineData>, ComServerError> {
+        if !*self.kline_open.read().unwrap() {
+            return Err(Self::not_open_err("ISO14230"));
+        }
+        Ok(Self::read_queue(&self.kline_rx, timeout_ms, max_msgs))
+    }
+
+    fn set_iso14230_params(
+        &mut self,
+        _p1_max: u32,
+        _p3_min: u32,
+        _p4_min: u32,
+    ) -> Result<(), ComServerError> {
+        Ok(()) // Bytes are not sent one at a time in the simulator
+    }
+
+    fn open_iso9141_interface(&mut self, _baud: u32) -> Result<(), ComServerError> {
+        Err(Self::iso9141_unsupported())
+    }
+
+    fn close_iso9141_interface(&mut self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn iso9141_five_baud_init(&mut self, _address: u8) -> Result<Vec<u8>, ComServerError> {
+        Err(Self::iso9141_unsupported())
+    }
+
+    fn send_iso9141_data(
+        &self,
+        _data: &[KLineData],
+        _timeout_ms: u32,
+    ) -> Result<usize, ComServerError> {
+        Err(Self::iso9141_unsupported())
+    }
+
+    fn read_iso9141_packets(
+        &self,
+        _timeout_ms: u32,
+        _max_msgs: usize,
+    ) -> Result<Vec<KLineData>, ComServerError> {
+        Err(Self::iso9141_unsupported())
+    }
+
+    fn set_iso9141_params(
+        &mut self,
+        _p1_max: u32,
+        _p3_min: u32,
+        _p4_min: u32,
+    ) -> Result<(), ComServerError> {
+        Err(Self::iso9141_unsupported())
+    }
+
+    fn open_j1850vpw_interface(&mut self, _baud: u32) -> Result<(), ComServerError> {
+        Err(Self::j1850_unsupported())
+    }
+
+    fn close_j1850vpw_interface(&mut self) -> Result<(), ComServerError> {
+        Ok(()) // Never opened
+    }
+
+    fn send_j1850vpw_data(
+        &self,
+        _data: &[J1850Data],
+        _timeout_ms: u32,
+    ) -> Result<usize, ComServerError> {
+        Err(Self::j1850_unsupported())
+    }
+
+    fn read_j1850vpw_packets(
+        &self,
+        _timeout_ms: u32,
+        _max_msgs: usize,
+    ) -> Result<Vec<J1850Data>, ComServerError> {
+        Err(Self::j1850_unsupported())
+    }
+
+    fn open_j1850pwm_interface(
+        &mut self,
+        _baud: u32,
+        _node_addr: u8,
+        _func_addrs: &[u8],
+    ) -> Result<(), ComServerError> {
+        Err(Self::j1850_unsupported())
+    }
+
+    fn close_j1850pwm_interface(&mut self) -> Result<(), ComServerError> {
+        Ok(()) // Never opened
+    }
+
+    fn send_j1850pwm_data(
+        &self,
+        _data: &[J1850Data],
+        _timeout_ms: u32,
+    ) -> Result<usize, ComServerError> {
+        Err(Self::j1850_unsupported())
+    }
+
+    fn read_j1850pwm_packets(
+        &self,
+        _timeout_ms: u32,
+        _max_msgs: usize,
+    ) -> Result<Vec<J1850Data>, ComServerError> {
+        Err(Self::j1850_unsupported())
+    }
+
+    fn clear_can_rx_buffer(&self) -> Result<(), ComServerError> {
+        self.can_rx.0.lock().unwrap().clear();
+        Ok(())
+    }
+
+    fn clear_can_tx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(()) // Tx is instant
+    }
+
+    fn clear_iso15765_rx_buffer(&self) -> Result<(), ComServerError> {
+        self.isotp_rx.0.lock().unwrap().clear();
+        Ok(())
+    }
+
+    fn clear_iso15765_tx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(()) // Tx is instant
+    }
+
+    fn clear_iso14230_rx_buffer(&self) -> Result<(), ComServerError> {
+        self.kline_rx.0.lock().unwrap().clear();
+        Ok(())
+    }
+
+    fn clear_iso14230_tx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(()) // Tx is instant
+    }
+
+    fn clear_iso9141_rx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn clear_iso9141_tx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn clear_j1850vpw_rx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn clear_j1850vpw_tx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn clear_j1850pwm_rx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn clear_j1850pwm_tx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn read_battery_voltage(&self) -> Result<f32, ComServerError> {
+        Ok(12.6)
+    }
+
+    fn clone_box(&self) -> Box<dyn ComServer> {
+        Box::new(self.clone())
+    }
+
+    fn get_capabilities(&self) -> DeviceCapabilities {
+        DeviceCapabilities {
+            name: format!("Simulator ({} ECUs)", self.ecus.read().unwrap().len()),
+            vendor: "OpenVehicleDiag".into(),
+            library_path: "N/A".into(),
+            device_fw_version: "N/A".into(),
+            library_version: env!("CARGO_PKG_VERSION").into(),
+            j1850vpw: Capability::No,
+            j1850pwm: Capability::No,
+            can: Capability::Yes,
+            can_fd: Capability::Yes,
+            iso15765: Capability::Yes,
+            iso9141: Capability::No,
+            iso14230: Capability::Yes,
+            ip: Capability::No,
+            battery_voltage: Capability::Yes,
+        }
+    }
+
+    fn get_api(&self) -> &str {
+        "Simulator"
+    }
+}
+
+/// Helpers for tests that talk to a simulated ECU over ISO-TP at 500kbps,
+/// using the request, response and functional IDs of the first ECU in the simulator
+#[cfg(test)]
+impl SimulatorAPI {
+    fn test_cfg(&self, timing: DiagTiming) -> (Box<dyn ComServer>, InterfaceConfig, DiagCfg) {
+        let ecu = &self.get_ecus()[0];
+        let mut cfg = InterfaceConfig::new();
+        cfg.add_param(IFACE_CFG::BAUDRATE, 500000);
+        let diag_cfg = DiagCfg {
+            send_id: ecu.request_id,
+            recv_id: ecu.response_id,
+            global_id: ecu.functional_id,
+            timing,
+        };
+        (Box::new(self.clone()), cfg, diag_cfg)
+    }
+
+    /// Starts a diagnostic session with the first ECU
+    pub(crate) fn start_test_session<P: ProtocolServer>(&self) -> P {
+        let (server, cfg, diag_cfg) = self.test_cfg(DiagTiming::default());
+        P::start_diag_session(&server, InterfaceType::IsoTp, cfg, None, diag_cfg).unwrap()
+    }
+
+    /// Starts a [DiagServer] for the first ECU, using the protocol the ECU speaks
+    pub(crate) fn start_test_server(&self, timing: DiagTiming) -> DiagServer {
+        let protocol = self.get_ecus()[0].protocol;
+        let (server, cfg, diag_cfg) = self.test_cfg(timing);
+        DiagServer::new(protocol, &server, InterfaceType::IsoTp, cfg, None, diag_cfg).unwrap()
+    }
+
+    /// Opens an ISO-TP interface which only receives the first ECU's responses
+    pub(crate) fn open_test_iface(&self) -> Box<dyn Interface> {
+        let (server, cfg, diag_cfg) = self.test_cfg(DiagTiming::default());
+        let mut iface = IsoTPInterface::new(server).unwrap();
+        iface.setup(&cfg).unwrap();
+        iface
+            .add_filter(FilterType::IsoTP {
+                id: diag_cfg.recv_id,
+                mask: 0xFFFF,
+                fc: diag_cfg.send_id,
+            })
+            .unwrap();
+        iface
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_ECU: &str = r#"{
+        "name": "SIM",
+        "description": "Simulated ECU",
+        "variants": [
+            {
+                "name": "SIM_V1",
+                "description": "First variant",
+                "patterns": [{ "vendor": "OVD", "vendor_id": 4660 }],
+                "errors": [
+                    { "error_name": "P200100", "summary": "", "description": "" }
+                ]
+            },
+            {
+                "name": "SIM_V2",
+                "description": "Second variant",
+                "patterns": [{ "vendor": "OVD", "vendor_id": 22136 }],
+                "errors": [
+                    { "error_name": "P2001", "summary": "", "description": "" },
+                    { "error_name": "P2", "summary": "", "description": "" },
+                    { "error_name": "ü123", "summary": "", "description": "" }
+                ],
+                "downloads": [
+                    { "name": "RD_TEMP", "description": "Temperature", "payload": "2101" }
+                ]
+            }
+        ],
+        "connections": [
+            {
+                "baud": 500000,
+                "send_id": 2016,
+                "connection_type": {
+                    "ISOTP": {
+                        "blocksize": 8,
+                        "st_min": 20,
+                        "ext_can_addr": false,
+                        "ext_isotp_addr": false
+                    }
+                },
+                "server_type": "UDS",
+                "recv_id": 2024
+            },
+            {
+                "baud": 10400,
+                "send_id": 16,
+                "global_send_id": 51,
+                "connection_type": {
+                    "LIN": { "max_segment_size": 254, "wake_up_method": "FastInit" }
+                },
+                "server_type": "KWP2000",
+                "recv_id": 241
+            }
+        ]
+    }"#;
+
+    /// Returns the single response the ECU sends to `req`
+    fn request(ecu: &mut VirtualECU, req: &[u8]) -> Vec<u8> {
+        let mut res = ecu.handle_request(req);
+        assert_eq!(res.len(), 1, "Expected one response to {:02X?}", req);
+        res.remove(0)
+    }
+
+    fn sim_dtc(id: u32, status: u8) -> SimDTC {
+        SimDTC {
+            id,
+            status,
+            env_data: vec![0x11, 0x22],
+        }
+    }
+
+    #[test]
+    fn uds_responses() {
+        let mut ecu = VirtualECU::new("ECU", DiagProtocol::UDS, 0x7E0, 0x7E8);
+        ecu.variant_id = 0x123456;
+        assert_eq!(
+            request(&mut ecu, &[0x10, 0x03]),
+            vec![0x50, 0x03, 0x00, 0x32, 0x01, 0xF4]
+        );
+        assert_eq!(request(&mut ecu, &[0x10, 0x7F]), vec![0x7F, 0x10, 0x12]);
+        assert_eq!(
+            request(&mut ecu, &[0x22, 0xF1, 0x00]),
+            vec![0x62, 0xF1, 0x00, 0x12, 0x34, 0x56, 0x00]
+        );
+        assert_eq!(request(&mut ecu, &[0x3E, 0x00]), vec![0x7E, 0x00]);
+        assert!(ecu.handle_request(&[0x3E, 0x80]).is_empty());
+        assert_eq!(request(&mut ecu, &[0x23, 0x00]), vec![0x7F, 0x23, 0x11]);
+
+        ecu.response_pending_count = 2;
+        assert_eq!(
+            ecu.handle_request(&[0x11, 0x01]),
+            vec![
+                vec![0x7F, 0x11, 0x78],
+                vec![0x7F, 0x11, 0x78],
+                vec![0x51, 0x01]
+            ]
+        );
+        // ResponsePending is never sent before a negative response
+        assert_eq!(request(&mut ecu, &[0x23, 0x00]), vec![0x7F, 0x23, 0x11]);
+    }
+
+    #[test]
+    fn kwp2000_responses() {
+        let mut ecu = VirtualECU::new("ECU", DiagProtocol::KWP2000, 0x10, 0xF1);
+        ecu.variant_id = 0x5678;
+        assert_eq!(request(&mut ecu, &[0x10, 0x92]), vec![0x50, 0x92]);
+        assert_eq!(request(&mut ecu, &[0x10, 0x01]), vec![0x7F, 0x10, 0x12]);
+
+        let res = request(&mut ecu, &[0x1A, 0x86]);
+        assert_eq!(&res[0..2], &[0x5A, 0x86]);
+        assert_eq!(&res[12..14], &[0x56, 0x78]);
+        let res = request(&mut ecu, &[0x1A, 0x87]);
+        assert_eq!(&res[0..6], &[0x5A, 0x87, 0x00, 0x00, 0x56, 0x78]);
+
+        assert_eq!(request(&mut ecu, &[0x3E, 0x01]), vec![0x7E]);
+        assert!(ecu.handle_request(&[0x3E, 0x02]).is_empty());
+        assert_eq!(request(&mut ecu, &[0x23, 0x00]), vec![0x7F, 0x23, 0x11]);
+
+        // Nothing can be transferred if a block cannot hold any data
+        ecu.session = 0x85;
+        ecu.security_level = Some(0x01);
+        ecu.max_block_length = 0;
+        let upload = [0x35, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10];
+        assert_eq!(request(&mut ecu, &upload), vec![0x7F, 0x35, 0x22]);
+        assert_eq!(request(&mut ecu, &[0x36]), vec![0x7F, 0x36, 0x24]);
+    }
+
+    #[test]
+    fn dtc_responses() {
+        let mut ecu = VirtualECU::new("ECU", DiagProtocol::UDS, 0x7E0, 0x7E8);
+        ecu.dtcs = vec![sim_dtc(0x200100, 0x09), sim_dtc(0x300200, 0x04)];
+        assert_eq!(
+            request(&mut ecu, &[0x19, 0x02, 0x08]),
+            vec![0x59, 0x02, 0xFF, 0x20, 0x01, 0x00, 0x09]
+        );
+        assert_eq!(request(&mut ecu, &[0x19, 0x02, 0xFF]).len(), 3 + 4 * 2);
+        assert_eq!(
+            request(&mut ecu, &[0x19, 0x06, 0x30, 0x02, 0x00, 0xFF]),
+            vec![0x59, 0x06, 0x30, 0x02, 0x00, 0x04, 0x11, 0x22]
+        );
+        assert_eq!(
+            request(&mut ecu, &[0x19, 0x06, 0x40, 0x00, 0x00, 0xFF]),
+            vec![0x7F, 0x19, 0x31]
+        );
+        assert_eq!(request(&mut ecu, &[0x14, 0xFF, 0xFF, 0xFF]), vec![0x54]);
+        assert!(ecu.dtcs.is_empty());
+
+        let mut ecu = VirtualECU::new("ECU", DiagProtocol::KWP2000, 0x10, 0xF1);
+        ecu.dtcs = vec![sim_dtc(0x2001, 0xA0)];
+        assert_eq!(
+            request(&mut ecu, &[0x18, 0x02, 0xFF, 0x00]),
+            vec![0x58, 0x01, 0x20, 0x01, 0xA0]
+        );
+        assert_eq!(
+            request(&mut ecu, &[0x17, 0x20, 0x01]),
+            vec![0x57, 0x01, 0x20, 0x01, 0xA0, 0x11, 0x22]
+        );
+        assert_eq!(request(&mut ecu, &[0x17, 0x20]), vec![0x7F, 0x17, 0x12]);
+        assert_eq!(
+            request(&mut ecu, &[0x14, 0xFF, 0x00]),
+            vec![0x54, 0xFF, 0x00]
+        );
+        assert!(ecu.dtcs.is_empty());
+    }
+
+    #[test]
+    fn variant_from_ovd_ecu() {
+        let def: OvdECU = serde_json::from_str(TEST_ECU).unwrap();
+
+        let mut ecu = VirtualECU::from_ovd_ecu(&def, 0, 0).unwrap();
+        assert!(matches!(ecu.protocol, DiagProtocol::UDS));
+        assert_eq!((ecu.request_id, ecu.response_id), (0x7E0, 0x7E8));
+        assert_eq!(ecu.variant_id, 0x1234);
+        assert_eq!(ecu.dtcs.len(), 1);
+        assert_eq!((ecu.dtcs[0].id, ecu.dtcs[0].status), (0x200100, 0x09));
+        assert_eq!(
+            request(&mut ecu, &[0x22, 0xF1, 0x00]),
+            vec![0x62, 0xF1, 0x00, 0x00, 0x12, 0x34, 0x00]
+        );
+
+        let mut ecu = VirtualECU::from_ovd_ecu(&def, 1, 1).unwrap();
+        assert!(matches!(ecu.protocol, DiagProtocol::KWP2000));
+        assert_eq!((ecu.request_id, ecu.response_id), (0x10, 0xF1));
+        assert_eq!(ecu.functional_id, Some(0x33));
+        assert_eq!(ecu.variant_id, 0x5678);
+        // DTC names too short to hold an ID, or without an ASCII ID, are skipped
+        assert_eq!(ecu.dtcs.len(), 1);
+        assert_eq!((ecu.dtcs[0].id, ecu.dtcs[0].status), (0x2001, 0xA0));
+        // Services of the variant are answered
+        assert_eq!(request(&mut ecu, &[0x21, 0x01]), vec![0x61, 0x01]);
+        assert_eq!(request(&mut ecu, &[0x21, 0x02]), vec![0x7F, 0x21, 0x11]);
+
+        assert!(VirtualECU::from_ovd_ecu(&def, 2, 0).is_err());
+        assert!(VirtualECU::from_ovd_ecu(&def, 0, 2).is_err());
+    }
+
+    #[test]
+    fn build_from_config() {
+        let def: OvdECU = serde_json::from_str(TEST_ECU).unwrap();
+        let cfg = VirtualECUConfig {
+            variant_idx: 1,
+            connection_idx: 0,
+            store_dtcs: false,
+            response_pending_count: 1,
+            response_pending_interval: 0,
+        };
+        let mut ecu = cfg.build(&def).unwrap();
+        assert!(matches!(ecu.protocol, DiagProtocol::UDS));
+        assert_eq!(ecu.variant_id, 0x5678);
+        assert!(ecu.dtcs.is_empty());
+        assert_eq!(
+            ecu.handle_request(&[0x19, 0x02, 0xFF]),
+            vec![vec![0x7F, 0x19, 0x78], vec![0x59, 0x02, 0xFF]]
+        );
+
+        let ecu = VirtualECUConfig::default().build(&def).unwrap();
+        assert_eq!(ecu.dtcs.len(), 1);
+        assert_eq!(ecu.response_pending_count, 0);
+    }
+
+    #[test]
+    fn invalid_requests() {
+        for protocol in &[DiagProtocol::UDS, DiagProtocol::KWP2000] {
+            let mut ecu = VirtualECU::new("ECU", *protocol, 0x7E0, 0x7E8);
+            assert!(ecu.handle_request(&[]).is_empty());
+            // Positive response SIDs are not requests, and must not overflow
+            for sid in &[0x40, 0x7F, 0xC0, 0xFE, 0xFF] {
+                assert_eq!(request(&mut ecu, &[*sid, 0x00]), vec![0x7F, *sid, 0x11]);
+            }
+            ecu.custom_responses
+                .push((vec![0xFF, 0x01], vec![0xFF, 0x02]));
+            ecu.custom_responses
+                .push((vec![0x22, 0x01, 0x02], Vec::new()));
+            ecu.response_pending_count = 1;
+            assert_eq!(
+                ecu.handle_request(&[0xFF, 0x01]),
+                vec![vec![0x7F, 0xFF, 0x78], vec![0xFF, 0x02]]
+            );
+            // An empty custom response means the ECU stays silent
+            assert!(ecu.handle_request(&[0x22, 0x01, 0x02]).is_empty());
+        }
+    }
+}
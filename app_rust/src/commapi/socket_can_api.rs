@@ -1,22 +1,52 @@
 use std::{
     borrow::Borrow,
-    sync::{Arc, PoisonError, RwLock},
+    os::unix::io::AsRawFd,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, PoisonError, RwLock,
+    },
     time::Instant,
 };
 
 use crate::commapi::comm_api::{
-    CanFrame, ComServerError, DeviceCapabilities, FilterType, ISO15765Data,
+    CanFrame, ComServerError, DeviceCapabilities, FilterType, ISO15765Data, J1850Data, KLineData,
 };
 use crate::{commapi, main};
 use commapi::comm_api::ComServer;
 use socketcan::{CANError, CANFilter, CANSocket, ConstructionError};
-use socketcan_isotp::{IsoTpOptions, IsoTpSocket};
+use socketcan_isotp::{IsoTpOptions, IsoTpSocket, LinkLayerOptions, TxFlags};
 
 use super::comm_api::Capability;
 
 #[derive(Debug, Copy, Clone)]
 pub enum SocketCanIfaceError {}
 
+// From linux/can.h and linux/can/raw.h
+const CAN_RAW: libc::c_int = 1;
+const CAN_ISOTP: libc::c_int = 6;
+const SOL_CAN_RAW: libc::c_int = 101;
+const CAN_RAW_FD_FRAMES: libc::c_int = 5;
+const CAN_EFF_FLAG: u32 = 0x80000000;
+const CAN_RTR_FLAG: u32 = 0x40000000;
+const CAN_ERR_FLAG: u32 = 0x20000000;
+const CAN_EFF_MASK: u32 = 0x1FFFFFFF;
+const CAN_SFF_MASK: u32 = 0x000007FF;
+const CANFD_BRS: u8 = 0x01;
+const CANFD_ESI: u8 = 0x02;
+const CAN_MTU: usize = 16;
+const CANFD_MTU: usize = 72;
+
+/// struct canfd_frame. A classic struct can_frame has the same layout, but with only 8 data bytes
+#[repr(C)]
+struct RawCanFdFrame {
+    can_id: u32,
+    len: u8,
+    flags: u8,
+    res0: u8,
+    res1: u8,
+    data: [u8; 64],
+}
+
 #[derive(Clone)]
 pub struct SocketCanAPI {
     iface: String,
@@ -25,7 +55,9 @@ pub struct SocketCanAPI {
     can_filters: [Option<CANFilter>; 10],
     isotp_in_use: bool,
     req_iso_tp_settings: (u32, bool, bool), // Baud, ext CAN, ext Addressing
-                                            // TODO SocketCAN
+    // TODO SocketCAN
+    req_iso_tp_fd: Option<(u8, bool)>, // TX_DL, BRS
+    can_fd_enabled: Arc<AtomicBool>,
 }
 
 impl std::fmt::Debug for SocketCanAPI {
@@ -79,6 +111,8 @@ impl SocketCanAPI {
             can_filters: [None; 10],
             isotp_in_use: false,
             req_iso_tp_settings: (0, false, false),
+            req_iso_tp_fd: None,
+            can_fd_enabled: Arc::new(AtomicBool::new(false)),
         }
     }
 }
@@ -115,6 +149,127 @@ impl SocketCanAPI {
             }),
         }
     }
+
+    /// Writes a frame directly to the raw CAN socket. Used rather than socketcan's own
+    /// write, as that cannot send CAN FD frames
+    fn write_raw_frame(socket: &CANSocket, cf: &CanFrame) -> Result<(), ComServerError> {
+        let mut raw = RawCanFdFrame {
+            can_id: cf.id,
+            len: cf.dlc,
+            flags: 0,
+            res0: 0,
+            res1: 0,
+            data: [0; 64],
+        };
+        if cf.id > CAN_SFF_MASK {
+            raw.can_id |= CAN_EFF_FLAG;
+        }
+        raw.data[0..cf.dlc as usize].copy_from_slice(cf.get_data());
+        let size = if cf.fd {
+            if cf.brs {
+                raw.flags |= CANFD_BRS;
+            }
+            if cf.esi {
+                raw.flags |= CANFD_ESI;
+            }
+            CANFD_MTU
+        } else {
+            CAN_MTU
+        };
+        let res = unsafe {
+            libc::write(
+                socket.as_raw_fd(),
+                &raw as *const _ as *const libc::c_void,
+                size,
+            )
+        };
+        if res != size as isize {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    /// Reads the next frame directly from the raw CAN socket. Error and remote frames are
+    /// not supported, so are skipped. Returns None once there is nothing left to read
+    fn read_raw_frame(socket: &CANSocket) -> Option<CanFrame> {
+        loop {
+            let mut raw = RawCanFdFrame {
+                can_id: 0,
+                len: 0,
+                flags: 0,
+                res0: 0,
+                res1: 0,
+                data: [0; 64],
+            };
+            let size = unsafe {
+                libc::read(
+                    socket.as_raw_fd(),
+                    &mut raw as *mut _ as *mut libc::c_void,
+                    CANFD_MTU,
+                )
+            };
+            if size <= 0 {
+                return None; // Nothing to read
+            }
+            if raw.can_id & (CAN_ERR_FLAG | CAN_RTR_FLAG) != 0 {
+                continue;
+            }
+            let id = if raw.can_id & CAN_EFF_FLAG != 0 {
+                raw.can_id & CAN_EFF_MASK
+            } else {
+                raw.can_id & CAN_SFF_MASK
+            };
+            match size as usize {
+                CAN_MTU => {
+                    return Some(CanFrame::new(
+                        id,
+                        &raw.data[0..std::cmp::min(raw.len, 8) as usize],
+                    ))
+                }
+                CANFD_MTU => {
+                    let mut cf = CanFrame::new_fd(
+                        id,
+                        &raw.data[0..std::cmp::min(raw.len, 64) as usize],
+                        raw.flags & CANFD_BRS != 0,
+                        raw.flags & CANFD_ESI != 0,
+                    );
+                    cf.dlc = std::cmp::min(raw.len, 64); // Kernel already gives a valid FD length
+                    return Some(cf);
+                }
+                _ => continue, // Partial frame
+            }
+        }
+    }
+
+    /// Allows CAN FD frames on a raw CAN socket. Returns false if the kernel does not
+    /// support CAN FD, in which case only classic frames can be used
+    fn enable_can_fd(fd: libc::c_int) -> bool {
+        let enable: libc::c_int = 1;
+        let res = unsafe {
+            libc::setsockopt(
+                fd,
+                SOL_CAN_RAW,
+                CAN_RAW_FD_FRAMES,
+                &enable as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        res == 0
+    }
+
+    fn kline_unsupported() -> ComServerError {
+        ComServerError {
+            err_code: 1,
+            err_desc: "Socket CAN does not support K-Line".into(),
+        }
+    }
+
+    fn j1850_unsupported() -> ComServerError {
+        ComServerError {
+            err_code: 1,
+            err_desc: "Socket CAN does not support J1850".into(),
+        }
+    }
 }
 
 #[allow(unused_variables)]
@@ -133,18 +288,14 @@ impl ComServer for SocketCanAPI {
         timeout_ms: u32,
     ) -> Result<usize, ComServerError> {
         if let Some(socket) = self.sockcan_iface.read().unwrap().as_ref() {
-            if timeout_ms == 0 {
-                for x in data {
-                    //socket.write_frame(x.into()).map_err(|x| ComServerError {
-                    //    err_code: 1,
-                    //    err_desc: x.to_string(),
-                    //})?;
-                }
-            } else {
-                //socket.write_frame_insist(x).map_err(|x| ComServerError {
-                //    err_code: 1,
-                //    err_desc: x.to_string(),
-                //})?;
+            if data.iter().any(|cf| cf.fd) && !self.can_fd_enabled.load(Ordering::Relaxed) {
+                return Err(ComServerError {
+                    err_code: 1,
+                    err_desc: format!("{} does not support CAN FD frames", self.iface),
+                });
+            }
+            for x in data {
+                Self::write_raw_frame(socket, x)?;
             }
             Ok(data.len())
         } else {
@@ -164,22 +315,18 @@ impl ComServer for SocketCanAPI {
         let mut res: Vec<CanFrame> = Vec::with_capacity(max_msgs);
 
         if timeout_ms == 0 {
-            let v_timeout = 10;
-            match &self.run_can_iface(|x| x.read_frame().map_err(|x| x.into())) {
-                Ok(cf) => res.push(CanFrame::from(*cf)),
-                Err(e) => {
-                    return Ok(res); // Return what we have
+            // Read whatever is in the socket's queue
+            while let Ok(Some(cf)) = self.run_can_iface(|x| Ok(Self::read_raw_frame(x))) {
+                res.push(cf);
+                if res.len() == max_msgs {
+                    return Ok(res);
                 }
             }
-            if res.len() == max_msgs {
-                return Ok(res);
-            }
         } else {
             let start = Instant::now();
             while start.elapsed().as_millis() <= timeout_ms as u128 {
-                match &self.run_can_iface(|x| x.read_frame().map_err(|x| x.into())) {
-                    Ok(cf) => res.push(CanFrame::from(*cf)),
-                    Err(_) => {} // Ignore error when using timeout
+                if let Ok(Some(cf)) = self.run_can_iface(|x| Ok(Self::read_raw_frame(x))) {
+                    res.push(cf);
                 }
                 if res.len() == max_msgs {
                     return Ok(res);
@@ -271,6 +418,10 @@ impl ComServer for SocketCanAPI {
                 err_code: 1,
                 err_desc: x.to_string(),
             })?; // Disable blocking
+        self.can_fd_enabled.store(
+            Self::enable_can_fd(tp_socket.as_raw_fd()),
+            Ordering::Relaxed,
+        );
         *self.sockcan_iface.write().unwrap() = Some(tp_socket);
         Ok(())
     }
@@ -280,7 +431,8 @@ impl ComServer for SocketCanAPI {
             return Ok(()); // No socket to close
         }
         self.can_filters = [None; 10]; // Remove all filters
-        drop(self.sockcan_iface.write().unwrap()); // Dropping the socketCAN Iface closes it
+        self.can_fd_enabled.store(false, Ordering::Relaxed);
+        self.sockcan_iface.write().unwrap().take(); // Dropping the socketCAN Iface closes it
         Ok(())
     }
 
@@ -301,6 +453,7 @@ impl ComServer for SocketCanAPI {
     fn close_iso15765_interface(&mut self) -> Result<(), ComServerError> {
         self.isotp_in_use = false;
         self.req_iso_tp_settings = (0, false, false);
+        self.req_iso_tp_fd = None;
         self.isotp_iface.write().unwrap().take(); // Bye bye iso-tp
         Ok(())
     }
@@ -366,7 +519,17 @@ impl ComServer for SocketCanAPI {
 
         if let FilterType::IsoTP { id, mask, fc } = f {
             // Now try to setup the ISO-TP interface
-            let iface = IsoTpSocket::open_with_opts(&self.iface, fc, id & mask, None, None, None)?;
+            // ISO-TP over CAN FD is configured through the link layer options
+            let ll_opts = self.req_iso_tp_fd.map(|(tx_dl, brs)| {
+                let flags = if brs {
+                    TxFlags::CANFD_BRS
+                } else {
+                    TxFlags::empty()
+                };
+                LinkLayerOptions::new(CANFD_MTU as u8, tx_dl, flags)
+            });
+            let iface =
+                IsoTpSocket::open_with_opts(&self.iface, fc, id & mask, None, None, ll_opts)?;
             iface.set_nonblocking(true)?; // Request non blocking!
             *self.isotp_iface.write().unwrap() = Some(iface);
             Ok(1)
@@ -392,6 +555,149 @@ impl ComServer for SocketCanAPI {
         Ok(()) // SocketCAN will not do this - It can auto negotiate with the ECU
     }
 
+    fn set_iso15765_fd_params(&mut self, tx_dl: u32, brs: bool) -> Result<(), ComServerError> {
+        if CanFrame::fd_padded_len(tx_dl as usize) != tx_dl as usize || tx_dl < 8 {
+            return Err(ComServerError {
+                err_code: 1,
+                err_desc: format!("{} is not a valid CAN FD frame length", tx_dl),
+            });
+        }
+        // Applied when the ISO-TP socket is opened
+        self.req_iso_tp_fd = Some((tx_dl as u8, brs));
+        Ok(())
+    }
+
+    fn open_iso14230_interface(&mut self, baud: u32) -> Result<(), ComServerError> {
+        Err(Self::kline_unsupported())
+    }
+
+    fn close_iso14230_interface(&mut self) -> Result<(), ComServerError> {
+        Ok(()) // Never opened
+    }
+
+    fn iso14230_fast_init(&mut self, init_msg: &[u8]) -> Result<Vec<u8>, ComServerError> {
+        Err(Self::kline_unsupported())
+    }
+
+    fn iso14230_five_baud_init(&mut self, address: u8) -> Result<Vec<u8>, ComServerError> {
+        Err(Self::kline_unsupported())
+    }
+
+    fn send_iso14230_data(
+        &self,
+        data: &[KLineData],
+        timeout_ms: u32,
+    ) -> Result<usize, ComServerError> {
+        Err(Self::kline_unsupported())
+    }
+
+    fn read_iso14230_packets(
+        &self,
+        timeout_ms: u32,
+        max_msgs: usize,
+    ) -> Result<Vec<KLineData>, ComServerError> {
+        Err(Self::kline_unsupported())
+    }
+
+    fn set_iso14230_params(
+        &mut self,
+        p1_max: u32,
+        p3_min: u32,
+        p4_min: u32,
+    ) -> Result<(), ComServerError> {
+        Err(Self::kline_unsupported())
+    }
+
+    fn open_iso9141_interface(&mut self, baud: u32) -> Result<(), ComServerError> {
+        Err(Self::kline_unsupported())
+    }
+
+    fn close_iso9141_interface(&mut self) -> Result<(), ComServerError> {
+        Ok(()) // Never opened
+    }
+
+    fn iso9141_five_baud_init(&mut self, address: u8) -> Result<Vec<u8>, ComServerError> {
+        Err(Self::kline_unsupported())
+    }
+
+    fn send_iso9141_data(
+        &self,
+        data: &[KLineData],
+        timeout_ms: u32,
+    ) -> Result<usize, ComServerError> {
+        Err(Self::kline_unsupported())
+    }
+
+    fn read_iso9141_packets(
+        &self,
+        timeout_ms: u32,
+        max_msgs: usize,
+    ) -> Result<Vec<KLineData>, ComServerError> {
+        Err(Self::kline_unsupported())
+    }
+
+    fn set_iso9141_params(
+        &mut self,
+        p1_max: u32,
+        p3_min: u32,
+        p4_min: u32,
+    ) -> Result<(), ComServerError> {
+        Err(Self::kline_unsupported())
+    }
+
+    fn open_j1850vpw_interface(&mut self, baud: u32) -> Result<(), ComServerError> {
+        Err(Self::j1850_unsupported())
+    }
+
+    fn close_j1850vpw_interface(&mut self) -> Result<(), ComServerError> {
+        Ok(()) // Never opened
+    }
+
+    fn send_j1850vpw_data(
+        &self,
+        data: &[J1850Data],
+        timeout_ms: u32,
+    ) -> Result<usize, ComServerError> {
+        Err(Self::j1850_unsupported())
+    }
+
+    fn read_j1850vpw_packets(
+        &self,
+        timeout_ms: u32,
+        max_msgs: usize,
+    ) -> Result<Vec<J1850Data>, ComServerError> {
+        Err(Self::j1850_unsupported())
+    }
+
+    fn open_j1850pwm_interface(
+        &mut self,
+        baud: u32,
+        node_addr: u8,
+        func_addrs: &[u8],
+    ) -> Result<(), ComServerError> {
+        Err(Self::j1850_unsupported())
+    }
+
+    fn close_j1850pwm_interface(&mut self) -> Result<(), ComServerError> {
+        Ok(()) // Never opened
+    }
+
+    fn send_j1850pwm_data(
+        &self,
+        data: &[J1850Data],
+        timeout_ms: u32,
+    ) -> Result<usize, ComServerError> {
+        Err(Self::j1850_unsupported())
+    }
+
+    fn read_j1850pwm_packets(
+        &self,
+        timeout_ms: u32,
+        max_msgs: usize,
+    ) -> Result<Vec<J1850Data>, ComServerError> {
+        Err(Self::j1850_unsupported())
+    }
+
     fn clear_can_rx_buffer(&self) -> Result<(), ComServerError> {
         Ok(()) // Socket CAN does not do this
     }
@@ -408,6 +714,38 @@ impl ComServer for SocketCanAPI {
         Ok(()) // Socket CAN does not do this
     }
 
+    fn clear_iso14230_rx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(()) // Socket CAN does not do this
+    }
+
+    fn clear_iso14230_tx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(()) // Socket CAN does not do this
+    }
+
+    fn clear_iso9141_rx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(()) // Socket CAN does not do this
+    }
+
+    fn clear_iso9141_tx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(()) // Socket CAN does not do this
+    }
+
+    fn clear_j1850vpw_rx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(()) // Socket CAN does not do this
+    }
+
+    fn clear_j1850vpw_tx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(()) // Socket CAN does not do this
+    }
+
+    fn clear_j1850pwm_rx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(()) // Socket CAN does not do this
+    }
+
+    fn clear_j1850pwm_tx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(()) // Socket CAN does not do this
+    }
+
     fn read_battery_voltage(&self) -> Result<f32, ComServerError> {
         // Socket CAN cannot measure battery voltage, so return -1.0 so user knows its not supported
         // rather than spitting out an error.
@@ -419,6 +757,27 @@ impl ComServer for SocketCanAPI {
     }
 
     fn get_capabilities(&self) -> DeviceCapabilities {
+        // The ISO-TP kernel module is not always available. Without it,
+        // the built in ISO-TP stack is used on top of raw CAN
+        let isotp_socket = unsafe { libc::socket(libc::AF_CAN, libc::SOCK_DGRAM, CAN_ISOTP) };
+        let iso15765 = if isotp_socket >= 0 {
+            unsafe { libc::close(isotp_socket) };
+            Capability::Yes
+        } else {
+            Capability::No
+        };
+        // Once the CAN interface is open, CAN FD is only supported if it could be enabled on
+        // its socket. Before then, check if it can be enabled on a new raw CAN socket
+        let can_fd_supported = if self.sockcan_iface.read().unwrap().is_some() {
+            self.can_fd_enabled.load(Ordering::Relaxed)
+        } else {
+            let raw_socket = unsafe { libc::socket(libc::AF_CAN, libc::SOCK_RAW, CAN_RAW) };
+            raw_socket >= 0 && {
+                let res = Self::enable_can_fd(raw_socket);
+                unsafe { libc::close(raw_socket) };
+                res
+            }
+        };
         DeviceCapabilities {
             name: self.iface.clone(),
             vendor: "Unknown".into(),
@@ -428,7 +787,12 @@ impl ComServer for SocketCanAPI {
             j1850vpw: Capability::NA,
             j1850pwm: Capability::NA,
             can: Capability::Yes,
-            iso15765: Capability::Yes,
+            can_fd: if can_fd_supported {
+                Capability::Yes
+            } else {
+                Capability::No
+            },
+            iso15765,
             iso9141: Capability::NA,
             iso14230: Capability::NA,
             ip: Capability::NA,
@@ -0,0 +1,1242 @@
+//! ISO 22900-2 (D-PDU API) driver bindings
+//!
+//! This mirrors [passthru](crate::passthru), but for VCIs that only ship a D-PDU API
+//! library. The library is located via the D-PDU root description file (RDF), and the
+//! VCIs it supports are enumerated from the module description file (MDF) and
+//! cable description file (CDF) it references.
+
+use crate::passthru::{DeviceError, LoadDeviceError};
+use libloading::Library;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::{ffi::*, fmt};
+
+#[cfg(windows)]
+use winreg::enums::*;
+
+#[cfg(windows)]
+use winreg::RegKey;
+
+/// Result which contains a [PduError] in it's Err() variant
+pub type Result<T> = std::result::Result<T, PduError>;
+
+/// Handle value used when a module, ComLogicalLink or ComPrimitive handle is not applicable
+pub const PDU_HANDLE_UNDEF: u32 = 0xFFFFFFFE;
+/// ID value used when an object ID is not applicable
+pub const PDU_ID_UNDEF: u32 = 0xFFFFFFFE;
+
+// Object types (T_PDU_OBJT)
+pub const PDU_OBJT_PROTOCOL: u32 = 0x8021;
+pub const PDU_OBJT_BUSTYPE: u32 = 0x8022;
+pub const PDU_OBJT_IO_CTRL: u32 = 0x8023;
+pub const PDU_OBJT_COMPARAM: u32 = 0x8024;
+pub const PDU_OBJT_PINTYPE: u32 = 0x8025;
+
+// ComPrimitive types (T_PDU_COPT)
+pub const PDU_COPT_STARTCOMM: u32 = 0x8001;
+pub const PDU_COPT_STOPCOMM: u32 = 0x8002;
+pub const PDU_COPT_UPDATEPARAM: u32 = 0x8003;
+pub const PDU_COPT_SENDRECV: u32 = 0x8004;
+
+// Item types (T_PDU_IT)
+pub const PDU_IT_IO_UNUM32: u32 = 0x1000;
+pub const PDU_IT_PARAM: u32 = 0x1200;
+pub const PDU_IT_RESULT: u32 = 0x1300;
+pub const PDU_IT_STATUS: u32 = 0x1301;
+pub const PDU_IT_ERROR: u32 = 0x1302;
+pub const PDU_IT_INFO: u32 = 0x1303;
+pub const PDU_IT_UNIQUE_RESP_ID_TABLE: u32 = 0x1700;
+
+// Status codes (T_PDU_STATUS)
+pub const PDU_COPST_FINISHED: u32 = 0x8012;
+pub const PDU_COPST_CANCELLED: u32 = 0x8013;
+pub const PDU_MODST_READY: u32 = 0x8060;
+pub const PDU_MODST_AVAIL: u32 = 0x8063;
+
+// ComParam data types (T_PDU_PT)
+pub const PDU_PT_UNUM32: u32 = 0x00000105;
+
+// ComParam classes (T_PDU_PC)
+pub const PDU_PC_TIMING: u32 = 1;
+pub const PDU_PC_COM: u32 = 3;
+pub const PDU_PC_BUSTYPE: u32 = 5;
+pub const PDU_PC_UNIQUE_ID: u32 = 6;
+
+/// Value of NumReceiveCycles which tells a ComPrimitive to receive forever
+pub const PDU_IS_INFINITE: i32 = -1;
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u32)]
+pub enum PduError {
+    PDU_ERR_FCT_FAILED = 0x01,
+    PDU_ERR_COMM_PC_TO_VCI_FAILED = 0x03,
+    PDU_ERR_PDUAPI_NOT_CONSTRUCTED = 0x10,
+    PDU_ERR_SHARING_VIOLATION = 0x11,
+    PDU_ERR_RESOURCE_BUSY = 0x12,
+    PDU_ERR_RESOURCE_TABLE_CHANGED = 0x13,
+    PDU_ERR_RESOURCE_ERROR = 0x14,
+    PDU_ERR_CLL_NOT_CONNECTED = 0x20,
+    PDU_ERR_CLL_NOT_STARTED = 0x21,
+    PDU_ERR_INVALID_PARAMETERS = 0x50,
+    PDU_ERR_INVALID_HANDLE = 0x60,
+    PDU_ERR_VALUE_NOT_SUPPORTED = 0x61,
+    PDU_ERR_ID_NOT_SUPPORTED = 0x62,
+    PDU_ERR_COMPARAM_NOT_SUPPORTED = 0x63,
+    PDU_ERR_COMPARAM_LOCKED = 0x64,
+    PDU_ERR_TX_QUEUE_FULL = 0x65,
+    PDU_ERR_EVENT_QUEUE_EMPTY = 0x66,
+    PDU_ERR_VOLTAGE_NOT_SUPPORTED = 0x67,
+    PDU_ERR_MUX_RSC_NOT_SUPPORTED = 0x68,
+    PDU_ERR_CABLE_UNKNOWN = 0x69,
+    PDU_ERR_NO_CABLE_DETECTED = 0x6A,
+    PDU_ERR_CLL_CONNECTED = 0x6B,
+    PDU_ERR_TEMPPARAM_NOT_ALLOWED = 0x70,
+    PDU_ERR_RSC_LOCKED = 0x71,
+    PDU_ERR_RSC_LOCKED_BY_OTHER_CLL = 0x72,
+    PDU_ERR_RSC_NOT_LOCKED = 0x73,
+    PDU_ERR_MODULE_NOT_CONNECTED = 0x74,
+    PDU_ERR_API_SW_OUT_OF_DATE = 0x75,
+    PDU_ERR_MODULE_FW_OUT_OF_DATE = 0x76,
+    PDU_ERR_PIN_NOT_CONNECTED = 0x77,
+}
+
+impl PduError {
+    pub fn from_raw(x: u32) -> Option<Self> {
+        use PduError::*;
+        Some(match x {
+            0x01 => PDU_ERR_FCT_FAILED,
+            0x03 => PDU_ERR_COMM_PC_TO_VCI_FAILED,
+            0x10 => PDU_ERR_PDUAPI_NOT_CONSTRUCTED,
+            0x11 => PDU_ERR_SHARING_VIOLATION,
+            0x12 => PDU_ERR_RESOURCE_BUSY,
+            0x13 => PDU_ERR_RESOURCE_TABLE_CHANGED,
+            0x14 => PDU_ERR_RESOURCE_ERROR,
+            0x20 => PDU_ERR_CLL_NOT_CONNECTED,
+            0x21 => PDU_ERR_CLL_NOT_STARTED,
+            0x50 => PDU_ERR_INVALID_PARAMETERS,
+            0x60 => PDU_ERR_INVALID_HANDLE,
+            0x61 => PDU_ERR_VALUE_NOT_SUPPORTED,
+            0x62 => PDU_ERR_ID_NOT_SUPPORTED,
+            0x63 => PDU_ERR_COMPARAM_NOT_SUPPORTED,
+            0x64 => PDU_ERR_COMPARAM_LOCKED,
+            0x65 => PDU_ERR_TX_QUEUE_FULL,
+            0x66 => PDU_ERR_EVENT_QUEUE_EMPTY,
+            0x67 => PDU_ERR_VOLTAGE_NOT_SUPPORTED,
+            0x68 => PDU_ERR_MUX_RSC_NOT_SUPPORTED,
+            0x69 => PDU_ERR_CABLE_UNKNOWN,
+            0x6A => PDU_ERR_NO_CABLE_DETECTED,
+            0x6B => PDU_ERR_CLL_CONNECTED,
+            0x70 => PDU_ERR_TEMPPARAM_NOT_ALLOWED,
+            0x71 => PDU_ERR_RSC_LOCKED,
+            0x72 => PDU_ERR_RSC_LOCKED_BY_OTHER_CLL,
+            0x73 => PDU_ERR_RSC_NOT_LOCKED,
+            0x74 => PDU_ERR_MODULE_NOT_CONNECTED,
+            0x75 => PDU_ERR_API_SW_OUT_OF_DATE,
+            0x76 => PDU_ERR_MODULE_FW_OUT_OF_DATE,
+            0x77 => PDU_ERR_PIN_NOT_CONNECTED,
+            _ => return None,
+        })
+    }
+
+    pub fn get_desc(&self) -> &'static str {
+        use PduError::*;
+        match self {
+            PDU_ERR_FCT_FAILED => "Function call failed",
+            PDU_ERR_COMM_PC_TO_VCI_FAILED => "Communication between PC and VCI failed",
+            PDU_ERR_PDUAPI_NOT_CONSTRUCTED => "D-PDU API has not been constructed",
+            PDU_ERR_SHARING_VIOLATION => "D-PDU API is already in use by another application",
+            PDU_ERR_RESOURCE_BUSY => "Resource is busy",
+            PDU_ERR_RESOURCE_TABLE_CHANGED => "Resource table has changed",
+            PDU_ERR_RESOURCE_ERROR => "Resource error",
+            PDU_ERR_CLL_NOT_CONNECTED => "ComLogicalLink is not connected",
+            PDU_ERR_CLL_NOT_STARTED => "ComLogicalLink has not started communication",
+            PDU_ERR_INVALID_PARAMETERS => "Invalid parameters",
+            PDU_ERR_INVALID_HANDLE => "Invalid handle",
+            PDU_ERR_VALUE_NOT_SUPPORTED => "Value not supported",
+            PDU_ERR_ID_NOT_SUPPORTED => "ID not supported",
+            PDU_ERR_COMPARAM_NOT_SUPPORTED => "ComParam not supported",
+            PDU_ERR_COMPARAM_LOCKED => "ComParam is locked",
+            PDU_ERR_TX_QUEUE_FULL => "Transmit queue is full",
+            PDU_ERR_EVENT_QUEUE_EMPTY => "Event queue is empty",
+            PDU_ERR_VOLTAGE_NOT_SUPPORTED => "Voltage not supported",
+            PDU_ERR_MUX_RSC_NOT_SUPPORTED => "Multiplexed resource not supported",
+            PDU_ERR_CABLE_UNKNOWN => "Unknown cable",
+            PDU_ERR_NO_CABLE_DETECTED => "No cable detected",
+            PDU_ERR_CLL_CONNECTED => "ComLogicalLink is already connected",
+            PDU_ERR_TEMPPARAM_NOT_ALLOWED => "Temporary ComParams are not allowed",
+            PDU_ERR_RSC_LOCKED => "Resource is locked",
+            PDU_ERR_RSC_LOCKED_BY_OTHER_CLL => "Resource is locked by another ComLogicalLink",
+            PDU_ERR_RSC_NOT_LOCKED => "Resource is not locked",
+            PDU_ERR_MODULE_NOT_CONNECTED => "Module is not connected",
+            PDU_ERR_API_SW_OUT_OF_DATE => "D-PDU API software is out of date",
+            PDU_ERR_MODULE_FW_OUT_OF_DATE => "Module firmware is out of date",
+            PDU_ERR_PIN_NOT_CONNECTED => "Pin is not connected",
+        }
+    }
+}
+
+impl fmt::Display for PduError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.get_desc())
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct PDU_FLAG_DATA {
+    pub num_flag_bytes: u32,
+    pub p_flag_data: *mut u8,
+}
+
+impl Default for PDU_FLAG_DATA {
+    fn default() -> Self {
+        Self {
+            num_flag_bytes: 0,
+            p_flag_data: std::ptr::null_mut(),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct PDU_PIN_DATA {
+    pub dlc_pin_number: u32,
+    pub dlc_pin_type_id: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct PDU_RSC_DATA {
+    pub bus_type_id: u32,
+    pub protocol_id: u32,
+    pub num_pin_data: u32,
+    pub p_dlc_pin_data: *mut PDU_PIN_DATA,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct PDU_PARAM_ITEM {
+    pub item_type: u32,
+    pub com_param_id: u32,
+    pub com_param_data_type: u32,
+    pub com_param_class: u32,
+    pub p_com_param_data: *mut c_void,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct PDU_ECU_UNIQUE_RESP_DATA {
+    pub unique_resp_identifier: u32,
+    pub num_param_items: u32,
+    pub p_params: *mut PDU_PARAM_ITEM,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct PDU_UNIQUE_RESP_ID_TABLE_ITEM {
+    pub item_type: u32,
+    pub num_entries: u32,
+    pub p_unique_data: *mut PDU_ECU_UNIQUE_RESP_DATA,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct PDU_EXP_RESP_DATA {
+    pub response_type: u32,
+    pub acceptance_id: u32,
+    pub num_mask_pattern_bytes: u32,
+    pub p_mask_data: *mut u8,
+    pub p_pattern_data: *mut u8,
+    pub num_unique_resp_ids: u32,
+    pub p_unique_resp_ids: *mut u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct PDU_COP_CTRL_DATA {
+    pub time: u32,
+    pub num_send_cycles: i32,
+    pub num_receive_cycles: i32,
+    pub temp_param_update: u32,
+    pub tx_flag: PDU_FLAG_DATA,
+    pub num_possible_expected_responses: u32,
+    pub p_expected_response_array: *mut PDU_EXP_RESP_DATA,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct PDU_EVENT_ITEM {
+    pub item_type: u32,
+    pub h_cop: u32,
+    pub p_cop_tag: *mut c_void,
+    pub timestamp: u32,
+    pub p_data: *mut c_void,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct PDU_RESULT_DATA {
+    pub rx_flag: PDU_FLAG_DATA,
+    pub unique_resp_identifier: u32,
+    pub acceptance_id: u32,
+    pub timestamp_flags: PDU_FLAG_DATA,
+    pub tx_msg_done_timestamp: u32,
+    pub start_msg_timestamp: u32,
+    pub p_extra_info: *mut c_void,
+    pub num_data_bytes: u32,
+    pub p_data_bytes: *mut u8,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct PDU_ERROR_DATA {
+    pub error_code_id: u32,
+    pub extra_error_info_id: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct PDU_DATA_ITEM {
+    pub item_type: u32,
+    pub p_data: *mut c_void,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct PDU_MODULE_DATA {
+    pub module_type_id: u32,
+    pub h_mod: u32,
+    pub p_vendor_module_name: *mut c_char,
+    pub p_vendor_additional_info: *mut c_char,
+    pub module_status: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct PDU_MODULE_ITEM {
+    pub item_type: u32,
+    pub num_entries: u32,
+    pub p_module_data: *mut PDU_MODULE_DATA,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct PDU_VERSION_DATA {
+    pub mvci_part1_standard_version: u32,
+    pub mvci_part2_standard_version: u32,
+    pub hw_serial_number: u32,
+    pub hw_name: [c_char; 64],
+    pub hw_version: u32,
+    pub hw_date: u32,
+    pub hw_interface: u32,
+    pub fw_name: [c_char; 64],
+    pub fw_version: u32,
+    pub fw_date: u32,
+    pub vendor_name: [c_char; 64],
+    pub pdu_api_sw_name: [c_char; 64],
+    pub pdu_api_sw_version: u32,
+    pub pdu_api_sw_date: u32,
+}
+
+type PDUConstructFn =
+    unsafe extern "stdcall" fn(option_str: *const c_char, api_tag: *mut c_void) -> u32;
+type PDUDestructFn = unsafe extern "stdcall" fn() -> u32;
+type PDUModuleConnectFn = unsafe extern "stdcall" fn(h_mod: u32) -> u32;
+type PDUModuleDisconnectFn = unsafe extern "stdcall" fn(h_mod: u32) -> u32;
+type PDUGetModuleIdsFn =
+    unsafe extern "stdcall" fn(module_id_list: *mut *mut PDU_MODULE_ITEM) -> u32;
+type PDUGetObjectIdFn = unsafe extern "stdcall" fn(
+    object_type: u32,
+    short_name: *const c_char,
+    object_id: *mut u32,
+) -> u32;
+type PDUCreateComLogicalLinkFn = unsafe extern "stdcall" fn(
+    h_mod: u32,
+    rsc_data: *mut PDU_RSC_DATA,
+    resource_id: u32,
+    cll_tag: *mut c_void,
+    h_cll: *mut u32,
+    cll_create_flag: *mut PDU_FLAG_DATA,
+) -> u32;
+type PDUDestroyComLogicalLinkFn = unsafe extern "stdcall" fn(h_mod: u32, h_cll: u32) -> u32;
+type PDUConnectFn = unsafe extern "stdcall" fn(h_mod: u32, h_cll: u32) -> u32;
+type PDUDisconnectFn = unsafe extern "stdcall" fn(h_mod: u32, h_cll: u32) -> u32;
+type PDUSetComParamFn =
+    unsafe extern "stdcall" fn(h_mod: u32, h_cll: u32, param_item: *mut PDU_PARAM_ITEM) -> u32;
+type PDUSetUniqueRespIdTableFn = unsafe extern "stdcall" fn(
+    h_mod: u32,
+    h_cll: u32,
+    table: *mut PDU_UNIQUE_RESP_ID_TABLE_ITEM,
+) -> u32;
+type PDUStartComPrimitiveFn = unsafe extern "stdcall" fn(
+    h_mod: u32,
+    h_cll: u32,
+    cop_type: u32,
+    cop_data_size: u32,
+    cop_data: *mut u8,
+    cop_ctrl_data: *mut PDU_COP_CTRL_DATA,
+    cop_tag: *mut c_void,
+    h_cop: *mut u32,
+) -> u32;
+type PDUCancelComPrimitiveFn =
+    unsafe extern "stdcall" fn(h_mod: u32, h_cll: u32, h_cop: u32) -> u32;
+type PDUGetEventItemFn =
+    unsafe extern "stdcall" fn(h_mod: u32, h_cll: u32, event_item: *mut *mut PDU_EVENT_ITEM) -> u32;
+type PDUDestroyItemFn = unsafe extern "stdcall" fn(item: *mut c_void) -> u32;
+type PDUIoCtlFn = unsafe extern "stdcall" fn(
+    h_mod: u32,
+    h_cll: u32,
+    ioctl_id: u32,
+    input: *mut PDU_DATA_ITEM,
+    output: *mut *mut PDU_DATA_ITEM,
+) -> u32;
+type PDUGetVersionFn =
+    unsafe extern "stdcall" fn(h_mod: u32, version: *mut PDU_VERSION_DATA) -> u32;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PduVersion {
+    /// D-PDU API library name and version
+    pub api_version: String,
+    /// Module firmware name and version
+    pub fw_version: String,
+}
+
+/// An event read from a ComLogicalLink's event queue, copied out of driver owned memory
+#[derive(Debug, Clone)]
+pub enum PduEvent {
+    /// Data received from the vehicle
+    Result {
+        h_cop: u32,
+        urid: u32,
+        data: Vec<u8>,
+    },
+    /// Status change of a ComPrimitive, ComLogicalLink or module
+    Status { h_cop: u32, status: u32 },
+    /// Error raised whilst running a ComPrimitive
+    Error { h_cop: u32, code: u32, extra: u32 },
+    /// Any other event that OVD does not care about
+    Other { item_type: u32 },
+}
+
+#[derive(Clone)]
+pub struct PduDrv {
+    /// Loaded library to interface with the device
+    lib: Arc<libloading::Library>,
+    /// Has PDUConstruct been called?
+    is_constructed: bool,
+    construct_fn: PDUConstructFn,
+    destruct_fn: PDUDestructFn,
+    module_connect_fn: PDUModuleConnectFn,
+    module_disconnect_fn: PDUModuleDisconnectFn,
+    get_module_ids_fn: PDUGetModuleIdsFn,
+    get_object_id_fn: PDUGetObjectIdFn,
+    create_cll_fn: PDUCreateComLogicalLinkFn,
+    destroy_cll_fn: PDUDestroyComLogicalLinkFn,
+    connect_fn: PDUConnectFn,
+    disconnect_fn: PDUDisconnectFn,
+    set_com_param_fn: PDUSetComParamFn,
+    set_urid_table_fn: PDUSetUniqueRespIdTableFn,
+    start_cop_fn: PDUStartComPrimitiveFn,
+    cancel_cop_fn: PDUCancelComPrimitiveFn,
+    get_event_item_fn: PDUGetEventItemFn,
+    destroy_item_fn: PDUDestroyItemFn,
+    ioctl_fn: PDUIoCtlFn,
+    get_version_fn: PDUGetVersionFn,
+}
+
+impl fmt::Debug for PduDrv {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PduDrv")
+            .field("is_constructed", &self.is_constructed)
+            .field("library", &self.lib)
+            .finish()
+    }
+}
+
+#[inline(always)]
+/// Function to reduce boilerplate code with returning a Result
+fn ret_res<T>(res: u32, ret: T) -> Result<T> {
+    match res {
+        0 => Ok(ret),
+        _ => Err(PduError::from_raw(res).unwrap_or(PduError::PDU_ERR_FCT_FAILED)),
+    }
+}
+
+/// Reads a null terminated C string into a String, returning an empty string if the pointer is null
+unsafe fn c_str_to_string(ptr: *const c_char) -> String {
+    if ptr.is_null() {
+        return String::new();
+    }
+    CStr::from_ptr(ptr).to_string_lossy().to_string()
+}
+
+/// Formats a D-PDU version number (Major.Minor.Revision in the upper 3 bytes)
+fn fmt_version(v: u32) -> String {
+    format!(
+        "{}.{}.{}",
+        (v >> 24) & 0xFF,
+        (v >> 16) & 0xFF,
+        (v >> 8) & 0xFF
+    )
+}
+
+impl PduDrv {
+    pub fn load_lib(path: String) -> std::result::Result<PduDrv, libloading::Error> {
+        let lib = unsafe { Library::new(path)? };
+        unsafe {
+            let construct_fn = *lib.get::<PDUConstructFn>(b"PDUConstruct\0")?.into_raw();
+            let destruct_fn = *lib.get::<PDUDestructFn>(b"PDUDestruct\0")?.into_raw();
+            let module_connect_fn = *lib
+                .get::<PDUModuleConnectFn>(b"PDUModuleConnect\0")?
+                .into_raw();
+            let module_disconnect_fn = *lib
+                .get::<PDUModuleDisconnectFn>(b"PDUModuleDisconnect\0")?
+                .into_raw();
+            let get_module_ids_fn = *lib
+                .get::<PDUGetModuleIdsFn>(b"PDUGetModuleIds\0")?
+                .into_raw();
+            let get_object_id_fn = *lib.get::<PDUGetObjectIdFn>(b"PDUGetObjectId\0")?.into_raw();
+            let create_cll_fn = *lib
+                .get::<PDUCreateComLogicalLinkFn>(b"PDUCreateComLogicalLink\0")?
+                .into_raw();
+            let destroy_cll_fn = *lib
+                .get::<PDUDestroyComLogicalLinkFn>(b"PDUDestroyComLogicalLink\0")?
+                .into_raw();
+            let connect_fn = *lib.get::<PDUConnectFn>(b"PDUConnect\0")?.into_raw();
+            let disconnect_fn = *lib.get::<PDUDisconnectFn>(b"PDUDisconnect\0")?.into_raw();
+            let set_com_param_fn = *lib.get::<PDUSetComParamFn>(b"PDUSetComParam\0")?.into_raw();
+            let set_urid_table_fn = *lib
+                .get::<PDUSetUniqueRespIdTableFn>(b"PDUSetUniqueRespIdTable\0")?
+                .into_raw();
+            let start_cop_fn = *lib
+                .get::<PDUStartComPrimitiveFn>(b"PDUStartComPrimitive\0")?
+                .into_raw();
+            let cancel_cop_fn = *lib
+                .get::<PDUCancelComPrimitiveFn>(b"PDUCancelComPrimitive\0")?
+                .into_raw();
+            let get_event_item_fn = *lib
+                .get::<PDUGetEventItemFn>(b"PDUGetEventItem\0")?
+                .into_raw();
+            let destroy_item_fn = *lib.get::<PDUDestroyItemFn>(b"PDUDestroyItem\0")?.into_raw();
+            let ioctl_fn = *lib.get::<PDUIoCtlFn>(b"PDUIoCtl\0")?.into_raw();
+            let get_version_fn = *lib.get::<PDUGetVersionFn>(b"PDUGetVersion\0")?.into_raw();
+
+            Ok(PduDrv {
+                lib: Arc::new(lib),
+                is_constructed: false,
+                construct_fn,
+                destruct_fn,
+                module_connect_fn,
+                module_disconnect_fn,
+                get_module_ids_fn,
+                get_object_id_fn,
+                create_cll_fn,
+                destroy_cll_fn,
+                connect_fn,
+                disconnect_fn,
+                set_com_param_fn,
+                set_urid_table_fn,
+                start_cop_fn,
+                cancel_cop_fn,
+                get_event_item_fn,
+                destroy_item_fn,
+                ioctl_fn,
+                get_version_fn,
+            })
+        }
+    }
+
+    //type PDUConstructFn = unsafe extern "stdcall" fn(option_str: *const c_char, api_tag: *mut c_void) -> u32;
+    pub fn construct(&mut self) -> Result<()> {
+        if self.is_constructed {
+            return Ok(());
+        }
+        let res = unsafe { (&self.construct_fn)(std::ptr::null(), std::ptr::null_mut()) };
+        if res == 0x00 {
+            self.is_constructed = true;
+        }
+        ret_res(res, ())
+    }
+
+    //type PDUDestructFn = unsafe extern "stdcall" fn() -> u32;
+    pub fn destruct(&mut self) -> Result<()> {
+        if !self.is_constructed {
+            return Ok(());
+        }
+        let res = unsafe { (&self.destruct_fn)() };
+        if res == 0x00 {
+            self.is_constructed = false;
+        }
+        ret_res(res, ())
+    }
+
+    //type PDUGetModuleIdsFn = unsafe extern "stdcall" fn(module_id_list: *mut *mut PDU_MODULE_ITEM) -> u32;
+    /// Returns a list of (Module type ID, Module handle, Module status) for every module the API can see
+    pub fn get_module_ids(&self) -> Result<Vec<(u32, u32, u32)>> {
+        let mut list: *mut PDU_MODULE_ITEM = std::ptr::null_mut();
+        let res = unsafe { (&self.get_module_ids_fn)(&mut list as *mut *mut PDU_MODULE_ITEM) };
+        if res != 0x00 || list.is_null() {
+            return ret_res(res, Vec::new());
+        }
+        let modules = unsafe {
+            let item = &*list;
+            let data = if item.p_module_data.is_null() {
+                &[]
+            } else {
+                std::slice::from_raw_parts(item.p_module_data, item.num_entries as usize)
+            };
+            data.iter()
+                .map(|m| (m.module_type_id, m.h_mod, m.module_status))
+                .collect()
+        };
+        self.destroy_item(list as *mut c_void);
+        Ok(modules)
+    }
+
+    //type PDUModuleConnectFn = unsafe extern "stdcall" fn(h_mod: u32) -> u32;
+    pub fn module_connect(&self, h_mod: u32) -> Result<()> {
+        ret_res(unsafe { (&self.module_connect_fn)(h_mod) }, ())
+    }
+
+    //type PDUModuleDisconnectFn = unsafe extern "stdcall" fn(h_mod: u32) -> u32;
+    pub fn module_disconnect(&self, h_mod: u32) -> Result<()> {
+        ret_res(unsafe { (&self.module_disconnect_fn)(h_mod) }, ())
+    }
+
+    //type PDUGetObjectIdFn = unsafe extern "stdcall" fn(object_type: u32, short_name: *const c_char, object_id: *mut u32) -> u32;
+    /// Looks up the ID of a protocol, bus type, pin type, ComParam or IOCTL from its short name
+    pub fn get_object_id(&self, object_type: u32, short_name: &str) -> Result<u32> {
+        let name = CString::new(short_name).map_err(|_| PduError::PDU_ERR_INVALID_PARAMETERS)?;
+        let mut id: u32 = PDU_ID_UNDEF;
+        let res =
+            unsafe { (&self.get_object_id_fn)(object_type, name.as_ptr(), &mut id as *mut u32) };
+        match ret_res(res, id)? {
+            PDU_ID_UNDEF => Err(PduError::PDU_ERR_ID_NOT_SUPPORTED),
+            x => Ok(x),
+        }
+    }
+
+    //type PDUCreateComLogicalLinkFn = unsafe extern "stdcall" fn(h_mod: u32, rsc_data: *mut PDU_RSC_DATA, resource_id: u32, cll_tag: *mut c_void, h_cll: *mut u32, cll_create_flag: *mut PDU_FLAG_DATA) -> u32;
+    /// Returns the ComLogicalLink handle
+    pub fn create_cll(&self, h_mod: u32, rsc_data: &mut PDU_RSC_DATA) -> Result<u32> {
+        let mut h_cll: u32 = PDU_HANDLE_UNDEF;
+        let res = unsafe {
+            (&self.create_cll_fn)(
+                h_mod,
+                rsc_data as *mut PDU_RSC_DATA,
+                PDU_ID_UNDEF,
+                std::ptr::null_mut(),
+                &mut h_cll as *mut u32,
+                std::ptr::null_mut(),
+            )
+        };
+        ret_res(res, h_cll)
+    }
+
+    //type PDUDestroyComLogicalLinkFn = unsafe extern "stdcall" fn(h_mod: u32, h_cll: u32) -> u32;
+    pub fn destroy_cll(&self, h_mod: u32, h_cll: u32) -> Result<()> {
+        ret_res(unsafe { (&self.destroy_cll_fn)(h_mod, h_cll) }, ())
+    }
+
+    //type PDUConnectFn = unsafe extern "stdcall" fn(h_mod: u32, h_cll: u32) -> u32;
+    pub fn connect(&self, h_mod: u32, h_cll: u32) -> Result<()> {
+        ret_res(unsafe { (&self.connect_fn)(h_mod, h_cll) }, ())
+    }
+
+    //type PDUDisconnectFn = unsafe extern "stdcall" fn(h_mod: u32, h_cll: u32) -> u32;
+    pub fn disconnect(&self, h_mod: u32, h_cll: u32) -> Result<()> {
+        ret_res(unsafe { (&self.disconnect_fn)(h_mod, h_cll) }, ())
+    }
+
+    //type PDUSetComParamFn = unsafe extern "stdcall" fn(h_mod: u32, h_cll: u32, param_item: *mut PDU_PARAM_ITEM) -> u32;
+    /// Writes a UNUM32 ComParam into the ComLogicalLink's working buffer. The value only becomes
+    /// active once a [PDU_COPT_UPDATEPARAM] ComPrimitive is run, or if the next ComPrimitive
+    /// requests a temporary parameter update
+    pub fn set_com_param(
+        &self,
+        h_mod: u32,
+        h_cll: u32,
+        param_id: u32,
+        class: u32,
+        mut value: u32,
+    ) -> Result<()> {
+        let mut item = PDU_PARAM_ITEM {
+            item_type: PDU_IT_PARAM,
+            com_param_id: param_id,
+            com_param_data_type: PDU_PT_UNUM32,
+            com_param_class: class,
+            p_com_param_data: &mut value as *mut u32 as *mut c_void,
+        };
+        ret_res(
+            unsafe { (&self.set_com_param_fn)(h_mod, h_cll, &mut item as *mut PDU_PARAM_ITEM) },
+            (),
+        )
+    }
+
+    //type PDUSetUniqueRespIdTableFn = unsafe extern "stdcall" fn(h_mod: u32, h_cll: u32, table: *mut PDU_UNIQUE_RESP_ID_TABLE_ITEM) -> u32;
+    /// Sets the unique response ID table of a ComLogicalLink.
+    ///
+    /// ## Params
+    /// * entries - List of (Unique response ID, list of (ComParam ID, value)) which describe each ECU
+    pub fn set_urid_table(
+        &self,
+        h_mod: u32,
+        h_cll: u32,
+        entries: &[(u32, Vec<(u32, u32)>)],
+    ) -> Result<()> {
+        // The values and param items must outlive the call, so keep them in their own vectors
+        let mut values: Vec<Vec<u32>> = entries
+            .iter()
+            .map(|(_, params)| params.iter().map(|(_, v)| *v).collect())
+            .collect();
+        let mut param_items: Vec<Vec<PDU_PARAM_ITEM>> = entries
+            .iter()
+            .zip(values.iter_mut())
+            .map(|((_, params), vals)| {
+                params
+                    .iter()
+                    .zip(vals.iter_mut())
+                    .map(|((id, _), v)| PDU_PARAM_ITEM {
+                        item_type: PDU_IT_PARAM,
+                        com_param_id: *id,
+                        com_param_data_type: PDU_PT_UNUM32,
+                        com_param_class: PDU_PC_UNIQUE_ID,
+                        p_com_param_data: v as *mut u32 as *mut c_void,
+                    })
+                    .collect()
+            })
+            .collect();
+        let mut ecu_data: Vec<PDU_ECU_UNIQUE_RESP_DATA> = entries
+            .iter()
+            .zip(param_items.iter_mut())
+            .map(|((urid, _), items)| PDU_ECU_UNIQUE_RESP_DATA {
+                unique_resp_identifier: *urid,
+                num_param_items: items.len() as u32,
+                p_params: items.as_mut_ptr(),
+            })
+            .collect();
+        let mut table = PDU_UNIQUE_RESP_ID_TABLE_ITEM {
+            item_type: PDU_IT_UNIQUE_RESP_ID_TABLE,
+            num_entries: ecu_data.len() as u32,
+            p_unique_data: ecu_data.as_mut_ptr(),
+        };
+        ret_res(
+            unsafe {
+                (&self.set_urid_table_fn)(
+                    h_mod,
+                    h_cll,
+                    &mut table as *mut PDU_UNIQUE_RESP_ID_TABLE_ITEM,
+                )
+            },
+            (),
+        )
+    }
+
+    //type PDUStartComPrimitiveFn = unsafe extern "stdcall" fn(h_mod: u32, h_cll: u32, cop_type: u32, cop_data_size: u32, cop_data: *mut u8, cop_ctrl_data: *mut PDU_COP_CTRL_DATA, cop_tag: *mut c_void, h_cop: *mut u32) -> u32;
+    /// Returns the ComPrimitive handle
+    pub fn start_com_primitive(
+        &self,
+        h_mod: u32,
+        h_cll: u32,
+        cop_type: u32,
+        data: &mut [u8],
+        ctrl: Option<&mut PDU_COP_CTRL_DATA>,
+    ) -> Result<u32> {
+        let mut h_cop: u32 = PDU_HANDLE_UNDEF;
+        let data_ptr = if data.is_empty() {
+            std::ptr::null_mut()
+        } else {
+            data.as_mut_ptr()
+        };
+        let ctrl_ptr = match ctrl {
+            Some(c) => c as *mut PDU_COP_CTRL_DATA,
+            None => std::ptr::null_mut(),
+        };
+        let res = unsafe {
+            (&self.start_cop_fn)(
+                h_mod,
+                h_cll,
+                cop_type,
+                data.len() as u32,
+                data_ptr,
+                ctrl_ptr,
+                std::ptr::null_mut(),
+                &mut h_cop as *mut u32,
+            )
+        };
+        ret_res(res, h_cop)
+    }
+
+    //type PDUCancelComPrimitiveFn = unsafe extern "stdcall" fn(h_mod: u32, h_cll: u32, h_cop: u32) -> u32;
+    pub fn cancel_com_primitive(&self, h_mod: u32, h_cll: u32, h_cop: u32) -> Result<()> {
+        ret_res(unsafe { (&self.cancel_cop_fn)(h_mod, h_cll, h_cop) }, ())
+    }
+
+    //type PDUGetEventItemFn = unsafe extern "stdcall" fn(h_mod: u32, h_cll: u32, event_item: *mut *mut PDU_EVENT_ITEM) -> u32;
+    /// Reads the next event from a ComLogicalLink's event queue. Returns None if the queue is empty
+    pub fn get_event_item(&self, h_mod: u32, h_cll: u32) -> Result<Option<PduEvent>> {
+        let mut item: *mut PDU_EVENT_ITEM = std::ptr::null_mut();
+        let res = unsafe {
+            (&self.get_event_item_fn)(h_mod, h_cll, &mut item as *mut *mut PDU_EVENT_ITEM)
+        };
+        if res == PduError::PDU_ERR_EVENT_QUEUE_EMPTY as u32 || (res == 0x00 && item.is_null()) {
+            return Ok(None);
+        }
+        ret_res(res, ())?;
+        let event = unsafe {
+            let e = &*item;
+            match e.item_type {
+                PDU_IT_RESULT if !e.p_data.is_null() => {
+                    let r = &*(e.p_data as *const PDU_RESULT_DATA);
+                    let data = if r.p_data_bytes.is_null() {
+                        Vec::new()
+                    } else {
+                        std::slice::from_raw_parts(r.p_data_bytes, r.num_data_bytes as usize)
+                            .to_vec()
+                    };
+                    PduEvent::Result {
+                        h_cop: e.h_cop,
+                        urid: r.unique_resp_identifier,
+                        data,
+                    }
+                }
+                PDU_IT_STATUS if !e.p_data.is_null() => PduEvent::Status {
+                    h_cop: e.h_cop,
+                    status: *(e.p_data as *const u32),
+                },
+                PDU_IT_ERROR if !e.p_data.is_null() => {
+                    let err = &*(e.p_data as *const PDU_ERROR_DATA);
+                    PduEvent::Error {
+                        h_cop: e.h_cop,
+                        code: err.error_code_id,
+                        extra: err.extra_error_info_id,
+                    }
+                }
+                x => PduEvent::Other { item_type: x },
+            }
+        };
+        self.destroy_item(item as *mut c_void);
+        Ok(Some(event))
+    }
+
+    //type PDUDestroyItemFn = unsafe extern "stdcall" fn(item: *mut c_void) -> u32;
+    fn destroy_item(&self, item: *mut c_void) {
+        if !item.is_null() {
+            unsafe { (&self.destroy_item_fn)(item) };
+        }
+    }
+
+    //type PDUIoCtlFn = unsafe extern "stdcall" fn(h_mod: u32, h_cll: u32, ioctl_id: u32, input: *mut PDU_DATA_ITEM, output: *mut *mut PDU_DATA_ITEM) -> u32;
+    /// Runs an IOCTL by name that takes no input. If the IOCTL returns a UNUM32, it is returned
+    pub fn ioctl(&self, h_mod: u32, h_cll: u32, name: &str) -> Result<Option<u32>> {
+        let ioctl_id = self.get_object_id(PDU_OBJT_IO_CTRL, name)?;
+        let mut output: *mut PDU_DATA_ITEM = std::ptr::null_mut();
+        let res = unsafe {
+            (&self.ioctl_fn)(
+                h_mod,
+                h_cll,
+                ioctl_id,
+                std::ptr::null_mut(),
+                &mut output as *mut *mut PDU_DATA_ITEM,
+            )
+        };
+        ret_res(res, ())?;
+        if output.is_null() {
+            return Ok(None);
+        }
+        let value = unsafe {
+            let o = &*output;
+            if o.item_type == PDU_IT_IO_UNUM32 && !o.p_data.is_null() {
+                Some(*(o.p_data as *const u32))
+            } else {
+                None
+            }
+        };
+        self.destroy_item(output as *mut c_void);
+        Ok(value)
+    }
+
+    //type PDUGetVersionFn = unsafe extern "stdcall" fn(h_mod: u32, version: *mut PDU_VERSION_DATA) -> u32;
+    pub fn get_version(&self, h_mod: u32) -> Result<PduVersion> {
+        let mut v: PDU_VERSION_DATA = unsafe { std::mem::zeroed() };
+        let res = unsafe { (&self.get_version_fn)(h_mod, &mut v as *mut PDU_VERSION_DATA) };
+        ret_res(res, ())?;
+        unsafe {
+            Ok(PduVersion {
+                api_version: format!(
+                    "{} {}",
+                    c_str_to_string(v.pdu_api_sw_name.as_ptr()),
+                    fmt_version(v.pdu_api_sw_version)
+                ),
+                fw_version: format!(
+                    "{} {}",
+                    c_str_to_string(v.fw_name.as_ptr()),
+                    fmt_version(v.fw_version)
+                ),
+            })
+        }
+    }
+}
+
+/// A VCI that can be used through a D-PDU API library
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PduDevice {
+    /// Driver path
+    pub drv_path: String,
+    /// Module description file path
+    pub mdf_path: String,
+    /// Cable description file path
+    pub cdf_path: String,
+
+    /// Name of the VCI
+    pub name: String,
+    /// Supplier of the D-PDU API
+    pub vendor: String,
+
+    /// Module type ID of the VCI as listed in the MDF. If None, the first
+    /// module the API finds is used
+    pub module_type_id: Option<u32>,
+    /// Short names of every protocol the VCI supports
+    pub protocols: Vec<String>,
+    /// Short names of every cable listed in the CDF
+    pub cables: Vec<String>,
+}
+
+impl PduDevice {
+    /// Returns true if the VCI supports a protocol whose short name contains `name`
+    pub fn supports_protocol(&self, name: &str) -> bool {
+        self.protocols.iter().any(|p| p.contains(name))
+    }
+
+    #[cfg(unix)]
+    /// Finds the D-PDU root description file. The system wide root file is in
+    /// /etc/pdu_api_root.xml, but ~/.pduapi/pdu_api_root.xml is also checked
+    fn find_root_file() -> DeviceError<String> {
+        let user = shellexpand::tilde("~/.pduapi/pdu_api_root.xml").to_string();
+        ["/etc/pdu_api_root.xml".to_string(), user]
+            .iter()
+            .find(|p| std::path::Path::new(p).exists())
+            .cloned()
+            .ok_or(LoadDeviceError::NoDeviceFound)
+    }
+
+    #[cfg(windows)]
+    /// Finds the D-PDU root description file from the registry
+    fn find_root_file() -> DeviceError<String> {
+        let hklm = RegKey::predef(HKEY_LOCAL_MACHINE);
+        let reg = match hklm
+            .open_subkey("SOFTWARE\\D-PDU API")
+            .or_else(|_| hklm.open_subkey("SOFTWARE\\WOW6432Node\\D-PDU API"))
+        {
+            Ok(r) => r,
+            Err(x) => return Err(LoadDeviceError::IoError(x.to_string())),
+        };
+        reg.get_value("Root File")
+            .map_err(|_| LoadDeviceError::NoDeviceFound)
+    }
+
+    /// Finds all VCIs listed by every D-PDU API in the root description file
+    pub fn find_all() -> DeviceError<Vec<PduDevice>> {
+        let root = PduDevice::find_root_file()?;
+        let xml =
+            std::fs::read_to_string(&root).map_err(|e| LoadDeviceError::IoError(e.to_string()))?;
+        let dev_list: Vec<PduDevice> = PduDevice::read_root_file(&xml)?
+            .into_iter()
+            // Any API whose MDF cannot be read is discarded
+            .filter_map(|(vendor, lib, mdf, cdf)| PduDevice::read_mdf(vendor, lib, mdf, cdf).ok())
+            .flatten()
+            .collect();
+        match dev_list.is_empty() {
+            true => Err(LoadDeviceError::NoDeviceFound),
+            false => Ok(dev_list),
+        }
+    }
+
+    /// Parses the root description file, returning (Supplier, Library path, MDF path, CDF path)
+    /// for each D-PDU API installed
+    fn read_root_file(xml: &str) -> DeviceError<Vec<(String, String, String, String)>> {
+        let doc = roxmltree::Document::parse(xml)
+            .map_err(|e| LoadDeviceError::IoError(format!("Malformed root file: {}", e)))?;
+        Ok(doc
+            .descendants()
+            .filter(|n| n.has_tag_name("MVCI_PDU_API"))
+            .filter_map(|api| {
+                let vendor = child_text(&api, "SUPPLIER_NAME")
+                    .or_else(|| child_text(&api, "SHORT_NAME"))
+                    .unwrap_or_default();
+                let lib = child_uri(&api, "LIBRARY_FILE")?;
+                let mdf = child_uri(&api, "MODULE_DESCRIPTION_FILE")?;
+                let cdf = child_uri(&api, "CABLE_DESCRIPTION_FILE").unwrap_or_default();
+                Some((vendor, lib, mdf, cdf))
+            })
+            .collect())
+    }
+
+    /// Reads a module description file (and its cable description file) into a list of VCIs
+    fn read_mdf(
+        vendor: String,
+        lib: String,
+        mdf: String,
+        cdf: String,
+    ) -> DeviceError<Vec<PduDevice>> {
+        let mdf_xml =
+            std::fs::read_to_string(&mdf).map_err(|e| LoadDeviceError::IoError(e.to_string()))?;
+        let doc = roxmltree::Document::parse(&mdf_xml)
+            .map_err(|e| LoadDeviceError::IoError(format!("Malformed MDF: {}", e)))?;
+
+        let protocols: Vec<String> = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("PROTOCOL"))
+            .filter_map(|n| child_text(&n, "SHORT_NAME"))
+            .collect();
+
+        // The CDF is optional, it only tells the user which cables can be used
+        let cables: Vec<String> = std::fs::read_to_string(&cdf)
+            .ok()
+            .and_then(|xml| {
+                roxmltree::Document::parse(&xml).ok().map(|d| {
+                    d.descendants()
+                        .filter(|n| n.has_tag_name("CABLE"))
+                        .filter_map(|n| child_text(&n, "SHORT_NAME"))
+                        .collect()
+                })
+            })
+            .unwrap_or_default();
+
+        let mut modules: Vec<PduDevice> = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("MODULETYPE") || n.has_tag_name("MODULE_TYPE"))
+            .filter_map(|n| {
+                let name = child_text(&n, "SHORT_NAME")?;
+                let id = n
+                    .attribute("ID")
+                    .map(|s| s.to_string())
+                    .or_else(|| child_text(&n, "ID"))
+                    .and_then(|s| s.trim().parse::<u32>().ok());
+                Some(PduDevice {
+                    drv_path: lib.clone(),
+                    mdf_path: mdf.clone(),
+                    cdf_path: cdf.clone(),
+                    name,
+                    vendor: vendor.clone(),
+                    module_type_id: id,
+                    protocols: protocols.clone(),
+                    cables: cables.clone(),
+                })
+            })
+            .collect();
+
+        if modules.is_empty() {
+            // MDF does not describe its module types, so just use whatever module the API finds
+            modules.push(PduDevice {
+                drv_path: lib,
+                mdf_path: mdf,
+                cdf_path: cdf,
+                name: vendor.clone(),
+                vendor,
+                module_type_id: None,
+                protocols,
+                cables,
+            })
+        }
+        Ok(modules)
+    }
+}
+
+/// Returns the trimmed text of the first child element with a given tag
+fn child_text(node: &roxmltree::Node, tag: &str) -> Option<String> {
+    node.children()
+        .find(|c| c.has_tag_name(tag))
+        .and_then(|c| c.text())
+        .map(|s| s.trim().to_string())
+}
+
+/// Returns the URI attribute of the first child element with a given tag, as a file path
+fn child_uri(node: &roxmltree::Node, tag: &str) -> Option<String> {
+    node.children()
+        .find(|c| c.has_tag_name(tag))
+        .and_then(|c| c.attribute("URI"))
+        .map(uri_to_path)
+}
+
+/// Converts a file:/// URI as found in D-PDU description files into a file path
+fn uri_to_path(uri: &str) -> String {
+    let path = uri.trim().trim_start_matches("file://");
+    // Windows paths are in the form file:///C:/...
+    if path.len() > 2 && path.as_bytes()[2] == b':' {
+        path[1..].to_string()
+    } else {
+        path.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT_FILE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MVCI_PDU_API_ROOT>
+    <MVCI_PDU_API>
+        <SHORT_NAME>VENDOR_A_API</SHORT_NAME>
+        <SUPPLIER_NAME> Vendor A </SUPPLIER_NAME>
+        <LIBRARY_FILE URI="file:///C:/Program Files/VendorA/pdu_api.dll"/>
+        <MODULE_DESCRIPTION_FILE URI="file:///C:/Program Files/VendorA/mdf.xml"/>
+        <CABLE_DESCRIPTION_FILE URI="file:///C:/Program Files/VendorA/cdf.xml"/>
+    </MVCI_PDU_API>
+    <MVCI_PDU_API>
+        <SHORT_NAME>VENDOR_B_API</SHORT_NAME>
+        <LIBRARY_FILE URI="file:///opt/vendorb/libpduapi.so"/>
+        <MODULE_DESCRIPTION_FILE URI="file:///opt/vendorb/mdf.xml"/>
+    </MVCI_PDU_API>
+    <MVCI_PDU_API>
+        <SHORT_NAME>NO_MDF_API</SHORT_NAME>
+        <LIBRARY_FILE URI="file:///opt/nomdf/libpduapi.so"/>
+    </MVCI_PDU_API>
+</MVCI_PDU_API_ROOT>"#;
+
+    const MDF: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MVCI_MODULE_DESCRIPTION>
+    <MODULETYPE ID="1">
+        <SHORT_NAME>VCI_USB</SHORT_NAME>
+    </MODULETYPE>
+    <MODULETYPE>
+        <ID> 2 </ID>
+        <SHORT_NAME>VCI_WLAN</SHORT_NAME>
+    </MODULETYPE>
+    <PROTOCOL ID="10">
+        <SHORT_NAME>ISO_15765_3_on_ISO_15765_2</SHORT_NAME>
+    </PROTOCOL>
+    <PROTOCOL ID="11">
+        <SHORT_NAME>ISO_14230_3_on_ISO_14230_2</SHORT_NAME>
+    </PROTOCOL>
+</MVCI_MODULE_DESCRIPTION>"#;
+
+    const MDF_NO_MODULES: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MVCI_MODULE_DESCRIPTION>
+    <PROTOCOL ID="10">
+        <SHORT_NAME>ISO_15765_3_on_ISO_15765_2</SHORT_NAME>
+    </PROTOCOL>
+</MVCI_MODULE_DESCRIPTION>"#;
+
+    const CDF: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MVCI_CABLE_DESCRIPTION>
+    <CABLE ID="1"><SHORT_NAME>OBD_J1962</SHORT_NAME></CABLE>
+    <CABLE ID="2"><SHORT_NAME>BMW_ICOM</SHORT_NAME></CABLE>
+</MVCI_CABLE_DESCRIPTION>"#;
+
+    /// Writes a fixture to the temp directory, returning its path
+    fn write_fixture(name: &str, xml: &str) -> String {
+        let path = std::env::temp_dir().join(format!("ovd_{}_{}.xml", name, std::process::id()));
+        std::fs::write(&path, xml).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn root_file() {
+        let apis = PduDevice::read_root_file(ROOT_FILE).unwrap();
+        // The API without an MDF cannot be used
+        assert_eq!(apis.len(), 2);
+        assert_eq!(
+            apis[0],
+            (
+                "Vendor A".to_string(),
+                "C:/Program Files/VendorA/pdu_api.dll".to_string(),
+                "C:/Program Files/VendorA/mdf.xml".to_string(),
+                "C:/Program Files/VendorA/cdf.xml".to_string()
+            )
+        );
+        // No supplier name, so the short name is used. The CDF is optional
+        assert_eq!(
+            apis[1],
+            (
+                "VENDOR_B_API".to_string(),
+                "/opt/vendorb/libpduapi.so".to_string(),
+                "/opt/vendorb/mdf.xml".to_string(),
+                String::new()
+            )
+        );
+    }
+
+    #[test]
+    fn malformed_root_file() {
+        assert!(
+            PduDevice::read_root_file("<MVCI_PDU_API_ROOT><MVCI_PDU_API></MVCI_PDU_API_ROOT>")
+                .is_err()
+        );
+        assert!(PduDevice::read_root_file("<MVCI_PDU_API_ROOT/>")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn mdf_with_modules() {
+        let mdf = write_fixture("mdf", MDF);
+        let cdf = write_fixture("cdf", CDF);
+        let devs = PduDevice::read_mdf("Vendor".into(), "pdu.so".into(), mdf.clone(), cdf.clone())
+            .unwrap();
+        std::fs::remove_file(&mdf).unwrap();
+        std::fs::remove_file(&cdf).unwrap();
+
+        assert_eq!(devs.len(), 2);
+        assert_eq!(devs[0].name, "VCI_USB");
+        assert_eq!(devs[0].module_type_id, Some(1));
+        assert_eq!(devs[1].name, "VCI_WLAN");
+        assert_eq!(devs[1].module_type_id, Some(2));
+        for d in &devs {
+            assert_eq!(d.vendor, "Vendor");
+            assert_eq!(d.drv_path, "pdu.so");
+            assert_eq!(d.cables, vec!["OBD_J1962", "BMW_ICOM"]);
+            assert!(d.supports_protocol("ISO_15765_3"));
+            assert!(d.supports_protocol("ISO_14230_3"));
+            assert!(!d.supports_protocol("SAE_J1850"));
+        }
+    }
+
+    #[test]
+    fn mdf_without_modules() {
+        let mdf = write_fixture("mdf_no_modules", MDF_NO_MODULES);
+        // A missing CDF only means no cables are listed
+        let devs = PduDevice::read_mdf(
+            "Vendor".into(),
+            "pdu.so".into(),
+            mdf.clone(),
+            "/nonexistent/cdf.xml".into(),
+        )
+        .unwrap();
+        std::fs::remove_file(&mdf).unwrap();
+
+        assert_eq!(devs.len(), 1);
+        assert_eq!(devs[0].name, "Vendor");
+        assert_eq!(devs[0].module_type_id, None);
+        assert!(devs[0].cables.is_empty());
+        assert_eq!(devs[0].protocols, vec!["ISO_15765_3_on_ISO_15765_2"]);
+    }
+
+    #[test]
+    fn bad_mdf() {
+        let missing = PduDevice::read_mdf(
+            "Vendor".into(),
+            "pdu.so".into(),
+            "/nonexistent/mdf.xml".into(),
+            String::new(),
+        );
+        assert!(missing.is_err());
+
+        let mdf = write_fixture(
+            "mdf_malformed",
+            "<MVCI_MODULE_DESCRIPTION><MODULETYPE></MVCI_MODULE_DESCRIPTION>",
+        );
+        let malformed =
+            PduDevice::read_mdf("Vendor".into(), "pdu.so".into(), mdf.clone(), String::new());
+        std::fs::remove_file(&mdf).unwrap();
+        assert!(malformed.is_err());
+    }
+
+    #[test]
+    fn uri_paths() {
+        assert_eq!(uri_to_path("file:///C:/VCI/mdf.xml"), "C:/VCI/mdf.xml");
+        assert_eq!(uri_to_path(" file:///usr/lib/mdf.xml "), "/usr/lib/mdf.xml");
+        assert_eq!(uri_to_path("/usr/lib/mdf.xml"), "/usr/lib/mdf.xml");
+    }
+}
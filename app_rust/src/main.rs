@@ -5,6 +5,7 @@ use dialog::DialogBox;
 use iced::{Application, Settings};
 mod cli_tests;
 mod commapi;
+mod dpdu;
 mod passthru;
 mod themes;
 mod widgets;
@@ -3,7 +3,9 @@ use crate::windows::window::WindowMessage;
 use crate::{
     commapi::{
         comm_api::{ComServer, FilterType},
-        iface::{CanbusInterface, Interface, InterfaceConfig, InterfacePayload, IFACE_CFG},
+        iface::{
+            CanbusInterface, Interface, InterfaceConfig, InterfacePayload, PayloadFlag, IFACE_CFG,
+        },
     },
     themes::{checkbox, picklist, text, TextType},
 };
@@ -272,7 +274,15 @@ impl<'a> CanTracer {
             let mut container = Row::new();
             container = container.push(
                 Row::new()
-                    .push(Text::new(format!("CID: {:04X}", i.id)))
+                    .push(Text::new(format!(
+                        "CID: {:04X}{}",
+                        i.id,
+                        if i.is_flag_set(PayloadFlag::CAN_FD) {
+                            " (FD)"
+                        } else {
+                            ""
+                        }
+                    )))
                     .width(Length::Units(200)),
             );
             if let Some(old_frame) = old_data.get(&cid) {
@@ -280,7 +290,7 @@ impl<'a> CanTracer {
                 let old_data = &old_frame.data;
                 for (i, byte) in i.data.iter().enumerate() {
                     container =
-                        if *byte == old_data[i] {
+                        if Some(byte) == old_data.get(i) {
                             // Same as old data
                             match binary {
                                 true => container
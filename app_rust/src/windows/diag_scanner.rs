@@ -346,6 +346,7 @@ impl DiagScanner {
                     send_id: ecu.send_id,
                     recv_id: ecu.recv_id,
                     global_id: None,
+                    timing: Default::default(),
                 };
 
                 let mut ecu_res = ECUDiagSettings {
@@ -399,6 +400,7 @@ impl DiagScanner {
                     send_id: ecu.send_id,
                     recv_id: ecu.recv_id,
                     global_id: None,
+                    timing: Default::default(),
                 };
 
                 // Interrogate the ECU with extended diagnostic session
@@ -1,11 +1,20 @@
 use crate::commapi::{
-    iface::{InterfaceConfig, InterfaceType, PayloadFlag, IFACE_CFG},
-    protocols::{kwp2000::read_ecu_identification, DiagCfg},
+    iface::{InterfaceConfig, InterfaceType, KLineInitMode, PayloadFlag, IFACE_CFG},
+    protocols::{
+        actuation,
+        adjustment::{self, AdjustmentLog, AdjustmentLogEntry},
+        executor::RequestHandle,
+        kwp2000::read_ecu_identification,
+        routine::{self, RoutineRun, RoutineState},
+        timing::DiagTiming,
+        uds::read_data,
+        DiagCfg,
+    },
 };
 use common::schema::{
     diag::{dtc::ECUDTC, service::Service},
     variant::{ECUVariantDefinition, ECUVariantPattern},
-    ConType, Connection, OvdECU,
+    ConType, Connection, LinWakeUpType, OvdECU,
 };
 use core::panic;
 use iced::{time, Align, Column, Length, Row, Subscription};
@@ -14,11 +23,11 @@ use std::{cell::RefCell, time::Instant, vec};
 use crate::{
     commapi::{
         comm_api::ComServer,
-        protocols::{DTCState, DiagProtocol, DiagServer, ProtocolResult},
+        protocols::{DiagProtocol, DiagServer, ProtocolResult, DTC},
     },
     themes::{
-        button_coloured, button_outlined, picklist, text, text_input, title_text, ButtonType,
-        TextType,
+        button_coloured, button_outlined, picklist, progress_bar, text, text_input, title_text,
+        ButtonType, TextType,
     },
     widgets::table::{Table, TableMsg},
 };
@@ -40,6 +49,16 @@ pub enum JsonDiagSessionMsg {
     ClearErrors,
     ReadInfo,
     ExecuteService(ServiceRef, Vec<u8>),
+    ReadAdjustment(ServiceRef),
+    Adjust(ServiceRef, Vec<String>),
+    UndoAdjustment,
+    Actuate(ServiceRef, Vec<String>),
+    ReleaseActuation(ServiceRef),
+    ReleaseAllActuations,
+    StartRoutine(ServiceRef, Vec<String>),
+    PollRoutine(Instant),
+    StopRoutine,
+    PollTask(Instant),
     ClearLogs,
     Selector(SelectorMsg),
     LoopRead(Instant),
@@ -53,6 +72,27 @@ impl From<SelectorMsg> for JsonDiagSessionMsg {
     }
 }
 
+impl JsonDiagSessionMsg {
+    /// Returns true if the message sends requests to the ECU
+    fn needs_ecu(&self) -> bool {
+        matches!(
+            self,
+            Self::ReadErrors
+                | Self::ClearErrors
+                | Self::ReadInfo
+                | Self::ExecuteService(..)
+                | Self::ReadAdjustment(_)
+                | Self::Adjust(..)
+                | Self::UndoAdjustment
+                | Self::Actuate(..)
+                | Self::ReleaseActuation(_)
+                | Self::ReleaseAllActuations
+                | Self::StartRoutine(..)
+                | Self::StopRoutine
+        )
+    }
+}
+
 impl DiagMessageTrait for JsonDiagSessionMsg {
     fn is_back(&self) -> bool {
         match self {
@@ -67,9 +107,25 @@ pub struct DisplayableDTC {
     code: String, // DTC Itself
     summary: String,
     desc: String,
-    state: DTCState,
-    mil_on: bool,
-    envs: Vec<(String, String)>, // Key, Value
+    dtc: DTC,
+}
+
+/// Result of a task run in the background, along with what is needed to display it
+enum TaskResult {
+    ReadInfo(Vec<Vec<String>>),
+    ReadErrors(ProtocolResult<Vec<DisplayableDTC>>),
+    ClearErrors(ProtocolResult<()>),
+    ExecuteService(ServiceRef, ProtocolResult<Vec<u8>>),
+    ReadAdjustment(Service, ProtocolResult<Vec<u8>>),
+    /// The log is returned even if the adjustment failed, as it may have been written
+    Adjust(Service, AdjustmentLog, ProtocolResult<Vec<u8>>),
+    UndoAdjustment(AdjustmentLog, ProtocolResult<AdjustmentLogEntry>),
+    Actuate(Service, ProtocolResult<Vec<u8>>),
+    ReleaseActuation(Service, ProtocolResult<()>),
+    ReleaseAllActuations(ProtocolResult<()>),
+    StartRoutine(Service, ProtocolResult<RoutineRun>),
+    PollRoutine(Service, ProtocolResult<RoutineState>),
+    StopRoutine(Service, ProtocolResult<Vec<u8>>),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Copy)]
@@ -92,10 +148,16 @@ pub struct JsonDiagSession {
     service_selector: ServiceSelector,
     looping_text: String,
     looping_service: Option<ServiceRef>, // Allow only read-only services to be loop read
+    loop_request: Option<RequestHandle<Vec<u8>>>, // Loop read waiting for the ECU to respond
     logged_dtcs: Vec<DisplayableDTC>,    // DTCs stored on ECU,
+    adjustment_log: AdjustmentLog,       // Adjustments that can be undone
+    running_routine: Option<RoutineRun>, // Routine being polled for its results
+    pending_task: Option<RequestHandle<TaskResult>>, // Task waiting for the ECU to respond
     btn1: iced::button::State,
     btn2: iced::button::State,
     btn3: iced::button::State,
+    btn4: iced::button::State,
+    btn5: iced::button::State,
     page_state: TargetPage,
     scroll_state1: iced::scrollable::State,
     scroll_state2: iced::scrollable::State,
@@ -114,8 +176,17 @@ impl JsonDiagSession {
         };
         println!("Detect. ECU uses {:?}", diag_server_type);
 
-        // TODO K-Line KWP2000
-        // For now, Diag server ONLY supports ISO-TP, not LIN!
+        let diag_cfg = DiagCfg {
+            send_id: connection_settings.send_id,
+            recv_id: connection_settings.recv_id,
+            global_id: connection_settings.global_send_id,
+            timing: DiagTiming::new(
+                connection_settings.p2_timeout,
+                connection_settings.p2_ext_timeout,
+                connection_settings.s3_timer,
+            ),
+        };
+
         let create_server = match connection_settings.connection_type {
             ConType::ISOTP {
                 blocksize,
@@ -130,12 +201,6 @@ impl JsonDiagSession {
                 cfg.add_param(IFACE_CFG::ISOTP_BS, blocksize);
                 cfg.add_param(IFACE_CFG::ISOTP_ST_MIN, st_min);
 
-                let diag_cfg = DiagCfg {
-                    send_id: connection_settings.send_id,
-                    recv_id: connection_settings.recv_id,
-                    global_id: connection_settings.global_send_id,
-                };
-
                 let tx_flags = vec![PayloadFlag::ISOTP_PAD_FRAME];
                 DiagServer::new(
                     diag_server_type,
@@ -146,10 +211,31 @@ impl JsonDiagSession {
                     diag_cfg,
                 )
             }
-            ConType::LIN { .. } => {
-                return Err(SessionError::Other(
-                    "K-Line is not implemented at this time".into(),
-                ))
+            ConType::LIN {
+                max_segment_size,
+                wake_up_method,
+            } => {
+                let init_mode = match wake_up_method {
+                    LinWakeUpType::FiveBaudInit => KLineInitMode::FiveBaud,
+                    LinWakeUpType::FastInit => KLineInitMode::FastInit,
+                };
+                let mut cfg = InterfaceConfig::new();
+                cfg.add_param(IFACE_CFG::BAUDRATE, connection_settings.baud);
+                cfg.add_param(IFACE_CFG::KLINE_INIT_MODE, init_mode as u32);
+                cfg.add_param(IFACE_CFG::KLINE_TARGET_ADDR, connection_settings.send_id);
+                cfg.add_param(IFACE_CFG::KLINE_MAX_SEGMENT_SIZE, max_segment_size);
+                if let Some(id) = connection_settings.global_send_id {
+                    cfg.add_param(IFACE_CFG::KLINE_FUNC_ADDR, id);
+                }
+
+                DiagServer::new(
+                    diag_server_type,
+                    &comm_server,
+                    InterfaceType::Iso14230,
+                    cfg,
+                    None,
+                    diag_cfg,
+                )
             }
         };
 
@@ -193,8 +279,27 @@ impl JsonDiagSession {
                     })
                     .collect();
 
-                let write_functions: Vec<ServiceRef> = Vec::new();
-                let actuation_functions: Vec<ServiceRef> = Vec::new();
+                let write_functions: Vec<ServiceRef> = ecu_varient
+                    .adjustments
+                    .iter()
+                    .map(|s| ServiceRef {
+                        inner: RefCell::new(s.clone()),
+                    })
+                    .collect();
+                let actuation_functions: Vec<ServiceRef> = ecu_varient
+                    .actuations
+                    .iter()
+                    .map(|s| ServiceRef {
+                        inner: RefCell::new(s.clone()),
+                    })
+                    .collect();
+                let routine_functions: Vec<ServiceRef> = ecu_varient
+                    .routines
+                    .iter()
+                    .map(|s| ServiceRef {
+                        inner: RefCell::new(s.clone()),
+                    })
+                    .collect();
 
                 Ok(Self {
                     unknown_variant,
@@ -207,14 +312,21 @@ impl JsonDiagSession {
                         read_functions,
                         write_functions,
                         actuation_functions,
+                        routine_functions,
                     ),
                     log_view: LogView::new(),
                     btn1: iced::button::State::default(),
                     btn2: iced::button::State::default(),
                     btn3: iced::button::State::default(),
+                    btn4: iced::button::State::default(),
+                    btn5: iced::button::State::default(),
                     looping_service: None,
+                    loop_request: None,
                     looping_text: String::new(),
                     logged_dtcs: Vec::new(),
+                    adjustment_log: AdjustmentLog::default(),
+                    running_routine: None,
+                    pending_task: None,
                     page_state: TargetPage::Main,
                     scroll_state1: iced::scrollable::State::default(),
                     scroll_state2: iced::scrollable::State::default(),
@@ -230,6 +342,238 @@ impl JsonDiagSession {
 }
 
 impl JsonDiagSession {
+    /// Cancels the loop read waiting for the ECU, if there is one
+    fn cancel_loop_read(&mut self) {
+        if let Some(req) = self.loop_request.take() {
+            req.cancel()
+        }
+    }
+
+    /// Runs `f` in the background. Its result is handled by [JsonDiagSession::on_task_complete]
+    /// once the ECU has responded
+    fn run_task<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut DiagServer) -> TaskResult + Send + 'static,
+    {
+        self.pending_task = Some(self.server.spawn_task(move |server| Ok(f(server))));
+    }
+
+    fn on_task_complete(&mut self, res: TaskResult) -> Option<JsonDiagSessionMsg> {
+        match res {
+            TaskResult::ReadInfo(ecu_params) => {
+                let header: Vec<String> = vec!["".into(), "".into()];
+                let mut params: Vec<Vec<String>> = Vec::new();
+                params.push(vec!["Name".into(), self.ecu_text.0.clone()]);
+                params.push(vec!["Description".into(), self.ecu_text.1.clone()]);
+                params.push(vec!["Software".into(), self.ecu_data.name.clone()]);
+                params.push(vec!["Manufacture".into(), self.pattern.vendor.clone()]);
+                params.extend(ecu_params);
+
+                self.tables[INFO_TABLE_ID] = Table::new(header, params, vec![400, 400], false, 900);
+                return Some(JsonDiagSessionMsg::Navigate(TargetPage::ECUInfo));
+            }
+            TaskResult::ReadErrors(res) => match res {
+                Ok(dtcs) => {
+                    self.logged_dtcs = dtcs;
+                    let entries: Vec<Vec<String>> = self
+                        .logged_dtcs
+                        .iter()
+                        .map(|dtc| {
+                            vec![
+                                dtc.code.clone(),
+                                dtc.desc.clone(),
+                                format!("{:?}", dtc.dtc.state),
+                                if dtc.dtc.check_engine_on {
+                                    "YES".into()
+                                } else {
+                                    "NO ".into()
+                                },
+                            ]
+                        })
+                        .collect();
+
+                    let table = Table::new(
+                        vec![
+                            "Error".into(),
+                            "Description".into(),
+                            "State".into(),
+                            "MIL on".into(),
+                        ],
+                        entries,
+                        vec![200, 600, 150, 100],
+                        true,
+                        400,
+                    );
+                    self.tables[TABLE_DTC] = table;
+                    if self.page_state == TargetPage::Main {
+                        // Goto DTC View!
+                        return Some(JsonDiagSessionMsg::Navigate(TargetPage::Error));
+                    }
+                }
+                Err(e) => {
+                    self.log_view.add_msg(
+                        format!("Error reading ECU Errors: {}", e.get_text()),
+                        LogType::Error,
+                    );
+                    self.logged_dtcs.clear();
+                }
+            },
+            TaskResult::ClearErrors(res) => match res {
+                Ok(_) => {
+                    self.log_view.add_msg("Clear ECU Errors OK!", LogType::Info);
+                    self.logged_dtcs.clear();
+                }
+                Err(e) => self.log_view.add_msg(
+                    format!("Error clearing ECU Errors: {}", e.get_text()),
+                    LogType::Error,
+                ),
+            },
+            TaskResult::ExecuteService(s, res) => match res {
+                Ok(res) => self.log_view.add_log(
+                    format!(
+                        "{} ({}):",
+                        s.inner.borrow().name,
+                        s.inner.borrow().description
+                    ),
+                    s.args_to_string(&res),
+                    LogType::Info,
+                ),
+                Err(e) => self.log_view.add_msg(
+                    format!("Error executing {}: {:?}", s.inner.borrow().name, e).as_str(),
+                    LogType::Error,
+                ),
+            },
+            TaskResult::ReadAdjustment(service, res) => match res {
+                Ok(res) => {
+                    let values = adjustment::decode_values(&service, &res);
+                    self.log_view.add_log(
+                        format!("{} ({}) current value:", service.name, service.description),
+                        values
+                            .iter()
+                            .map(|(name, v)| format!("{}: {}", name, v))
+                            .collect::<Vec<String>>()
+                            .join("\n"),
+                        LogType::Info,
+                    );
+                    self.service_selector
+                        .set_inputs(values.into_iter().map(|(_, v)| v).collect());
+                }
+                Err(e) => self.log_view.add_msg(
+                    format!("Error reading {}: {}", service.name, e.get_text()),
+                    LogType::Error,
+                ),
+            },
+            TaskResult::Adjust(service, log, res) => {
+                self.adjustment_log = log;
+                match res {
+                    Ok(res) => self.log_view.add_log(
+                        format!("{} ({}) written:", service.name, service.description),
+                        format!("{:02X?}", res),
+                        LogType::Info,
+                    ),
+                    Err(e) => self.log_view.add_msg(
+                        format!("Error writing {}: {}", service.name, e.get_text()),
+                        LogType::Error,
+                    ),
+                }
+            }
+            TaskResult::UndoAdjustment(log, res) => {
+                self.adjustment_log = log;
+                match res {
+                    Ok(entry) => self.log_view.add_log(
+                        format!("Undo {}:", entry.service.name),
+                        format!("Restored {:02X?}", entry.original),
+                        LogType::Info,
+                    ),
+                    Err(e) => self.log_view.add_msg(
+                        format!("Error undoing adjustment: {}", e.get_text()),
+                        LogType::Error,
+                    ),
+                }
+            }
+            TaskResult::Actuate(service, res) => match res {
+                Ok(res) => self.log_view.add_log(
+                    format!("{} ({}):", service.name, service.description),
+                    format!("Actuating. Control status {:02X?}", res),
+                    LogType::Warn,
+                ),
+                Err(e) => self.log_view.add_msg(
+                    format!("Error actuating {}: {}", service.name, e.get_text()),
+                    LogType::Error,
+                ),
+            },
+            TaskResult::ReleaseActuation(service, res) => match res {
+                Ok(_) => self.log_view.add_msg(
+                    format!("{} returned to ECU control", service.name),
+                    LogType::Info,
+                ),
+                Err(e) => self.log_view.add_msg(
+                    format!("Error releasing {}: {}", service.name, e.get_text()),
+                    LogType::Error,
+                ),
+            },
+            TaskResult::ReleaseAllActuations(res) => match res {
+                Ok(_) => self
+                    .log_view
+                    .add_msg("All actuations returned to ECU control", LogType::Info),
+                Err(e) => self.log_view.add_msg(
+                    format!("Error releasing actuations: {}", e.get_text()),
+                    LogType::Error,
+                ),
+            },
+            TaskResult::StartRoutine(service, res) => match res {
+                Ok(run) => {
+                    self.log_view.add_msg(
+                        format!("{} ({}) started", service.name, service.description),
+                        LogType::Info,
+                    );
+                    self.running_routine = Some(run);
+                }
+                Err(e) => self.log_view.add_msg(
+                    format!("Error starting {}: {}", service.name, e.get_text()),
+                    LogType::Error,
+                ),
+            },
+            TaskResult::PollRoutine(service, res) => {
+                if self.running_routine.is_none() {
+                    return None; // Stopped whilst polling
+                }
+                match res {
+                    Ok(RoutineState::Running) => {}
+                    Ok(RoutineState::Complete(res)) => {
+                        self.log_view.add_log(
+                            format!("{} ({}) complete:", service.name, service.description),
+                            routine::decode_results(&service, &res)
+                                .iter()
+                                .map(|(name, v)| format!("{}: {}", name, v))
+                                .collect::<Vec<String>>()
+                                .join("\n"),
+                            LogType::Info,
+                        );
+                        self.running_routine = None;
+                    }
+                    Err(e) => {
+                        self.log_view.add_msg(
+                            format!("Error running {}: {}", service.name, e.get_text()),
+                            LogType::Error,
+                        );
+                        self.running_routine = None;
+                    }
+                }
+            }
+            TaskResult::StopRoutine(service, res) => match res {
+                Ok(_) => self
+                    .log_view
+                    .add_msg(format!("{} stopped", service.name), LogType::Warn),
+                Err(e) => self.log_view.add_msg(
+                    format!("Error stopping {}: {}", service.name, e.get_text()),
+                    LogType::Error,
+                ),
+            },
+        }
+        None
+    }
+
     pub fn draw_main_ui(&mut self) -> iced::Element<JsonDiagSessionMsg> {
         let mut btn_view = Column::new()
             .push(
@@ -250,6 +594,46 @@ impl JsonDiagSession {
         if self.looping_service.is_some() {
             btn_view = btn_view.push(text(&self.looping_text, TextType::Normal).size(14));
         }
+        if self.pending_task.is_some() {
+            btn_view = btn_view.push(text("Waiting for the ECU...", TextType::Disabled));
+        }
+        if let Some(last) = self.adjustment_log.get_entries().last() {
+            btn_view = btn_view.push(
+                button_outlined(
+                    &mut self.btn3,
+                    format!("Undo {}", last.service.name).as_str(),
+                    ButtonType::Warning,
+                )
+                .on_press(JsonDiagSessionMsg::UndoAdjustment),
+            );
+        }
+        let active_actuations = actuation::get_active_count(&self.server);
+        if active_actuations > 0 {
+            btn_view = btn_view.push(
+                button_coloured(
+                    &mut self.btn4,
+                    format!("Release {} active actuation(s)", active_actuations).as_str(),
+                    ButtonType::Danger,
+                )
+                .on_press(JsonDiagSessionMsg::ReleaseAllActuations),
+            );
+        }
+        if let Some(run) = &self.running_routine {
+            btn_view = btn_view
+                .push(text(
+                    format!("Running {}...", run.get_service().name).as_str(),
+                    TextType::Normal,
+                ))
+                .push(progress_bar(
+                    0f32..=100f32,
+                    run.get_progress(),
+                    ButtonType::Info,
+                ))
+                .push(
+                    button_outlined(&mut self.btn5, "Stop routine", ButtonType::Danger)
+                        .on_press(JsonDiagSessionMsg::StopRoutine),
+                );
+        }
         Column::new()
             .align_items(Align::Center)
             .spacing(8)
@@ -399,16 +783,19 @@ impl SessionTrait for JsonDiagSession {
 
     fn update(&mut self, msg: &Self::msg) -> Option<Self::msg> {
         //self.log_view.clear_logs();
+        if self.pending_task.is_some() && msg.needs_ecu() {
+            // Only one task is run at a time, so the ECU is never sent requests out of order
+            self.log_view.add_msg(
+                "Still waiting for the ECU to respond to the last request",
+                LogType::Warn,
+            );
+            return None;
+        }
         match msg {
             JsonDiagSessionMsg::Navigate(target) => self.page_state = *target,
-            JsonDiagSessionMsg::ReadInfo => {
-                let header: Vec<String> = vec!["".into(), "".into()];
+            JsonDiagSessionMsg::ReadInfo => self.run_task(|server| {
                 let mut params: Vec<Vec<String>> = Vec::new();
-                params.push(vec!["Name".into(), self.ecu_text.0.clone()]);
-                params.push(vec!["Description".into(), self.ecu_text.1.clone()]);
-                params.push(vec!["Software".into(), self.ecu_data.name.clone()]);
-                params.push(vec!["Manufacture".into(), self.pattern.vendor.clone()]);
-                if let Some(kwp) = self.server.into_kwp() {
+                if let Some(kwp) = server.into_kwp() {
                     if let Ok(res) = read_ecu_identification::read_dcx_mmc_id(kwp) {
                         params.push(vec!["Part number".into(), res.part_number.clone()]);
                         params.push(vec![
@@ -444,111 +831,59 @@ impl SessionTrait for JsonDiagSession {
                         params.push(vec!["Production date (DD/MM/YY)".into(), "Unknown".into()]);
                     }
                 }
-
-                self.tables[INFO_TABLE_ID] = Table::new(header, params, vec![400, 400], false, 900);
-                return Some(JsonDiagSessionMsg::Navigate(TargetPage::ECUInfo));
-            }
-            JsonDiagSessionMsg::ReadErrors => match self.server.read_errors() {
-                Ok(res) => {
-                    let dtc_list = self.ecu_data.errors.clone();
-                    self.logged_dtcs = res
-                        .iter()
-                        .map(|raw_dtc| {
-                            let ecu_dtc = dtc_list
-                                .clone()
-                                .into_iter()
-                                .find(|x| x.error_name.ends_with(&raw_dtc.error))
-                                .unwrap_or(ECUDTC {
-                                    error_name: raw_dtc.error.clone(),
-                                    summary: "UNKNOWN ERROR".into(),
-                                    description: "UNKNOWN DTC".into(),
-                                    envs: Vec::new(),
-                                });
-                            let mut res = DisplayableDTC {
-                                code: ecu_dtc.error_name.clone(),
-                                summary: ecu_dtc.summary.clone(),
-                                desc: ecu_dtc.description.clone(),
-                                state: raw_dtc.state,
-                                mil_on: raw_dtc.check_engine_on,
-                                envs: Vec::new(),
-                            };
-
-                            if ecu_dtc.envs.len() > 0 {
-                                // If parsable freeze frame data exists, then read it
-                                if let Ok(args) = self.server.get_dtc_env_data(raw_dtc) {
-                                    for e in &ecu_dtc.envs {
-                                        match e.decode_value_to_string(&args) {
-                                            Ok(s) => res.envs.push((e.name.clone(), s)),
-                                            Err(err) => {
-                                                eprintln!(
-                                                    "Warning could not decode param: {:?}",
-                                                    err
-                                                )
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            res
-                        })
-                        .collect();
-                    let entries: Vec<Vec<String>> = self
-                        .logged_dtcs
-                        .iter()
-                        .map(|dtc| {
-                            vec![
-                                dtc.code.clone(),
-                                dtc.desc.clone(),
-                                format!("{:?}", dtc.state),
-                                if dtc.mil_on {
-                                    "YES".into()
-                                } else {
-                                    "NO ".into()
-                                },
-                            ]
-                        })
-                        .collect();
-
-                    let table = Table::new(
-                        vec![
-                            "Error".into(),
-                            "Description".into(),
-                            "State".into(),
-                            "MIL on".into(),
-                        ],
-                        entries,
-                        vec![200, 600, 150, 100],
-                        true,
-                        400,
-                    );
-                    self.tables[TABLE_DTC] = table;
-                    if self.page_state == TargetPage::Main {
-                        // Goto DTC View!
-                        return Some(JsonDiagSessionMsg::Navigate(TargetPage::Error));
+                if let Some(uds) = server.into_uds() {
+                    for (name, value) in read_data::read_ecu_identification(uds) {
+                        params.push(vec![name, value]);
                     }
                 }
-                Err(e) => {
-                    self.log_view.add_msg(
-                        format!("Error reading ECU Errors: {}", e.get_text()),
-                        LogType::Error,
-                    );
-                    self.logged_dtcs.clear();
-                }
-            },
-            JsonDiagSessionMsg::ClearErrors => match self.server.clear_errors() {
-                Ok(_) => {
-                    self.log_view.add_msg("Clear ECU Errors OK!", LogType::Info);
-                    self.logged_dtcs.clear();
-                }
-                Err(e) => self.log_view.add_msg(
-                    format!("Error clearing ECU Errors: {}", e.get_text()),
-                    LogType::Error,
-                ),
-            },
+                TaskResult::ReadInfo(params)
+            }),
+            JsonDiagSessionMsg::ReadErrors => {
+                let dtc_list = self.ecu_data.errors.clone();
+                self.run_task(move |server| {
+                    let res = server.read_errors().map(|res| {
+                        res.iter()
+                            .map(|raw_dtc| {
+                                let ecu_dtc = dtc_list
+                                    .clone()
+                                    .into_iter()
+                                    .find(|x| {
+                                        x.error_name == raw_dtc.error
+                                            || x.error_name.ends_with(&raw_dtc.get_raw_code())
+                                    })
+                                    .unwrap_or(ECUDTC {
+                                        error_name: raw_dtc.error.clone(),
+                                        summary: "UNKNOWN ERROR".into(),
+                                        description: "UNKNOWN DTC".into(),
+                                        envs: Vec::new(),
+                                    });
+                                // Read freeze frame data, severity and counters if the ECU has them
+                                let dtc = server
+                                    .get_dtc_env_data(raw_dtc, &ecu_dtc.envs)
+                                    .unwrap_or_else(|_| raw_dtc.clone());
+                                DisplayableDTC {
+                                    code: ecu_dtc.error_name.clone(),
+                                    summary: ecu_dtc.summary.clone(),
+                                    desc: ecu_dtc.description.clone(),
+                                    dtc,
+                                }
+                            })
+                            .collect()
+                    });
+                    TaskResult::ReadErrors(res)
+                })
+            }
+            JsonDiagSessionMsg::ClearErrors => {
+                self.run_task(|server| TaskResult::ClearErrors(server.clear_errors()))
+            }
 
             JsonDiagSessionMsg::Selector(s) => match s {
-                SelectorMsg::PickLoopService(l) => self.looping_service = Some(l.clone()),
+                SelectorMsg::PickLoopService(l) => {
+                    self.cancel_loop_read();
+                    self.looping_service = Some(l.clone())
+                }
                 SelectorMsg::StopLoopService => {
+                    self.cancel_loop_read();
                     self.looping_service = None;
                     return self.service_selector.update(s);
                 }
@@ -557,33 +892,124 @@ impl SessionTrait for JsonDiagSession {
 
             JsonDiagSessionMsg::ExecuteService(s, args) => {
                 println!("Exec {}", s.inner.borrow().name);
-                match s.exec(args, &mut self.server) {
-                    Ok(res) => self.log_view.add_log(
-                        format!(
-                            "{} ({}):",
-                            s.inner.borrow().name,
-                            s.inner.borrow().description
-                        ),
-                        s.args_to_string(&res),
-                        LogType::Info,
-                    ),
-                    Err(e) => self.log_view.add_msg(
-                        format!("Error executing {}: {:?}", s.inner.borrow().name, e).as_str(),
-                        LogType::Error,
-                    ),
+                let (s, args) = (s.clone(), args.clone());
+                self.run_task(move |server| {
+                    let res = s.exec(&args, server);
+                    TaskResult::ExecuteService(s, res)
+                })
+            }
+            JsonDiagSessionMsg::ReadAdjustment(s) => {
+                let service = s.inner.borrow().clone();
+                self.run_task(move |server| {
+                    let res = adjustment::read_current_value(server, &service);
+                    TaskResult::ReadAdjustment(service, res)
+                })
+            }
+            JsonDiagSessionMsg::Adjust(s, inputs) => {
+                let service = s.inner.borrow().clone();
+                let inputs = inputs.clone();
+                let mut log = self.adjustment_log.clone();
+                self.run_task(move |server| {
+                    let res = log.adjust(server, &service, &inputs);
+                    TaskResult::Adjust(service, log, res)
+                })
+            }
+            JsonDiagSessionMsg::UndoAdjustment => {
+                let mut log = self.adjustment_log.clone();
+                self.run_task(move |server| {
+                    let res = log.undo_last(server);
+                    TaskResult::UndoAdjustment(log, res)
+                })
+            }
+            JsonDiagSessionMsg::Actuate(s, inputs) => {
+                let service = s.inner.borrow().clone();
+                let inputs = inputs.clone();
+                self.run_task(move |server| {
+                    let res = actuation::actuate(server, &service, &inputs);
+                    TaskResult::Actuate(service, res)
+                })
+            }
+            JsonDiagSessionMsg::ReleaseActuation(s) => {
+                let service = s.inner.borrow().clone();
+                self.run_task(move |server| {
+                    let res = actuation::release(server, &service);
+                    TaskResult::ReleaseActuation(service, res)
+                })
+            }
+            JsonDiagSessionMsg::ReleaseAllActuations => self.run_task(|server| {
+                TaskResult::ReleaseAllActuations(actuation::release_all(server))
+            }),
+            JsonDiagSessionMsg::StartRoutine(s, inputs) => {
+                let service = s.inner.borrow().clone();
+                let inputs = inputs.clone();
+                self.run_task(move |server| {
+                    let res = RoutineRun::start(
+                        server,
+                        &service,
+                        &inputs,
+                        routine::DEFAULT_ROUTINE_TIMEOUT,
+                    );
+                    TaskResult::StartRoutine(service, res)
+                })
+            }
+            JsonDiagSessionMsg::PollRoutine(_) => {
+                // Skip this poll if the last one is still waiting for the ECU
+                if let (Some(run), None) = (&self.running_routine, &self.pending_task) {
+                    let run = run.clone();
+                    self.run_task(move |server| {
+                        let res = run.poll(server);
+                        TaskResult::PollRoutine(run.get_service().clone(), res)
+                    })
+                }
+            }
+            JsonDiagSessionMsg::StopRoutine => {
+                if let Some(run) = self.running_routine.take() {
+                    self.run_task(move |server| {
+                        let res = run.stop(server);
+                        TaskResult::StopRoutine(run.get_service().clone(), res)
+                    })
+                }
+            }
+            JsonDiagSessionMsg::PollTask(_) => {
+                if let Some(task) = self.pending_task.take() {
+                    match task.try_take() {
+                        None => self.pending_task = Some(task), // Still waiting for the ECU
+                        Some(Ok(res)) => return self.on_task_complete(res),
+                        Some(Err(e)) => {
+                            // Task never ran, or the ECU stopped responding
+                            self.log_view.add_msg(
+                                format!("Error communicating with ECU: {}", e.get_text()),
+                                LogType::Error,
+                            );
+                            self.running_routine = None;
+                        }
+                    }
                 }
             }
             JsonDiagSessionMsg::ClearLogs => self.log_view.clear_logs(),
             JsonDiagSessionMsg::LoopRead(_) => {
                 if let Some(s) = &self.looping_service {
-                    if let Ok(res) = s.exec(&[], &mut self.server) {
-                        self.looping_text = format!(
-                            "{}({})\n->{}",
-                            s.inner.borrow().name,
-                            s.inner.borrow().description,
-                            s.args_to_string(&res)
-                        )
+                    // Show the previous read once the ECU has responded, then queue the next.
+                    // This way the UI is never held up by the ECU
+                    if let Some(req) = self.loop_request.take() {
+                        match req.try_take() {
+                            None => {
+                                // Still waiting for the ECU
+                                self.loop_request = Some(req);
+                                return None;
+                            }
+                            Some(Ok(res)) => {
+                                self.looping_text = format!(
+                                    "{}({})\n->{}",
+                                    s.inner.borrow().name,
+                                    s.inner.borrow().description,
+                                    s.args_to_string(&res)
+                                )
+                            }
+                            Some(Err(_)) => {}
+                        }
                     }
+                    self.loop_request = Some(s.exec_async(&[], &self.server));
                 }
             }
             JsonDiagSessionMsg::Select(table_id, x, y) => {
@@ -592,8 +1018,14 @@ impl SessionTrait for JsonDiagSession {
                 if *table_id == TABLE_DTC {
                     let header = vec!["Parameter".into(), "Value".into()];
                     let mut values: Vec<Vec<String>> = Vec::new();
-                    for (name, v) in &self.logged_dtcs[*y].envs {
-                        values.push(vec![name.clone(), v.clone()]);
+                    let dtc = &self.logged_dtcs[*y].dtc;
+                    for (name, v) in dtc.get_status_values() {
+                        values.push(vec![name, v]);
+                    }
+                    for record in &dtc.records {
+                        for (name, v) in &record.values {
+                            values.push(vec![name.clone(), v.clone()]);
+                        }
                     }
                     self.tables[ENV_TABLE] = Table::new(header, values, vec![400, 200], false, 300);
                 }
@@ -603,11 +1035,25 @@ impl SessionTrait for JsonDiagSession {
     }
 
     fn subscription(&self) -> iced::Subscription<Self::msg> {
+        let mut subs = Vec::new();
         if self.looping_service.is_some() {
-            return time::every(std::time::Duration::from_millis(500))
-                .map(JsonDiagSessionMsg::LoopRead);
+            subs.push(
+                time::every(std::time::Duration::from_millis(500))
+                    .map(JsonDiagSessionMsg::LoopRead),
+            );
+        }
+        if self.running_routine.is_some() {
+            subs.push(
+                time::every(std::time::Duration::from_millis(250))
+                    .map(JsonDiagSessionMsg::PollRoutine),
+            );
+        }
+        if self.pending_task.is_some() {
+            subs.push(
+                time::every(std::time::Duration::from_millis(50)).map(JsonDiagSessionMsg::PollTask),
+            );
         }
-        Subscription::none()
+        Subscription::batch(subs)
     }
 }
 
@@ -629,6 +1075,21 @@ impl ServiceRef {
     }
 
     pub fn exec(&self, replace_args: &[u8], server: &mut DiagServer) -> ProtocolResult<Vec<u8>> {
+        server.run_cmd(
+            self.inner.borrow().payload[0],
+            &self.build_args(replace_args),
+        )
+    }
+
+    /// Queues the service, without waiting for the ECU to respond
+    pub fn exec_async(&self, replace_args: &[u8], server: &DiagServer) -> RequestHandle<Vec<u8>> {
+        server.run_cmd_async(
+            self.inner.borrow().payload[0],
+            &self.build_args(replace_args),
+        )
+    }
+
+    fn build_args(&self, replace_args: &[u8]) -> Vec<u8> {
         let p = &self.inner.borrow().payload;
         let mut args = if p.is_empty() {
             Vec::new()
@@ -640,7 +1101,7 @@ impl ServiceRef {
                 args[pos] |= x;
             }
         }
-        server.run_cmd(self.inner.borrow().payload[0], &args)
+        args
     }
 
     pub fn args_to_string(&self, args: &[u8]) -> String {
@@ -674,12 +1135,15 @@ pub enum SelectorMsg {
     ViewRead,
     ViewWrite,
     ViewActuation,
+    ViewRoutine,
     PickService(ServiceRef),
     PickLoopService(ServiceRef),
     StopLoopService,
     BeginLoopService,
     ExecService,
+    ReleaseService,
     Search(String),
+    Input(usize, String),
 }
 
 #[derive(Debug, Clone)]
@@ -687,6 +1151,7 @@ pub struct ServiceSelector {
     read_services: Vec<ServiceRef>,
     write_services: Vec<ServiceRef>,
     actuation_services: Vec<ServiceRef>,
+    routine_services: Vec<ServiceRef>,
 
     shown_services: Vec<ServiceRef>,
 
@@ -695,10 +1160,12 @@ pub struct ServiceSelector {
     r_btn: iced::button::State,
     w_btn: iced::button::State,
     a_btn: iced::button::State,
+    o_btn: iced::button::State,
     execb: iced::button::State,
     l_btn: iced::button::State,
     is_loop: bool,
     args: Vec<u8>,
+    inputs: Vec<(String, iced::text_input::State)>, // Value of each input param
 
     s_bar: iced::text_input::State,
 
@@ -708,33 +1175,42 @@ pub struct ServiceSelector {
     selected_service: Option<ServiceRef>,
     picker: iced::pick_list::State<ServiceRef>,
 
-    view_selection: [bool; 3], // Read, Write, Actuation
+    view_selection: [bool; 4], // Read, Write, Actuation, Routine
 }
 
 impl ServiceSelector {
-    pub fn new(r: Vec<ServiceRef>, w: Vec<ServiceRef>, a: Vec<ServiceRef>) -> Self {
+    pub fn new(
+        r: Vec<ServiceRef>,
+        w: Vec<ServiceRef>,
+        a: Vec<ServiceRef>,
+        o: Vec<ServiceRef>,
+    ) -> Self {
         println!(
-            "{} Read services, {} Write services, {} Actuation services",
+            "{} Read services, {} Write services, {} Actuation services, {} Routine services",
             r.len(),
             w.len(),
-            a.len()
+            a.len(),
+            o.len()
         );
 
         Self {
             read_services: r.clone(),
             write_services: w,
             actuation_services: a,
+            routine_services: o,
             query_string: String::new(),
             r_btn: Default::default(),
             w_btn: Default::default(),
             a_btn: Default::default(),
+            o_btn: Default::default(),
             s_bar: Default::default(),
             picker: Default::default(),
             execb: Default::default(),
             l_btn: Default::default(),
             args: Vec::new(),
+            inputs: Vec::new(),
             selected_service: None,
-            view_selection: [true, false, false], // Read is default view
+            view_selection: [true, false, false, false], // Read is default view
             shown_services: r,
             can_execute: false,
             input_require: false,
@@ -758,6 +1234,11 @@ impl ServiceSelector {
             true => button_coloured(&mut self.a_btn, "Actuate", ButtonType::Info),
         };
 
+        let o_btn = match self.view_selection[3] {
+            false => button_outlined(&mut self.o_btn, "Routine", ButtonType::Info),
+            true => button_coloured(&mut self.o_btn, "Routine", ButtonType::Info),
+        };
+
         let search_bar = text_input(
             &mut self.s_bar,
             "Search for function",
@@ -783,6 +1264,11 @@ impl ServiceSelector {
                 a_btn
                     .on_press(SelectorMsg::ViewActuation)
                     .width(Length::FillPortion(1)),
+            )
+            .push(
+                o_btn
+                    .on_press(SelectorMsg::ViewRoutine)
+                    .width(Length::FillPortion(1)),
             );
 
         let mut content_view = if self.shown_services.is_empty() {
@@ -814,7 +1300,32 @@ impl ServiceSelector {
                 TextType::Normal,
             ));
 
-            if self.input_require {
+            if self.input_require && !self.inputs.is_empty() {
+                // Editable values, read from the ECU
+                for (idx, (x, (value, state))) in curr_service
+                    .inner
+                    .borrow()
+                    .input_params
+                    .iter()
+                    .zip(self.inputs.iter_mut())
+                    .enumerate()
+                {
+                    let mut label = x.name.clone();
+                    if let Some(unit) = x.get_unit() {
+                        label.push_str(format!(" ({})", unit).as_str());
+                    }
+                    if let Some(bounds) = &x.valid_bounds {
+                        label.push_str(
+                            format!(" [{} - {}]", bounds.get_lower(), bounds.get_upper()).as_str(),
+                        );
+                    }
+                    content_view = content_view
+                        .push(text(label.as_str(), TextType::Normal))
+                        .push(text_input(state, &x.name, value, move |s| {
+                            SelectorMsg::Input(idx, s)
+                        }));
+                }
+            } else if self.input_require {
                 for x in &curr_service.inner.borrow().input_params {
                     content_view = content_view.push(text(
                         format!("Input {} Required. Type: {:?}", x.name, x.data_format).as_str(),
@@ -829,8 +1340,10 @@ impl ServiceSelector {
                         "Read "
                     } else if self.view_selection[1] {
                         "Write "
-                    } else {
+                    } else if self.view_selection[2] {
                         "Actuate "
+                    } else {
+                        "Start "
                     };
                     content_view = content_view.push(
                         button_coloured(
@@ -841,7 +1354,17 @@ impl ServiceSelector {
                         .on_press(SelectorMsg::ExecService),
                     )
                 }
-                if self.can_execute == self.view_selection[0] {
+                if self.view_selection[2] {
+                    content_view = content_view.push(
+                        button_outlined(
+                            &mut self.l_btn,
+                            format!("Release {}", curr_service.inner.borrow().name).as_str(),
+                            ButtonType::Info,
+                        )
+                        .on_press(SelectorMsg::ReleaseService),
+                    )
+                }
+                if self.can_execute && self.view_selection[0] {
                     // Show the graph button
                     content_view = content_view.push(
                         button_coloured(&mut self.l_btn, "Begin graphing", ButtonType::Info)
@@ -883,25 +1406,40 @@ impl ServiceSelector {
         self.selected_service = None;
         self.can_execute = false;
         self.input_require = false;
+        self.inputs.clear();
+    }
+
+    /// Sets the current value of each input param of the selected service, so they can be edited
+    pub fn set_inputs(&mut self, values: Vec<String>) {
+        self.inputs = values
+            .into_iter()
+            .map(|v| (v, iced::text_input::State::default()))
+            .collect();
+        self.can_execute = self.selected_service.is_some();
     }
 
     pub fn update(&mut self, msg: &SelectorMsg) -> Option<JsonDiagSessionMsg> {
         match &msg {
             SelectorMsg::ViewActuation => {
-                self.view_selection = [false, false, true];
+                self.view_selection = [false, false, true, false];
                 self.shown_services = self.get_shown_services(&self.actuation_services);
                 self.on_change_items();
             }
             SelectorMsg::ViewRead => {
-                self.view_selection = [true, false, false];
+                self.view_selection = [true, false, false, false];
                 self.shown_services = self.get_shown_services(&self.read_services);
                 self.on_change_items();
             }
             SelectorMsg::ViewWrite => {
-                self.view_selection = [false, true, false];
+                self.view_selection = [false, true, false, false];
                 self.shown_services = self.get_shown_services(&self.write_services);
                 self.on_change_items();
             }
+            SelectorMsg::ViewRoutine => {
+                self.view_selection = [false, false, false, true];
+                self.shown_services = self.get_shown_services(&self.routine_services);
+                self.on_change_items();
+            }
             SelectorMsg::Search(s) => {
                 let old_len = self.query_string.len();
                 self.query_string = s.clone();
@@ -916,13 +1454,17 @@ impl ServiceSelector {
                     } else if self.view_selection[1] {
                         // Write
                         self.get_shown_services(&self.write_services)
-                    } else {
+                    } else if self.view_selection[2] {
                         // Actuations
                         self.get_shown_services(&self.actuation_services)
+                    } else {
+                        // Routines
+                        self.get_shown_services(&self.routine_services)
                     }
                 }
             }
             SelectorMsg::PickService(s) => {
+                self.inputs.clear();
                 if s.require_input() {
                     self.can_execute = false;
                     self.input_require = true;
@@ -932,10 +1474,26 @@ impl ServiceSelector {
                 }
                 self.selected_service = Some(s.clone());
                 println!("{} selected", s.inner.borrow().name);
+                if self.view_selection[1] {
+                    // Adjustments are edited starting from the ECU's current value
+                    return Some(JsonDiagSessionMsg::ReadAdjustment(s.clone()));
+                } else if self.view_selection[2] || self.view_selection[3] {
+                    self.set_inputs(vec![String::new(); s.inner.borrow().input_params.len()]);
+                }
+            }
+            SelectorMsg::Input(idx, value) => {
+                if let Some(input) = self.inputs.get_mut(*idx) {
+                    input.0 = value.clone();
+                }
             }
             SelectorMsg::StopLoopService => {
                 self.is_loop = false;
             }
+            SelectorMsg::ReleaseService => {
+                if let Some(s) = &self.selected_service {
+                    return Some(JsonDiagSessionMsg::ReleaseActuation(s.clone()));
+                }
+            }
             SelectorMsg::BeginLoopService => {
                 if let Some(s) = &self.selected_service {
                     self.is_loop = true;
@@ -945,10 +1503,26 @@ impl ServiceSelector {
                 }
             }
             SelectorMsg::ExecService => {
+                if self.view_selection[1] {
+                    return Some(JsonDiagSessionMsg::Adjust(
+                        self.selected_service.clone().unwrap(),
+                        self.inputs.iter().map(|(v, _)| v.clone()).collect(),
+                    ));
+                } else if self.view_selection[2] {
+                    return Some(JsonDiagSessionMsg::Actuate(
+                        self.selected_service.clone().unwrap(),
+                        self.inputs.iter().map(|(v, _)| v.clone()).collect(),
+                    ));
+                } else if self.view_selection[3] {
+                    return Some(JsonDiagSessionMsg::StartRoutine(
+                        self.selected_service.clone().unwrap(),
+                        self.inputs.iter().map(|(v, _)| v.clone()).collect(),
+                    ));
+                }
                 return Some(JsonDiagSessionMsg::ExecuteService(
                     self.selected_service.clone().unwrap(),
                     self.args.clone(),
-                ))
+                ));
             }
             _ => {}
         }
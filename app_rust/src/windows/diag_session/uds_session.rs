@@ -13,10 +13,14 @@ use crate::{
     commapi::{
         comm_api::{ComServer, ISO15765Config},
         iface::{InterfaceConfig, InterfaceType, PayloadFlag, IFACE_CFG},
-        protocols::uds::UDSECU,
-        protocols::{DiagCfg, ProtocolServer},
+        protocols::uds::{security_access, UDSECU},
+        protocols::{
+            executor::RequestHandle, security, DiagCfg, ProtocolResult, ProtocolServer, DTC,
+        },
+    },
+    themes::{
+        button_outlined, picklist, text, text_input, title_text, ButtonType, TextType, TitleSize,
     },
-    themes::{button_outlined, text, text_input, title_text, ButtonType, TextType, TitleSize},
     windows::window,
 };
 
@@ -33,6 +37,9 @@ pub enum UDSDiagSessionMsg {
     ReadCodes,
     SendPayload,
     EnterPayload(String),
+    SelectAlgorithm(String),
+    EnterSecurityLevel(String),
+    Unlock,
 }
 
 impl DiagMessageTrait for UDSDiagSessionMsg {
@@ -52,10 +59,19 @@ pub struct UDSDiagSession {
     clear_btn: iced::button::State,
     read_codes_btn: iced::button::State,
     diag_server: Option<UDSECU>,
+    /// Request waiting for the ECU to respond
+    pending_task: Option<RequestHandle<TaskResult>>,
     payload_string: String,
     payload_send_btn: iced::button::State,
     payload_input: iced::text_input::State,
     can_send: bool,
+    /// Seed/key algorithms that can be used to unlock the ECU
+    algo_names: Vec<String>,
+    selected_algo: Option<String>,
+    algo_select: iced::pick_list::State<String>,
+    level_string: String,
+    level_input: iced::text_input::State,
+    unlock_btn: iced::button::State,
     logview: LogView,
 }
 
@@ -68,6 +84,7 @@ impl UDSDiagSession {
             disconnect_btn: Default::default(),
             back_btn: Default::default(),
             diag_server: None,
+            pending_task: None,
             logview: LogView::new(),
             can_clear_codes: false,
             clear_btn: Default::default(),
@@ -76,10 +93,103 @@ impl UDSDiagSession {
             payload_send_btn: Default::default(),
             payload_input: Default::default(),
             can_send: false,
+            algo_names: Vec::new(),
+            selected_algo: None,
+            algo_select: Default::default(),
+            level_string: "01".into(),
+            level_input: Default::default(),
+            unlock_btn: Default::default(),
         })
     }
 }
 
+/// Result of a request run in the background
+enum TaskResult {
+    Connect(ProtocolResult<UDSECU>),
+    ClearErrors(ProtocolResult<()>),
+    ReadCodes(ProtocolResult<Vec<DTC>>),
+    /// Request that was sent, and the ECU's response
+    SendPayload(Vec<u8>, ProtocolResult<Vec<u8>>),
+    /// Security level that was requested, and the result of unlocking it
+    Unlock(u8, ProtocolResult<()>),
+}
+
+impl UDSDiagSession {
+    fn on_task_complete(&mut self, res: TaskResult) {
+        match res {
+            TaskResult::Connect(res) => match res {
+                Ok(server) => {
+                    window::disable_home();
+                    self.diag_server = Some(server);
+                    self.algo_names = security::get_algorithm_names();
+                    if self.selected_algo.is_none() {
+                        self.selected_algo = self.algo_names.get(0).cloned();
+                    }
+                    self.logview
+                        .add_msg("Connection to ECU established", LogType::Info)
+                }
+                Err(e) => self.logview.add_msg(
+                    format!("Error connecting to ECU ({})", e.get_text()),
+                    LogType::Info,
+                ),
+            },
+            TaskResult::ClearErrors(res) => match res {
+                Err(e) => self.logview.add_msg(
+                    format!("Error clearing ECU errors: {}", e.get_text()).as_str(),
+                    LogType::Error,
+                ),
+                Ok(_) => self
+                    .logview
+                    .add_msg("ECU Errors cleared successfully", LogType::Error),
+            },
+            TaskResult::ReadCodes(res) => match res {
+                Err(e) => self.logview.add_msg(
+                    format!("Error reading ECU errors: {}", e.get_text()).as_str(),
+                    LogType::Error,
+                ),
+                Ok(errors) => {
+                    if errors.is_empty() {
+                        self.logview.add_msg("No ECU Errors found", LogType::Info)
+                    } else {
+                        self.logview
+                            .add_msg(format!("Found {} errors", errors.len()), LogType::Warn);
+                        self.can_clear_codes = true;
+                        for x in &errors {
+                            self.logview.add_msg(x.error.as_str(), LogType::Warn);
+                        }
+                    }
+                }
+            },
+            TaskResult::SendPayload(r, res) => match res {
+                Ok(res) => self.logview.add_log(
+                    format!("Req:  {:02X?}", r),
+                    format!("Resp: {:02X?}", res),
+                    LogType::Info,
+                ),
+                Err(e) => self.logview.add_log(
+                    format!("Req:  {:02X?}", r),
+                    format!("Exec error: {}", e.get_text()),
+                    LogType::Error,
+                ),
+            },
+            TaskResult::Unlock(level, res) => match res {
+                Ok(_) => self.logview.add_msg(
+                    format!("Security level 0x{:02X} unlocked", level),
+                    LogType::Info,
+                ),
+                Err(e) => self.logview.add_msg(
+                    format!(
+                        "Error unlocking security level 0x{:02X}: {}",
+                        level,
+                        e.get_text()
+                    ),
+                    LogType::Error,
+                ),
+            },
+        }
+    }
+}
+
 impl SessionTrait for UDSDiagSession {
     type msg = UDSDiagSessionMsg;
 
@@ -148,6 +258,44 @@ impl SessionTrait for UDSDiagSession {
                 btn = btn.on_press(UDSDiagSessionMsg::SendPayload);
             }
             ui = ui.push(btn);
+
+            // Security access
+            ui = ui.push(text("Security access", TextType::Normal));
+            if self.algo_names.is_empty() {
+                ui = ui.push(text(
+                    "No seed/key algorithms loaded. Load one from the launcher",
+                    TextType::Disabled,
+                ));
+            } else {
+                let mut btn =
+                    button_outlined(&mut self.unlock_btn, "Unlock ECU", ButtonType::Warning);
+                if self.selected_algo.is_some() && !self.level_string.is_empty() {
+                    btn = btn.on_press(UDSDiagSessionMsg::Unlock);
+                }
+                ui = ui.push(
+                    Row::new()
+                        .spacing(8)
+                        .push(picklist(
+                            &mut self.algo_select,
+                            &self.algo_names,
+                            self.selected_algo.clone(),
+                            UDSDiagSessionMsg::SelectAlgorithm,
+                        ))
+                        .push(
+                            text_input(
+                                &mut self.level_input,
+                                "Level (Hex)",
+                                &self.level_string,
+                                UDSDiagSessionMsg::EnterSecurityLevel,
+                            )
+                            .width(Length::Units(100)),
+                        )
+                        .push(btn),
+                );
+            }
+        }
+        if self.pending_task.is_some() {
+            ui = ui.push(text("Waiting for the ECU...", TextType::Disabled));
         }
         ui = ui.push(Space::with_height(Length::Fill));
         if let Some(se) = &self.diag_server {
@@ -169,6 +317,21 @@ impl SessionTrait for UDSDiagSession {
     }
 
     fn update(&mut self, msg: &Self::msg) -> Option<Self::msg> {
+        let needs_ecu = matches!(
+            msg,
+            UDSDiagSessionMsg::ConnectECU
+                | UDSDiagSessionMsg::ClearErrors
+                | UDSDiagSessionMsg::ReadCodes
+                | UDSDiagSessionMsg::SendPayload
+                | UDSDiagSessionMsg::Unlock
+        );
+        if needs_ecu && self.pending_task.is_some() {
+            self.logview.add_msg(
+                "Still waiting for the ECU to respond to the last request",
+                LogType::Warn,
+            );
+            return None;
+        }
         match msg {
             UDSDiagSessionMsg::ConnectECU => {
                 let mut cfg = InterfaceConfig::new();
@@ -182,26 +345,19 @@ impl SessionTrait for UDSDiagSession {
                     send_id: self.ecu.send_id,
                     recv_id: self.ecu.recv_id,
                     global_id: None,
+                    timing: Default::default(),
                 };
 
-                match UDSECU::start_diag_session(
-                    &self.server,
-                    InterfaceType::IsoTp,
-                    cfg,
-                    Some(vec![PayloadFlag::ISOTP_PAD_FRAME]),
-                    diag_cfg,
-                ) {
-                    Ok(server) => {
-                        window::disable_home();
-                        self.diag_server = Some(server);
-                        self.logview
-                            .add_msg("Connection to ECU established", LogType::Info)
-                    }
-                    Err(e) => self.logview.add_msg(
-                        format!("Error connecting to ECU ({})", e.get_text()),
-                        LogType::Info,
-                    ),
-                }
+                let comm_server = self.server.clone();
+                self.pending_task = Some(RequestHandle::spawn(move || {
+                    Ok(TaskResult::Connect(UDSECU::start_diag_session(
+                        &comm_server,
+                        InterfaceType::IsoTp,
+                        cfg,
+                        Some(vec![PayloadFlag::ISOTP_PAD_FRAME]),
+                        diag_cfg,
+                    )))
+                }));
             }
             UDSDiagSessionMsg::DisconnectECU => {
                 if let Some(ref mut server) = self.diag_server {
@@ -214,6 +370,16 @@ impl SessionTrait for UDSDiagSession {
             }
 
             UDSDiagSessionMsg::PollServer(_) => {
+                if let Some(task) = self.pending_task.take() {
+                    match task.try_take() {
+                        None => self.pending_task = Some(task), // Still waiting for the ECU
+                        Some(Ok(res)) => self.on_task_complete(res),
+                        Some(Err(e)) => self.logview.add_msg(
+                            format!("Error communicating with ECU: {}", e.get_text()),
+                            LogType::Error,
+                        ),
+                    }
+                }
                 if let Some(ref mut server) = self.diag_server {
                     if !server.is_in_diag_session() {
                         // Woops server terminated without interaction
@@ -231,40 +397,19 @@ impl SessionTrait for UDSDiagSession {
             UDSDiagSessionMsg::ClearLogs => self.logview.clear_logs(),
             UDSDiagSessionMsg::ClearErrors => {
                 if let Some(s) = &self.diag_server {
-                    match s.clear_errors() {
-                        Err(e) => self.logview.add_msg(
-                            format!("Error clearing ECU errors: {}", e.get_text()).as_str(),
-                            LogType::Error,
-                        ),
-                        Ok(_) => self
-                            .logview
-                            .add_msg("ECU Errors cleared successfully", LogType::Error),
-                    }
+                    let server = s.clone();
+                    self.pending_task = Some(RequestHandle::spawn(move || {
+                        Ok(TaskResult::ClearErrors(server.clear_errors()))
+                    }));
                 }
             }
             UDSDiagSessionMsg::ReadCodes => {
                 self.can_clear_codes = false;
                 if let Some(s) = &self.diag_server {
-                    match s.read_errors() {
-                        Err(e) => self.logview.add_msg(
-                            format!("Error reading ECU errors: {}", e.get_text()).as_str(),
-                            LogType::Error,
-                        ),
-                        Ok(errors) => {
-                            if errors.is_empty() {
-                                self.logview.add_msg("No ECU Errors found", LogType::Info)
-                            } else {
-                                self.logview.add_msg(
-                                    format!("Found {} errors", errors.len()),
-                                    LogType::Warn,
-                                );
-                                self.can_clear_codes = true;
-                                for x in &errors {
-                                    self.logview.add_msg(x.error.as_str(), LogType::Warn);
-                                }
-                            }
-                        }
-                    }
+                    let server = s.clone();
+                    self.pending_task = Some(RequestHandle::spawn(move || {
+                        Ok(TaskResult::ReadCodes(server.read_errors()))
+                    }));
                 }
             }
             UDSDiagSessionMsg::EnterPayload(s) => {
@@ -279,29 +424,41 @@ impl SessionTrait for UDSDiagSession {
                 if let Ok(r) = hex::decode(&self.payload_string) {
                     if r.len() >= 2 {
                         if let Some(server) = &self.diag_server {
-                            match server.run_command(r[0], &r[1..]) {
-                                Ok(res) => self.logview.add_log(
-                                    format!("Req:  {:02X?}", r),
-                                    format!("Resp: {:02X?}", res),
-                                    LogType::Info,
-                                ),
-                                Err(e) => self.logview.add_log(
-                                    format!("Req:  {:02X?}", r),
-                                    format!("Exec error: {}", e.get_text()),
-                                    LogType::Error,
-                                ),
-                            }
+                            let server = server.clone();
+                            self.pending_task = Some(RequestHandle::spawn(move || {
+                                let res = server.run_command(r[0], &r[1..]);
+                                Ok(TaskResult::SendPayload(r, res))
+                            }));
                         }
                     }
                 }
             }
+            UDSDiagSessionMsg::SelectAlgorithm(a) => self.selected_algo = Some(a.clone()),
+            UDSDiagSessionMsg::EnterSecurityLevel(s) => self.level_string = s.clone(),
+            UDSDiagSessionMsg::Unlock => match u8::from_str_radix(&self.level_string, 16) {
+                Ok(level) => {
+                    if let (Some(server), Some(algo)) = (&self.diag_server, &self.selected_algo) {
+                        let server = server.clone();
+                        let algo = algo.clone();
+                        // Can wait out the ECU's security delay, so never run this on the GUI thread
+                        self.pending_task = Some(RequestHandle::spawn(move || {
+                            let res = security_access::unlock_with(&server, level, &algo);
+                            Ok(TaskResult::Unlock(level, res))
+                        }));
+                    }
+                }
+                Err(_) => self.logview.add_msg(
+                    format!("'{}' is not a valid security level", self.level_string),
+                    LogType::Error,
+                ),
+            },
             UDSDiagSessionMsg::Back => {}
         }
         None
     }
 
     fn subscription(&self) -> iced::Subscription<Self::msg> {
-        if self.diag_server.is_some() {
+        if self.diag_server.is_some() || self.pending_task.is_some() {
             time::every(std::time::Duration::from_millis(250)).map(UDSDiagSessionMsg::PollServer)
         } else {
             Subscription::none()
@@ -314,5 +471,13 @@ impl Drop for UDSDiagSession {
         if let Some(ref mut session) = self.diag_server {
             session.exit_diag_session()
         }
+        if let Some(task) = self.pending_task.take() {
+            // Still connecting, so end the session once it has started
+            task.on_complete(|res| {
+                if let Ok(TaskResult::Connect(Ok(mut server))) = res {
+                    server.exit_diag_session()
+                }
+            })
+        }
     }
 }
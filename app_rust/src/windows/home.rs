@@ -84,12 +84,14 @@ impl Home {
                     .push(
                         Column::new()
                             .push(text("CAN", TextType::Normal))
+                            .push(text("CAN FD", TextType::Normal))
                             .push(text("ISO-TP", TextType::Normal))
                             .push(text("ISO9141", TextType::Normal))
                             .push(text("ISO14230", TextType::Normal)),
                     )
                     .push(
                         Column::new()
+                            .push(Home::gen_cap_contents(cap.supports_can()))
                             .push(Home::gen_cap_contents(cap.support_can_fd()))
                             .push(Home::gen_cap_contents(cap.supports_iso15765()))
                             .push(Home::gen_cap_contents(cap.supports_iso9141()))
@@ -1,8 +1,16 @@
+use std::fs::File;
+use std::io::Read;
 use std::process::Command;
 
 use crate::commapi::passthru_api::PassthruApi;
+use crate::commapi::pdu_api::DpduAPI;
+use crate::commapi::protocols::security;
+use crate::commapi::simulator_api::{SimulatorAPI, VirtualECU, VirtualECUConfig};
+use crate::dpdu::{PduDevice, PduDrv};
 use crate::passthru::{PassthruDevice, PassthruDrv};
-use crate::themes::{button_coloured, container, picklist, radio_btn, text, ButtonType, TextType};
+use crate::themes::{
+    button_coloured, checkbox, container, picklist, radio_btn, text, ButtonType, TextType,
+};
 use crate::windows::launcher::LauncherMessage::LaunchRequested;
 use crate::windows::window::ApplicationError::DriverError;
 use crate::windows::window::{ApplicationError, WindowMessage};
@@ -10,6 +18,7 @@ use crate::{
     commapi::comm_api::{ComServer, ComServerError},
     themes::images::get_launcher_image,
 };
+use common::schema::{ConType, OvdECU};
 use iced::{button, pick_list, Align, Column, Element, Length, Row, Text};
 
 #[cfg(target_os = "linux")]
@@ -23,6 +32,7 @@ pub struct Launcher {
 
     selection: pick_list::State<String>,
 
+    device_list_dpdu: Vec<PduDevice>,
     device_names_dpdu: Vec<String>,
     selected_device_dpdu: String,
 
@@ -31,6 +41,20 @@ pub struct Launcher {
     #[cfg(target_os = "linux")]
     selected_device_socketcan: String,
 
+    sim_ecus: Vec<VirtualECU>,
+    load_ecu_state: button::State,
+    /// ECU JSON that was loaded, but not yet added to the simulator
+    sim_loaded: Option<OvdECU>,
+    sim_config: VirtualECUConfig,
+    sim_variant_names: Vec<String>,
+    sim_variant_state: pick_list::State<String>,
+    sim_connection_names: Vec<String>,
+    sim_connection_state: pick_list::State<String>,
+    add_ecu_state: button::State,
+    cancel_ecu_state: button::State,
+
+    load_algo_state: button::State,
+
     api_selection: API,
 
     launch_state: button::State,
@@ -43,12 +67,21 @@ pub enum API {
     DPdu,
     Passthru,
     SocketCAN,
+    Simulator,
 }
 
 #[derive(Debug, Clone)]
 pub enum LauncherMessage {
     SwitchAPI(API),
     DeviceSelected(String),
+    LoadSimulatorECU,
+    SimVariantSelected(String),
+    SimConnectionSelected(String),
+    SimToggleDtcs(bool),
+    SimToggleResponsePending(bool),
+    AddSimulatorECU,
+    CancelSimulatorECU,
+    LoadSeedKeyAlgorithm,
     LaunchRequested,
 }
 
@@ -69,20 +102,38 @@ impl Launcher {
         let selected_passthru_device: String =
             passthru_device_names.get(0).cloned().unwrap_or_default();
 
+        let dpdu_devices = PduDevice::find_all().unwrap_or_default();
+        let dpdu_device_names: Vec<String> = dpdu_devices.iter().map(|d| d.name.clone()).collect();
+        let selected_dpdu_device: String = dpdu_device_names.get(0).cloned().unwrap_or_default();
+
         Self {
             device_list_passthru: passthru_devices,
 
             device_names_passthru: passthru_device_names,
             selected_device_passthru: selected_passthru_device,
 
-            device_names_dpdu: vec![],
-            selected_device_dpdu: "".to_string(),
+            device_list_dpdu: dpdu_devices,
+            device_names_dpdu: dpdu_device_names,
+            selected_device_dpdu: selected_dpdu_device,
 
             #[cfg(target_os = "linux")]
             device_names_socketcan: Self::find_devices_socketcan(),
             #[cfg(target_os = "linux")]
             selected_device_socketcan: "".to_string(),
 
+            sim_ecus: Vec::new(),
+            load_ecu_state: button::State::default(),
+            sim_loaded: None,
+            sim_config: VirtualECUConfig::default(),
+            sim_variant_names: Vec::new(),
+            sim_variant_state: pick_list::State::default(),
+            sim_connection_names: Vec::new(),
+            sim_connection_state: pick_list::State::default(),
+            add_ecu_state: button::State::default(),
+            cancel_ecu_state: button::State::default(),
+
+            load_algo_state: button::State::default(),
+
             selection: pick_list::State::default(),
             api_selection: API::Passthru,
             launch_state: button::State::default(),
@@ -105,6 +156,86 @@ impl Launcher {
                     }
                 }
             }
+            LauncherMessage::LoadSimulatorECU => match Self::load_sim_ecu() {
+                Ok(Some(ecu)) => {
+                    self.sim_variant_names = ecu
+                        .variants
+                        .iter()
+                        .map(|v| match v.patterns.get(0) {
+                            Some(p) => format!("{} (0x{:04X})", v.name, p.vendor_id),
+                            None => v.name.clone(),
+                        })
+                        .collect();
+                    self.sim_connection_names = ecu
+                        .connections
+                        .iter()
+                        .enumerate()
+                        .map(|(idx, c)| {
+                            let transport = match c.connection_type {
+                                ConType::ISOTP { .. } => "ISO-TP",
+                                ConType::LIN { .. } => "K-Line",
+                            };
+                            format!(
+                                "{}: {:?} over {} (Request ID 0x{:04X})",
+                                idx + 1,
+                                c.server_type,
+                                transport,
+                                c.send_id
+                            )
+                        })
+                        .collect();
+                    self.sim_config = VirtualECUConfig::default();
+                    self.status_text = format!("Loaded ECU JSON {}", ecu.name);
+                    self.sim_loaded = Some(ecu);
+                }
+                Ok(None) => {}
+                Err(e) => self.status_text = e.to_string(),
+            },
+            LauncherMessage::SimVariantSelected(v) => {
+                if let Some(idx) = self.sim_variant_names.iter().position(|x| x == v) {
+                    self.sim_config.variant_idx = idx
+                }
+            }
+            LauncherMessage::SimConnectionSelected(c) => {
+                if let Some(idx) = self.sim_connection_names.iter().position(|x| x == c) {
+                    self.sim_config.connection_idx = idx
+                }
+            }
+            LauncherMessage::SimToggleDtcs(state) => self.sim_config.store_dtcs = *state,
+            LauncherMessage::SimToggleResponsePending(state) => {
+                // A slow ECU sends 2 ResponsePending messages, 1 second apart
+                if *state {
+                    self.sim_config.response_pending_count = 2;
+                    self.sim_config.response_pending_interval = 1000;
+                } else {
+                    self.sim_config.response_pending_count = 0;
+                    self.sim_config.response_pending_interval = 0;
+                }
+            }
+            LauncherMessage::AddSimulatorECU => {
+                if let Some(def) = &self.sim_loaded {
+                    match self.sim_config.build(def) {
+                        Ok(ecu) => {
+                            self.status_text = format!(
+                                "Added virtual ECU {} with {} stored DTCs",
+                                ecu.name,
+                                ecu.dtcs.len()
+                            );
+                            self.sim_ecus.push(ecu);
+                            self.sim_loaded = None;
+                        }
+                        Err(e) => self.status_text = e.to_string(),
+                    }
+                }
+            }
+            LauncherMessage::CancelSimulatorECU => self.sim_loaded = None,
+            LauncherMessage::LoadSeedKeyAlgorithm => match Self::load_seed_key_algorithm() {
+                Ok(Some(name)) => {
+                    self.status_text = format!("Loaded seed/key algorithm '{}'", name)
+                }
+                Ok(None) => {}
+                Err(e) => self.status_text = e.to_string(),
+            },
             LauncherMessage::LaunchRequested => {
                 if self.api_selection == API::Passthru {
                     match self.get_device_passthru() {
@@ -120,7 +251,26 @@ impl Launcher {
                         Err(x) => self.status_text = x.to_string(),
                     }
                 } else if self.api_selection == API::DPdu {
-                    // TODO D-PDU Launching
+                    match self.get_device_dpdu() {
+                        Ok((details, driver)) => {
+                            let mut server = DpduAPI::new(details, driver);
+                            if let Err(e) = server.open_device() {
+                                self.status_text = e.to_string()
+                            } else {
+                                // Ready to launch OVD!
+                                return Some(WindowMessage::StartApp(server.clone_box()));
+                            }
+                        }
+                        Err(x) => self.status_text = x.to_string(),
+                    }
+                } else if self.api_selection == API::Simulator {
+                    let mut server = SimulatorAPI::new(self.sim_ecus.clone());
+                    if let Err(e) = server.open_device() {
+                        self.status_text = e.to_string()
+                    } else {
+                        // Ready to launch OVD!
+                        return Some(WindowMessage::StartApp(server.clone_box()));
+                    }
                 } else if self.api_selection == API::SocketCAN {
                     #[cfg(target_os = "linux")]
                     {
@@ -155,6 +305,13 @@ impl Launcher {
                 LauncherMessage::SwitchAPI,
                 ButtonType::Primary,
             ))
+            .push(radio_btn(
+                API::Simulator,
+                "Simulator",
+                Some(self.api_selection),
+                LauncherMessage::SwitchAPI,
+                ButtonType::Primary,
+            ))
             .padding(20)
             .spacing(10)
             .align_items(Align::Center);
@@ -171,17 +328,130 @@ impl Launcher {
         }
 
         let mut contents = if self.api_selection == API::DPdu {
-            Column::new()
+            let mut c = Column::new()
                 .push(
                     get_launcher_image()
                         .width(Length::Units(300))
                         .height(Length::Units(300)),
                 )
                 .push(selection)
-                .push(Text::new(
-                    "D-PDU API is unimplemented, check back in a future release!",
+                .spacing(10);
+            if self.selected_device_dpdu.is_empty() {
+                // No D-PDU devices
+                c = c.push(text(
+                    "No D-PDU devices found on this system",
+                    TextType::Normal,
                 ))
+            } else {
+                c = c
+                    .push(Text::new("Select D-PDU device"))
+                    .push(picklist(
+                        &mut self.selection,
+                        &self.device_names_dpdu,
+                        Some(self.selected_device_dpdu.clone()),
+                        LauncherMessage::DeviceSelected,
+                    ))
+                    .push(
+                        button_coloured(&mut self.launch_state, "Launch OVD", ButtonType::Primary)
+                            .on_press(LaunchRequested),
+                    )
+                    .push(Text::new(&self.status_text));
+            }
+            c
+        } else if self.api_selection == API::Simulator {
+            let mut c = Column::new()
+                .push(
+                    get_launcher_image()
+                        .width(Length::Units(300))
+                        .height(Length::Units(300)),
+                )
+                .push(selection)
                 .spacing(10)
+                .push(
+                    button_coloured(
+                        &mut self.load_ecu_state,
+                        "Load ECU JSON",
+                        ButtonType::Secondary,
+                    )
+                    .on_press(LauncherMessage::LoadSimulatorECU),
+                );
+            if self.sim_loaded.is_some() {
+                c = c
+                    .push(Text::new("Variant to simulate"))
+                    .push(picklist(
+                        &mut self.sim_variant_state,
+                        &self.sim_variant_names,
+                        self.sim_variant_names
+                            .get(self.sim_config.variant_idx)
+                            .cloned(),
+                        LauncherMessage::SimVariantSelected,
+                    ))
+                    .push(Text::new("Connection (Diagnostic protocol)"))
+                    .push(picklist(
+                        &mut self.sim_connection_state,
+                        &self.sim_connection_names,
+                        self.sim_connection_names
+                            .get(self.sim_config.connection_idx)
+                            .cloned(),
+                        LauncherMessage::SimConnectionSelected,
+                    ))
+                    .push(checkbox(
+                        self.sim_config.store_dtcs,
+                        "Store the variant's DTCs",
+                        LauncherMessage::SimToggleDtcs,
+                    ))
+                    .push(checkbox(
+                        self.sim_config.response_pending_count != 0,
+                        "Respond slowly (ResponsePending)",
+                        LauncherMessage::SimToggleResponsePending,
+                    ))
+                    .push(
+                        Row::new()
+                            .spacing(10)
+                            .push(
+                                button_coloured(
+                                    &mut self.add_ecu_state,
+                                    "Add virtual ECU",
+                                    ButtonType::Primary,
+                                )
+                                .on_press(LauncherMessage::AddSimulatorECU),
+                            )
+                            .push(
+                                button_coloured(
+                                    &mut self.cancel_ecu_state,
+                                    "Cancel",
+                                    ButtonType::Secondary,
+                                )
+                                .on_press(LauncherMessage::CancelSimulatorECU),
+                            ),
+                    )
+            }
+            if self.sim_ecus.is_empty() {
+                c = c.push(text(
+                    "No virtual ECUs loaded. Load an OVD ECU JSON to simulate it",
+                    TextType::Normal,
+                ))
+            } else {
+                for ecu in &self.sim_ecus {
+                    c = c.push(text(
+                        format!(
+                            "{} ({:?}) - Request ID 0x{:04X}, Response ID 0x{:04X}, {} DTCs",
+                            ecu.name,
+                            ecu.protocol,
+                            ecu.request_id,
+                            ecu.response_id,
+                            ecu.dtcs.len()
+                        )
+                        .as_str(),
+                        TextType::Normal,
+                    ))
+                }
+                c = c.push(
+                    button_coloured(&mut self.launch_state, "Launch OVD", ButtonType::Primary)
+                        .on_press(LaunchRequested),
+                );
+            }
+            c.push(Text::new(&self.status_text))
         } else if self.api_selection == API::SocketCAN {
             let mut c = Column::new()
                 .push(
@@ -254,6 +524,14 @@ impl Launcher {
             }
             c.align_items(Align::Center)
         };
+        contents = contents.push(
+            button_coloured(
+                &mut self.load_algo_state,
+                "Load seed/key algorithm",
+                ButtonType::Secondary,
+            )
+            .on_press(LauncherMessage::LoadSeedKeyAlgorithm),
+        );
         contents = contents.align_items(Align::Center);
         container(contents)
             .center_x()
@@ -283,6 +561,78 @@ impl Launcher {
         }
     }
 
+    fn get_device_dpdu(&self) -> Result<(PduDevice, PduDrv)> {
+        match self
+            .device_list_dpdu
+            .iter()
+            .find(|d| d.name == self.selected_device_dpdu)
+        {
+            Some(d) => match PduDrv::load_lib(d.drv_path.clone()) {
+                Ok(lib) => Ok((d.clone(), lib)),
+                Err(_) => Err(DriverError(ComServerError {
+                    err_code: 99,
+                    err_desc: format!("Cannot locate driver at {}", d.drv_path),
+                })),
+            },
+            // This should NEVER happen.
+            None => Err(DriverError(ComServerError {
+                err_code: 99,
+                err_desc: "Located device is not valid??".to_string(),
+            })),
+        }
+    }
+
+    /// Asks the user for an OVD ECU JSON to simulate. The variant and connection to use
+    /// are picked afterwards
+    fn load_sim_ecu() -> Result<Option<OvdECU>> {
+        let f_path = match nfd::open_file_dialog(Some("json"), None) {
+            Ok(nfd::Response::Okay(f_path)) => f_path,
+            _ => return Ok(None),
+        };
+        let mut str = String::new();
+        File::open(f_path)
+            .and_then(|mut f| f.read_to_string(&mut str))
+            .map_err(|e| {
+                DriverError(ComServerError {
+                    err_code: 99,
+                    err_desc: format!("Cannot read ECU JSON: {}", e),
+                })
+            })?;
+        let ecu = serde_json::from_str::<OvdECU>(&str).map_err(|e| {
+            DriverError(ComServerError {
+                err_code: 99,
+                err_desc: format!("Invalid ECU JSON: {}", e),
+            })
+        })?;
+        if ecu.variants.is_empty() || ecu.connections.is_empty() {
+            return Err(DriverError(ComServerError {
+                err_code: 99,
+                err_desc: format!("ECU {} has no variants or connections", ecu.name),
+            }));
+        }
+        Ok(Some(ecu))
+    }
+
+    /// Asks the user for a seed/key library or script, and adds it to the algorithm registry
+    /// under its file name, so that it can be used to unlock ECUs
+    fn load_seed_key_algorithm() -> Result<Option<String>> {
+        let f_path = match nfd::open_file_dialog(None, None) {
+            Ok(nfd::Response::Okay(f_path)) => f_path,
+            _ => return Ok(None),
+        };
+        let name = std::path::Path::new(&f_path)
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| f_path.clone());
+        security::load_algorithm(name.clone(), &f_path).map_err(|e| {
+            DriverError(ComServerError {
+                err_code: 99,
+                err_desc: e.get_text(),
+            })
+        })?;
+        Ok(Some(name))
+    }
+
     fn find_devices_socketcan() -> Vec<String> {
         let cmd = Command::new("ip")
             .arg("-o")
@@ -1,115 +1,294 @@
+use std::{collections::BTreeMap, time::Instant};
+
 use crate::themes::{button_outlined, text, title_text, ButtonType, TextType, TitleSize};
 use crate::{
     commapi::{
         comm_api::{Capability, ComServer},
-        iface::{InterfaceConfig, InterfaceType, PayloadFlag, IFACE_CFG},
+        iface::InterfaceType,
         protocols::{
-            obd2::{service09::Service09Data, ObdServer},
-            DiagCfg, ProtocolServer,
+            executor::RequestHandle,
+            obd2::{
+                service01::{Monitor, MonitorState, ReadinessStatus},
+                service02::FreezeFrame,
+                service03::Service03,
+                service06::MonitorTestResult,
+                service07::Service07,
+                service09::Service09Data,
+                service10::Service0A,
+                OBDError, ObdServer,
+            },
+            DTCState, ProtocolResult, DTC,
         },
     },
     themes::button_coloured,
 };
-use iced::{button, Align, Button, Column, Element, Length, Row, Space, Text};
+use iced::{
+    button, scrollable, time, Align, Button, Column, Element, Length, Row, Scrollable, Space,
+    Subscription, Text,
+};
 
 #[derive(Debug, Clone)]
 pub enum OBDMessage {
+    InitAuto,
     InitIsoTP,
+    InitKLine,
+    InitJ1850,
     Disconnect,
     ChooseService(u8),
+    ShowReadiness,
+    PollTask(Instant),
+}
+
+/// Page of the readiness monitors. Not an OBD service
+const READINESS_PAGE: u8 = 0xFF;
+
+/// Result of a request run in the background
+enum TaskResult {
+    /// Bus the session was started on, and the session with the vehicle's information
+    Connect(&'static str, ProtocolResult<(ObdServer, Service09Data)>),
+    /// DTC page, the DTCs and freeze frame shown on it, and any errors reading them
+    Dtcs(u8, Vec<(u32, DTC)>, Option<FreezeFrame>, Vec<String>),
+    MonitorResults(Vec<MonitorTestResult>),
+    Readiness(Option<ReadinessStatus>, Vec<Monitor>),
 }
 
 #[derive(Debug, Clone)]
 pub struct OBDHome {
     server: Box<dyn ComServer>,
+    auto_state: button::State,
     kline_state: button::State,
+    j1850_state: button::State,
     can_state: button::State,
+    readiness_state: button::State,
     obd_server: Option<ObdServer>,
+    /// Request waiting for the ECU to respond
+    pending_task: Option<RequestHandle<TaskResult>>,
     in_session: bool,
     s09_data: Service09Data,
+    /// DTCs and the ID of the ECU which reported them
+    dtcs: Vec<(u32, DTC)>,
+    freeze_frame: Option<FreezeFrame>,
+    monitor_results: Vec<MonitorTestResult>,
+    /// Readiness since DTCs were last cleared
+    readiness: Option<ReadinessStatus>,
+    /// Monitors of this drive cycle
+    drive_cycle_monitors: Vec<Monitor>,
     curr_service: u8,
+    scroll_state: scrollable::State,
     service_btn_states: [button::State; 10],
+    /// Errors from the ECU, or why a request could not be sent
+    status_text: String,
 }
 
 impl OBDHome {
     pub(crate) fn new(server: Box<dyn ComServer>) -> Self {
         Self {
             server,
+            auto_state: Default::default(),
             kline_state: Default::default(),
+            j1850_state: Default::default(),
             can_state: Default::default(),
+            readiness_state: Default::default(),
             obd_server: None,
+            pending_task: None,
             in_session: false,
             s09_data: Default::default(),
+            dtcs: Vec::new(),
+            freeze_frame: None,
+            monitor_results: Vec::new(),
+            readiness: None,
+            drive_cycle_monitors: Vec::new(),
             curr_service: 0,
+            scroll_state: scrollable::State::default(),
             service_btn_states: [button::State::default(); 10],
+            status_text: "".into(),
         }
     }
 
     pub fn update(&mut self, msg: &OBDMessage) -> Option<OBDMessage> {
+        let needs_ecu = matches!(
+            msg,
+            OBDMessage::InitAuto
+                | OBDMessage::InitIsoTP
+                | OBDMessage::InitKLine
+                | OBDMessage::InitJ1850
+                | OBDMessage::ChooseService(_)
+                | OBDMessage::ShowReadiness
+        );
+        if needs_ecu && self.pending_task.is_some() {
+            self.status_text = "Still waiting for the ECU to respond to the last request".into();
+            return None;
+        }
+        if needs_ecu {
+            self.status_text.clear();
+        }
         match msg {
-            OBDMessage::InitIsoTP => {
-                // Try all the CAN IDs
-                for test_id in [0x07E8, 0x07E9, 0x07E0].iter() {
-                    let mut cfg = InterfaceConfig::new();
-                    cfg.add_param(IFACE_CFG::BAUDRATE, 500_000);
-                    cfg.add_param(IFACE_CFG::EXT_CAN_ADDR, 0);
-                    cfg.add_param(IFACE_CFG::EXT_ISOTP_ADDR, 0);
-
-                    let diag_cfg = DiagCfg {
-                        send_id: 0x07DF,
-                        recv_id: *test_id,
-                        global_id: None,
-                    };
-                    if let Ok(server) = ObdServer::start_diag_session(
-                        &self.server,
-                        InterfaceType::IsoTp,
-                        cfg,
-                        Some(vec![PayloadFlag::ISOTP_PAD_FRAME]),
-                        diag_cfg,
-                    ) {
-                        if let Ok(r) = server.req_service09(|x| Ok(x.get_everything(&server))) {
-                            self.s09_data = r;
-                        }
-                        self.obd_server = Some(server);
-                        self.in_session = true;
-                        self.curr_service = 0; // Reset to landing page of OBD
-                        println!("Found OBD receiver on address 0x{:04X}", test_id);
-                        break;
-                    }
-                }
-            }
+            OBDMessage::InitAuto => self.connect("any bus", ObdServer::start_auto_session),
+            OBDMessage::InitIsoTP => self.connect("CAN", ObdServer::start_can_session),
+            OBDMessage::InitKLine => self.connect("K-Line", ObdServer::start_kline_session),
+            OBDMessage::InitJ1850 => self.connect("J1850", ObdServer::start_j1850_session),
             OBDMessage::Disconnect => {
+                self.status_text.clear();
+                if let Some(task) = self.pending_task.take() {
+                    task.cancel()
+                }
                 if self.obd_server.is_some() {
                     self.obd_server.take(); // Take and destroy
                     self.in_session = false;
                 }
             }
             &OBDMessage::ChooseService(sid) => {
-                if sid == 0x03 {
-                    for dtc in &self.obd_server.as_ref().unwrap().read_errors().unwrap() {
-                        println!(
-                            "Code: {} State: {:?} Desc: {}",
-                            dtc.error,
-                            dtc.state,
-                            ObdServer::get_dtc_desc(dtc)
-                        )
+                if matches!(sid, 0x02 | 0x03 | 0x07 | 0x0A) {
+                    let server = self.obd_server.as_ref().unwrap().clone();
+                    self.pending_task = Some(RequestHandle::spawn(move || {
+                        // Stored, pending and permanent DTCs are shown together,
+                        // alongside the conditions when the fault was stored
+                        let reads: [fn(&ObdServer) -> OBDError<BTreeMap<u32, Vec<DTC>>>; 3] = [
+                            Service03::read_dtcs_by_ecu,
+                            Service07::read_dtcs_by_ecu,
+                            Service0A::read_dtcs_by_ecu,
+                        ];
+                        let mut dtcs = Vec::new();
+                        let mut errors = Vec::new();
+                        for read in &reads {
+                            match read(&server) {
+                                Ok(by_ecu) => {
+                                    for (id, ecu_dtcs) in by_ecu {
+                                        dtcs.extend(ecu_dtcs.into_iter().map(|dtc| (id, dtc)))
+                                    }
+                                }
+                                Err(e) => {
+                                    errors.push(format!("Could not read DTCs: {}", e.get_text()))
+                                }
+                            }
+                        }
+                        let freeze_frame = server
+                            .req_service02(|s| s.read_freeze_frame(&server, 0))
+                            .unwrap_or(None);
+                        Ok(TaskResult::Dtcs(sid, dtcs, freeze_frame, errors))
+                    }));
+                } else if sid == 0x06 {
+                    let server = self.obd_server.as_ref().unwrap().clone();
+                    self.pending_task = Some(RequestHandle::spawn(move || {
+                        let results = server
+                            .req_service06(|s| Ok(s.get_all_test_results(&server)))
+                            .unwrap_or_default();
+                        Ok(TaskResult::MonitorResults(results))
+                    }));
+                } else {
+                    self.curr_service = sid; // What service UI should we be in?
+                }
+            }
+            OBDMessage::ShowReadiness => {
+                let server = self.obd_server.as_ref().unwrap().clone();
+                self.pending_task = Some(RequestHandle::spawn(move || {
+                    let readiness = server.req_service01(|s| s.get_readiness(&server)).ok();
+                    let drive_cycle_monitors = match &readiness {
+                        Some(r) => server
+                            .req_service01(|s| s.get_drive_cycle_monitors(&server, r.ignition))
+                            .unwrap_or_default(),
+                        None => Vec::new(),
+                    };
+                    Ok(TaskResult::Readiness(readiness, drive_cycle_monitors))
+                }));
+            }
+            OBDMessage::PollTask(_) => {
+                if let Some(task) = self.pending_task.take() {
+                    match task.try_take() {
+                        None => self.pending_task = Some(task), // Still waiting for the ECU
+                        Some(Ok(res)) => self.on_task_complete(res),
+                        Some(Err(e)) => {
+                            self.status_text =
+                                format!("Error communicating with ECU: {}", e.get_text())
+                        }
                     }
-                    return None;
                 }
-                self.curr_service = sid; // What service UI should we be in?
             }
         }
         None
     }
 
+    /// Starts a session in the background with `start`, and reads the vehicle's
+    /// information once it has started
+    fn connect<F>(&mut self, bus: &'static str, start: F)
+    where
+        F: FnOnce(&Box<dyn ComServer>) -> ProtocolResult<ObdServer> + Send + 'static,
+    {
+        let comm_server = self.server.clone();
+        self.pending_task = Some(RequestHandle::spawn(move || {
+            let res = start(&comm_server).map(|server| {
+                let s09_data = server
+                    .req_service09(|x| Ok(x.get_everything(&server)))
+                    .unwrap_or_default();
+                (server, s09_data)
+            });
+            Ok(TaskResult::Connect(bus, res))
+        }));
+    }
+
+    fn on_task_complete(&mut self, res: TaskResult) {
+        match res {
+            TaskResult::Connect(bus, res) => match res {
+                Ok((server, s09_data)) => {
+                    self.s09_data = s09_data;
+                    self.obd_server = Some(server);
+                    self.in_session = true;
+                    self.curr_service = 0; // Reset to landing page of OBD
+                }
+                Err(e) => {
+                    self.status_text = format!("No OBD ECU found on {}: {}", bus, e.get_text())
+                }
+            },
+            TaskResult::Dtcs(sid, dtcs, freeze_frame, errors) => {
+                self.status_text = errors.join("\n");
+                self.dtcs = dtcs;
+                self.freeze_frame = freeze_frame;
+                self.curr_service = sid;
+            }
+            TaskResult::MonitorResults(results) => {
+                self.monitor_results = results;
+                self.curr_service = 0x06;
+            }
+            TaskResult::Readiness(readiness, drive_cycle_monitors) => {
+                self.readiness = readiness;
+                self.drive_cycle_monitors = drive_cycle_monitors;
+                self.curr_service = READINESS_PAGE;
+            }
+        }
+    }
+
+    pub fn subscription(&self) -> Subscription<OBDMessage> {
+        if self.pending_task.is_some() {
+            time::every(std::time::Duration::from_millis(50)).map(OBDMessage::PollTask)
+        } else {
+            Subscription::none()
+        }
+    }
+
     pub fn view(&mut self) -> Element<OBDMessage> {
-        if self.in_session {
+        let status_text = self.status_text.clone();
+        let page = if self.in_session {
             match self.curr_service {
+                0x02 | 0x03 | 0x07 | 0x0A => self.create_dtc_ui(),
+                0x06 => self.create_s06_ui(),
                 0x09 => self.create_s09_ui(),
+                READINESS_PAGE => self.create_readiness_ui(),
                 _ => self.create_main_ui(),
             }
         } else {
             self.create_connect_ui()
+        };
+        if status_text.is_empty() {
+            page
+        } else {
+            Column::new()
+                .push(page)
+                .push(
+                    Row::new()
+                        .padding(10)
+                        .push(text(&status_text, TextType::Warning)),
+                )
+                .into()
         }
     }
 
@@ -121,6 +300,8 @@ impl OBDHome {
 
         let mut support_list = self.obd_server.as_ref().unwrap().get_supported_services();
         support_list.sort_by(|x, y| y.0.partial_cmp(&x.0).unwrap());
+        // Readiness monitors are Service 01 PIDs
+        let s01_supported = support_list.iter().any(|(s, sid, _)| *s && *sid == 0x01);
 
         for (idx, state) in self.service_btn_states.iter_mut().enumerate() {
             let (supported, pos, name) = &support_list[idx];
@@ -131,24 +312,62 @@ impl OBDHome {
             row = row.push(btn)
         }
 
-        Column::new()
+        let iface_name = match self.obd_server.as_ref().unwrap().get_interface_type() {
+            InterfaceType::IsoTp => "ISO15765-4 (CAN)",
+            InterfaceType::Iso9141 => "ISO9141-2 (K-Line)",
+            InterfaceType::Iso14230 => "ISO14230-4 (K-Line)",
+            InterfaceType::J1850Vpw => "SAE J1850 VPW",
+            InterfaceType::J1850Pwm => "SAE J1850 PWM",
+            InterfaceType::DoIp => "ISO13400 (DoIP)",
+            InterfaceType::Can => "CAN",
+        };
+
+        let ecu_ids = self.obd_server.as_ref().unwrap().get_ecu_ids();
+
+        let mut readiness_btn = button_outlined(
+            &mut self.readiness_state,
+            "Readiness monitors",
+            ButtonType::Info,
+        );
+        if s01_supported {
+            readiness_btn = readiness_btn.on_press(OBDMessage::ShowReadiness)
+        }
+
+        let mut ui = Column::new()
             .padding(10)
             .spacing(10)
             .push(title_text("OBD Diagnostics", TitleSize::P2))
+            .push(text(
+                format!("Connected via {}", iface_name).as_str(),
+                TextType::Normal,
+            ))
+            .push(text(
+                format!("{} emissions ECU(s) found: {:04X?}", ecu_ids.len(), ecu_ids).as_str(),
+                TextType::Normal,
+            ))
             .push(
                 button_outlined(&mut self.can_state, "Disconnect", ButtonType::Primary)
                     .on_press(OBDMessage::Disconnect),
             )
-            .push(row)
-            .into()
+            .push(readiness_btn)
+            .push(row);
+        if self.pending_task.is_some() {
+            ui = ui.push(text("Waiting for the ECU...", TextType::Disabled));
+        }
+        ui.into()
     }
 
     pub fn create_connect_ui(&mut self) -> Element<OBDMessage> {
-        let obd_btn = button_outlined(
-            &mut self.kline_state,
-            "K-Line not implemented",
-            ButtonType::Danger,
-        ); // TODO Add K-LINE OBD
+        let caps = self.server.get_capabilities();
+        let supports_kline = caps.supports_iso9141() == Capability::Yes
+            || caps.supports_iso14230() == Capability::Yes;
+        let supports_j1850 = caps.supports_j1850vpw() == Capability::Yes
+            || caps.supports_j1850pwm() == Capability::Yes;
+        let obd_btn = button_outlined(&mut self.kline_state, "OBD over K-Line", ButtonType::Danger)
+            .on_press(OBDMessage::InitKLine);
+        let j1850_btn =
+            button_outlined(&mut self.j1850_state, "OBD over J1850", ButtonType::Danger)
+                .on_press(OBDMessage::InitJ1850);
         let can_btn = match self.server.get_capabilities().supports_iso15765() {
             Capability::Yes => {
                 button_outlined(&mut self.can_state, "OBD over CANBUS", ButtonType::Danger)
@@ -163,10 +382,14 @@ impl OBDHome {
         let mut btn_row = Row::new().padding(10).spacing(10);
 
         let mut connect_shown = false;
-        if self.server.get_capabilities().supports_iso9141() == Capability::Yes {
+        if supports_kline {
             btn_row = btn_row.push(obd_btn);
             connect_shown = true;
         }
+        if supports_j1850 {
+            btn_row = btn_row.push(j1850_btn);
+            connect_shown = true;
+        }
         if self.server.get_capabilities().supports_iso15765() == Capability::Yes {
             btn_row = btn_row.push(can_btn);
             connect_shown = true;
@@ -179,7 +402,7 @@ impl OBDHome {
                 .spacing(10)
                 .push(title_text("OBD Diagnostics", TitleSize::P2))
                 .push(text(
-                    "Unfortunately, your adapter does not support ISO9141 or ISO15765.",
+                    "Unfortunately, your adapter does not support ISO9141, ISO14230, J1850 or ISO15765.",
                     TextType::Warning,
                 ))
                 .push(btn_row)
@@ -187,16 +410,165 @@ impl OBDHome {
                 .into();
         }
 
-        Column::new()
+        let mut ui = Column::new()
             .padding(10)
             .spacing(10)
             .push(title_text("OBD Diagnostics", TitleSize::P2))
             .push(Space::with_height(Length::Units(10)))
+            .push(
+                button_outlined(
+                    &mut self.auto_state,
+                    "Detect OBD bus automatically",
+                    ButtonType::Primary,
+                )
+                .on_press(OBDMessage::InitAuto),
+            )
+            .push(text("Or connect using a specific bus", TextType::Normal))
             .push(btn_row)
-            .align_items(Align::Center)
+            .align_items(Align::Center);
+        if self.pending_task.is_some() {
+            ui = ui.push(text("Connecting...", TextType::Disabled));
+        }
+        ui.into()
+    }
+
+    pub fn create_s06_ui(&mut self) -> Element<OBDMessage> {
+        let failed = self
+            .monitor_results
+            .iter()
+            .filter(|r| !r.is_passed())
+            .count();
+        let summary = if self.monitor_results.is_empty() {
+            text("No test results found", TextType::Warning)
+        } else if failed == 0 {
+            text(
+                format!("All {} tests passed", self.monitor_results.len()).as_str(),
+                TextType::Success,
+            )
+        } else {
+            text(
+                format!("{} of {} tests failed", failed, self.monitor_results.len()).as_str(),
+                TextType::Danger,
+            )
+        };
+
+        let mut results = Scrollable::new(&mut self.scroll_state)
+            .height(Length::Fill)
+            .spacing(5);
+        for r in &self.monitor_results {
+            let limit = |x: Option<u16>| match x {
+                Some(l) => r.format_value(l),
+                None => "-".into(),
+            };
+            let (result, txt_type) = match r.is_passed() {
+                true => ("PASS", TextType::Success),
+                false => ("FAIL", TextType::Danger),
+            };
+            results = results.push(
+                Row::new()
+                    .spacing(10)
+                    .push(
+                        text(
+                            format!("{}\n{}", r.get_monitor_name(), r.get_test_name()).as_str(),
+                            TextType::Normal,
+                        )
+                        .width(Length::FillPortion(3)),
+                    )
+                    .push(
+                        text(r.format_value(r.value).as_str(), TextType::Normal)
+                            .width(Length::FillPortion(1)),
+                    )
+                    .push(
+                        text(format!("Min: {}", limit(r.min)).as_str(), TextType::Normal)
+                            .width(Length::FillPortion(1)),
+                    )
+                    .push(
+                        text(format!("Max: {}", limit(r.max)).as_str(), TextType::Normal)
+                            .width(Length::FillPortion(1)),
+                    )
+                    .push(text(result, txt_type).width(Length::FillPortion(1))),
+            );
+        }
+
+        Column::new()
+            .padding(10)
+            .spacing(10)
+            .push(title_text(
+                "On-board monitoring test results",
+                TitleSize::P3,
+            ))
+            .push(summary)
+            .push(results)
+            .push(
+                button_coloured(
+                    &mut self.service_btn_states[0],
+                    "Go back",
+                    ButtonType::Primary,
+                )
+                .on_press(OBDMessage::ChooseService(0)),
+            )
             .into()
     }
 
+    pub fn create_readiness_ui(&mut self) -> Element<OBDMessage> {
+        let monitor_text = |m: &Monitor, unsupported: &str| {
+            let (state, txt_type) = match m.state {
+                MonitorState::Complete => ("Complete", TextType::Success),
+                MonitorState::Incomplete => ("Incomplete", TextType::Warning),
+                MonitorState::Unsupported => (unsupported, TextType::Disabled),
+            };
+            text(format!("{}: {}", m.name, state).as_str(), txt_type)
+        };
+
+        let mut content = Column::new()
+            .padding(10)
+            .spacing(10)
+            .push(title_text("Readiness monitors", TitleSize::P3));
+        match &self.readiness {
+            Some(status) => {
+                let verdict = if status.is_ready_for_inspection() {
+                    text("Ready for inspection", TextType::Success)
+                } else {
+                    text("Not ready for inspection", TextType::Danger)
+                };
+                let mut since_clear = Column::new()
+                    .spacing(5)
+                    .width(Length::FillPortion(1))
+                    .push(title_text("Since DTCs cleared", TitleSize::P4));
+                for m in &status.monitors {
+                    since_clear = since_clear.push(monitor_text(m, "Unsupported"))
+                }
+                let mut this_cycle = Column::new()
+                    .spacing(5)
+                    .width(Length::FillPortion(1))
+                    .push(title_text("This drive cycle", TitleSize::P4));
+                if self.drive_cycle_monitors.is_empty() {
+                    this_cycle = this_cycle.push(text("Not supported by ECU", TextType::Disabled))
+                }
+                for m in &self.drive_cycle_monitors {
+                    this_cycle = this_cycle.push(monitor_text(m, "Disabled"))
+                }
+                content = content
+                    .push(verdict)
+                    .push(text(
+                        format!(
+                            "MIL {}, {} DTC(s) stored, {:?} ignition",
+                            if status.mil_on { "on" } else { "off" },
+                            status.dtc_count,
+                            status.ignition
+                        )
+                        .as_str(),
+                        TextType::Normal,
+                    ))
+                    .push(Row::new().spacing(10).push(since_clear).push(this_cycle));
+            }
+            None => {
+                content = content.push(text("Could not read readiness monitors", TextType::Danger))
+            }
+        }
+        content.push(self.add_back_button()).into()
+    }
+
     pub fn create_s09_ui(&mut self) -> Element<OBDMessage> {
         Column::new()
             .push(title_text("Vehicle information", TitleSize::P3))
@@ -220,6 +592,75 @@ impl OBDHome {
             .into()
     }
 
+    pub fn create_dtc_ui(&mut self) -> Element<OBDMessage> {
+        let mut dtc_view = Column::new()
+            .spacing(5)
+            .width(Length::FillPortion(1))
+            .push(title_text("Errors", TitleSize::P3));
+        for (state, name) in &[
+            (DTCState::Stored, "Stored"),
+            (DTCState::Pending, "Pending"),
+            (DTCState::Permanent, "Permanent"),
+        ] {
+            dtc_view = dtc_view.push(title_text(name, TitleSize::P4));
+            let dtcs: Vec<&(u32, DTC)> = self
+                .dtcs
+                .iter()
+                .filter(|(_, d)| d.state == *state)
+                .collect();
+            if dtcs.is_empty() {
+                dtc_view = dtc_view.push(text("No errors", TextType::Success));
+            }
+            for (id, dtc) in dtcs {
+                dtc_view = dtc_view.push(text(
+                    format!(
+                        "{} - {} (ECU {:02X})",
+                        dtc.error,
+                        ObdServer::get_dtc_desc(dtc),
+                        id
+                    )
+                    .as_str(),
+                    TextType::Normal,
+                ));
+            }
+        }
+
+        let mut freeze_frame_view = Column::new()
+            .spacing(5)
+            .width(Length::FillPortion(1))
+            .push(title_text("Freeze frame", TitleSize::P3));
+        match &self.freeze_frame {
+            Some(frame) => {
+                freeze_frame_view = freeze_frame_view.push(text(
+                    format!("Stored by {}", frame.dtc.error).as_str(),
+                    TextType::Normal,
+                ));
+                for (name, value) in frame.get_display_values() {
+                    freeze_frame_view = freeze_frame_view.push(text(
+                        format!("{}: {}", name, value).as_str(),
+                        TextType::Normal,
+                    ));
+                }
+            }
+            None => {
+                freeze_frame_view =
+                    freeze_frame_view.push(text("No freeze frame stored", TextType::Normal))
+            }
+        }
+
+        Column::new()
+            .padding(10)
+            .spacing(10)
+            .push(
+                Row::new()
+                    .spacing(10)
+                    .push(dtc_view)
+                    .push(freeze_frame_view),
+            )
+            .push(self.add_back_button())
+            .into()
+    }
+
     pub fn add_back_button(&mut self) -> Element<OBDMessage> {
         button_coloured(
             &mut self.service_btn_states[0],
@@ -216,6 +216,8 @@ impl Application for MainWindow {
                 batch.push(tracer.subscription().map(WindowMessage::CanTracer))
             } else if let WindowState::DiagHome(d) = &self.state {
                 batch.push(d.subscription().map(WindowMessage::DiagHome))
+            } else if let WindowState::OBDTools(o) = &self.state {
+                batch.push(o.subscription().map(WindowMessage::OBDTools))
             }
             Subscription::batch(batch)
         }
@@ -44,6 +44,16 @@ pub enum ParamDecodeError {
     StringDecodeFailure(FromUtf8Error)
 }
 
+#[derive(Debug)]
+pub enum ParamEncodeError {
+    NotImplemented,
+    BitRangeError,
+    /// Input could not be parsed for the data format of the parameter
+    InvalidInput(String),
+    /// Input is outside of the parameters valid bounds
+    OutOfBounds(f32),
+}
+
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Limit {
@@ -51,6 +61,25 @@ pub struct Limit {
     lower: f32,
 }
 
+impl Limit {
+    pub fn new(lower: f32, upper: f32) -> Self {
+        Self { upper, lower }
+    }
+
+    pub fn get_lower(&self) -> f32 {
+        self.lower
+    }
+
+    pub fn get_upper(&self) -> f32 {
+        self.upper
+    }
+
+    /// Returns true if value is within the limit (Inclusive)
+    pub fn contains(&self, value: f32) -> bool {
+        value >= self.lower && value <= self.upper
+    }
+}
+
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Parameter {
@@ -162,6 +191,79 @@ impl Parameter {
         }
     }
 
+    /// Encodes a user input into the parameters location in a payload. This is the
+    /// reverse of [decode_value_to_string](Parameter::decode_value_to_string), so input
+    /// should be formatted the same way (Without the unit)
+    pub fn encode_value_from_string(&self, input: &str, dest: &mut [u8]) -> std::result::Result<(), ParamEncodeError> {
+        let input = input.trim();
+        let start_byte = self.start_bit/8;
+        let end_byte = (self.start_bit+self.length_bits)/8;
+        match &self.data_format {
+            DataFormat::HexDump => {
+                let bytes = Self::parse_byte_list(input, 16)?;
+                self.copy_bytes(&bytes, dest, false)
+            }
+            DataFormat::Binary => {
+                let bytes = Self::parse_byte_list(&input.replace('b', ""), 2)?;
+                self.copy_bytes(&bytes, dest, false)
+            }
+            DataFormat::String(s) => {
+                let mut bytes: Vec<u8> = Vec::new();
+                if *s == StringEncoding::Utf16 {
+                    for c in input.encode_utf16() {
+                        match self.byte_order {
+                            ParamByteOrder::BigEndian => bytes.extend_from_slice(&c.to_be_bytes()),
+                            ParamByteOrder::LittleEndian => bytes.extend_from_slice(&c.to_le_bytes()),
+                        }
+                    }
+                } else {
+                    bytes.extend_from_slice(input.as_bytes());
+                }
+                if bytes.len() > end_byte-start_byte {
+                    return Err(ParamEncodeError::InvalidInput(format!("String can be at most {} bytes", end_byte-start_byte)))
+                }
+                // Strings shorter than the parameter are padded with spaces
+                bytes.resize(end_byte-start_byte, b' ');
+                self.copy_bytes(&bytes, dest, true)
+            }
+            DataFormat::Bool { pos_name, neg_name } => {
+                let pos = pos_name.clone().unwrap_or("True".into());
+                let neg = neg_name.clone().unwrap_or("False".into());
+                let raw = if input.eq_ignore_ascii_case(&pos) || input == "1" {
+                    1
+                } else if input.eq_ignore_ascii_case(&neg) || input == "0" {
+                    0
+                } else {
+                    return Err(ParamEncodeError::InvalidInput(format!("Expected '{}' or '{}'", pos, neg)))
+                };
+                self.set_number(dest, raw)
+            }
+            DataFormat::Table(t) => {
+                match t.iter().find(|v| v.name.eq_ignore_ascii_case(input)) {
+                    Some(v) => self.set_number(dest, v.start as u32),
+                    None => Err(ParamEncodeError::InvalidInput(format!("'{}' is not a valid option", input)))
+                }
+            }
+            DataFormat::Identical | DataFormat::Linear { multiplier: _, offset: _ } => {
+                let value = input.parse::<f32>().map_err(|_| ParamEncodeError::InvalidInput(format!("'{}' is not a number", input)))?;
+                if let Some(bounds) = &self.valid_bounds {
+                    if !bounds.contains(value) {
+                        return Err(ParamEncodeError::OutOfBounds(value))
+                    }
+                }
+                let raw = match &self.data_format {
+                    DataFormat::Linear { multiplier, offset } => ((value - offset) / multiplier).round(),
+                    _ => value.round(),
+                };
+                if raw < 0.0 || (self.length_bits < 32 && raw >= (1u64 << self.length_bits) as f32) {
+                    return Err(ParamEncodeError::OutOfBounds(value))
+                }
+                self.set_number(dest, raw as u32)
+            }
+            _ => Err(ParamEncodeError::NotImplemented)
+        }
+    }
+
     /// Returns if the data type is capable of being plotted on a chart or not
     pub fn can_plot(&self) -> bool {
         match &self.data_format {
@@ -189,6 +291,57 @@ impl Parameter {
         }
     }
 
+    /// Parses a list of bytes such as "[0A, 0B]" or "0A 0B"
+    fn parse_byte_list(input: &str, radix: u32) -> std::result::Result<Vec<u8>, ParamEncodeError> {
+        input.trim_start_matches('[').trim_end_matches(']')
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|x| !x.is_empty())
+            .map(|x| u8::from_str_radix(x, radix).map_err(|_| ParamEncodeError::InvalidInput(format!("'{}' is not a valid byte", x))))
+            .collect()
+    }
+
+    /// Copies bytes into the parameters location. If `exact` is not set, fewer bytes
+    /// than the parameter can hold may be provided
+    fn copy_bytes(&self, bytes: &[u8], dest: &mut [u8], exact: bool) -> std::result::Result<(), ParamEncodeError> {
+        let start_byte = self.start_bit/8;
+        let end_byte = (self.start_bit+self.length_bits)/8;
+        if end_byte > dest.len() {
+            return Err(ParamEncodeError::BitRangeError)
+        }
+        if bytes.len() > end_byte-start_byte || (exact && bytes.len() != end_byte-start_byte) {
+            return Err(ParamEncodeError::InvalidInput(format!("Expected {} bytes", end_byte-start_byte)))
+        }
+        dest[start_byte..start_byte+bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Reverse of get_number
+    fn set_number(&self, dest: &mut [u8], value: u32) -> std::result::Result<(), ParamEncodeError> {
+        if self.length_bits > 32 || self.start_bit + self.length_bits > dest.len() * 8 {
+            return Err(ParamEncodeError::BitRangeError)
+        }
+        if self.length_bits < 32 && value >> self.length_bits != 0 {
+            return Err(ParamEncodeError::BitRangeError)
+        }
+        if self.length_bits <= 8 {
+            dest.set_bits(self.start_bit..self.start_bit+self.length_bits, value as u8);
+        } else {
+            let num_bytes = self.length_bits.div_ceil(8);
+            let buf: Vec<u8> = match self.byte_order {
+                ParamByteOrder::BigEndian => Vec::from(&value.to_be_bytes()[4-num_bytes..]),
+                ParamByteOrder::LittleEndian => Vec::from(&value.to_le_bytes()[..num_bytes]),
+            };
+            let mut start = self.start_bit;
+            for b in buf {
+                let max_write = min(self.start_bit + self.length_bits, start + 8);
+                let mask = (0xFFu16 >> (8 - (max_write - start))) as u8;
+                dest.set_bits(start..max_write, b & mask);
+                start += 8;
+            }
+        }
+        Ok(())
+    }
+
     fn get_number(&self, resp: &[u8]) -> std::result::Result<u32, ParamDecodeError> {
         if self.length_bits <= 32 {
             let result = std::panic::catch_unwind(||{
@@ -231,4 +384,96 @@ impl Parameter {
             Err(ParamDecodeError::BitRangeError)
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::diag::TableData;
+
+    fn param(start_bit: usize, length_bits: usize, byte_order: ParamByteOrder, data_format: DataFormat) -> Parameter {
+        Parameter {
+            name: "Test".into(),
+            unit: "".into(),
+            start_bit,
+            length_bits,
+            byte_order,
+            data_format,
+            valid_bounds: None,
+        }
+    }
+
+    /// Encodes `input` into a copy of `payload`, and decodes it back again
+    fn round_trip(p: &Parameter, payload: &[u8], input: &str) -> (Vec<u8>, String) {
+        let mut dest = Vec::from(payload);
+        p.encode_value_from_string(input, &mut dest).unwrap();
+        let decoded = p.decode_value_to_string(&dest).unwrap();
+        (dest, decoded)
+    }
+
+    #[test]
+    fn set_number() {
+        let mut dest = [0xFF; 4];
+        param(8, 16, ParamByteOrder::BigEndian, DataFormat::Identical).set_number(&mut dest, 0x1234).unwrap();
+        assert_eq!(dest, [0xFF, 0x12, 0x34, 0xFF]);
+        param(8, 16, ParamByteOrder::LittleEndian, DataFormat::Identical).set_number(&mut dest, 0x1234).unwrap();
+        assert_eq!(dest, [0xFF, 0x34, 0x12, 0xFF]);
+        param(0, 32, ParamByteOrder::LittleEndian, DataFormat::Identical).set_number(&mut dest, 0x01020304).unwrap();
+        assert_eq!(dest, [0x04, 0x03, 0x02, 0x01]);
+
+        // Only the bits of the parameter are changed
+        let p = param(3, 2, ParamByteOrder::BigEndian, DataFormat::Identical);
+        let mut dest = [0x00];
+        p.set_number(&mut dest, 0b11).unwrap();
+        assert_eq!(dest, [0b0001_1000]);
+        assert_eq!(p.get_number(&dest).unwrap(), 0b11);
+
+        // Values too big for the parameter, or parameters outside of the payload
+        assert!(p.set_number(&mut dest, 0b100).is_err());
+        assert!(param(8, 16, ParamByteOrder::BigEndian, DataFormat::Identical).set_number(&mut dest, 1).is_err());
+    }
+
+    #[test]
+    fn encode_numbers() {
+        let p = param(8, 16, ParamByteOrder::BigEndian, DataFormat::Identical);
+        assert_eq!(round_trip(&p, &[0x22, 0x00, 0x00], "1234"), (vec![0x22, 0x04, 0xD2], "1234".into()));
+        let p = param(0, 32, ParamByteOrder::LittleEndian, DataFormat::Identical);
+        assert_eq!(round_trip(&p, &[0x00; 4], "16909060").1, "16909060");
+
+        let mut p = param(0, 8, ParamByteOrder::BigEndian, DataFormat::Linear { multiplier: 0.5, offset: -40.0 });
+        assert_eq!(round_trip(&p, &[0x00], "20"), (vec![120], "20".into()));
+        assert!(matches!(p.encode_value_from_string("100", &mut [0x00]), Err(ParamEncodeError::OutOfBounds(_))));
+        p.valid_bounds = Some(Limit::new(-10.0, 50.0));
+        assert!(matches!(p.encode_value_from_string("-20", &mut [0x00]), Err(ParamEncodeError::OutOfBounds(_))));
+        assert!(matches!(p.encode_value_from_string("hot", &mut [0x00]), Err(ParamEncodeError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn encode_named_values() {
+        let p = param(3, 1, ParamByteOrder::BigEndian, DataFormat::Bool { pos_name: Some("On".into()), neg_name: Some("Off".into()) });
+        assert_eq!(round_trip(&p, &[0x81], "on"), (vec![0x89], "On".into()));
+        assert_eq!(round_trip(&p, &[0x89], "Off"), (vec![0x81], "Off".into()));
+        assert!(p.encode_value_from_string("True", &mut [0x00]).is_err());
+
+        let table = vec![
+            TableData { name: "Closed".into(), start: 0.0, end: 0.0 },
+            TableData { name: "Open".into(), start: 2.0, end: 2.0 },
+        ];
+        let p = param(0, 8, ParamByteOrder::BigEndian, DataFormat::Table(table));
+        assert_eq!(round_trip(&p, &[0x00], "open"), (vec![0x02], "Open".into()));
+        assert!(p.encode_value_from_string("Ajar", &mut [0x00]).is_err());
+    }
+
+    #[test]
+    fn encode_bytes() {
+        let p = param(8, 16, ParamByteOrder::BigEndian, DataFormat::HexDump);
+        assert_eq!(round_trip(&p, &[0x00; 3], "[0A, 0B]"), (vec![0x00, 0x0A, 0x0B], "[0A, 0B]".into()));
+
+        let p = param(0, 8, ParamByteOrder::BigEndian, DataFormat::Binary);
+        assert_eq!(round_trip(&p, &[0x00], "[b00010110]"), (vec![0x16], "[b00010110]".into()));
+
+        // Short strings are padded with spaces
+        let p = param(0, 32, ParamByteOrder::BigEndian, DataFormat::String(StringEncoding::ASCII));
+        assert_eq!(round_trip(&p, &[0x00; 4], "AB"), (b"AB  ".to_vec(), "AB  ".into()));
+        assert!(p.encode_value_from_string("ABCDE", &mut [0x00; 4]).is_err());
+    }
+}
@@ -29,6 +29,21 @@ pub struct Connection {
     pub server_type: ServerType,
     /// Receive ID for receiving data from the ECU
     pub recv_id: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default = "Option::default")]
+    /// Optional P2 timeout in milliseconds.
+    /// How long the ECU has to respond to a request
+    pub p2_timeout: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default = "Option::default")]
+    /// Optional P2* timeout in milliseconds.
+    /// How long the ECU has to respond after replying with ResponsePending
+    pub p2_ext_timeout: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default = "Option::default")]
+    /// Optional S3 timer in milliseconds.
+    /// How often tester present is sent to keep the diagnostic session alive
+    pub s3_timer: Option<u32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
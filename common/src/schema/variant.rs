@@ -23,6 +23,10 @@ pub struct ECUVariantDefinition {
     /// Miscellaneous functions
     #[serde(default = "Vec::new")]
     pub functions: Vec<Service>,
+    /// Routines are functions the ECU runs by itself once started, such as a self test.
+    /// They can be stopped early, and their results are requested once they complete
+    #[serde(default = "Vec::new")]
+    pub routines: Vec<Service>,
     /// These are functions that simply retrieve data from an ECU, and do not
     /// write anything to it. For example, asking the ECU for current fuel rail pressure
     #[serde(default = "Vec::new")]